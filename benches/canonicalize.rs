@@ -0,0 +1,77 @@
+//! Compares `fs::canonicalize` with imp's `PathCache` on a config-like set of
+//! sources that share a deep common root.
+//!
+//! Run with `cargo bench --bench canonicalize`.
+
+#[allow(dead_code)]
+#[path = "../src/pathcache.rs"]
+mod pathcache;
+
+use pathcache::PathCache;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
+
+const DIRECTORIES: usize = 20;
+const FILES_PER_DIRECTORY: usize = 100;
+const ROUNDS: usize = 20;
+
+fn main() {
+    let root = std::env::temp_dir().join(format!("imp-bench-{}", std::process::id()));
+    let real_root = root.join("real/mnt/persist/system/home/user");
+    fs::create_dir_all(&real_root).expect("create bench tree");
+
+    // Reach the tree through a symlinked ancestor, like /persist -> /mnt/persist
+    let linked_root = root.join("persist");
+    std::os::unix::fs::symlink(root.join("real/mnt/persist"), &linked_root)
+        .expect("create symlinked ancestor");
+
+    let mut sources: Vec<PathBuf> = Vec::new();
+    for d in 0..DIRECTORIES {
+        let dir = real_root.join(format!("dir{}", d));
+        fs::create_dir_all(&dir).expect("create bench directory");
+        for f in 0..FILES_PER_DIRECTORY {
+            fs::write(dir.join(format!("file{}", f)), b"").expect("create bench file");
+            sources.push(linked_root.join(format!("system/home/user/dir{}/file{}", d, f)));
+        }
+    }
+
+    // realpath() looks up every component of every path
+    let uncached_lookups: usize = sources.iter().map(|s| s.components().count() - 1).sum();
+    let started = Instant::now();
+    for _ in 0..ROUNDS {
+        for source in &sources {
+            fs::canonicalize(source).expect("canonicalize");
+        }
+    }
+    let uncached = started.elapsed() / ROUNDS as u32;
+
+    let cache = PathCache::new();
+    let mut cached_lookups = 0;
+    let started = Instant::now();
+    for _ in 0..ROUNDS {
+        cache.clear();
+        for source in &sources {
+            let resolved = cache.canonicalize(source).expect("cached canonicalize");
+            debug_assert_eq!(resolved, fs::canonicalize(source).unwrap());
+        }
+        cached_lookups = cache.lookups();
+    }
+    let cached = started.elapsed() / ROUNDS as u32;
+
+    println!(
+        "{} sources under a shared root, {} rounds",
+        sources.len(),
+        ROUNDS
+    );
+    println!(
+        "  fs::canonicalize: {:>8.2?} per apply, ~{} lookups",
+        uncached, uncached_lookups
+    );
+    println!(
+        "  PathCache:        {:>8.2?} per apply, {} lookups",
+        cached, cached_lookups
+    );
+
+    fs::remove_dir_all(&root).ok();
+}
@@ -0,0 +1,169 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::path::{Component, Path, PathBuf};
+
+use crate::generation::{Generation, LinkKind};
+
+/// Name of the manifest, always the first member of an archive
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Directory inside the archive holding the persisted data
+const DATA_DIR: &str = "data";
+
+/// Describes what an archive contains and where each piece of data belongs
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub generation: u64,
+    pub created_at: DateTime<Utc>,
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Source path when the archive was created
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub kind: LinkKind,
+    /// Source path relative to its persistence root; the data is stored
+    /// under `data/<path>` and restored to `<new root>/<path>`
+    pub path: PathBuf,
+}
+
+/// The part of `source` below its persistence root. Sources are built as
+/// `<root>/<target path>`, so the root is whatever precedes the components
+/// the source has in common with the end of its target.
+fn relative_to_persistence_root(source: &Path, target: &Path) -> PathBuf {
+    let source_components: Vec<Component> = source.components().collect();
+    let common = source_components
+        .iter()
+        .rev()
+        .zip(target.components().rev())
+        .take_while(|(s, t)| **s == *t && matches!(s, Component::Normal(_)))
+        .count();
+
+    if common == 0 {
+        // Not laid out like a persistence root; keep the whole path
+        return source.strip_prefix("/").unwrap_or(source).to_path_buf();
+    }
+    source_components[source_components.len() - common..]
+        .iter()
+        .collect()
+}
+
+/// Write the sources of `gen` and a manifest to a zstd-compressed tar at
+/// `output`. Returns the manifest and the sources left out because they don't exist.
+pub fn create_archive(gen: &Generation, output: &Path) -> Result<(Manifest, Vec<PathBuf>)> {
+    let mut manifest = Manifest {
+        generation: gen.number,
+        created_at: gen.created_at,
+        entries: Vec::new(),
+    };
+    let mut missing = Vec::new();
+
+    for gen_symlink in &gen.symlinks {
+        if gen_symlink.source.symlink_metadata().is_err() {
+            missing.push(gen_symlink.source.clone());
+            continue;
+        }
+        manifest.entries.push(ManifestEntry {
+            source: gen_symlink.source.clone(),
+            target: gen_symlink.target.clone(),
+            kind: gen_symlink.kind.unwrap_or(if gen_symlink.is_mount() {
+                LinkKind::Mount
+            } else {
+                LinkKind::Symlink
+            }),
+            path: relative_to_persistence_root(&gen_symlink.source, &gen_symlink.target),
+        });
+    }
+
+    let file =
+        File::create(output).context(format!("Failed to create archive: {}", output.display()))?;
+    let encoder = zstd::Encoder::new(file, 0).context("Failed to start zstd compression")?;
+    let mut builder = tar::Builder::new(encoder);
+    // Keep symlinks inside the persisted data as symlinks
+    builder.follow_symlinks(false);
+
+    let manifest_json =
+        serde_json::to_vec_pretty(&manifest).context("Failed to serialize archive manifest")?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, MANIFEST_NAME, manifest_json.as_slice())
+        .context("Failed to write archive manifest")?;
+
+    for entry in &manifest.entries {
+        let name = Path::new(DATA_DIR).join(&entry.path);
+        let source = &entry.source;
+        if source.symlink_metadata()?.is_dir() {
+            builder.append_dir_all(&name, source)
+        } else {
+            builder.append_path_with_name(source, &name)
+        }
+        .context(format!("Failed to archive: {}", source.display()))?;
+    }
+
+    builder
+        .into_inner()
+        .and_then(|encoder| encoder.finish())
+        .context(format!("Failed to finish archive: {}", output.display()))?;
+
+    Ok((manifest, missing))
+}
+
+/// Restore the data in `archive` below `root`, which must not exist yet or be
+/// empty. Returns the archive's manifest.
+pub fn extract_archive(archive: &Path, root: &Path) -> Result<Manifest> {
+    if fs::read_dir(root).is_ok_and(|mut entries| entries.next().is_some()) {
+        anyhow::bail!(
+            "Cannot restore into {}: the directory is not empty",
+            root.display()
+        );
+    }
+
+    let file =
+        File::open(archive).context(format!("Failed to open archive: {}", archive.display()))?;
+    let decoder = zstd::Decoder::new(file).context("Failed to start zstd decompression")?;
+    let mut tar = tar::Archive::new(decoder);
+    tar.set_preserve_permissions(true);
+    tar.set_preserve_mtime(true);
+    // Only root can hand files back to their original owners
+    tar.set_preserve_ownerships(nix::unistd::geteuid().is_root());
+
+    // Unpack next to the data's final place so it can be moved in with renames
+    let staging = root.join(".imp-unarchive");
+    fs::create_dir_all(&staging).context(format!("Failed to create: {}", staging.display()))?;
+    tar.unpack(&staging)
+        .context(format!("Failed to extract archive: {}", archive.display()))?;
+
+    let manifest_path = staging.join(MANIFEST_NAME);
+    let manifest: Manifest = match fs::read_to_string(&manifest_path) {
+        Ok(contents) => {
+            serde_json::from_str(&contents).context("Failed to parse archive manifest")?
+        }
+        Err(_) => {
+            fs::remove_dir_all(&staging)?;
+            anyhow::bail!("{} is not an imp archive (no manifest)", archive.display());
+        }
+    };
+
+    let data = staging.join(DATA_DIR);
+    if data.exists() {
+        for entry in fs::read_dir(&data).context(format!("Failed to read: {}", data.display()))? {
+            let entry = entry?;
+            let dest = root.join(entry.file_name());
+            fs::rename(entry.path(), &dest)
+                .context(format!("Failed to restore: {}", dest.display()))?;
+        }
+    }
+    fs::remove_dir_all(&staging).context(format!("Failed to remove: {}", staging.display()))?;
+
+    Ok(manifest)
+}
@@ -0,0 +1,259 @@
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::generation::{Generation, GenerationManager};
+
+/// How directory targets are stored when they are backed up
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupCompression {
+    /// Rename the directory, e.g. `<name>.imp-backup.<timestamp>`
+    #[default]
+    None,
+    /// Pack the directory into `<name>.imp-backup.<timestamp>.tar.zst`
+    Zstd,
+    /// Pack the directory into `<name>.imp-backup.<timestamp>.tar.gz`
+    Gzip,
+}
+
+/// File name extension of a backup archived with zstd
+const ZSTD_EXTENSION: &str = ".tar.zst";
+
+/// File name extension of a backup archived with gzip
+const GZIP_EXTENSION: &str = ".tar.gz";
+
+/// Name of the backed up directory inside the archive. The tar crate skips a
+/// "." entry when unpacking, so the directory itself gets a name to keep its
+/// permissions and owner.
+const ARCHIVED_DIR: &str = "content";
+
+impl BackupCompression {
+    /// Extension appended to the backup name of a directory
+    pub fn extension(self) -> &'static str {
+        match self {
+            BackupCompression::None => "",
+            BackupCompression::Zstd => ZSTD_EXTENSION,
+            BackupCompression::Gzip => GZIP_EXTENSION,
+        }
+    }
+
+    /// The compression a backup was archived with, going by its name
+    pub fn of_backup(path: &Path) -> BackupCompression {
+        let name = path.to_string_lossy();
+        if name.ends_with(ZSTD_EXTENSION) {
+            BackupCompression::Zstd
+        } else if name.ends_with(GZIP_EXTENSION) {
+            BackupCompression::Gzip
+        } else {
+            BackupCompression::None
+        }
+    }
+}
+
+/// Whether `path` is a directory backup packed by `compress_dir`
+pub fn is_compressed(path: &Path) -> bool {
+    BackupCompression::of_backup(path) != BackupCompression::None
+        && fs::symlink_metadata(path).is_ok_and(|m| m.is_file())
+}
+
+/// Pack the content of `dir` (including its own permissions) into a tar file
+/// at `archive`, compressed with `compression`
+pub fn compress_dir(dir: &Path, archive: &Path, compression: BackupCompression) -> Result<()> {
+    let file =
+        File::create(archive).context(format!("Failed to create backup: {}", archive.display()))?;
+    let finished = match compression {
+        BackupCompression::Zstd => {
+            let encoder =
+                zstd::Encoder::new(file, 0).context("Failed to start zstd compression")?;
+            archive_dir(dir, encoder)?.finish().map(drop)
+        }
+        BackupCompression::Gzip => {
+            let encoder = GzEncoder::new(file, flate2::Compression::default());
+            archive_dir(dir, encoder)?.finish().map(drop)
+        }
+        BackupCompression::None => {
+            anyhow::bail!("No compression to archive {} with", dir.display())
+        }
+    };
+    finished.context(format!("Failed to finish backup: {}", archive.display()))?;
+    Ok(())
+}
+
+/// Write `dir` as a tar stream to `writer`, returning the writer to be finished
+fn archive_dir<W: Write>(dir: &Path, writer: W) -> Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    // Keep symlinks inside the backed up directory as symlinks
+    builder.follow_symlinks(false);
+    builder
+        .append_dir_all(ARCHIVED_DIR, dir)
+        .context(format!("Failed to archive: {}", dir.display()))?;
+    builder
+        .into_inner()
+        .context(format!("Failed to archive: {}", dir.display()))
+}
+
+/// Unpack a backup made by `compress_dir` to `dir`, which must not exist or be
+/// empty. The compression is recognized by the archive's extension.
+pub fn extract_dir(archive: &Path, dir: &Path) -> Result<()> {
+    let file =
+        File::open(archive).context(format!("Failed to open backup: {}", archive.display()))?;
+    let decoder: Box<dyn Read> = match BackupCompression::of_backup(archive) {
+        BackupCompression::Zstd => {
+            Box::new(zstd::Decoder::new(file).context("Failed to start zstd decompression")?)
+        }
+        BackupCompression::Gzip => Box::new(GzDecoder::new(file)),
+        BackupCompression::None => {
+            anyhow::bail!("Not a compressed backup: {}", archive.display())
+        }
+    };
+    let mut tar = tar::Archive::new(decoder);
+    tar.set_preserve_permissions(true);
+    tar.set_preserve_mtime(true);
+    // Only root can hand files back to their original owners
+    tar.set_preserve_ownerships(nix::unistd::geteuid().is_root());
+
+    // Unpack next to the target so the directory can be moved in with a rename
+    let mut staging_name = dir.file_name().unwrap_or_default().to_os_string();
+    staging_name.push(".imp-restore");
+    let staging = dir.with_file_name(staging_name);
+    fs::create_dir_all(&staging).context(format!("Failed to create: {}", staging.display()))?;
+    tar.unpack(&staging)
+        .context(format!("Failed to extract backup: {}", archive.display()))?;
+    fs::rename(staging.join(ARCHIVED_DIR), dir)
+        .context(format!("Failed to restore backup to: {}", dir.display()))?;
+    fs::remove_dir_all(&staging).context(format!("Failed to remove: {}", staging.display()))?;
+    Ok(())
+}
+
+/// A backup that was removed to stay under the configured size limit
+pub struct EvictedBackup {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// Total size in bytes of a file, symlink or directory tree (symlinks are not followed)
+pub fn backup_size(path: &Path) -> Result<u64> {
+    let metadata = fs::symlink_metadata(path)
+        .context(format!("Failed to get metadata for: {}", path.display()))?;
+
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+
+    let mut total = metadata.len();
+    for entry in fs::read_dir(path).context(format!("Failed to read: {}", path.display()))? {
+        total += backup_size(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// Evict the oldest backups (by mtime) across all generations until the total
+/// size is at most `max_bytes`. Backups belonging to `protected_generation`
+/// are counted but never evicted.
+pub fn enforce_backup_limit(
+    generation_manager: &GenerationManager,
+    max_bytes: u64,
+    protected_generation: u64,
+) -> Result<Vec<EvictedBackup>> {
+    let generations = generation_manager.load_generations()?;
+
+    let protected: HashSet<&PathBuf> = generations
+        .iter()
+        .filter(|g| g.number == protected_generation)
+        .flat_map(|g| g.symlinks.iter().filter_map(|s| s.backup_path.as_ref()))
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut total = 0u64;
+    let mut candidates: Vec<(SystemTime, PathBuf, u64)> = Vec::new();
+
+    for gen in &generations {
+        for gen_symlink in &gen.symlinks {
+            let backup_path = match &gen_symlink.backup_path {
+                Some(p) => p,
+                None => continue,
+            };
+            if !seen.insert(backup_path.clone()) {
+                continue;
+            }
+            let metadata = match fs::symlink_metadata(backup_path) {
+                Ok(m) => m,
+                Err(_) => continue, // Already gone (e.g. restored)
+            };
+
+            let bytes = backup_size(backup_path)?;
+            total += bytes;
+
+            if !protected.contains(backup_path) {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                candidates.push((modified, backup_path.clone(), bytes));
+            }
+        }
+    }
+
+    // Oldest first
+    candidates.sort_by_key(|c| c.0);
+
+    let mut evicted = Vec::new();
+    for (_, path, bytes) in candidates {
+        if total <= max_bytes {
+            break;
+        }
+
+        remove_backup(&path).context(format!("Failed to evict backup: {}", path.display()))?;
+
+        total -= bytes;
+        evicted.push(EvictedBackup { path, bytes });
+    }
+
+    if !evicted.is_empty() {
+        let paths: Vec<PathBuf> = evicted.iter().map(|e| e.path.clone()).collect();
+        generation_manager.forget_backups(&paths)?;
+    }
+
+    Ok(evicted)
+}
+
+/// Delete the backups of a generation's entries from disk and forget them,
+/// so leaving the generation doesn't restore them. Returns the deleted paths.
+pub fn discard_backups(
+    generation_manager: &GenerationManager,
+    generation: &Generation,
+) -> Result<Vec<PathBuf>> {
+    let mut discarded = Vec::new();
+    for backup_path in generation
+        .symlinks
+        .iter()
+        .filter_map(|s| s.backup_path.as_ref())
+    {
+        if fs::symlink_metadata(backup_path).is_err() || discarded.contains(backup_path) {
+            continue;
+        }
+        remove_backup(backup_path).context(format!(
+            "Failed to discard backup: {}",
+            backup_path.display()
+        ))?;
+        discarded.push(backup_path.clone());
+    }
+
+    if !discarded.is_empty() {
+        generation_manager.forget_backups(&discarded)?;
+    }
+    Ok(discarded)
+}
+
+fn remove_backup(path: &Path) -> std::io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
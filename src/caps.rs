@@ -0,0 +1,171 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::config::Symlink;
+
+/// Capabilities that applying entries can need
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capability {
+    /// Changing ownership, for entries with `user` or `group`
+    Chown,
+    /// Mounting, for directory, device and image entries
+    SysAdmin,
+}
+
+impl Capability {
+    /// Every capability imp checks for
+    pub const ALL: [Capability; 2] = [Capability::Chown, Capability::SysAdmin];
+
+    /// Bit number in the capability sets of /proc/<pid>/status
+    fn bit(self) -> u32 {
+        match self {
+            Capability::Chown => 0,
+            Capability::SysAdmin => 21,
+        }
+    }
+
+    /// What the capability is needed for, for messages
+    pub fn purpose(self) -> &'static str {
+        match self {
+            Capability::Chown => "ownership",
+            Capability::SysAdmin => "mounts",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::Chown => write!(f, "CAP_CHOWN"),
+            Capability::SysAdmin => write!(f, "CAP_SYS_ADMIN"),
+        }
+    }
+}
+
+/// The capabilities applying `symlinks` needs, each with the targets that
+/// need it. Mounts are only counted when they are really performed.
+pub fn required(symlinks: &[Symlink], mounts: bool) -> BTreeMap<Capability, Vec<PathBuf>> {
+    let mut required: BTreeMap<Capability, Vec<PathBuf>> = BTreeMap::new();
+
+    for symlink in symlinks {
+        if symlink.is_directory && mounts {
+            required
+                .entry(Capability::SysAdmin)
+                .or_default()
+                .push(symlink.target.clone());
+        }
+        if symlink.user.is_some() || symlink.group.is_some() {
+            required
+                .entry(Capability::Chown)
+                .or_default()
+                .push(symlink.target.clone());
+        }
+    }
+
+    required
+}
+
+/// Effective capability set of this process, from the CapEff line of /proc/self/status
+fn effective() -> Result<u64> {
+    let status =
+        std::fs::read_to_string("/proc/self/status").context("Failed to read /proc/self/status")?;
+    let value = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .context("No CapEff in /proc/self/status")?;
+    u64::from_str_radix(value.trim(), 16).context(format!("Invalid CapEff: {}", value.trim()))
+}
+
+/// Whether this process has `capability` in its effective set
+pub fn has(capability: Capability) -> Result<bool> {
+    Ok(effective()? & (1 << capability.bit()) != 0)
+}
+
+/// The required capabilities this process lacks, each with the targets that need it
+pub fn missing(symlinks: &[Symlink], mounts: bool) -> Result<BTreeMap<Capability, Vec<PathBuf>>> {
+    let effective = effective()?;
+    let mut missing = required(symlinks, mounts);
+    missing.retain(|capability, _| effective & (1 << capability.bit()) == 0);
+    Ok(missing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn symlinks(config: &str) -> Vec<Symlink> {
+        config.parse::<Config>().unwrap().to_symlinks()
+    }
+
+    #[test]
+    fn directories_need_sys_admin_when_mounting() {
+        let symlinks = symlinks(
+            r#"
+            [persistence."/persist"]
+            directories = ["/var/lib/a", "/var/lib/b"]
+            files = ["/etc/machine-id"]
+            "#,
+        );
+
+        let required = required(&symlinks, true);
+        assert_eq!(
+            required.get(&Capability::SysAdmin),
+            Some(&vec![
+                PathBuf::from("/var/lib/a"),
+                PathBuf::from("/var/lib/b")
+            ])
+        );
+        assert!(!required.contains_key(&Capability::Chown));
+    }
+
+    #[test]
+    fn no_mounts_need_nothing_without_ownership() {
+        let symlinks = symlinks(
+            r#"
+            [persistence."/persist"]
+            directories = ["/var/lib/a"]
+            "#,
+        );
+
+        assert!(required(&symlinks, false).is_empty());
+    }
+
+    #[test]
+    fn ownership_needs_chown() {
+        let symlinks = symlinks(
+            r#"
+            [persistence."/persist"]
+            directories = [
+                { directory = "/var/lib/a", user = "nobody" },
+                { directory = "/var/lib/b", group = "nogroup" },
+                "/var/lib/c",
+            ]
+            "#,
+        );
+
+        let required = required(&symlinks, false);
+        assert_eq!(
+            required.get(&Capability::Chown),
+            Some(&vec![
+                PathBuf::from("/var/lib/a"),
+                PathBuf::from("/var/lib/b")
+            ])
+        );
+        assert!(!required.contains_key(&Capability::SysAdmin));
+    }
+
+    #[test]
+    fn files_need_nothing() {
+        let symlinks = symlinks(
+            r#"
+            [persistence."/persist"]
+            files = ["/etc/machine-id"]
+            "#,
+        );
+
+        assert!(required(&symlinks, true).is_empty());
+    }
+}
@@ -1,9 +1,75 @@
+use anyhow::Context;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+
+use crate::backup::BackupCompression;
+use crate::generation::StateFormat;
+use crate::identity::{self, FsIdentity};
+use crate::mounts::Atime;
+use crate::problems;
+use crate::remote;
+use crate::retention::Retention;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Config path that reads the configuration from stdin
+pub const STDIN_PATH: &str = "-";
+
+/// Syntax of a configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// The format to parse `path` as: `forced` if given (by `--config-format`),
+    /// otherwise JSON for a `.json` extension, YAML for `.yaml` or `.yml` and
+    /// TOML for anything else
+    pub fn for_path(path: &Path, forced: Option<Self>) -> Self {
+        forced.unwrap_or(match path.extension() {
+            Some(ext) if ext == "json" => ConfigFormat::Json,
+            Some(ext) if ext == "yaml" || ext == "yml" => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml,
+        })
+    }
+
+    /// File extension for a configuration written in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Json => "json",
+            ConfigFormat::Yaml => "yaml",
+        }
+    }
+
+    /// Parse a configuration written in this format
+    pub fn parse(self, contents: &str) -> anyhow::Result<Config> {
+        let mut config: Config = match self {
+            ConfigFormat::Toml => toml::from_str(contents)?,
+            ConfigFormat::Json => serde_json::from_str(contents)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(contents)?,
+        };
+        config.expand_ownership_vars()?;
+        Ok(config)
+    }
+}
+
+/// Read stdin once; every later load of `-` in the same run sees the same config
+fn read_stdin() -> anyhow::Result<&'static str> {
+    static STDIN: OnceLock<String> = OnceLock::new();
+    if let Some(contents) = STDIN.get() {
+        return Ok(contents);
+    }
+    let contents = std::io::read_to_string(std::io::stdin()).context("Failed to read stdin")?;
+    Ok(STDIN.get_or_init(|| contents))
+}
 
 /// Main configuration structure
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct Config {
     /// Persistence configurations, keyed by persistence directory path
     #[serde(default)]
@@ -12,22 +78,194 @@ pub struct Config {
     /// Optional: Where to store generation metadata (defaults to ~/.local/share/imp)
     #[serde(default = "default_state_dir")]
     pub state_dir: PathBuf,
+
+    /// Optional: Format of the generation state file (default "json")
+    #[serde(default)]
+    pub state_format: StateFormat,
+
+    /// Optional: Keep each machine's generations apart, keyed by its machine id,
+    /// for a state dir shared between machines (default false)
+    #[serde(default)]
+    pub per_machine: bool,
+
+    /// Optional: Back up existing targets instead of removing them (default false)
+    #[serde(default)]
+    pub backup: bool,
+
+    /// Optional: Maximum total size of all backups; the oldest are evicted first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_backup_bytes: Option<u64>,
+
+    /// Optional: Suffix inserted between a target's name and the backup timestamp
+    #[serde(default = "default_backup_suffix")]
+    pub backup_suffix: String,
+
+    /// Optional: Pack backed up directories into a compressed archive (default "none")
+    #[serde(default)]
+    pub backup_compression: BackupCompression,
+
+    /// Optional: Back up content found at targets when switching generations (default false)
+    #[serde(default)]
+    pub backup_on_switch: bool,
+
+    /// Optional: Replace mounts that the previous generation also had at a
+    /// target without unmounting them first (default false)
+    #[serde(default)]
+    pub atomic_remount: bool,
+
+    /// Optional: Stage and verify the whole generation before taking down
+    /// the active one (default false)
+    #[serde(default)]
+    pub two_phase: bool,
+
+    /// Optional: Programs that check the resolved entries (as JSON on stdin)
+    /// during validation; any non-zero exit aborts the apply
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub validators: Vec<PathBuf>,
+
+    /// Optional: Generations to keep after each apply; the rest are deleted
+    #[serde(default)]
+    pub retention: Retention,
+
+    /// Directory that every target path is placed under (set via `--target-root`)
+    #[serde(skip)]
+    pub target_root: Option<PathBuf>,
+
+    /// Treat every entry as optional (set via `--skip-missing`)
+    #[serde(skip)]
+    pub skip_missing: bool,
+
+    /// Optional: Per-environment overrides, merged over `persistence` by `with_env`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, EnvConfig>,
+
+    /// The environment whose overrides were merged in, if any
+    #[serde(skip)]
+    pub selected_env: Option<String>,
 }
 
+/// Overrides for one environment (a hostname or an `IMP_ENV`/`--env` name)
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+pub struct EnvConfig {
+    /// Persistence directories that replace or extend the base ones
+    #[serde(default)]
+    pub persistence: HashMap<String, PersistenceConfig>,
+}
+
+/// Add `other`'s persistence directories to `into`, refusing one that is
+/// already defined
+fn merge_persistence(
+    into: &mut HashMap<String, PersistenceConfig>,
+    other: HashMap<String, PersistenceConfig>,
+    path: &Path,
+) -> anyhow::Result<()> {
+    for (persist_dir, persist_config) in other {
+        if into.contains_key(&persist_dir) {
+            anyhow::bail!(
+                "Cannot merge {}: persistence directory {} is already defined by another config",
+                path.display(),
+                persist_dir
+            );
+        }
+        into.insert(persist_dir, persist_config);
+    }
+    Ok(())
+}
+
+/// Replace `$NAME` and `${NAME}` with the value of environment variable NAME,
+/// failing if it isn't set. `$$` is a literal `$`, as is a `$` not followed
+/// by a name.
+fn expand_vars(value: &str) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        let (name, remainder) = if let Some(braced) = after.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .context(format!("Unclosed ${{ in {:?}", value))?;
+            (&braced[..end], &braced[end + 1..])
+        } else if let Some(remainder) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = remainder;
+            continue;
+        } else {
+            let end = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(after.len());
+            if end == 0 || after.starts_with(|c: char| c.is_ascii_digit()) {
+                expanded.push('$');
+                rest = after;
+                continue;
+            }
+            (&after[..end], &after[end..])
+        };
+
+        let substituted = std::env::var(name).map_err(|_| {
+            anyhow::anyhow!(
+                "{:?} uses environment variable {} which is not set",
+                value,
+                name
+            )
+        })?;
+        expanded.push_str(&substituted);
+        rest = remainder;
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// Whether `path` is a glob pattern of config files rather than one file:
+/// it doesn't exist as given and contains a wildcard
+pub fn is_pattern(path: &Path) -> bool {
+    let text = path.to_string_lossy();
+    remote::as_url(path).is_none() && text.contains(['*', '?', '[']) && !path.exists()
+}
+
+/// The config files matching a pattern, in sorted order
+pub fn expand_pattern(pattern: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let text = pattern.to_string_lossy();
+    let mut paths: Vec<PathBuf> = glob::glob(&text)
+        .context(format!("Invalid config pattern: {}", text))?
+        .filter_map(Result::ok)
+        .filter(|p| p.is_file())
+        .collect();
+    if paths.is_empty() {
+        anyhow::bail!("No config files match: {}", text);
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Environment variable selecting an `[env.<name>]` section
+pub const ENV_VAR: &str = "IMP_ENV";
+
 fn default_state_dir() -> PathBuf {
     dirs::data_local_dir()
         .unwrap_or_else(|| PathBuf::from("."))
         .join("imp")
 }
 
+fn default_backup_suffix() -> String {
+    crate::symlink::DEFAULT_BACKUP_SUFFIX.to_string()
+}
+
 /// Configuration for a single persistence directory
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct PersistenceConfig {
     /// Whether to hide mounts (optional, default false)
     #[serde(default)]
     pub hide_mounts: bool,
 
+    /// Optional: Directory that relative directory and file paths are taken relative to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<PathBuf>,
+
     /// Directories to persist
     #[serde(default)]
     pub directories: Vec<DirectoryEntry>,
@@ -35,10 +273,335 @@ pub struct PersistenceConfig {
     /// Files to persist
     #[serde(default)]
     pub files: Vec<FileEntry>,
+
+    /// How an entry's source path is derived from its target (default: mirror)
+    #[serde(default, skip_serializing_if = "SourceLayout::is_mirror")]
+    pub source_layout: SourceLayout,
+
+    /// Optional: Prefix removed from each target before mirroring it under the
+    /// persistence directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_prefix: Option<PathBuf>,
+
+    /// Optional: Persistence roots to place sources on instead of the group's
+    /// key, in order of preference (see `entry_root`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roots: Vec<PathBuf>,
+
+    /// Plain symlinks into persisted locations, created after every mount
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<LinkEntry>,
+}
+
+/// A convenience symlink pointing into an already persisted location, e.g.
+/// `/var/lib/app -> /data/app` where `/data` is a persisted directory
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+pub struct LinkEntry {
+    /// Where the symlink is created (relative paths are placed under `base`)
+    pub link: String,
+
+    /// What the symlink points to, inside a persisted directory or at a persisted file
+    pub to: PathBuf,
+}
+
+/// How the source of an entry is laid out inside its persistence directory
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceLayout {
+    /// The full target path, e.g. /etc/nixos -> <persist_dir>/etc/nixos
+    #[default]
+    Mirror,
+    /// Only the target's file name, e.g. /etc/nixos -> <persist_dir>/nixos
+    Flat,
+}
+
+impl SourceLayout {
+    fn is_mirror(&self) -> bool {
+        *self == SourceLayout::Mirror
+    }
+}
+
+/// What happens to an entry whose source doesn't exist
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingSource {
+    /// Create the source: an empty directory, or a file seeded from the target
+    #[default]
+    Create,
+    /// Leave the entry out of the generation (what `optional = true` does)
+    Skip,
+    /// Fail validation, for sources that must be restored before applying
+    Error,
+    /// Leave the target an empty directory and create the source from what
+    /// is first written there (directories only)
+    Lazy,
+}
+
+impl MissingSource {
+    /// The policy as written in the config
+    fn name(&self) -> &'static str {
+        match self {
+            MissingSource::Create => "create",
+            MissingSource::Skip => "skip",
+            MissingSource::Error => "error",
+            MissingSource::Lazy => "lazy",
+        }
+    }
+}
+
+/// The policy of an entry: `on_missing_source` if set, else what `optional` implies
+fn missing_source_policy(
+    optional: bool,
+    on_missing_source: Option<MissingSource>,
+) -> MissingSource {
+    match on_missing_source {
+        Some(policy) => policy,
+        None if optional => MissingSource::Skip,
+        None => MissingSource::Create,
+    }
+}
+
+/// Whether the filesystem holding `root` has any space left for a new source.
+/// A root that doesn't exist (e.g. an unplugged disk) has none.
+fn has_free_space(root: &Path) -> bool {
+    nix::sys::statvfs::statvfs(root).is_ok_and(|stat| stat.blocks_available() > 0)
+}
+
+/// The machine's hostname, used to pick an `[env.<name>]` section by default
+pub fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+}
+
+/// Set to 1 to treat every filesystem as case-insensitive (testing only)
+const CASE_INSENSITIVE_VAR: &str = "IMP_CASE_INSENSITIVE";
+
+/// Whether names in `dir` (or its nearest existing ancestor, where it will be
+/// created) are case-insensitive. Probed by creating a lower-case temporary
+/// file and looking it up in upper case; a directory that can't be written
+/// to is taken to be case-sensitive.
+fn is_case_insensitive(dir: &Path) -> bool {
+    if std::env::var(CASE_INSENSITIVE_VAR).is_ok_and(|v| v == "1") {
+        return true;
+    }
+    let Some(dir) = dir.ancestors().find(|p| p.is_dir()) else {
+        return false;
+    };
+
+    let name = format!(".imp-case-probe-{}", std::process::id());
+    let probe = dir.join(&name);
+    if std::fs::File::create_new(&probe).is_err() {
+        return false;
+    }
+    let insensitive = dir.join(name.to_uppercase()).symlink_metadata().is_ok();
+    let _ = std::fs::remove_file(&probe);
+    insensitive
+}
+
+impl PersistenceConfig {
+    /// Target path of an entry, with relative paths placed under `base`
+    fn entry_target(&self, path: &str) -> PathBuf {
+        let path = PathBuf::from(path);
+        match &self.base {
+            Some(base) if path.is_relative() => base.join(path),
+            _ => path,
+        }
+    }
+
+    /// The root an entry's source is placed on. Without `roots` that is the
+    /// persistence directory itself. Otherwise it is the entry's explicit
+    /// `root`, else the first root already holding the source (so data stays
+    /// where it is), else the first root with free space, else the first root.
+    fn entry_root(&self, persist_dir: &str, root: Option<&Path>, target: &Path) -> PathBuf {
+        if self.roots.is_empty() {
+            return PathBuf::from(persist_dir);
+        }
+        if let Some(root) = root {
+            return root.to_path_buf();
+        }
+        let holding = self
+            .roots
+            .iter()
+            .find(|root| self.entry_source(root, target).symlink_metadata().is_ok());
+        let free = || self.roots.iter().find(|root| has_free_space(root));
+        holding.or_else(free).unwrap_or(&self.roots[0]).clone()
+    }
+
+    /// Source path of an entry's target according to `sourceLayout` and `stripPrefix`
+    fn entry_source(&self, persist_dir: impl AsRef<Path>, target: &Path) -> PathBuf {
+        let relative = match self.source_layout {
+            SourceLayout::Flat => target.file_name().map(Path::new).unwrap_or(target),
+            SourceLayout::Mirror => self
+                .strip_prefix
+                .as_deref()
+                .and_then(|prefix| target.strip_prefix(prefix).ok())
+                .unwrap_or_else(|| target.strip_prefix("/").unwrap_or(target)),
+        };
+        persist_dir.as_ref().join(relative)
+    }
+
+    /// Check that `roots` are absolute and that every explicit `root` is one of them
+    fn validate_roots(&self, persist_dir: &str) -> anyhow::Result<()> {
+        for root in &self.roots {
+            if root.is_relative() {
+                anyhow::bail!(
+                    "Invalid root {} in {}: must be an absolute path",
+                    root.display(),
+                    persist_dir
+                );
+            }
+        }
+        let explicit = self
+            .directories
+            .iter()
+            .map(|d| (d.path(), d.root()))
+            .chain(self.files.iter().map(|f| (f.path(), f.root())));
+        for (path, root) in explicit {
+            if let Some(root) = root {
+                if !self.roots.iter().any(|r| r == root) {
+                    anyhow::bail!(
+                        "{} in {} sets root {}, which is not one of the group's roots",
+                        path,
+                        persist_dir,
+                        root.display()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that each entry's on_missing_source policy fits the entry.
+    /// `optional = true` means skip, so it can't be paired with another policy
+    fn validate_missing_source(&self, persist_dir: &str) -> anyhow::Result<()> {
+        let policies = self
+            .directories
+            .iter()
+            .filter_map(|entry| match entry {
+                DirectoryEntry::Detailed {
+                    directory,
+                    optional: true,
+                    on_missing_source: Some(policy),
+                    ..
+                } => Some((directory, policy)),
+                _ => None,
+            })
+            .chain(self.files.iter().filter_map(|entry| match entry {
+                FileEntry::Detailed {
+                    file,
+                    optional: true,
+                    on_missing_source: Some(policy),
+                    ..
+                } => Some((file, policy)),
+                _ => None,
+            }));
+        for (path, policy) in policies {
+            if *policy != MissingSource::Skip {
+                anyhow::bail!(
+                    "{} in {} sets optional = true, which contradicts on_missing_source = \"{}\"",
+                    path,
+                    persist_dir,
+                    policy.name()
+                );
+            }
+        }
+
+        // A lazy source is created from a directory target's content
+        for file_entry in &self.files {
+            if file_entry.on_missing_source() == MissingSource::Lazy {
+                anyhow::bail!(
+                    "File {} in {} sets on_missing_source = \"lazy\", which only directories support",
+                    file_entry.path(),
+                    persist_dir
+                );
+            }
+        }
+        for dir_entry in &self.directories {
+            if let DirectoryEntry::Detailed {
+                device,
+                image,
+                on_missing_source: Some(MissingSource::Lazy),
+                ..
+            } = dir_entry
+            {
+                if device.is_some() || image.is_some() {
+                    anyhow::bail!(
+                        "Directory {} in {} mounts a device or image, which can't be combined \
+                         with on_missing_source = \"lazy\"",
+                        dir_entry.path(),
+                        persist_dir
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `stripPrefix` covers every entry and that the flat layout
+    /// gives every entry its own source
+    fn validate_layout(&self, persist_dir: &str) -> anyhow::Result<()> {
+        if let Some(prefix) = &self.strip_prefix {
+            if prefix.is_relative() {
+                anyhow::bail!(
+                    "Invalid stripPrefix {} in {}: must be an absolute path",
+                    prefix.display(),
+                    persist_dir
+                );
+            }
+            if self.source_layout == SourceLayout::Flat {
+                anyhow::bail!(
+                    "{} sets stripPrefix, which can't be combined with sourceLayout = \"flat\"",
+                    persist_dir
+                );
+            }
+        }
+
+        let paths = self
+            .directories
+            .iter()
+            .map(DirectoryEntry::path)
+            .chain(self.files.iter().map(FileEntry::path));
+        let mut sources: HashMap<PathBuf, PathBuf> = HashMap::new();
+        for path in paths {
+            let target = self.entry_target(path);
+            if let Some(prefix) = &self.strip_prefix {
+                if target
+                    .strip_prefix(prefix)
+                    .map_or(true, |rest| rest.as_os_str().is_empty())
+                {
+                    anyhow::bail!(
+                        "{} in {} is not under stripPrefix {}",
+                        target.display(),
+                        persist_dir,
+                        prefix.display()
+                    );
+                }
+            }
+            // Only the flat layout can map two different targets to one source
+            if self.source_layout != SourceLayout::Flat {
+                continue;
+            }
+            let source = self.entry_source(persist_dir, &target);
+            if let Some(other) = sources.insert(source.clone(), target.clone()) {
+                anyhow::bail!(
+                    "{} and {} in {} would both use source {}",
+                    other.display(),
+                    target.display(),
+                    persist_dir,
+                    source.display()
+                );
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Represents a directory entry - can be a simple string or a detailed object
-#[derive(Debug, Deserialize, Serialize, Clone)]
+// Entries are parsed once per command, so their size doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 #[serde(untagged)]
 pub enum DirectoryEntry {
     /// Simple string path
@@ -52,6 +615,44 @@ pub enum DirectoryEntry {
         group: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         mode: Option<String>,
+        /// Permissions of the mount point itself, set before mounting
+        /// (default: `mode`, or the source's mode for a new mount point)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mount_point_mode: Option<String>,
+        /// Glob patterns (relative to the target) of existing content to move
+        /// into the source before mounting
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        migrate: Vec<String>,
+        /// Skip this entry instead of creating its source if the source is missing
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        optional: bool,
+        /// What to do if the source is missing (default: create it, or skip
+        /// the entry if `optional` is set)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        on_missing_source: Option<MissingSource>,
+        /// Create missing parent directories of the target (default: true)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        create_parents: Option<bool>,
+        /// Mount this block device at the target instead of bind mounting a source
+        #[serde(skip_serializing_if = "Option::is_none")]
+        device: Option<PathBuf>,
+        /// Mount this filesystem image (through a loop device) at the target
+        /// instead of bind mounting a source
+        #[serde(skip_serializing_if = "Option::is_none")]
+        image: Option<PathBuf>,
+        /// Filesystem type of `device` or `image` (detected if not set)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fstype: Option<String>,
+        /// Place the source on this root of the group's `roots`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        root: Option<PathBuf>,
+        /// Move the target's backup back when the entry is removed (default: true)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        restore_backup: Option<bool>,
+        /// Access time updates on the mount, "noatime" or "relatime"
+        /// (default: those of the source's filesystem)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        atime: Option<Atime>,
     },
 }
 
@@ -87,10 +688,105 @@ impl DirectoryEntry {
             DirectoryEntry::Detailed { mode, .. } => mode.as_deref(),
         }
     }
+
+    /// Get the mount point mode (if specified)
+    pub fn mount_point_mode(&self) -> Option<&str> {
+        match self {
+            DirectoryEntry::Simple(_) => None,
+            DirectoryEntry::Detailed {
+                mount_point_mode, ..
+            } => mount_point_mode.as_deref(),
+        }
+    }
+
+    /// Get the migrate patterns (empty if not specified)
+    pub fn migrate(&self) -> &[String] {
+        match self {
+            DirectoryEntry::Simple(_) => &[],
+            DirectoryEntry::Detailed { migrate, .. } => migrate,
+        }
+    }
+
+    /// What happens to the entry when its source is missing
+    pub fn on_missing_source(&self) -> MissingSource {
+        match self {
+            DirectoryEntry::Simple(_) => MissingSource::Create,
+            DirectoryEntry::Detailed {
+                optional,
+                on_missing_source,
+                ..
+            } => missing_source_policy(*optional, *on_missing_source),
+        }
+    }
+
+    /// Explicit root of the group's `roots` to place the source on
+    pub fn root(&self) -> Option<&Path> {
+        match self {
+            DirectoryEntry::Simple(_) => None,
+            DirectoryEntry::Detailed { root, .. } => root.as_deref(),
+        }
+    }
+
+    /// Whether missing parent directories of the target are created
+    pub fn create_parents(&self) -> bool {
+        match self {
+            DirectoryEntry::Simple(_) => true,
+            DirectoryEntry::Detailed { create_parents, .. } => create_parents.unwrap_or(true),
+        }
+    }
+
+    /// Whether removing the entry restores the target's backup
+    pub fn restore_backup(&self) -> bool {
+        match self {
+            DirectoryEntry::Simple(_) => true,
+            DirectoryEntry::Detailed { restore_backup, .. } => restore_backup.unwrap_or(true),
+        }
+    }
+
+    /// Access time behavior set for the mount, if any
+    pub fn atime(&self) -> Option<Atime> {
+        match self {
+            DirectoryEntry::Simple(_) => None,
+            DirectoryEntry::Detailed { atime, .. } => *atime,
+        }
+    }
+
+    /// The device or image mounted at the target instead of a bind mount, if any
+    pub fn device_mount(&self) -> Option<DeviceMount> {
+        match self {
+            DirectoryEntry::Simple(_) => None,
+            DirectoryEntry::Detailed {
+                device,
+                image,
+                fstype,
+                ..
+            } => {
+                let (path, is_image) = match (device, image) {
+                    (Some(device), _) => (device, false),
+                    (None, Some(image)) => (image, true),
+                    (None, None) => return None,
+                };
+                Some(DeviceMount {
+                    path: path.clone(),
+                    is_image,
+                    fstype: fstype.clone(),
+                })
+            }
+        }
+    }
+}
+
+/// A block device or filesystem image mounted at a directory target
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceMount {
+    pub path: PathBuf,
+    /// Whether `path` is an image file that needs a loop device
+    pub is_image: bool,
+    pub fstype: Option<String>,
 }
 
 /// Represents a file entry - can be a simple string or a detailed object
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 #[serde(untagged)]
 pub enum FileEntry {
     /// Simple string path
@@ -100,6 +796,27 @@ pub enum FileEntry {
         file: String,
         #[serde(skip_serializing_if = "Option::is_none")]
         parent_directory: Option<ParentDirectoryConfig>,
+        /// If the source is itself a symlink, recreate that symlink at the
+        /// target instead of linking to its resolved path
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        preserve_symlink: bool,
+        /// Skip this entry instead of creating its source if the source is missing
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        optional: bool,
+        /// What to do if the source is missing (default: create it, or skip
+        /// the entry if `optional` is set)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        on_missing_source: Option<MissingSource>,
+        /// Create missing parent directories of the target (default: only
+        /// when `parent_directory` is set)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        create_parents: Option<bool>,
+        /// Place the source on this root of the group's `roots`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        root: Option<PathBuf>,
+        /// Move the target's backup back when the entry is removed (default: true)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        restore_backup: Option<bool>,
     },
 }
 
@@ -121,10 +838,60 @@ impl FileEntry {
             } => parent_directory.as_ref(),
         }
     }
+
+    /// Whether a symlinked source should be copied as-is rather than resolved
+    pub fn preserve_symlink(&self) -> bool {
+        match self {
+            FileEntry::Simple(_) => false,
+            FileEntry::Detailed {
+                preserve_symlink, ..
+            } => *preserve_symlink,
+        }
+    }
+
+    /// What happens to the entry when its source is missing
+    pub fn on_missing_source(&self) -> MissingSource {
+        match self {
+            FileEntry::Simple(_) => MissingSource::Create,
+            FileEntry::Detailed {
+                optional,
+                on_missing_source,
+                ..
+            } => missing_source_policy(*optional, *on_missing_source),
+        }
+    }
+
+    /// Explicit root of the group's `roots` to place the source on
+    pub fn root(&self) -> Option<&Path> {
+        match self {
+            FileEntry::Simple(_) => None,
+            FileEntry::Detailed { root, .. } => root.as_deref(),
+        }
+    }
+
+    /// Whether missing parent directories of the target are created
+    pub fn create_parents(&self) -> bool {
+        match self {
+            FileEntry::Simple(_) => false,
+            FileEntry::Detailed {
+                create_parents,
+                parent_directory,
+                ..
+            } => create_parents.unwrap_or(parent_directory.is_some()),
+        }
+    }
+
+    /// Whether removing the entry restores the target's backup
+    pub fn restore_backup(&self) -> bool {
+        match self {
+            FileEntry::Simple(_) => true,
+            FileEntry::Detailed { restore_backup, .. } => restore_backup.unwrap_or(true),
+        }
+    }
 }
 
 /// Configuration for parent directory of a file
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct ParentDirectoryConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mode: Option<String>,
@@ -139,6 +906,11 @@ pub struct Symlink {
     /// The target path (where the symlink will be created)
     pub target: PathBuf,
 
+    /// The persistence group key and the entry's path as written in the
+    /// config, for pointing problems back at the entry
+    pub persist_dir: String,
+    pub entry: String,
+
     /// If true, create parent directories as needed
     pub create_parents: bool,
 
@@ -159,56 +931,356 @@ pub struct Symlink {
     /// Optional: Permissions mode (reserved for future use)
     #[allow(dead_code)]
     pub mode: Option<String>,
+
+    /// Optional: Permissions of a directory entry's mount point, as opposed
+    /// to `mode` for the mounted content
+    pub mount_point_mode: Option<String>,
+
+    /// Glob patterns of existing target content to move into the source before mounting
+    pub migrate: Vec<String>,
+
+    /// If true and the source is a symlink, recreate it at the target unresolved
+    pub preserve_symlink: bool,
+
+    /// Whether the entry's source is created, the entry left out, or an
+    /// error raised when the source is missing
+    pub on_missing_source: MissingSource,
+
+    /// Mount this device or image at the target instead of bind mounting `source`
+    /// (which is then the device or image path)
+    pub device: Option<DeviceMount>,
+
+    /// The root of the group's `roots` the source was placed on
+    pub root: Option<PathBuf>,
+
+    /// A plain symlink to `source` from `links`: the source lies inside
+    /// another entry's target, so it is neither created nor resolved
+    pub link: bool,
+
+    /// If false, removing the entry leaves its backup where it is instead
+    /// of moving it back to the target
+    pub restore_backup: bool,
+
+    /// Access time behavior set on a directory entry's mount
+    pub atime: Option<Atime>,
+}
+
+impl FromStr for Config {
+    type Err = anyhow::Error;
+
+    fn from_str(contents: &str) -> anyhow::Result<Self> {
+        ConfigFormat::Toml.parse(contents)
+    }
 }
 
 impl Config {
-    /// Load configuration from a TOML file
-    pub fn from_file(path: &PathBuf) -> anyhow::Result<Self> {
+    /// Load configuration from a TOML, JSON or YAML file (see `ConfigFormat::for_path`),
+    /// or from stdin for `-`. A glob pattern loads every matching file and
+    /// merges them (see `merged`). `format` overrides the format of every file.
+    pub fn from_file(path: &PathBuf, format: Option<ConfigFormat>) -> anyhow::Result<Self> {
+        if is_pattern(path) {
+            return Self::merged(&expand_pattern(path)?, format);
+        }
+        let format = ConfigFormat::for_path(path, format);
+        if let Some(url) = remote::as_url(path) {
+            let contents = remote::fetch_config(url, format)?;
+            let config = format.parse(&contents)?;
+            remote::cache_config(url, format, &contents)?;
+            return Ok(config);
+        }
+        if path.as_os_str() == STDIN_PATH {
+            return format.parse(read_stdin()?);
+        }
+
         let contents = std::fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&contents)?;
-        Ok(config)
+        format.parse(&contents)
+    }
+
+    /// Expand environment variables in the `user`, `group` and `mode` fields
+    /// of every entry, including those of `[env.<name>]` sections
+    fn expand_ownership_vars(&mut self) -> anyhow::Result<()> {
+        let sections = self.persistence.values_mut().chain(
+            self.env
+                .values_mut()
+                .flat_map(|e| e.persistence.values_mut()),
+        );
+
+        for persist_config in sections {
+            for dir_entry in &mut persist_config.directories {
+                if let DirectoryEntry::Detailed {
+                    directory,
+                    user,
+                    group,
+                    mode,
+                    mount_point_mode,
+                    ..
+                } = dir_entry
+                {
+                    let fields = [
+                        ("user", user),
+                        ("group", group),
+                        ("mode", mode),
+                        ("mount_point_mode", mount_point_mode),
+                    ];
+                    for (field, value) in fields {
+                        if let Some(value) = value {
+                            *value = expand_vars(value)
+                                .context(format!("Invalid {} for {}", field, directory))?;
+                        }
+                    }
+                }
+            }
+            for file_entry in &mut persist_config.files {
+                if let FileEntry::Detailed {
+                    file,
+                    parent_directory: Some(ParentDirectoryConfig { mode: Some(mode) }),
+                    ..
+                } = file_entry
+                {
+                    *mode = expand_vars(mode)
+                        .context(format!("Invalid parentDirectory.mode for {}", file))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge several configs into one. Each persistence directory and target
+    /// may come from only one of them, and they must share a `state_dir`;
+    /// other top-level settings are taken from the first.
+    pub fn merged(paths: &[PathBuf], format: Option<ConfigFormat>) -> anyhow::Result<Self> {
+        let (first, rest) = paths.split_first().context("No config files to merge")?;
+        let mut merged = Self::from_file(first, format)
+            .context(format!("Failed to load config: {}", first.display()))?;
+        let mut origins: HashMap<PathBuf, &PathBuf> = merged
+            .all_symlinks()
+            .into_iter()
+            .map(|s| (s.target, first))
+            .collect();
+
+        for path in rest {
+            let config = Self::from_file(path, format)
+                .context(format!("Failed to load config: {}", path.display()))?;
+            if config.state_dir != merged.state_dir {
+                anyhow::bail!(
+                    "Cannot merge {} and {}: they use different state_dir ({} and {})",
+                    first.display(),
+                    path.display(),
+                    merged.state_dir.display(),
+                    config.state_dir.display()
+                );
+            }
+            for symlink in config.all_symlinks() {
+                if let Some(other) = origins.insert(symlink.target.clone(), path) {
+                    anyhow::bail!(
+                        "Cannot merge {} and {}: both persist {}",
+                        other.display(),
+                        path.display(),
+                        symlink.target.display()
+                    );
+                }
+            }
+
+            merge_persistence(&mut merged.persistence, config.persistence, path)?;
+            for (name, env) in config.env {
+                let merged_env = merged.env.entry(name).or_insert_with(|| EnvConfig {
+                    persistence: HashMap::new(),
+                });
+                merge_persistence(&mut merged_env.persistence, env.persistence, path)?;
+            }
+        }
+
+        Ok(merged)
+    }
+
+    /// Place all targets under `target_root` instead of the filesystem root
+    pub fn with_target_root(mut self, target_root: Option<PathBuf>) -> Self {
+        self.target_root = target_root;
+        self
+    }
+
+    /// Resolve a configured target path, prefixing it with `target_root` if set
+    fn resolve_target(&self, path: &Path) -> PathBuf {
+        match &self.target_root {
+            Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Directory this machine's generations are kept in: `state_dir`, or its
+    /// subdirectory for this machine with `per_machine`
+    pub fn generations_dir(&self) -> anyhow::Result<PathBuf> {
+        if self.per_machine {
+            crate::machine::state_dir(&self.state_dir)
+        } else {
+            Ok(self.state_dir.clone())
+        }
+    }
+
+    /// Merge the overrides of the selected environment over `persistence`.
+    /// The environment is `env` if given, else `$IMP_ENV`, else the hostname;
+    /// an explicitly named environment must exist in the config.
+    pub fn with_env(mut self, env: Option<&str>) -> anyhow::Result<Self> {
+        let explicit = env
+            .map(String::from)
+            .or_else(|| std::env::var(ENV_VAR).ok().filter(|e| !e.is_empty()));
+
+        let name = match explicit {
+            Some(name) => {
+                if !self.env.contains_key(&name) {
+                    let mut available: Vec<&str> = self.env.keys().map(String::as_str).collect();
+                    available.sort();
+                    anyhow::bail!(
+                        "Unknown environment {:?}: the config defines {}",
+                        name,
+                        if available.is_empty() {
+                            "no [env.<name>] sections".to_string()
+                        } else {
+                            available.join(", ")
+                        }
+                    );
+                }
+                name
+            }
+            None => match hostname() {
+                Some(host) if self.env.contains_key(&host) => host,
+                _ => return Ok(self),
+            },
+        };
+
+        if let Some(overrides) = self.env.remove(&name) {
+            // Like a later definition, the environment's entry for a
+            // persistence directory replaces the base entry as a whole
+            self.persistence.extend(overrides.persistence);
+        }
+        self.selected_env = Some(name);
+        Ok(self)
+    }
+
+    /// Skip every entry whose source is missing, as if all were `optional`,
+    /// except those with `on_missing_source = "error"` or `"lazy"`
+    pub fn with_skip_missing(mut self, skip_missing: bool) -> Self {
+        self.skip_missing = skip_missing;
+        self
+    }
+
+    /// Whether an entry is left out because its source is missing
+    fn is_skipped(&self, symlink: &Symlink) -> bool {
+        let skip = match symlink.on_missing_source {
+            MissingSource::Create => self.skip_missing,
+            MissingSource::Skip => true,
+            MissingSource::Error | MissingSource::Lazy => false,
+        };
+        skip && symlink.source.symlink_metadata().is_err()
     }
 
-    /// Convert the persistence config to a flat list of symlinks
+    /// Convert the persistence config to a flat list of symlinks, leaving out
+    /// optional entries whose source is missing
     pub fn to_symlinks(&self) -> Vec<Symlink> {
+        self.all_symlinks()
+            .into_iter()
+            .filter(|s| !self.is_skipped(s))
+            .collect()
+    }
+
+    /// Optional entries left out of `to_symlinks` because their source is missing
+    pub fn skipped_symlinks(&self) -> Vec<Symlink> {
+        self.all_symlinks()
+            .into_iter()
+            .filter(|s| self.is_skipped(s))
+            .collect()
+    }
+
+    fn all_symlinks(&self) -> Vec<Symlink> {
         let mut symlinks = Vec::new();
 
         for (persist_dir, persist_config) in &self.persistence {
             // Process directories
             for dir_entry in &persist_config.directories {
-                let target_path = PathBuf::from(dir_entry.path());
-                let source_path = PathBuf::from(persist_dir)
-                    .join(target_path.strip_prefix("/").unwrap_or(&target_path));
+                let target_path = persist_config.entry_target(dir_entry.path());
+                let device = dir_entry.device_mount();
+                let root = persist_config.entry_root(persist_dir, dir_entry.root(), &target_path);
+                let source_path = match &device {
+                    Some(device) => device.path.clone(),
+                    None => persist_config.entry_source(&root, &target_path),
+                };
 
                 symlinks.push(Symlink {
                     source: source_path,
-                    target: target_path,
-                    create_parents: true,
-                    backup: false,
+                    target: self.resolve_target(&target_path),
+                    persist_dir: persist_dir.clone(),
+                    entry: dir_entry.path().to_string(),
+                    create_parents: dir_entry.create_parents(),
+                    backup: self.backup,
                     is_directory: true,
                     user: dir_entry.user().map(String::from),
                     group: dir_entry.group().map(String::from),
                     mode: dir_entry.mode().map(String::from),
+                    mount_point_mode: dir_entry.mount_point_mode().map(String::from),
+                    migrate: dir_entry.migrate().to_vec(),
+                    preserve_symlink: false,
+                    on_missing_source: dir_entry.on_missing_source(),
+                    root: (!persist_config.roots.is_empty() && device.is_none()).then_some(root),
+                    device,
+                    link: false,
+                    restore_backup: dir_entry.restore_backup(),
+                    atime: dir_entry.atime(),
                 });
             }
 
             // Process files
             for file_entry in &persist_config.files {
-                let target_path = PathBuf::from(file_entry.path());
-                let source_path = PathBuf::from(persist_dir)
-                    .join(target_path.strip_prefix("/").unwrap_or(&target_path));
-
-                let create_parents = file_entry.parent_directory().is_some();
+                let target_path = persist_config.entry_target(file_entry.path());
+                let root = persist_config.entry_root(persist_dir, file_entry.root(), &target_path);
+                let source_path = persist_config.entry_source(&root, &target_path);
 
                 symlinks.push(Symlink {
                     source: source_path,
-                    target: target_path,
-                    create_parents,
-                    backup: false,
+                    target: self.resolve_target(&target_path),
+                    persist_dir: persist_dir.clone(),
+                    entry: file_entry.path().to_string(),
+                    create_parents: file_entry.create_parents(),
+                    backup: self.backup,
                     is_directory: false,
                     user: None,
                     group: None,
                     mode: file_entry.parent_directory().and_then(|p| p.mode.clone()),
+                    mount_point_mode: None,
+                    migrate: Vec::new(),
+                    preserve_symlink: file_entry.preserve_symlink(),
+                    on_missing_source: file_entry.on_missing_source(),
+                    root: (!persist_config.roots.is_empty()).then_some(root),
+                    device: None,
+                    link: false,
+                    restore_backup: file_entry.restore_backup(),
+                    atime: None,
+                });
+            }
+        }
+
+        // Links point into the targets above, so they come after all of them
+        for (persist_dir, persist_config) in &self.persistence {
+            for link_entry in &persist_config.links {
+                symlinks.push(Symlink {
+                    source: self.resolve_target(&link_entry.to),
+                    target: self.resolve_target(&persist_config.entry_target(&link_entry.link)),
+                    persist_dir: persist_dir.clone(),
+                    entry: link_entry.link.clone(),
+                    create_parents: true,
+                    backup: self.backup,
+                    is_directory: false,
+                    user: None,
+                    group: None,
+                    mode: None,
+                    mount_point_mode: None,
+                    migrate: Vec::new(),
+                    preserve_symlink: false,
+                    on_missing_source: MissingSource::Create,
+                    device: None,
+                    root: None,
+                    link: true,
+                    restore_backup: true,
+                    atime: None,
                 });
             }
         }
@@ -216,11 +1288,170 @@ impl Config {
         symlinks
     }
 
-    /// Validate the configuration and create missing source paths
-    pub fn validate(&self) -> anyhow::Result<()> {
+    /// Check for targets that differ only in case, themselves or in a parent
+    /// directory, where the filesystem holding them is case-insensitive and
+    /// would make them the same path
+    fn validate_case_collisions(&self) -> anyhow::Result<()> {
         let symlinks = self.to_symlinks();
+
+        // Lower-cased path -> that path as first spelled, and its target
+        let mut seen: HashMap<String, (&Path, &Path)> = HashMap::new();
+        let mut probed: HashMap<&Path, bool> = HashMap::new();
         for symlink in &symlinks {
-            if !symlink.source.exists() {
+            for path in symlink.target.ancestors() {
+                let key = path.to_string_lossy().to_lowercase();
+                let (other_path, other_target) = match seen.get(&key) {
+                    Some(&(other_path, _)) if other_path == path => continue,
+                    Some(&found) => found,
+                    None => {
+                        seen.insert(key, (path, &symlink.target));
+                        continue;
+                    }
+                };
+
+                let dir = path.parent().unwrap_or(path);
+                if *probed
+                    .entry(dir)
+                    .or_insert_with(|| is_case_insensitive(dir))
+                {
+                    anyhow::bail!(
+                        "Targets {} and {} collide: {} and {} differ only in case, \
+                         and {} is on a case-insensitive filesystem",
+                        other_target.display(),
+                        symlink.target.display(),
+                        other_path.display(),
+                        path.display(),
+                        dir.display()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that every link points into a persisted directory or at a persisted file
+    fn validate_links(&self) -> anyhow::Result<()> {
+        let persisted: Vec<(PathBuf, bool)> = self
+            .persistence
+            .values()
+            .flat_map(|p| {
+                let dirs = p
+                    .directories
+                    .iter()
+                    .map(|d| (p.entry_target(d.path()), true));
+                let files = p.files.iter().map(|f| (p.entry_target(f.path()), false));
+                dirs.chain(files).collect::<Vec<_>>()
+            })
+            .collect();
+
+        for (persist_dir, persist_config) in &self.persistence {
+            for link_entry in &persist_config.links {
+                if link_entry.to.is_relative() {
+                    anyhow::bail!(
+                        "Invalid link {} in {}: {} must be an absolute path",
+                        link_entry.link,
+                        persist_dir,
+                        link_entry.to.display()
+                    );
+                }
+                let inside = persisted.iter().any(|(target, is_dir)| {
+                    link_entry.to == *target || (*is_dir && link_entry.to.starts_with(target))
+                });
+                if !inside {
+                    anyhow::bail!(
+                        "Link {} in {} points to {}, which is not inside a persisted directory or file",
+                        link_entry.link,
+                        persist_dir,
+                        link_entry.to.display()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate the configuration and create missing source paths, optionally
+    /// creating them as a specific user
+    pub fn validate(&self, fs_identity: Option<&FsIdentity>) -> anyhow::Result<()> {
+        self.validate_structure()?;
+        if let Some(problem) = problems::problems(self)
+            .into_iter()
+            .find(|p| p.rule.blocks_apply())
+        {
+            anyhow::bail!("{}", problem.message);
+        }
+        identity::run_as(fs_identity, || self.create_missing_sources())
+    }
+
+    /// The checks of `validate` that aren't tied to a single entry (see
+    /// `problems::problems` for those), without creating anything
+    pub fn validate_structure(&self) -> anyhow::Result<()> {
+        if self.backup_suffix.is_empty() || self.backup_suffix.contains('/') {
+            anyhow::bail!(
+                "Invalid backup_suffix {:?}: must be non-empty and must not contain '/'",
+                self.backup_suffix
+            );
+        }
+        self.retention.validate()?;
+        for (persist_dir, persist_config) in &self.persistence {
+            if let Some(base) = &persist_config.base {
+                if base.is_relative() {
+                    anyhow::bail!(
+                        "Invalid base {} in {}: must be an absolute path",
+                        base.display(),
+                        persist_dir
+                    );
+                }
+            }
+            persist_config.validate_layout(persist_dir)?;
+            persist_config.validate_roots(persist_dir)?;
+            persist_config.validate_missing_source(persist_dir)?;
+            for dir_entry in &persist_config.directories {
+                if let DirectoryEntry::Detailed {
+                    device,
+                    image,
+                    fstype,
+                    migrate,
+                    ..
+                } = dir_entry
+                {
+                    let path = dir_entry.path();
+                    if device.is_some() && image.is_some() {
+                        anyhow::bail!("Directory {} sets both device and image", path);
+                    }
+                    if device.is_none() && image.is_none() && fstype.is_some() {
+                        anyhow::bail!("Directory {} sets fstype without a device or image", path);
+                    }
+                    if (device.is_some() || image.is_some()) && !migrate.is_empty() {
+                        anyhow::bail!(
+                            "Directory {} mounts a device or image, which can't be combined with migrate",
+                            path
+                        );
+                    }
+                }
+            }
+        }
+        self.validate_links()?;
+        self.validate_case_collisions()
+    }
+
+    fn create_missing_sources(&self) -> anyhow::Result<()> {
+        let symlinks = self.to_symlinks();
+        for symlink in &symlinks {
+            // A preserved symlink is used as-is, even if it dangles
+            let preserved = symlink.preserve_symlink && symlink.source.is_symlink();
+            // Devices and images can't be made up; a missing one fails at apply.
+            // A link's source only appears once the entry it points into is applied
+            if symlink.device.is_some() || symlink.link {
+                continue;
+            }
+            // A lazy source is created from the target once it is written to
+            if symlink.on_missing_source == MissingSource::Lazy {
+                continue;
+            }
+            // Missing sources with on_missing_source = "error" were reported
+            // as problems before this runs
+            if !symlink.source.exists() && !preserved {
                 if symlink.is_directory {
                     // For directories, create the full directory path
                     println!("Creating source directory: {}", symlink.source.display());
@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use serde::Serialize;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::generation::GenerationSymlink;
+use crate::symlink::SymlinkManager;
+
+/// Set by the signal handler when SIGTERM or SIGINT is received
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often to check for a pending shutdown while waiting
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+extern "C" fn handle_shutdown_signal(_: nix::libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Install handlers that turn SIGTERM and SIGINT into a shutdown request
+pub fn install_shutdown_handler() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_shutdown_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+
+    for signal in [Signal::SIGTERM, Signal::SIGINT] {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        unsafe { sigaction(signal, &action) }
+            .context(format!("Failed to install handler for {}", signal))?;
+    }
+
+    Ok(())
+}
+
+/// Block until a shutdown is requested or `timeout` elapses (forever if `None`).
+/// Returns true if a shutdown was requested.
+pub fn wait_for_shutdown(timeout: Option<Duration>) -> bool {
+    let started = Instant::now();
+
+    loop {
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        if let Some(timeout) = timeout {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// How long a health check client may take to send its request
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reply to a `{"request": "health"}` line on the health socket
+#[derive(Serialize)]
+struct HealthResponse {
+    pid: u32,
+    generation: u64,
+    healthy: bool,
+    /// What `imp verify` would report for the held generation
+    problems: Vec<String>,
+}
+
+/// Answer health checks on a Unix socket at `path` from a background thread.
+/// Each request and reply is one line of JSON.
+pub fn serve_health(path: &Path, generation: u64, symlinks: Vec<GenerationSymlink>) -> Result<()> {
+    // A socket left behind by a daemon that didn't shut down cleanly
+    if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        fs::remove_file(path)
+            .context(format!("Failed to remove stale socket: {}", path.display()))?;
+    }
+    let listener =
+        UnixListener::bind(path).context(format!("Failed to listen on: {}", path.display()))?;
+
+    thread::spawn(move || {
+        let symlink_manager = SymlinkManager::new();
+        for stream in listener.incoming().flatten() {
+            // A misbehaving client must not stop the daemon from answering others
+            let _ = answer_health_check(stream, generation, &symlinks, &symlink_manager);
+        }
+    });
+
+    Ok(())
+}
+
+fn answer_health_check(
+    mut stream: UnixStream,
+    generation: u64,
+    symlinks: &[GenerationSymlink],
+    symlink_manager: &SymlinkManager,
+) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    let request: serde_json::Value = serde_json::from_str(&line).unwrap_or_default();
+    let reply = match request.get("request").and_then(|r| r.as_str()) {
+        Some("health") => {
+            let problems = symlink_manager
+                .verify(symlinks)
+                .unwrap_or_else(|e| vec![format!("Verification failed: {:#}", e)]);
+            serde_json::to_string(&HealthResponse {
+                pid: std::process::id(),
+                generation,
+                healthy: problems.is_empty(),
+                problems,
+            })?
+        }
+        _ => {
+            serde_json::json!({ "error": format!("Unknown request: {}", line.trim()) }).to_string()
+        }
+    };
+
+    writeln!(stream, "{}", reply)?;
+    Ok(())
+}
+
+/// Remove the health socket on shutdown
+pub fn remove_health_socket(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+/// Send a health check to the daemon listening at `path` and return its reply
+pub fn ping(path: &PathBuf) -> Result<serde_json::Value> {
+    let mut stream = UnixStream::connect(path).context(format!(
+        "Failed to connect to {} (is `imp daemon --socket` running?)",
+        path.display()
+    ))?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    writeln!(stream, "{}", serde_json::json!({ "request": "health" }))?;
+
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .context("Failed to read the daemon's reply")?;
+    serde_json::from_str(&line).context(format!("Invalid reply from daemon: {}", line.trim()))
+}
@@ -0,0 +1,251 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::caps::{self, Capability};
+use crate::config::{Config, ConfigFormat};
+use crate::environment::GenerationEnvironment;
+use crate::generation::{Generation, GenerationManager};
+use crate::mounts::{self, MountInfo};
+
+/// Everything `imp debug-dump` bundles for a bug report
+#[derive(Serialize)]
+pub struct DebugDump {
+    pub imp_version: String,
+    pub config_path: PathBuf,
+    /// The config file as it is on disk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<String>,
+    /// Why the config file couldn't be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_error: Option<String>,
+    pub state_dir: PathBuf,
+    pub environment: GenerationEnvironment,
+    /// Whether imp has each capability (absent if they couldn't be read)
+    pub capabilities: BTreeMap<String, Option<bool>>,
+    pub active_generation: Option<Generation>,
+    /// Lines of /proc/self/mountinfo for the mounts at the active
+    /// generation's targets and the filesystems holding its targets and sources
+    pub mountinfo: Vec<String>,
+    /// Persistence roots of the config, when it parses, for redaction
+    #[serde(skip)]
+    roots: Vec<PathBuf>,
+}
+
+/// Gather the report. Nothing in it is required: what can't be read is
+/// reported as such, since the dump is most needed when something is broken.
+pub fn collect(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    generation_manager: &GenerationManager,
+) -> Result<DebugDump> {
+    let (config, config_error) = match fs::read_to_string(config_path) {
+        Ok(contents) => (Some(contents), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    let mut roots: Vec<PathBuf> = Config::from_file(config_path, config_format)
+        .map(|config| config.persistence.keys().map(PathBuf::from).collect())
+        .unwrap_or_default();
+    roots.sort();
+
+    let capabilities = Capability::ALL
+        .iter()
+        .map(|&capability| (capability.to_string(), caps::has(capability).ok()))
+        .collect();
+    let active_generation = generation_manager.get_active_generation()?;
+    let mountinfo = match &active_generation {
+        Some(gen) => relevant_mounts(&mounts::read_mountinfo_lines()?, gen),
+        None => Vec::new(),
+    };
+
+    Ok(DebugDump {
+        imp_version: env!("CARGO_PKG_VERSION").to_string(),
+        config_path: config_path.to_path_buf(),
+        config,
+        config_error,
+        state_dir: generation_manager.state_dir().to_path_buf(),
+        environment: GenerationEnvironment::capture(),
+        capabilities,
+        active_generation,
+        mountinfo,
+        roots,
+    })
+}
+
+/// The lines of the mounts at `gen`'s targets, stacked ones included, and of
+/// the mounts containing its targets' parents and its sources, in mount order
+fn relevant_mounts(entries: &[(String, MountInfo)], gen: &Generation) -> Vec<String> {
+    let containing = |path: &Path| {
+        entries
+            .iter()
+            .map(|(_, mount)| mount.mount_point.as_path())
+            .filter(|mount_point| path.starts_with(mount_point))
+            .max_by_key(|mount_point| mount_point.components().count())
+    };
+
+    let mut wanted: HashSet<&Path> = HashSet::new();
+    for gen_symlink in &gen.symlinks {
+        wanted.insert(&gen_symlink.target);
+        wanted.extend(gen_symlink.target.parent().and_then(containing));
+        wanted.extend(containing(&gen_symlink.source));
+    }
+
+    entries
+        .iter()
+        .filter(|(_, mount)| wanted.contains(mount.mount_point.as_path()))
+        .map(|(line, _)| line.clone())
+        .collect()
+}
+
+impl DebugDump {
+    /// Render as plain text, one section per part of the report
+    pub fn render(&self) -> Result<String> {
+        let mut out = String::new();
+        let yes_no = |value: Option<bool>| match value {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "unknown",
+        };
+
+        writeln!(out, "=== imp ===")?;
+        writeln!(out, "version: {}", self.imp_version)?;
+        writeln!(out, "config: {}", self.config_path.display())?;
+        writeln!(out, "state dir: {}", self.state_dir.display())?;
+
+        writeln!(out, "\n=== environment ===")?;
+        let environment = &self.environment;
+        let unknown = || "unknown".to_string();
+        writeln!(
+            out,
+            "hostname: {}",
+            environment.hostname.clone().unwrap_or_else(unknown)
+        )?;
+        writeln!(
+            out,
+            "kernel: {}",
+            environment.kernel.clone().unwrap_or_else(unknown)
+        )?;
+        writeln!(out, "container: {}", yes_no(environment.container))?;
+        writeln!(
+            out,
+            "umask: {}",
+            environment.umask.clone().unwrap_or_else(unknown)
+        )?;
+
+        writeln!(out, "\n=== capabilities ===")?;
+        for (capability, has) in &self.capabilities {
+            writeln!(out, "{}: {}", capability, yes_no(*has))?;
+        }
+
+        writeln!(out, "\n=== active generation ===")?;
+        match &self.active_generation {
+            Some(gen) => writeln!(out, "{}", serde_json::to_string_pretty(gen)?)?,
+            None => writeln!(out, "none")?,
+        }
+
+        writeln!(out, "\n=== mountinfo ===")?;
+        for line in &self.mountinfo {
+            writeln!(out, "{}", line)?;
+        }
+
+        writeln!(out, "\n=== config ===")?;
+        match (&self.config, &self.config_error) {
+            (Some(contents), _) => write!(out, "{}", contents)?,
+            (None, Some(error)) => writeln!(out, "(unreadable: {})", error)?,
+            (None, None) => {}
+        }
+
+        Ok(out)
+    }
+
+    /// The paths the report mentions that say something about the machine:
+    /// the config, the state directory, the persistence roots, and the
+    /// targets, sources and backups of the active generation
+    fn private_paths(&self) -> Vec<PathBuf> {
+        let mut paths = vec![self.config_path.clone(), self.state_dir.clone()];
+        paths.extend(self.roots.iter().cloned());
+        if let Some(gen) = &self.active_generation {
+            paths.push(gen.config_path.clone());
+            for gen_symlink in &gen.symlinks {
+                paths.push(gen_symlink.target.clone());
+                paths.push(gen_symlink.source.clone());
+                paths.extend(gen_symlink.backup_path.iter().cloned());
+                paths.extend(gen_symlink.root.iter().cloned());
+            }
+        }
+        let mut seen = HashSet::new();
+        paths.retain(|path| path != Path::new("/") && !path.as_os_str().is_empty());
+        paths.retain(|path| seen.insert(path.clone()));
+        paths
+    }
+
+    /// Replace every private path in `report` (a rendering of this dump)
+    /// with a placeholder, numbered in the order the paths come up, so the
+    /// same path reads the same everywhere. Paths under a redacted path
+    /// keep the rest of their components.
+    pub fn redact_paths(&self, report: &str) -> String {
+        let mut placeholders: Vec<(String, String)> = Vec::new();
+        for (index, path) in self.private_paths().iter().enumerate() {
+            let placeholder = format!("<path-{}>", index + 1);
+            let path = path.display().to_string();
+            // As mountinfo writes it, too
+            let escaped = mountinfo_escape(&path);
+            if escaped != path {
+                placeholders.push((escaped, placeholder.clone()));
+            }
+            placeholders.push((path, placeholder));
+        }
+        // Longest first, so a path is replaced whole before any path it starts with
+        placeholders.sort_by_key(|(path, _)| std::cmp::Reverse(path.len()));
+
+        let mut report = report.to_string();
+        for (path, placeholder) in &placeholders {
+            report = replace_path(&report, path, placeholder);
+        }
+        report
+    }
+}
+
+/// Whether `c` can be part of a path component, so that a match running into
+/// it is only part of a longer name
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || "-_.@+~".contains(c)
+}
+
+/// Replace the occurrences of `path` in `text` that are whole paths, or
+/// followed by more components
+fn replace_path(text: &str, path: &str, placeholder: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(path) {
+        let end = start + path.len();
+        let before = rest[..start].chars().next_back();
+        let after = rest[end..].chars().next();
+        out.push_str(&rest[..start]);
+        if before.is_some_and(is_name_char) || after.is_some_and(is_name_char) {
+            out.push_str(path);
+        } else {
+            out.push_str(placeholder);
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Escape `path` the way the kernel does in mount tables (see `mounts::unescape`)
+fn mountinfo_escape(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            ' ' | '\t' | '\n' | '\\' => {
+                write!(out, "\\{:03o}", c as u32).expect("writing to a String")
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
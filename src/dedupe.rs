@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::generation::{Generation, LinkKind};
+
+/// What makes two generations identical: the same entries (ignoring backups
+/// and loop devices, which differ between applies of the same config) and
+/// the same failed targets
+type Fingerprint = (
+    Vec<(
+        PathBuf,
+        PathBuf,
+        Option<LinkKind>,
+        Vec<String>,
+        Option<String>,
+    )>,
+    Vec<PathBuf>,
+);
+
+fn fingerprint(generation: &Generation) -> Fingerprint {
+    let mut entries: Vec<_> = generation
+        .symlinks
+        .iter()
+        .map(|s| {
+            let mut options = s.mount_options.clone();
+            options.extend(s.atime.map(|atime| atime.name().to_string()));
+            if s.lazy {
+                options.push("lazy".to_string());
+            }
+            options.sort();
+            (
+                s.target.clone(),
+                s.source.clone(),
+                s.kind,
+                options,
+                s.fstype.clone(),
+            )
+        })
+        .collect();
+    entries.sort();
+
+    let mut failed = generation.failed.clone();
+    failed.sort();
+
+    (entries, failed)
+}
+
+/// A group of generations with identical symlink sets
+pub struct Cluster {
+    /// All generation numbers in the group, oldest first
+    pub numbers: Vec<u64>,
+
+    /// The one to keep: the active generation if it is in the group,
+    /// otherwise the newest
+    pub keep: u64,
+
+    /// Other generations of the group that were asked to be kept
+    pub protected: Vec<u64>,
+}
+
+impl Cluster {
+    /// The generations that are kept, oldest first
+    pub fn kept(&self) -> impl Iterator<Item = u64> + '_ {
+        self.numbers
+            .iter()
+            .copied()
+            .filter(|n| *n == self.keep || self.protected.contains(n))
+    }
+
+    /// The generations that can be deleted without losing anything
+    pub fn redundant(&self) -> impl Iterator<Item = u64> + '_ {
+        self.numbers
+            .iter()
+            .copied()
+            .filter(|n| *n != self.keep && !self.protected.contains(n))
+    }
+}
+
+/// Group generations by fingerprint, returning only groups of two or more,
+/// ordered by their oldest generation. Generations in `protected` are kept
+/// in addition to each group's own choice.
+pub fn clusters(generations: &[Generation], protected: &[u64]) -> Vec<Cluster> {
+    let mut groups: HashMap<Fingerprint, Vec<&Generation>> = HashMap::new();
+    for generation in generations {
+        groups
+            .entry(fingerprint(generation))
+            .or_default()
+            .push(generation);
+    }
+
+    let mut clusters: Vec<Cluster> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|group| {
+            let mut numbers: Vec<u64> = group.iter().map(|g| g.number).collect();
+            numbers.sort();
+            let keep = group
+                .iter()
+                .find(|g| g.active)
+                .map(|g| g.number)
+                .unwrap_or(numbers[numbers.len() - 1]);
+            let protected = numbers
+                .iter()
+                .copied()
+                .filter(|n| *n != keep && protected.contains(n))
+                .collect();
+            Cluster {
+                numbers,
+                keep,
+                protected,
+            }
+        })
+        .collect();
+    clusters.sort_by_key(|c| c.numbers[0]);
+
+    clusters
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::caps::{self, Capability};
+use crate::config;
+
+/// The environment a generation was applied in, for diagnosing a generation
+/// that behaves differently from one machine to the next. Fields are `None`
+/// if they couldn't be read, and all of them for generations created before
+/// the environment was recorded.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct GenerationEnvironment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Kernel release, as printed by `uname -r`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imp_version: Option<String>,
+    /// Whether imp had CAP_SYS_ADMIN, which mounts need
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub privileged: Option<bool>,
+    /// Whether imp ran inside a container
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<bool>,
+    /// umask of the imp process, in octal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub umask: Option<String>,
+}
+
+impl GenerationEnvironment {
+    /// The environment of this process
+    pub fn capture() -> Self {
+        Self {
+            hostname: config::hostname(),
+            kernel: fs::read_to_string("/proc/sys/kernel/osrelease")
+                .ok()
+                .map(|release| release.trim().to_string()),
+            imp_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            privileged: caps::has(Capability::SysAdmin).ok(),
+            container: Some(is_container()),
+            umask: umask(),
+        }
+    }
+
+    /// Whether nothing was recorded, as for generations created before the
+    /// environment was
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Whether this process runs in a container. Docker and Podman leave a
+/// marker file at the root; systemd-nspawn, LXC and others set `container`
+/// in the environment of pid 1.
+pub fn is_container() -> bool {
+    Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+        || fs::read("/proc/1/environ").is_ok_and(|environ| {
+            environ
+                .split(|&byte| byte == 0)
+                .any(|var| var.starts_with(b"container="))
+        })
+}
+
+/// The umask from /proc/self/status, which (unlike umask(2)) reads it
+/// without changing it
+fn umask() -> Option<String> {
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("Umask:"))
+        .map(|umask| umask.trim().to_string())
+}
@@ -0,0 +1,168 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::generation::{Generation, GenerationSymlink, LinkKind};
+use crate::mounts;
+use crate::symlink::SymlinkManager;
+
+/// One way the live filesystem disagrees with the active generation
+pub enum Discrepancy {
+    /// Recorded in the generation but not in place at all
+    Missing {
+        entry: GenerationSymlink,
+        problem: String,
+    },
+
+    /// In place, but not as recorded (wrong source, drifted options, ...)
+    Differs {
+        entry: GenerationSymlink,
+        problem: String,
+    },
+
+    /// In place but not recorded by the active generation: left behind by
+    /// another generation, or mounted from a persistence directory by hand
+    Orphaned(GenerationSymlink),
+}
+
+impl Discrepancy {
+    pub fn target(&self) -> &Path {
+        match self {
+            Discrepancy::Missing { entry, .. }
+            | Discrepancy::Differs { entry, .. }
+            | Discrepancy::Orphaned(entry) => &entry.target,
+        }
+    }
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::Missing { problem, .. } => write!(f, "- {}", problem),
+            Discrepancy::Differs { problem, .. } => write!(f, "~ {}", problem),
+            Discrepancy::Orphaned(entry) if entry.is_mount() => write!(
+                f,
+                "+ Orphaned mount: {} (from {})",
+                entry.target.display(),
+                entry.source.display()
+            ),
+            Discrepancy::Orphaned(entry) => write!(
+                f,
+                "+ Orphaned symlink: {} -> {}",
+                entry.target.display(),
+                entry.source.display()
+            ),
+        }
+    }
+}
+
+/// Compare the active generation with the filesystem in both directions:
+/// its entries that aren't in place, and mounts and symlinks that are in
+/// place without being part of it
+pub fn check(
+    active: &Generation,
+    generations: &[Generation],
+    persist_dirs: &[PathBuf],
+    symlink_manager: &SymlinkManager,
+) -> Result<Vec<Discrepancy>> {
+    let mut discrepancies = Vec::new();
+
+    for entry in &active.symlinks {
+        let problem = match symlink_manager.verify_one(entry)? {
+            Some(problem) => problem,
+            None => continue,
+        };
+        let present = if entry.is_mount() {
+            entry.target.is_dir() && symlink_manager.is_mount_point(&entry.target)?
+        } else {
+            entry.target.is_symlink()
+        };
+        let entry = entry.clone();
+        discrepancies.push(if present {
+            Discrepancy::Differs { entry, problem }
+        } else {
+            Discrepancy::Missing { entry, problem }
+        });
+    }
+
+    discrepancies.extend(
+        orphans(active, generations, persist_dirs, symlink_manager)?
+            .into_iter()
+            .map(Discrepancy::Orphaned),
+    );
+
+    Ok(discrepancies)
+}
+
+/// Mounts and symlinks that are in place without being part of `generation`:
+/// entries of other generations, and mounts of a persistence directory's content
+pub fn orphans(
+    generation: &Generation,
+    generations: &[Generation],
+    persist_dirs: &[PathBuf],
+    symlink_manager: &SymlinkManager,
+) -> Result<Vec<GenerationSymlink>> {
+    let mut orphans = Vec::new();
+    let mut recorded: HashSet<PathBuf> = generation
+        .symlinks
+        .iter()
+        .map(|s| s.target.clone())
+        .collect();
+
+    // Entries of other generations that are still exactly in place, e.g.
+    // after a switch that was interrupted before removing them
+    let others = generations
+        .iter()
+        .filter(|g| g.number != generation.number)
+        .flat_map(|g| &g.symlinks);
+    for entry in others {
+        if recorded.contains(&entry.target) {
+            continue;
+        }
+        if symlink_manager.verify_one(entry)?.is_none() {
+            recorded.insert(entry.target.clone());
+            orphans.push(entry.clone());
+        }
+    }
+
+    // Mounts of a persistence directory's content that no generation accounts for
+    let mounts = mounts::read_mountinfo()?;
+    for mount in &mounts {
+        if recorded.contains(&mount.mount_point) {
+            continue;
+        }
+        let source = PathBuf::from(mounts::mount_source(&mounts, mount));
+        let from_persist_dir = persist_dirs
+            .iter()
+            .any(|dir| source.starts_with(dir) && !mount.mount_point.starts_with(dir));
+        if from_persist_dir {
+            recorded.insert(mount.mount_point.clone());
+            orphans.push(GenerationSymlink {
+                source,
+                target: mount.mount_point.clone(),
+                backup_path: None,
+                kind: Some(LinkKind::Mount),
+                mount_options: Vec::new(),
+                fstype: None,
+                loop_device: None,
+                root: None,
+                restore_backup: true,
+                atime: None,
+                lazy: false,
+            });
+        }
+    }
+
+    Ok(orphans)
+}
+
+/// Reconcile one discrepancy: restore a recorded entry, or take down an orphan
+pub fn fix(discrepancy: &Discrepancy, symlink_manager: &SymlinkManager) -> Result<()> {
+    match discrepancy {
+        Discrepancy::Missing { entry, .. } | Discrepancy::Differs { entry, .. } => {
+            symlink_manager.repair(entry)
+        }
+        Discrepancy::Orphaned(entry) => symlink_manager.remove(std::slice::from_ref(entry)),
+    }
+}
@@ -1,8 +1,23 @@
 use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
+use nix::fcntl::{Flock, FlockArg};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::environment::GenerationEnvironment;
+use crate::mounts::Atime;
+
+/// Version of the state file written by this build. Older files are
+/// upgraded in memory by `migrate` and rewritten in this format on next save.
+///
+/// - 1: a bare JSON array of generations; entries may lack `kind`
+/// - 2: `{"version": 2, "generations": [...]}`, every entry has a `kind`
+pub const STATE_VERSION: u64 = 2;
 
 /// Represents a single generation
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -21,6 +36,28 @@ pub struct Generation {
 
     /// Whether this generation is currently active
     pub active: bool,
+
+    /// Targets that failed to apply under --keep-going and are missing from
+    /// `symlinks` until `apply --resume` completes them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<PathBuf>,
+
+    /// Free-form note set with `imp note`, for the user only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+
+    /// Commit the config was checked out at, for configs applied with `--config-git`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_commit: Option<String>,
+
+    /// Machine and process the generation was created on
+    #[serde(default, skip_serializing_if = "GenerationEnvironment::is_empty")]
+    pub environment: GenerationEnvironment,
+
+    /// When the last `verify --only-changed` or `verify --full` that found
+    /// no problems started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_verified: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -29,39 +66,571 @@ pub struct GenerationSymlink {
     pub target: PathBuf,
     /// If a backup was created, store its path
     pub backup_path: Option<PathBuf>,
+    /// Whether this entry is a bind mount or a symlink (absent in older state files)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<LinkKind>,
+    /// Options of the bind mount when it was created (empty for symlinks and
+    /// older state files, in which case they aren't verified)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mount_options: Vec<String>,
+    /// Filesystem type of a device mount
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fstype: Option<String>,
+    /// Loop device an image was attached to when it was mounted (device mounts
+    /// of image files only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loop_device: Option<PathBuf>,
+    /// Root of a tiered persistence group the source was placed on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    /// Whether removing the entry moves its backup back to the target (the
+    /// entry's `restore_backup`; only `false` is stored)
+    #[serde(default = "restores_backup", skip_serializing_if = "Clone::clone")]
+    pub restore_backup: bool,
+    /// Access time behavior set on the mount with the entry's `atime`, which
+    /// verify checks is still in place
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime: Option<Atime>,
+    /// Whether the entry has `on_missing_source = "lazy"`: while the source
+    /// doesn't exist, the target is a plain directory waiting to be written to
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub lazy: bool,
+}
+
+fn restores_backup() -> bool {
+    true
+}
+
+/// How a generation entry is materialized on the filesystem
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkKind {
+    /// Directory bind mount
+    Mount,
+    /// File symlink
+    Symlink,
+    /// Block device or filesystem image mounted at a directory
+    Device,
+}
+
+impl GenerationSymlink {
+    /// Whether this entry is mounted at its target (a bind mount or a device),
+    /// falling back to inspecting the source for generations recorded before
+    /// the kind was stored
+    pub fn is_mount(&self) -> bool {
+        match self.kind {
+            Some(kind) => kind != LinkKind::Symlink,
+            None => self.source.is_dir(),
+        }
+    }
+
+    /// Whether this entry mounts a device or image rather than bind mounting a directory
+    pub fn is_device(&self) -> bool {
+        self.kind == Some(LinkKind::Device)
+    }
+
+    /// Whether this is a lazy entry whose source hasn't been created yet, so
+    /// its target is left unmounted
+    pub fn is_waiting(&self) -> bool {
+        self.lazy && self.source.symlink_metadata().is_err()
+    }
+
+    /// The source as a path to inspect: relative sources come from preserved
+    /// symlinks and resolve against the target's directory
+    pub fn resolved_source(&self) -> PathBuf {
+        match self.target.parent() {
+            Some(parent) if self.source.is_relative() => parent.join(&self.source),
+            _ => self.source.clone(),
+        }
+    }
+
+    /// Whether the source was modified, or its ownership or permissions
+    /// changed, at or after `time`. A source that can't be inspected counts
+    /// as changed.
+    pub fn source_changed_since(&self, time: DateTime<Utc>) -> bool {
+        let Ok(metadata) = fs::symlink_metadata(self.resolved_source()) else {
+            return true;
+        };
+        let modified = DateTime::from_timestamp(metadata.mtime(), metadata.mtime_nsec() as u32);
+        let changed = DateTime::from_timestamp(metadata.ctime(), metadata.ctime_nsec() as u32);
+        [modified, changed]
+            .into_iter()
+            .any(|t| t.is_none_or(|t| t >= time))
+    }
+
+    /// Name of the kind for display and progress events
+    pub fn kind_name(&self) -> &'static str {
+        if self.is_device() {
+            "device"
+        } else if self.is_mount() {
+            "mount"
+        } else {
+            "symlink"
+        }
+    }
+}
+
+/// Layout of the state file since version 2
+#[derive(Serialize)]
+struct StateFile<'a> {
+    version: u64,
+    generations: &'a [Generation],
+}
+
+/// Format the state file is written in. Either format is read back.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StateFormat {
+    /// generations.json
+    #[default]
+    Json,
+    /// generations.toml, easier to repair by hand
+    Toml,
+}
+
+/// Written at the top of TOML state files, which are meant for people to read
+const TOML_HEADER: &str = "# imp generation state. imp rewrites this file on every change;\n\
+                           # edit it only while no imp command is running.\n\n";
+
+impl StateFormat {
+    fn file_name(self) -> &'static str {
+        match self {
+            StateFormat::Json => "generations.json",
+            StateFormat::Toml => "generations.toml",
+        }
+    }
+
+    fn other(self) -> StateFormat {
+        match self {
+            StateFormat::Json => StateFormat::Toml,
+            StateFormat::Toml => StateFormat::Json,
+        }
+    }
+
+    fn serialize(self, state: &StateFile) -> Result<String> {
+        Ok(match self {
+            StateFormat::Json => serde_json::to_string_pretty(state)?,
+            StateFormat::Toml => format!("{}{}", TOML_HEADER, toml::to_string_pretty(state)?),
+        })
+    }
+}
+
+impl std::fmt::Display for StateFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            StateFormat::Json => "json",
+            StateFormat::Toml => "toml",
+        })
+    }
+}
+
+/// Parse a state file as JSON or, failing that, TOML. Hand-edited files are
+/// accepted whatever their name; `expected` picks which error to report if
+/// neither parses.
+fn parse_state(contents: &str, expected: StateFormat) -> Result<(StateFormat, serde_json::Value)> {
+    let json = serde_json::from_str(contents);
+    if let Ok(state) = json {
+        return Ok((StateFormat::Json, state));
+    }
+    let toml = toml::from_str(contents);
+    if let Ok(state) = toml {
+        return Ok((StateFormat::Toml, state));
+    }
+
+    match expected {
+        StateFormat::Json => Err(json.unwrap_err().into()),
+        StateFormat::Toml => Err(toml.unwrap_err().into()),
+    }
+}
+
+/// Parse a state file into its format, version and generations (not yet
+/// deserialized, as their layout depends on the version)
+fn parse_generations(
+    contents: &str,
+    expected: StateFormat,
+) -> Result<(StateFormat, u64, serde_json::Value)> {
+    let (format, state) = parse_state(contents, expected)?;
+    let (version, generations) = match state {
+        serde_json::Value::Array(_) => (1, state),
+        mut state => {
+            let version = state
+                .get("version")
+                .and_then(serde_json::Value::as_u64)
+                .context("State file has no version")?;
+            (version, state["generations"].take())
+        }
+    };
+    Ok((format, version, generations))
+}
+
+/// Copy of a state file as it was before the last save, kept to recover from
+/// if the state file is found corrupt
+fn backup_file(state_file: &Path) -> PathBuf {
+    let mut name = state_file.as_os_str().to_owned();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+/// Replace `file` with `contents` in one step. The new contents are flushed
+/// before the rename, so a crash leaves the old file or the new one, never an
+/// empty or partial one.
+fn replace_file(file: &Path, contents: &str, what: &str) -> Result<()> {
+    let mut name = file.as_os_str().to_owned();
+    name.push(".tmp");
+    let temp_file = PathBuf::from(name);
+
+    let mut temp = File::create(&temp_file).context(format!(
+        "Failed to write {}: {}",
+        what,
+        temp_file.display()
+    ))?;
+    temp.write_all(contents.as_bytes())
+        .and_then(|_| temp.sync_all())
+        .context(format!("Failed to write {}: {}", what, temp_file.display()))?;
+    fs::rename(&temp_file, file).context(format!("Failed to replace {}: {}", what, file.display()))
+}
+
+/// Upgrade generations read from a state file of `version` to the current format
+fn migrate(version: u64, generations: &mut [Generation]) {
+    if version < 2 {
+        // Settle the kind now, while the source can still be inspected
+        for gen_symlink in generations.iter_mut().flat_map(|g| g.symlinks.iter_mut()) {
+            if gen_symlink.kind.is_none() {
+                gen_symlink.kind = Some(if gen_symlink.is_mount() {
+                    LinkKind::Mount
+                } else {
+                    LinkKind::Symlink
+                });
+            }
+        }
+    }
+}
+
+/// A command that changed the generations, kept (only the last one) so that
+/// `imp undo` can revert it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    /// `apply` created and activated `created` in place of `previous`
+    Apply { previous: Option<u64>, created: u64 },
+    /// `switch` activated `to` in place of `previous`
+    Switch { previous: Option<u64>, to: u64 },
+    /// `apply --resume` added entries to `generation`
+    Resume { generation: u64 },
+    /// `delete` or `dedupe --apply` deleted `deleted`
+    Delete { deleted: Vec<u64> },
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Apply { created, .. } => write!(f, "apply of generation {}", created),
+            Operation::Switch { to, .. } => write!(f, "switch to generation {}", to),
+            Operation::Resume { generation } => {
+                write!(f, "resumed apply of generation {}", generation)
+            }
+            Operation::Delete { deleted } => {
+                let numbers: Vec<String> = deleted.iter().map(u64::to_string).collect();
+                write!(f, "deletion of generation(s) {}", numbers.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LastOperation {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub operation: Operation,
 }
 
 pub struct GenerationManager {
-    _state_dir: PathBuf,
-    generations_file: PathBuf,
+    state_dir: PathBuf,
+    /// Format new state is written in
+    format: StateFormat,
+    /// Readers hold a shared lock on this and writers an exclusive one
+    lock_file: PathBuf,
+    /// The state as last read or saved, so a command reads the file only once
+    cache: RefCell<Option<CachedState>>,
+}
+
+/// The state file as a command last read or saved it
+struct CachedState {
+    generations: Vec<Generation>,
+    /// Format the file was written in, `None` if there was no state to read
+    format: Option<StateFormat>,
+    /// The file that was read or saved, to notice another process replacing it
+    stamp: Option<FileStamp>,
+}
+
+/// Identity of a state file. Every save renames a new file into place, so it
+/// changes whenever the state is saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    dev: u64,
+    ino: u64,
+    mtime: i64,
+    mtime_nsec: i64,
 }
 
 impl GenerationManager {
     pub fn new(state_dir: PathBuf) -> Result<Self> {
         fs::create_dir_all(&state_dir)?;
-        let generations_file = state_dir.join("generations.json");
+        let lock_file = state_dir.join("generations.lock");
 
         Ok(Self {
-            _state_dir: state_dir,
-            generations_file,
+            state_dir,
+            format: StateFormat::default(),
+            lock_file,
+            cache: RefCell::new(None),
+        })
+    }
+
+    /// Write the state file in `format`. A state file in the other format is
+    /// still read, and replaced on the next save.
+    pub fn with_format(mut self, format: StateFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Format new state is written in
+    pub fn format(&self) -> StateFormat {
+        self.format
+    }
+
+    /// Directory the state file is kept in
+    pub fn state_dir(&self) -> &Path {
+        &self.state_dir
+    }
+
+    fn state_file(&self, format: StateFormat) -> PathBuf {
+        self.state_dir.join(format.file_name())
+    }
+
+    /// The state file on disk and the format its name implies, preferring the
+    /// configured format if both exist
+    fn existing_state_file(&self) -> Option<(PathBuf, StateFormat)> {
+        [self.format, self.format.other()]
+            .into_iter()
+            .map(|format| (self.state_file(format), format))
+            .find(|(path, _)| path.exists())
+    }
+
+    /// Lock the state file, waiting for any conflicting holder to finish
+    fn lock(&self, arg: FlockArg) -> Result<Flock<File>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.lock_file)
+            .or_else(|_| File::open(&self.lock_file))
+            .context(format!(
+                "Failed to open state lock: {}",
+                self.lock_file.display()
+            ))?;
+
+        Flock::lock(file, arg).map_err(|(_, errno)| {
+            anyhow::anyhow!(
+                "Failed to lock state: {}: {}",
+                self.lock_file.display(),
+                errno
+            )
         })
     }
 
-    /// Load all generations from disk
+    /// Load all generations, upgraded to the current format. The state file
+    /// is read on first use; later calls return what was read or last saved.
     pub fn load_generations(&self) -> Result<Vec<Generation>> {
-        if !self.generations_file.exists() {
-            return Ok(Vec::new());
+        if let Some(cached) = &*self.cache.borrow() {
+            return Ok(cached.generations.clone());
         }
-
-        let contents = fs::read_to_string(&self.generations_file)?;
-        let generations: Vec<Generation> = serde_json::from_str(&contents)?;
+        let state = self.read_state()?.2;
+        let generations = state.generations.clone();
+        *self.cache.borrow_mut() = Some(state);
         Ok(generations)
     }
 
-    /// Save generations to disk
+    /// Identity of the state file on disk, if there is one
+    fn stamp(&self) -> Option<FileStamp> {
+        let (state_file, _) = self.existing_state_file()?;
+        let metadata = fs::metadata(state_file).ok()?;
+        Some(FileStamp {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        })
+    }
+
+    /// `read_state_locked` under a shared lock
+    fn read_state(&self) -> Result<(u64, bool, CachedState)> {
+        // A reader that can't create the lock (e.g. a read-only state dir)
+        // can't be racing a writer in that directory either
+        let _lock = self
+            .existing_state_file()
+            .and_then(|_| self.lock(FlockArg::LockShared).ok());
+        self.read_state_locked()
+    }
+
+    /// Load the state file, the version it was written in and whether it is
+    /// already stored in the configured format. The caller holds the lock.
+    fn read_state_locked(&self) -> Result<(u64, bool, CachedState)> {
+        let stamp = self.stamp();
+        let none = CachedState {
+            generations: Vec::new(),
+            format: None,
+            stamp,
+        };
+        let (state_file, named_format) = match self.existing_state_file() {
+            Some(found) => found,
+            None => return Ok((STATE_VERSION, true, none)),
+        };
+
+        let contents = fs::read_to_string(&state_file)?;
+        let parsed = match parse_generations(&contents, named_format) {
+            // An empty state file (e.g. left by a failed write) is as good as none
+            _ if contents.trim().is_empty() => None,
+            Ok((format, version, generations)) => {
+                let in_configured_format = format == self.format && named_format == self.format;
+                Some((in_configured_format, format, version, generations))
+            }
+            Err(e) => Some(self.recover(&state_file, named_format).ok_or_else(|| {
+                e.context(format!(
+                    "Failed to parse state file: {}",
+                    state_file.display()
+                ))
+            })?),
+        };
+        let Some((in_configured_format, format, version, generations)) = parsed else {
+            return Ok((STATE_VERSION, true, none));
+        };
+        if version > STATE_VERSION {
+            anyhow::bail!(
+                "State file {} has version {}, but this imp only understands up to {}. \
+                 It was written by a newer imp.",
+                state_file.display(),
+                version,
+                STATE_VERSION
+            );
+        }
+
+        let mut generations: Vec<Generation> = serde_json::from_value(generations).context(
+            format!("Failed to read generations from: {}", state_file.display()),
+        )?;
+        migrate(version, &mut generations);
+        let state = CachedState {
+            generations,
+            format: Some(format),
+            stamp,
+        };
+        Ok((version, in_configured_format, state))
+    }
+
+    /// The state as it was before the last save, for a state file that
+    /// can't be parsed. Reported as not in the configured format, so
+    /// that `migrate_state` rewrites the damaged file.
+    fn recover(
+        &self,
+        state_file: &Path,
+        expected: StateFormat,
+    ) -> Option<(bool, StateFormat, u64, serde_json::Value)> {
+        let backup = backup_file(state_file);
+        let contents = fs::read_to_string(&backup).ok()?;
+        let (format, version, generations) = parse_generations(&contents, expected).ok()?;
+        eprintln!(
+            "⚠ State file {} is corrupt; using {} instead. The last change to the \
+             generations may be missing. Run 'imp migrate-state' to rewrite the state file.",
+            state_file.display(),
+            backup.display()
+        );
+        Some((false, format, version, generations))
+    }
+
+    /// Rewrite the state file in the current version and the configured
+    /// format. Returns the version it had before (`STATE_VERSION` if it was
+    /// current) and whether it had to be converted to the configured format.
+    pub fn migrate_state(&self) -> Result<(u64, bool)> {
+        let _lock = self.lock(FlockArg::LockExclusive)?;
+        let (version, in_configured_format, state) = self.read_state_locked()?;
+        let generations = state.generations.clone();
+        *self.cache.borrow_mut() = Some(state);
+        if version < STATE_VERSION || !in_configured_format {
+            self.save_generations(&generations)?;
+        }
+        Ok((version, !in_configured_format))
+    }
+
+    /// Change the generations and save them, holding the exclusive lock from
+    /// reading to saving. If another process saved since this one read the
+    /// state, `change` is made to what it saved rather than to the cached
+    /// state, so that neither change is lost.
+    fn update<T>(&self, change: impl FnOnce(&mut Vec<Generation>) -> Result<T>) -> Result<T> {
+        let _lock = self.lock(FlockArg::LockExclusive)?;
+        let current = self
+            .cache
+            .borrow()
+            .as_ref()
+            .is_some_and(|cached| cached.stamp == self.stamp());
+        if !current {
+            let state = self.read_state_locked()?.2;
+            *self.cache.borrow_mut() = Some(state);
+        }
+
+        let mut generations = self
+            .cache
+            .borrow()
+            .as_ref()
+            .map(|cached| cached.generations.clone())
+            .unwrap_or_default();
+        let result = change(&mut generations)?;
+        self.save_generations(&generations)?;
+        Ok(result)
+    }
+
+    /// Save generations to disk in the configured format, replacing the file
+    /// in one step so readers never see a partial write. The caller holds
+    /// the exclusive lock.
     fn save_generations(&self, generations: &[Generation]) -> Result<()> {
-        let contents = serde_json::to_string_pretty(generations)?;
-        fs::write(&self.generations_file, contents)?;
+        let contents = self.format.serialize(&StateFile {
+            version: STATE_VERSION,
+            generations,
+        })?;
+
+        let state_file = self.state_file(self.format);
+        // Keep the state being replaced to recover from, as it was read (a
+        // damaged file was recovered from the backup, so this is the last
+        // good state). Every save follows a read, so it is cached.
+        if let Some(cached) = &*self.cache.borrow() {
+            if let Some(format) = cached.format {
+                let previous = format.serialize(&StateFile {
+                    version: STATE_VERSION,
+                    generations: &cached.generations,
+                })?;
+                replace_file(&backup_file(&state_file), &previous, "state backup")?;
+            }
+        }
+
+        replace_file(&state_file, &contents, "state")?;
+        *self.cache.borrow_mut() = Some(CachedState {
+            generations: generations.to_vec(),
+            format: Some(self.format),
+            stamp: self.stamp(),
+        });
+
+        // Leave only one state file, so the two can't disagree
+        let other_file = self.state_file(self.format.other());
+        for file in [backup_file(&other_file), other_file] {
+            match fs::remove_file(&file) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).context(format!(
+                        "Failed to remove old state file: {}",
+                        file.display()
+                    ))
+                }
+                _ => {}
+            }
+        }
         Ok(())
     }
 
@@ -77,25 +646,28 @@ impl GenerationManager {
         config_path: PathBuf,
         symlinks: Vec<GenerationSymlink>,
     ) -> Result<Generation> {
-        let mut generations = self.load_generations()?;
-
-        // Deactivate all previous generations
-        for gen in &mut generations {
-            gen.active = false;
-        }
-
-        let generation = Generation {
-            number: self.next_generation_number()?,
-            created_at: Utc::now(),
-            config_path,
-            symlinks,
-            active: true,
-        };
+        self.update(|generations| {
+            // Deactivate all previous generations
+            for gen in generations.iter_mut() {
+                gen.active = false;
+            }
 
-        generations.push(generation.clone());
-        self.save_generations(&generations)?;
+            let generation = Generation {
+                number: generations.iter().map(|g| g.number).max().unwrap_or(0) + 1,
+                created_at: Utc::now(),
+                config_path,
+                symlinks,
+                active: true,
+                failed: Vec::new(),
+                note: None,
+                config_commit: None,
+                environment: GenerationEnvironment::capture(),
+                last_verified: None,
+            };
 
-        Ok(generation)
+            generations.push(generation.clone());
+            Ok(generation)
+        })
     }
 
     /// Get the currently active generation
@@ -111,39 +683,213 @@ impl GenerationManager {
 
     /// Switch to a specific generation
     pub fn switch_generation(&self, number: u64) -> Result<Generation> {
-        let mut generations = self.load_generations()?;
+        self.update(|generations| {
+            // Find the index first
+            let gen_index = generations
+                .iter()
+                .position(|g| g.number == number)
+                .context("Generation not found")?;
 
-        // Find the index first
-        let gen_index = generations
-            .iter()
-            .position(|g| g.number == number)
-            .context("Generation not found")?;
+            // Deactivate all
+            for g in generations.iter_mut() {
+                g.active = false;
+            }
 
-        // Deactivate all
-        for g in &mut generations {
-            g.active = false;
-        }
+            // Activate the selected one
+            generations[gen_index].active = true;
+            Ok(generations[gen_index].clone())
+        })
+    }
 
-        // Activate the selected one
-        generations[gen_index].active = true;
-        let result = generations[gen_index].clone();
+    /// Clear references to backups that no longer exist on disk
+    pub fn forget_backups(&self, backup_paths: &[PathBuf]) -> Result<()> {
+        self.update(|generations| {
+            for gen in generations.iter_mut() {
+                for gen_symlink in &mut gen.symlinks {
+                    let forgotten = gen_symlink
+                        .backup_path
+                        .as_deref()
+                        .is_some_and(|p| backup_paths.iter().any(|b| b.as_path() == p));
+                    if forgotten {
+                        gen_symlink.backup_path = None;
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
 
-        self.save_generations(&generations)?;
-        Ok(result)
+    /// Point entries of a generation at new backups, keyed by target
+    pub fn record_backups(&self, number: u64, backups: &[(PathBuf, PathBuf)]) -> Result<()> {
+        self.update(|generations| {
+            let gen = generations
+                .iter_mut()
+                .find(|g| g.number == number)
+                .context("Generation not found")?;
+            for gen_symlink in &mut gen.symlinks {
+                if let Some((_, backup)) = backups.iter().find(|(t, _)| *t == gen_symlink.target) {
+                    gen_symlink.backup_path = Some(backup.clone());
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Record the targets of a generation that failed to apply
+    pub fn record_failures(&self, number: u64, failed: Vec<PathBuf>) -> Result<()> {
+        self.update(|generations| {
+            let gen = generations
+                .iter_mut()
+                .find(|g| g.number == number)
+                .context("Generation not found")?;
+            gen.failed = failed;
+            Ok(())
+        })
+    }
+
+    /// Record when a verification of a generation that found no problems started
+    pub fn record_verified(&self, number: u64, at: DateTime<Utc>) -> Result<()> {
+        self.update(|generations| {
+            let gen = generations
+                .iter_mut()
+                .find(|g| g.number == number)
+                .context("Generation not found")?;
+            gen.last_verified = Some(at);
+            Ok(())
+        })
+    }
+
+    /// Record the commit a generation's config was checked out at
+    pub fn record_config_commit(&self, number: u64, commit: String) -> Result<()> {
+        self.update(|generations| {
+            let gen = generations
+                .iter_mut()
+                .find(|g| g.number == number)
+                .context("Generation not found")?;
+            gen.config_commit = Some(commit);
+            Ok(())
+        })
+    }
+
+    /// Add entries that were applied later to a generation, replacing its
+    /// list of failed targets with the ones that still fail
+    pub fn complete_generation(
+        &self,
+        number: u64,
+        symlinks: Vec<GenerationSymlink>,
+        failed: Vec<PathBuf>,
+    ) -> Result<Generation> {
+        self.update(|generations| {
+            let gen = generations
+                .iter_mut()
+                .find(|g| g.number == number)
+                .context("Generation not found")?;
+            gen.symlinks.extend(symlinks);
+            gen.failed = failed;
+            Ok(gen.clone())
+        })
+    }
+
+    /// Replace the note of a generation, or remove it with `None`
+    pub fn set_note(&self, number: u64, note: Option<String>) -> Result<()> {
+        self.update(|generations| {
+            let gen = generations
+                .iter_mut()
+                .find(|g| g.number == number)
+                .context(format!("Generation {} not found", number))?;
+            gen.note = note;
+            Ok(())
+        })
     }
 
     /// Delete a generation
     pub fn delete_generation(&self, number: u64) -> Result<()> {
-        let mut generations = self.load_generations()?;
+        self.update(|generations| {
+            if let Some(gen) = generations.iter().find(|g| g.number == number) {
+                if gen.active {
+                    anyhow::bail!("Cannot delete active generation");
+                }
+            }
 
-        if let Some(gen) = generations.iter().find(|g| g.number == number) {
-            if gen.active {
-                anyhow::bail!("Cannot delete active generation");
+            generations.retain(|g| g.number != number);
+            Ok(())
+        })
+    }
+
+    fn last_operation_file(&self) -> PathBuf {
+        self.state_dir.join("last-operation.json")
+    }
+
+    /// Remember `operation` as the one `imp undo` reverts
+    pub fn record_operation(&self, operation: Operation) -> Result<()> {
+        let contents = serde_json::to_string_pretty(&LastOperation {
+            at: Utc::now(),
+            operation,
+        })?;
+        let _lock = self.lock(FlockArg::LockExclusive)?;
+
+        let file = self.last_operation_file();
+        let temp_file = file.with_extension("tmp");
+        fs::write(&temp_file, contents).context(format!(
+            "Failed to write last operation: {}",
+            temp_file.display()
+        ))?;
+        fs::rename(&temp_file, &file).context(format!(
+            "Failed to replace last operation: {}",
+            file.display()
+        ))
+    }
+
+    /// The last recorded operation, if any
+    pub fn last_operation(&self) -> Result<Option<LastOperation>> {
+        let file = self.last_operation_file();
+        let contents = match fs::read_to_string(&file) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).context(format!("Failed to read last operation: {}", file.display()))
             }
+        };
+        serde_json::from_str(&contents).map(Some).context(format!(
+            "Failed to parse last operation: {}",
+            file.display()
+        ))
+    }
+
+    /// Forget the last operation once it has been undone
+    pub fn forget_operation(&self) -> Result<()> {
+        let file = self.last_operation_file();
+        match fs::remove_file(&file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).context(format!(
+                "Failed to remove last operation: {}",
+                file.display()
+            )),
+            _ => Ok(()),
         }
+    }
 
-        generations.retain(|g| g.number != number);
-        self.save_generations(&generations)?;
-        Ok(())
+    /// Leave no generation active, e.g. after taking down the only one
+    pub fn deactivate(&self) -> Result<()> {
+        self.update(|generations| {
+            for g in generations.iter_mut() {
+                g.active = false;
+            }
+            Ok(())
+        })
+    }
+
+    /// Delete several inactive generations with a single write
+    pub fn delete_generations(&self, numbers: &[u64]) -> Result<()> {
+        self.update(|generations| {
+            if generations
+                .iter()
+                .any(|g| g.active && numbers.contains(&g.number))
+            {
+                anyhow::bail!("Cannot delete active generation");
+            }
+
+            generations.retain(|g| !numbers.contains(&g.number));
+            Ok(())
+        })
     }
 }
@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A config file at a ref of a git repository, written `<repo>#<ref>:<path>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitSource {
+    /// Anything `git fetch` accepts: a URL, `host:path` or a local path
+    pub repo: String,
+    /// A branch, tag or commit hash
+    pub reference: String,
+    /// Path of the config file inside the repository
+    pub path: PathBuf,
+}
+
+impl std::str::FromStr for GitSource {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let invalid = || {
+            anyhow::anyhow!(
+                "Invalid git config source {:?}: expected <repo>#<ref>:<path>",
+                spec
+            )
+        };
+        // Repositories may contain ':' (URLs, scp-like syntax) but ref names can't
+        let (repo, rest) = spec.rsplit_once('#').ok_or_else(invalid)?;
+        let (reference, path) = rest.split_once(':').ok_or_else(invalid)?;
+        if repo.is_empty() || reference.is_empty() || path.is_empty() {
+            return Err(invalid());
+        }
+        // git would take these for options, e.g. --upload-pack=<command>
+        if repo.starts_with('-') || reference.starts_with('-') {
+            anyhow::bail!(
+                "Invalid git config source {:?}: the repository and ref can't start with '-'",
+                spec
+            );
+        }
+        Ok(Self {
+            repo: repo.to_string(),
+            reference: reference.to_string(),
+            path: PathBuf::from(path.trim_start_matches('/')),
+        })
+    }
+}
+
+impl std::fmt::Display for GitSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}#{}:{}",
+            self.repo,
+            self.reference,
+            self.path.display()
+        )
+    }
+}
+
+/// A shallow checkout of a `GitSource`, removed when dropped
+pub struct Checkout {
+    dir: PathBuf,
+    /// The commit the ref resolved to
+    pub commit: String,
+    /// The config file inside the checkout
+    pub config_path: PathBuf,
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Run git, failing with its stderr if it exits non-zero. Credentials come
+/// from git's own mechanisms (credential helpers, ssh-agent, GIT_ASKPASS, ...).
+fn git(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .context("Failed to run git; is it installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Fetch only the commit `source.reference` points to into a temporary
+/// directory and check it out
+pub fn checkout(source: &GitSource) -> Result<Checkout> {
+    let dir = std::env::temp_dir().join(format!("imp-git-config-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).context(format!(
+        "Failed to create checkout directory: {}",
+        dir.display()
+    ))?;
+    // Owns the directory from here on, so it goes away on any error
+    let mut checkout = Checkout {
+        dir,
+        commit: String::new(),
+        config_path: PathBuf::new(),
+    };
+    let dir = checkout.dir.to_string_lossy().to_string();
+
+    git(&["init", "--quiet", &dir])?;
+    git(&[
+        "-C",
+        &dir,
+        "fetch",
+        "--quiet",
+        "--depth",
+        "1",
+        "--",
+        &source.repo,
+        &source.reference,
+    ])
+    .context(format!(
+        "Failed to fetch {} from {}",
+        source.reference, source.repo
+    ))?;
+    git(&["-C", &dir, "checkout", "--quiet", "FETCH_HEAD"])?;
+    checkout.commit = git(&["-C", &dir, "rev-parse", "HEAD"])?;
+
+    checkout.config_path = Path::new(&dir).join(&source.path);
+    if !checkout.config_path.is_file() {
+        anyhow::bail!(
+            "{} does not exist at {} ({}) in {}",
+            source.path.display(),
+            source.reference,
+            checkout.commit,
+            source.repo
+        );
+    }
+    Ok(checkout)
+}
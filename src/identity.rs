@@ -0,0 +1,46 @@
+use anyhow::{Context, Result};
+use nix::unistd::{setfsgid, setfsuid, Gid, Uid, User};
+
+/// The filesystem uid/gid that files and directories created by imp are attributed to.
+///
+/// Switching only the filesystem identity (rather than the effective uid) keeps
+/// root's CAP_SYS_ADMIN available for the mount syscalls.
+#[derive(Debug, Clone, Copy)]
+pub struct FsIdentity {
+    uid: Uid,
+    gid: Gid,
+}
+
+impl FsIdentity {
+    /// Look up a user and use its uid and primary gid
+    pub fn from_username(username: &str) -> Result<Self> {
+        let user = User::from_name(username)
+            .context(format!("Failed to lookup user: {}", username))?
+            .context(format!("User not found: {}", username))?;
+
+        Ok(Self {
+            uid: user.uid,
+            gid: user.gid,
+        })
+    }
+
+    /// Run `f` with the filesystem uid/gid switched to this identity
+    pub fn run<T>(&self, f: impl FnOnce() -> T) -> T {
+        let previous_gid = setfsgid(self.gid);
+        let previous_uid = setfsuid(self.uid);
+
+        let result = f();
+
+        setfsuid(previous_uid);
+        setfsgid(previous_gid);
+        result
+    }
+}
+
+/// Run `f` as `identity` if one is given, otherwise as the current user
+pub fn run_as<T>(identity: Option<&FsIdentity>, f: impl FnOnce() -> T) -> T {
+    match identity {
+        Some(identity) => identity.run(f),
+        None => f(),
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by the first SIGINT or SIGTERM while a guard is installed
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+const SIGNALS: [Signal; 2] = [Signal::SIGINT, Signal::SIGTERM];
+
+/// Written straight to stderr by the handler, which can't use `eprintln!`
+const EXIT_MESSAGE: &[u8] = b"\nInterrupted again: exiting immediately without rolling back\n";
+
+extern "C" fn handle_interrupt(_: nix::libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // SAFETY: write and _exit are async-signal-safe
+        unsafe {
+            nix::libc::write(2, EXIT_MESSAGE.as_ptr().cast(), EXIT_MESSAGE.len());
+            nix::libc::_exit(130);
+        }
+    }
+}
+
+/// Turns the first SIGINT/SIGTERM into a request to stop (see `requested`)
+/// and the second into an immediate exit, until dropped
+pub struct InterruptGuard {
+    previous: Vec<(Signal, SigAction)>,
+}
+
+/// Catch SIGINT and SIGTERM so a partial apply can be rolled back
+pub fn install() -> Result<InterruptGuard> {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    let action = SigAction::new(
+        SigHandler::Handler(handle_interrupt),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+
+    let mut previous = Vec::new();
+    for signal in SIGNALS {
+        // SAFETY: the handler only touches an atomic and calls async-signal-safe functions
+        let old = unsafe { sigaction(signal, &action) }
+            .context(format!("Failed to install handler for {}", signal))?;
+        previous.push((signal, old));
+    }
+
+    Ok(InterruptGuard { previous })
+}
+
+/// Whether SIGINT or SIGTERM arrived since the guard was installed
+pub fn requested() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        // Put back whatever was there before, e.g. the daemon's shutdown handler
+        for (signal, old) in &self.previous {
+            // SAFETY: restores a previously installed, valid action
+            let _ = unsafe { sigaction(*signal, old) };
+        }
+    }
+}
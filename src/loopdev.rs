@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Major device number of loop devices
+const LOOP_MAJOR: u64 = 7;
+
+/// Detach the loop device automatically once it is no longer in use
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+
+/// How often to retry when another process claims the free loop device first
+const ATTACH_ATTEMPTS: usize = 5;
+
+/// `struct loop_info64` from <linux/loop.h>
+#[repr(C)]
+pub struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; 64],
+    lo_crypt_name: [u8; 64],
+    lo_encrypt_key: [u8; 32],
+    lo_init: [u64; 2],
+}
+
+nix::ioctl_none_bad!(loop_ctl_get_free, 0x4C82);
+nix::ioctl_write_int_bad!(loop_set_fd, 0x4C00);
+nix::ioctl_none_bad!(loop_clr_fd, 0x4C01);
+nix::ioctl_write_ptr_bad!(loop_set_status64, 0x4C04, LoopInfo64);
+
+/// A loop device backed by an image file. The device detaches itself once it
+/// is unmounted and this handle is dropped, so keep it until the mount exists.
+pub struct LoopDevice {
+    pub path: PathBuf,
+    _file: File,
+}
+
+/// Attach `image` to a free loop device
+pub fn attach(image: &Path) -> Result<LoopDevice> {
+    let image_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image)
+        .context(format!("Failed to open image: {}", image.display()))?;
+    let control = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/loop-control")
+        .context("Failed to open /dev/loop-control (is the loop module loaded?)")?;
+
+    for _ in 0..ATTACH_ATTEMPTS {
+        // SAFETY: LOOP_CTL_GET_FREE takes no argument
+        let number = unsafe { loop_ctl_get_free(control.as_raw_fd()) }
+            .context("Failed to find a free loop device")?;
+        let path = PathBuf::from(format!("/dev/loop{}", number));
+        let device = open_loop_device(&path, number as u64)?;
+
+        // SAFETY: LOOP_SET_FD takes the backing file descriptor by value
+        match unsafe { loop_set_fd(device.as_raw_fd(), image_file.as_raw_fd()) } {
+            Ok(_) => {}
+            Err(Errno::EBUSY) => continue,
+            Err(e) => {
+                return Err(e).context(format!(
+                    "Failed to attach {} to {}",
+                    image.display(),
+                    path.display()
+                ))
+            }
+        }
+
+        // SAFETY: all-zero is a valid loop_info64 (integers and byte arrays only)
+        let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+        info.lo_flags = LO_FLAGS_AUTOCLEAR;
+        let name = image.as_os_str().as_encoded_bytes();
+        let len = name.len().min(info.lo_file_name.len() - 1);
+        info.lo_file_name[..len].copy_from_slice(&name[..len]);
+
+        // SAFETY: `info` is a valid loop_info64 that outlives the call
+        if let Err(e) = unsafe { loop_set_status64(device.as_raw_fd(), &info) } {
+            // SAFETY: LOOP_CLR_FD takes no argument
+            let _ = unsafe { loop_clr_fd(device.as_raw_fd()) };
+            return Err(e).context(format!("Failed to configure {}", path.display()));
+        }
+
+        return Ok(LoopDevice {
+            path,
+            _file: device,
+        });
+    }
+
+    anyhow::bail!(
+        "Failed to attach {}: loop devices kept being claimed by other processes",
+        image.display()
+    )
+}
+
+/// Open a loop device node, creating it first if /dev doesn't have it yet
+/// (e.g. inside a container whose /dev was populated before the device existed)
+fn open_loop_device(path: &Path, number: u64) -> Result<File> {
+    if !path.exists() {
+        mknod(
+            path,
+            SFlag::S_IFBLK,
+            Mode::from_bits_truncate(0o660),
+            makedev(LOOP_MAJOR, number),
+        )
+        .context(format!("Failed to create {}", path.display()))?;
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context(format!("Failed to open {}", path.display()))
+}
+
+/// The file backing a loop device, or `None` if it isn't attached
+pub fn backing_file(loop_device: &Path) -> Option<PathBuf> {
+    let name = loop_device.file_name()?.to_str()?;
+    let backing = fs::read_to_string(format!("/sys/block/{}/loop/backing_file", name)).ok()?;
+    Some(PathBuf::from(backing.trim_end()))
+}
+
+/// Detach `loop_device` if it is still backed by `image`. Returns whether it
+/// was detached; one that was already released (or reused for another file)
+/// is left alone.
+pub fn detach_if_backed_by(loop_device: &Path, image: &Path) -> Result<bool> {
+    let image = fs::canonicalize(image).unwrap_or_else(|_| image.to_path_buf());
+    if backing_file(loop_device).as_deref() != Some(image.as_path()) {
+        return Ok(false);
+    }
+
+    let device =
+        File::open(loop_device).context(format!("Failed to open {}", loop_device.display()))?;
+    // SAFETY: LOOP_CLR_FD takes no argument
+    match unsafe { loop_clr_fd(device.as_raw_fd()) } {
+        Ok(_) => Ok(true),
+        // Released by autoclear in the meantime
+        Err(Errno::ENXIO) => Ok(false),
+        Err(e) => Err(e).context(format!("Failed to detach {}", loop_device.display())),
+    }
+}
@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable overriding the machine id, e.g. for containers that
+/// share the host's `/etc/machine-id`
+pub const MACHINE_ID_VAR: &str = "IMP_MACHINE_ID";
+
+const MACHINE_ID_FILE: &str = "/etc/machine-id";
+
+/// Subdirectory of the state dir holding one state directory per machine
+const MACHINES_DIR: &str = "machines";
+
+/// This machine's id: `$IMP_MACHINE_ID` if set, else `/etc/machine-id`
+pub fn id() -> Result<String> {
+    let id = match std::env::var(MACHINE_ID_VAR) {
+        Ok(id) if !id.is_empty() => id,
+        _ => fs::read_to_string(MACHINE_ID_FILE).context(format!(
+            "Failed to read machine id from {} (set {} to override it)",
+            MACHINE_ID_FILE, MACHINE_ID_VAR
+        ))?,
+    };
+    let id = id.trim().to_string();
+
+    // Used as a directory name
+    if id.is_empty() || id == "." || id == ".." || id.contains('/') {
+        anyhow::bail!("Invalid machine id: {:?}", id);
+    }
+    Ok(id)
+}
+
+/// Where this machine's generations are kept within a shared state dir
+pub fn state_dir(state_dir: &Path) -> Result<PathBuf> {
+    Ok(state_dir.join(MACHINES_DIR).join(id()?))
+}
+
+/// Every machine with generations in a shared state dir, as (id, state
+/// directory) pairs ordered by id
+pub fn all(state_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let machines_dir = state_dir.join(MACHINES_DIR);
+    if !machines_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut machines = Vec::new();
+    for entry in fs::read_dir(&machines_dir).context(format!(
+        "Failed to read machines directory: {}",
+        machines_dir.display()
+    ))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            machines.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                entry.path(),
+            ));
+        }
+    }
+    machines.sort();
+    Ok(machines)
+}
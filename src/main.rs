@@ -1,14 +1,49 @@
+mod archive;
+mod backup;
+mod caps;
 mod config;
+mod daemon;
+mod debugdump;
+mod dedupe;
+mod environment;
+mod fsck;
 mod generation;
+mod gitsource;
+mod identity;
+mod interrupt;
+mod loopdev;
+mod machine;
+mod metrics;
+mod mode;
+mod mounts;
+mod namespace;
+mod nix_import;
+mod pathcache;
+mod problems;
+mod progress;
+mod remote;
+mod remount;
+mod retention;
+mod staging;
+mod switchplan;
 mod symlink;
+mod validators;
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::collections::{BTreeMap, HashMap};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use config::Config;
-use generation::GenerationManager;
-use symlink::SymlinkManager;
+use config::{Config, ConfigFormat};
+use generation::{Generation, GenerationManager, GenerationSymlink, Operation};
+use gitsource::GitSource;
+use identity::FsIdentity;
+use progress::Event;
+use retention::Retention;
+use staging::StagingArea;
+use symlink::{ApplyOutcome, SymlinkManager};
 
 #[derive(Parser)]
 #[command(name = "imp")]
@@ -18,6 +53,32 @@ struct Cli {
     #[arg(short, long, global = true, default_value = "imp.toml")]
     config: PathBuf,
 
+    /// Parse the config as this format regardless of its file name
+    /// (default: JSON for a .json extension, YAML for .yaml or .yml, TOML otherwise)
+    #[arg(long, global = true, value_name = "FORMAT")]
+    config_format: Option<ConfigFormat>,
+
+    /// Show detailed diagnostics for errors (e.g. where a path fails to resolve)
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Create all targets under this directory instead of / (e.g. a mounted disk image)
+    #[arg(long, global = true, value_name = "DIR")]
+    target_root: Option<PathBuf>,
+
+    /// Abandon any single mount or unmount that takes longer than this
+    #[arg(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// Merge this [env.<name>] section of the config (default: $IMP_ENV, then the hostname)
+    #[arg(long, global = true, value_name = "NAME")]
+    env: Option<String>,
+
+    /// Testing only: record bind mounts in generations without performing them
+    /// (same as IMP_NO_MOUNT=1)
+    #[arg(long, global = true, hide = true)]
+    no_mount: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -29,21 +90,239 @@ enum Commands {
         /// Skip validation before applying
         #[arg(short, long)]
         skip_validation: bool,
+
+        /// Create sources, directories and symlinks as this user (mounts still run as root)
+        #[arg(long, value_name = "USER")]
+        as_user: Option<String>,
+
+        /// Create mounts in a private mount namespace (they vanish when imp exits)
+        #[arg(long)]
+        namespace: bool,
+
+        /// Summarize targets added, removed and changed relative to the active generation
+        #[arg(long)]
+        compare_to_current: bool,
+
+        /// Skip entries whose source doesn't exist instead of creating it
+        #[arg(long)]
+        skip_missing: bool,
+
+        /// Apply every entry that can be applied, record those, and report failures at the end
+        #[arg(long)]
+        keep_going: bool,
+
+        /// Back up and replace files that sit where a target's parent directory should be
+        #[arg(long)]
+        force: bool,
+
+        /// Only apply directory bind mounts, leaving file symlinks out of the generation
+        #[arg(long, conflicts_with = "links_only")]
+        mount_only: bool,
+
+        /// Only apply file symlinks, leaving directory bind mounts out of the generation
+        #[arg(long)]
+        links_only: bool,
+
+        /// Only apply the entries that failed under --keep-going, adding them to the
+        /// active generation instead of creating a new one
+        #[arg(long, conflicts_with_all = ["mount_only", "links_only", "compare_to_current"])]
+        resume: bool,
+
+        /// Don't verify the new generation's symlinks and mounts before activating it
+        #[arg(long)]
+        no_verify: bool,
+
+        /// List the targets that would be backed up and where the backups would go,
+        /// then stop without changing anything
+        #[arg(long, conflicts_with = "resume")]
+        preview_backups: bool,
+
+        /// Print what would happen to each entry (sources created, targets
+        /// backed up or removed, conflicts) and the problems validate would
+        /// report, then stop without changing anything
+        #[arg(long, conflicts_with_all = ["resume", "preview_backups", "confirm_each"])]
+        dry_run: bool,
+
+        /// Ask before replacing anything that exists at a target: apply, skip the
+        /// entry (leaving it out of the generation) or abort. Needs a terminal
+        #[arg(long, conflicts_with = "resume")]
+        confirm_each: bool,
+
+        /// When --config is a glob pattern, merge the matching configs into one
+        /// generation instead of applying each as its own
+        #[arg(long)]
+        merge: bool,
+
+        /// How to report progress
+        #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+        output: OutputFormat,
+
+        /// Replace bind mounts the active generation also has at a target in
+        /// place, so the target is never left unmounted (Linux 6.5 or later)
+        #[arg(long)]
+        atomic_remount: bool,
+
+        /// Create and verify every entry in a staging area first, and only then
+        /// take down the active generation and move the new one into place
+        #[arg(
+            long,
+            conflicts_with_all = ["resume", "keep_going", "confirm_each", "atomic_remount", "no_verify", "as_user"]
+        )]
+        two_phase: bool,
+
+        /// Check that every directory target ends up with the owner, group and
+        /// mode the config requests, rolling back the apply if any doesn't
+        #[arg(long, conflicts_with = "resume")]
+        strict_ownership: bool,
+
+        /// Apply the config file at a ref of a git repository instead of --config,
+        /// e.g. https://example.com/configs.git#main:hosts/web.toml
+        #[arg(long, value_name = "REPO#REF:PATH", conflicts_with = "merge")]
+        config_git: Option<GitSource>,
+    },
+
+    /// Check the configuration without changing anything, reporting every
+    /// problem with the entry it comes from
+    Validate {
+        /// Print the problems as a JSON document
+        #[arg(long)]
+        json: bool,
+
+        /// Also check the config against the generations recorded in this
+        /// state directory: targets they record differently, and backups
+        /// the next apply would leave unrecorded
+        #[arg(long, value_name = "STATE_DIR")]
+        against: Option<PathBuf>,
     },
 
     /// List all generations
-    List,
+    List {
+        /// Print each generation with this template instead, e.g. "{number}\t{date}".
+        /// Placeholders: {number}, {date}, {count}, {active}, {note}
+        #[arg(long, value_name = "TEMPLATE")]
+        format: Option<String>,
+
+        /// Show creation times in the local timezone instead of UTC
+        #[arg(long)]
+        local: bool,
+
+        /// Print only "total=N active=M" (active=none if no generation is active)
+        #[arg(long, conflicts_with_all = ["format", "local", "sort", "reverse", "notes"])]
+        count: bool,
+
+        /// Show each generation's note below it
+        #[arg(long, conflicts_with = "format")]
+        notes: bool,
+
+        /// Order generations by this key
+        #[arg(long, value_enum, default_value_t = ListSort::Number)]
+        sort: ListSort,
+
+        /// Reverse the order
+        #[arg(long)]
+        reverse: bool,
+
+        /// List the generations of every machine sharing a per_machine state dir
+        #[arg(long, conflicts_with_all = ["format", "count"])]
+        all_machines: bool,
+    },
+
+    /// Show what each generation added, removed and changed relative to the one before it
+    Log {
+        /// Show creation times in the local timezone instead of UTC
+        #[arg(long)]
+        local: bool,
+    },
 
     /// Show information about a specific generation
     Show {
         /// Generation number to show
         number: u64,
+
+        /// Show the creation time in the local timezone instead of UTC
+        #[arg(long)]
+        local: bool,
+
+        /// Compare the generation with what its config file produces now
+        #[arg(long)]
+        diff_config: bool,
+    },
+
+    /// Compare a generation with another generation, or with the filesystem
+    Diff {
+        /// Generation to compare
+        number: u64,
+
+        /// Generation to compare it with
+        #[arg(required_unless_present = "fs", conflicts_with = "fs")]
+        other: Option<u64>,
+
+        /// Compare with what is actually on disk now
+        #[arg(long)]
+        fs: bool,
     },
 
     /// Switch to a different generation
     Switch {
         /// Generation number to switch to
         number: u64,
+
+        /// Don't verify the generation's symlinks and mounts after switching
+        #[arg(long)]
+        no_verify: bool,
+
+        /// Back up content found at the generation's targets before recreating them
+        #[arg(long)]
+        backup_on_switch: bool,
+
+        /// Delete the backups of the generation being left instead of restoring them
+        #[arg(long)]
+        clean: bool,
+
+        /// Show what would be removed and recreated without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// With --dry-run, print the plan as JSON
+        #[arg(long, requires = "dry_run")]
+        json: bool,
+    },
+
+    /// Set, extend or remove the free-form note of a generation
+    Note {
+        /// Generation number
+        number: u64,
+
+        /// The note, replacing any existing one
+        #[arg(required_unless_present = "clear")]
+        text: Option<String>,
+
+        /// Add the text as a new line of the existing note instead
+        #[arg(long)]
+        append: bool,
+
+        /// Remove the note
+        #[arg(long, conflicts_with_all = ["text", "append"])]
+        clear: bool,
+    },
+
+    /// Revert the last apply (switching back and deleting the generation it
+    /// created) or the last switch
+    Undo,
+
+    /// Find generations with identical symlink sets and delete the redundant ones
+    Dedupe {
+        /// Only report the groups of identical generations (the default)
+        #[arg(long, conflicts_with = "apply")]
+        dry_run: bool,
+
+        /// Delete all but one generation of each group: the active one, or the newest
+        #[arg(long)]
+        apply: bool,
+
+        /// Never delete this generation (can be repeated)
+        #[arg(long = "keep-generation", value_name = "NUMBER")]
+        keep_generations: Vec<u64>,
     },
 
     /// Delete a generation
@@ -54,268 +333,2568 @@ enum Commands {
         /// Force deletion without confirmation
         #[arg(short, long)]
         force: bool,
+
+        /// Show what would be deleted without changing anything
+        #[arg(long)]
+        dry_run: bool,
     },
 
     /// Verify the current generation's symlinks
-    Verify,
+    Verify {
+        /// Recreate any broken symlinks or mounts and re-verify
+        #[arg(long)]
+        fix: bool,
+
+        /// With --fix, print the repair actions instead of performing them
+        #[arg(long, requires = "fix")]
+        dry_run: bool,
+
+        /// Also fail on missing sources and backups, and exit non-zero on any problem
+        #[arg(long)]
+        strict: bool,
+
+        /// Only check entries whose source changed since the last --only-changed
+        /// or --full run that found no problems (trusts source timestamps)
+        #[arg(long, conflicts_with = "fix", overrides_with = "full")]
+        only_changed: bool,
+
+        /// Check every entry, and record the time for later --only-changed runs
+        #[arg(long, overrides_with = "only_changed")]
+        full: bool,
+    },
+
+    /// Reconcile the state file with the live filesystem in both directions
+    Fsck {
+        /// Restore missing or wrong entries and take down orphaned ones
+        #[arg(long)]
+        fix: bool,
+    },
+
+    /// List the active generation's mounts as the kernel reports them
+    Mounts,
 
     /// Show the currently active generation
-    Current,
+    Current {
+        /// Show the creation time in the local timezone instead of UTC
+        #[arg(long)]
+        local: bool,
+
+        /// Show the active generation of every machine sharing a per_machine state dir
+        #[arg(long, conflicts_with = "local")]
+        all_machines: bool,
+    },
+
+    /// Print generation and verification metrics in the Prometheus text format
+    Metrics {
+        /// Write to this file instead of stdout (replaced atomically, for
+        /// node_exporter's textfile collector)
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Print a report to attach to bug reports: imp's version, the environment,
+    /// capabilities, the active generation, the mounts around its targets and
+    /// the config file
+    DebugDump {
+        /// Print the report as a JSON document
+        #[arg(long)]
+        json: bool,
+
+        /// Replace the config and state paths, persistence roots, targets,
+        /// sources and backups with placeholders
+        #[arg(long)]
+        redact_paths: bool,
+    },
+
+    /// Rewrite the state file in the current format
+    MigrateState,
+
+    /// Convert a NixOS impermanence `environment.persistence` definition to imp.toml
+    ImportNix {
+        /// Nix file, or JSON from `nix eval --json ...environment.persistence`
+        file: PathBuf,
+
+        /// Write the config here instead of printing it
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+
+    /// Bundle the persisted data of a generation into a .tar.zst archive
+    Archive {
+        /// Generation number
+        number: u64,
+
+        /// Archive file to write
+        output: PathBuf,
+    },
+
+    /// Restore the data from an archive into a new persistence root
+    Unarchive {
+        /// Archive created by `imp archive`
+        archive: PathBuf,
+
+        /// Directory to restore into (must be empty or not exist)
+        root: PathBuf,
+    },
+
+    /// Apply a configuration and hold its mounts until SIGTERM/SIGINT
+    Daemon {
+        /// Re-verify the mounts every N seconds, repairing any that broke
+        #[arg(long, value_name = "SECONDS")]
+        verify_interval: Option<u64>,
+
+        /// Create mounts in the host mount namespace instead of a private one
+        #[arg(long)]
+        no_namespace: bool,
+
+        /// Answer health checks (see `imp ping`) on a Unix socket at this path
+        #[arg(long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+    },
+
+    /// Query the health socket of a running `imp daemon --socket`
+    Ping {
+        /// Socket path given to `imp daemon --socket`
+        socket: PathBuf,
+    },
+
+    /// Print a JSON Schema of the config file format, for editors and linters
+    #[command(hide = true)]
+    GenConfigSchema,
+
+    /// Print generation numbers one per line, for shell completion scripts
+    #[command(name = "__complete-generations", hide = true)]
+    CompleteGenerations,
+}
+
+/// Order of `list` output
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ListSort {
+    /// Generation number
+    Number,
+    /// Creation time, ties broken by number
+    Date,
+}
+
+/// Progress output of `apply`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable lines
+    Human,
+    /// One JSON event per line on stdout; human-readable lines go to stderr
+    JsonLines,
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    let timeout = cli.timeout.map(Duration::from_secs);
+
+    if cli.no_mount {
+        // Set before any SymlinkManager is created, which reads it
+        std::env::set_var(symlink::NO_MOUNT_VAR, "1");
+    }
+    if symlink::no_mount_requested() {
+        eprintln!(
+            "⚠ {} is set: bind mounts are recorded but not performed. For testing only.",
+            symlink::NO_MOUNT_VAR
+        );
+    }
 
     match cli.command {
-        Commands::Apply { skip_validation } => apply_config(&cli.config, skip_validation)?,
-        Commands::List => list_generations(&cli.config)?,
-        Commands::Show { number } => show_generation(&cli.config, number)?,
-        Commands::Switch { number } => switch_generation(&cli.config, number)?,
-        Commands::Delete { number, force } => delete_generation(&cli.config, number, force)?,
-        Commands::Verify => verify_generation(&cli.config)?,
-        Commands::Current => show_current_generation(&cli.config)?,
+        Commands::Apply {
+            skip_validation,
+            as_user,
+            namespace,
+            compare_to_current,
+            skip_missing,
+            keep_going,
+            force,
+            mount_only,
+            links_only,
+            resume,
+            no_verify,
+            preview_backups,
+            dry_run,
+            confirm_each,
+            merge,
+            output,
+            atomic_remount,
+            two_phase,
+            strict_ownership,
+            config_git,
+        } => {
+            if confirm_each && !std::io::stdin().is_terminal() {
+                anyhow::bail!(
+                    "--confirm-each needs a terminal to prompt on, but stdin is not a terminal"
+                );
+            }
+            if output == OutputFormat::JsonLines {
+                progress::enable_json_lines()?;
+            }
+            if namespace {
+                namespace::enter_private_mount_namespace()?;
+                println!("ℹ Using a private mount namespace: mounts will disappear when imp exits");
+            }
+            let checkout = config_git.as_ref().map(gitsource::checkout).transpose()?;
+            if let (Some(source), Some(checkout)) = (&config_git, &checkout) {
+                println!("Checked out {} at {}", source, checkout.commit);
+            }
+            let options = ApplyOptions {
+                config_format: cli.config_format,
+                skip_validation,
+                as_user: as_user.as_deref(),
+                target_root: cli.target_root.as_deref(),
+                env: cli.env.as_deref(),
+                compare_to_current,
+                skip_missing,
+                keep_going,
+                force,
+                mount_only,
+                links_only,
+                resume,
+                no_verify,
+                preview_backups,
+                dry_run,
+                confirm_each,
+                atomic_remount,
+                two_phase,
+                strict_ownership,
+                git_source: config_git.as_ref().zip(checkout.as_ref()),
+                timeout,
+                verbose: cli.verbose,
+            };
+            let result = if let Some(checkout) = &checkout {
+                apply_config(&checkout.config_path, &options)
+            } else if config::is_pattern(&cli.config) && !merge {
+                apply_each_config(&cli.config, &options)
+            } else {
+                apply_config(&cli.config, &options)
+            };
+            if let Err(e) = &result {
+                progress::emit(&Event::Error {
+                    message: format!("{:#}", e),
+                });
+            }
+            result?
+        }
+        Commands::Validate { json, against } => {
+            validate_config(&cli.config, cli.config_format, json, against.as_deref())?
+        }
+        Commands::List {
+            format,
+            local,
+            count,
+            notes,
+            sort,
+            reverse,
+            all_machines,
+        } => {
+            if all_machines {
+                list_machine_generations(
+                    &cli.config,
+                    cli.config_format,
+                    local,
+                    notes,
+                    sort,
+                    reverse,
+                )?
+            } else {
+                list_generations(
+                    &open_generations(&cli.config, cli.config_format)?,
+                    format.as_deref(),
+                    local,
+                    count,
+                    notes,
+                    sort,
+                    reverse,
+                )?
+            }
+        }
+        Commands::Note {
+            number,
+            text,
+            append,
+            clear,
+        } => note_generation(&cli.config, cli.config_format, number, text, append, clear)?,
+        Commands::Log { local } => log_generations(&cli.config, cli.config_format, local)?,
+        Commands::Show {
+            number,
+            local,
+            diff_config,
+        } => show_generation(
+            &cli.config,
+            cli.config_format,
+            number,
+            local,
+            diff_config,
+            cli.target_root.as_deref(),
+            cli.env.as_deref(),
+        )?,
+        Commands::Switch {
+            number,
+            clean,
+            dry_run: true,
+            json,
+            ..
+        } => preview_switch(&cli.config, cli.config_format, number, clean, json)?,
+        Commands::Switch {
+            number,
+            no_verify,
+            backup_on_switch,
+            clean,
+            ..
+        } => switch_generation(
+            &cli.config,
+            cli.config_format,
+            number,
+            no_verify,
+            backup_on_switch,
+            clean,
+            timeout,
+        )?,
+        Commands::Undo => undo_last_operation(&cli.config, cli.config_format, timeout)?,
+        Commands::Dedupe {
+            dry_run: _,
+            apply,
+            keep_generations,
+        } => dedupe_generations(&cli.config, cli.config_format, apply, &keep_generations)?,
+        Commands::Delete {
+            number,
+            force,
+            dry_run,
+        } => delete_generation(&cli.config, cli.config_format, number, force, dry_run)?,
+        Commands::Verify {
+            fix,
+            dry_run,
+            strict,
+            only_changed,
+            full,
+        } => verify_generation(
+            &cli.config,
+            cli.config_format,
+            fix,
+            dry_run,
+            strict,
+            VerifyScope::from_flags(only_changed, full),
+            timeout,
+        )?,
+        Commands::Fsck { fix } => fsck_generation(&cli.config, cli.config_format, fix, timeout)?,
+        Commands::Diff { number, other, fs } => match other {
+            Some(other) if !fs => diff_generations(&cli.config, cli.config_format, number, other)?,
+            _ => diff_filesystem(&cli.config, cli.config_format, number, timeout)?,
+        },
+        Commands::Mounts => list_mounts(&cli.config, cli.config_format)?,
+        Commands::Current {
+            local,
+            all_machines,
+        } => {
+            if all_machines {
+                show_machine_current_generations(&cli.config, cli.config_format)?
+            } else {
+                show_current_generation(&cli.config, cli.config_format, local)?
+            }
+        }
+        Commands::Metrics { output } => {
+            write_metrics(&cli.config, cli.config_format, output.as_deref(), timeout)?
+        }
+        Commands::DebugDump { json, redact_paths } => {
+            debug_dump(&cli.config, cli.config_format, json, redact_paths)?
+        }
+        Commands::MigrateState => migrate_state(&cli.config, cli.config_format)?,
+        Commands::ImportNix { file, output } => import_nix(&file, output.as_ref())?,
+        Commands::Archive { number, output } => {
+            archive_generation(&cli.config, cli.config_format, number, &output)?
+        }
+        Commands::Unarchive { archive, root } => unarchive(&archive, &root)?,
+        Commands::Daemon {
+            verify_interval,
+            no_namespace,
+            socket,
+        } => run_daemon(
+            &cli.config,
+            verify_interval,
+            no_namespace,
+            socket.as_deref(),
+            &ApplyOptions {
+                config_format: cli.config_format,
+                target_root: cli.target_root.as_deref(),
+                env: cli.env.as_deref(),
+                timeout,
+                verbose: cli.verbose,
+                ..Default::default()
+            },
+        )?,
+        Commands::Ping { socket } => ping_daemon(&socket)?,
+        Commands::GenConfigSchema => {
+            let schema = schemars::schema_for!(Config);
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+        }
+        Commands::CompleteGenerations => complete_generations(&cli.config, cli.config_format),
     }
 
     Ok(())
 }
 
-/// Get the state directory from config file, or use default if config doesn't exist
-fn get_state_dir(config_path: &PathBuf) -> PathBuf {
+/// Open the generations kept in the config's state directory
+fn open_generations(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+) -> Result<GenerationManager> {
     // Try to load config and get state_dir
-    if let Ok(config) = Config::from_file(config_path) {
-        config.state_dir
+    if let Ok(config) = Config::from_file(config_path, config_format) {
+        Ok(GenerationManager::new(config.generations_dir()?)?.with_format(config.state_format))
     } else {
         // Fall back to default location
-        dirs::data_local_dir()
-            .unwrap_or_else(|| PathBuf::from("."))
-            .join("imp")
+        GenerationManager::new(
+            dirs::data_local_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("imp"),
+        )
     }
 }
 
-fn apply_config(config_path: &PathBuf, skip_validation: bool) -> Result<()> {
-    println!("Loading configuration from: {}", config_path.display());
-
-    let config = Config::from_file(config_path)?;
-
-    if !skip_validation {
-        println!("Validating configuration...");
-        config.validate()?;
+/// Annotation for generations whose config file has since been moved or deleted
+fn config_missing_note(gen: &Generation) -> &'static str {
+    if gen.config_path.exists()
+        || gen.config_commit.is_some()
+        || remote::as_url(&gen.config_path).is_some()
+        || (config::is_pattern(&gen.config_path)
+            && config::expand_pattern(&gen.config_path).is_ok())
+    {
+        ""
+    } else {
+        " (config file missing)"
     }
+}
 
-    // Convert persistence config to symlinks
-    let symlinks = config.to_symlinks();
-
-    let symlink_manager = SymlinkManager::new();
-    let generation_manager = GenerationManager::new(config.state_dir.clone())?;
+/// Options for `apply_config`
+#[derive(Default)]
+struct ApplyOptions<'a> {
+    /// Parse the config as this format whatever its file name
+    config_format: Option<ConfigFormat>,
+    skip_validation: bool,
+    as_user: Option<&'a str>,
+    target_root: Option<&'a Path>,
+    env: Option<&'a str>,
+    compare_to_current: bool,
+    skip_missing: bool,
+    keep_going: bool,
+    force: bool,
+    mount_only: bool,
+    links_only: bool,
+    resume: bool,
+    no_verify: bool,
+    preview_backups: bool,
+    dry_run: bool,
+    confirm_each: bool,
+    atomic_remount: bool,
+    two_phase: bool,
+    strict_ownership: bool,
+    /// Where the config came from when it was checked out of git
+    git_source: Option<(&'a GitSource, &'a gitsource::Checkout)>,
+    timeout: Option<Duration>,
+    verbose: bool,
+}
 
-    let next_gen = generation_manager.next_generation_number()?;
-    println!("\nCreating generation {}...", next_gen);
+/// Apply every config matching a pattern in turn, each as its own generation
+/// in its own state directory
+fn apply_each_config(pattern: &Path, options: &ApplyOptions) -> Result<()> {
+    let config_paths = config::expand_pattern(pattern)?;
 
-    // Remove old symlinks if there's an active generation
-    if let Some(active_gen) = generation_manager.get_active_generation()? {
-        println!("Removing symlinks from generation {}...", active_gen.number);
-        symlink_manager.remove(&active_gen.symlinks)?;
+    // A generation replaces the active one in its state directory, so configs
+    // sharing one would remove each other's mounts
+    let mut state_dirs: BTreeMap<PathBuf, &PathBuf> = BTreeMap::new();
+    for config_path in &config_paths {
+        let config = Config::from_file(config_path, options.config_format)
+            .context(format!("Failed to load config: {}", config_path.display()))?;
+        if let Some(other) = state_dirs.insert(config.state_dir.clone(), config_path) {
+            anyhow::bail!(
+                "{} and {} both use state_dir {}. Applying them separately would make each \
+                 remove the other's mounts: give each config its own state_dir, or use --merge \
+                 to apply them as one generation",
+                other.display(),
+                config_path.display(),
+                config.state_dir.display()
+            );
+        }
     }
 
-    println!("\nApplying {} symlinks...", symlinks.len());
-    let generation_symlinks = symlink_manager.apply(&symlinks)?;
-
-    let generation =
-        generation_manager.create_generation(config_path.clone(), generation_symlinks)?;
-
     println!(
-        "\n✓ Successfully created and activated generation {}",
-        generation.number
+        "Applying {} configs matching {}, one generation each",
+        config_paths.len(),
+        pattern.display()
     );
-    println!("  Created at: {}", generation.created_at);
-    println!("  Symlinks: {}", generation.symlinks.len());
-
+    for config_path in &config_paths {
+        println!();
+        apply_config(config_path, options)
+            .context(format!("Failed to apply {}", config_path.display()))?;
+    }
     Ok(())
 }
 
-fn list_generations(config_path: &PathBuf) -> Result<()> {
-    let state_dir = get_state_dir(config_path);
-    let generation_manager = GenerationManager::new(state_dir)?;
-    let generations = generation_manager.list_generations()?;
+fn apply_config(config_path: &PathBuf, options: &ApplyOptions) -> Result<()> {
+    println!("Loading configuration from: {}", config_path.display());
 
-    if generations.is_empty() {
-        println!("No generations found.");
-        return Ok(());
+    let config = Config::from_file(config_path, options.config_format)?
+        .with_target_root(options.target_root.map(Path::to_path_buf))
+        .with_skip_missing(options.skip_missing)
+        .with_env(options.env)?;
+    if let Some(env) = &config.selected_env {
+        println!("Using environment: {}", env);
+    }
+    if let Some(root) = &config.target_root {
+        println!("Placing targets under: {}", root.display());
     }
 
-    println!("Generations:");
-    for gen in generations {
-        let active_marker = if gen.active { " (active)" } else { "" };
+    let skipped = config.skipped_symlinks();
+    for symlink in &skipped {
         println!(
-            "  {} - {} - {} symlinks{}",
-            gen.number,
-            gen.created_at.format("%Y-%m-%d %H:%M:%S"),
-            gen.symlinks.len(),
-            active_marker
+            "  ⚠ Skipping {}: source does not exist: {}",
+            symlink.target.display(),
+            symlink.source.display()
         );
+        progress::emit(&Event::Skipped {
+            target: &symlink.target,
+            source: &symlink.source,
+        });
     }
 
-    Ok(())
-}
+    let fs_identity = options.as_user.map(FsIdentity::from_username).transpose()?;
 
-fn show_generation(config_path: &PathBuf, number: u64) -> Result<()> {
-    let state_dir = get_state_dir(config_path);
-    let generation_manager = GenerationManager::new(state_dir)?;
-    let generations = generation_manager.list_generations()?;
+    // Validation creates missing sources; a dry run reports its problems instead
+    if !options.skip_validation && !options.dry_run {
+        println!("Validating configuration...");
+        config.validate(fs_identity.as_ref())?;
+    }
 
-    let gen = generations
-        .iter()
-        .find(|g| g.number == number)
-        .ok_or_else(|| anyhow::anyhow!("Generation {} not found", number))?;
+    // Convert persistence config to symlinks
+    let mut symlinks = config.to_symlinks();
+    if options.mount_only || options.links_only {
+        let flag = if options.mount_only {
+            "--mount-only"
+        } else {
+            "--links-only"
+        };
+        symlinks.retain(|s| s.is_directory == options.mount_only);
+        if symlinks.is_empty() {
+            anyhow::bail!(
+                "Nothing to apply: the config has no entries left after {}",
+                flag
+            );
+        }
+        println!("ℹ {}: applying {} entries", flag, symlinks.len());
+    }
+    if !options.skip_validation && !options.dry_run {
+        validators::run(&config.validators, &symlinks)?;
+    }
+    let two_phase = options.two_phase || config.two_phase;
+    if two_phase {
+        if options.as_user.is_some() || options.confirm_each {
+            anyhow::bail!("A two-phase apply can't be combined with --as-user or --confirm-each");
+        }
+        if let Some(reason) = staging::unsupported(&symlinks) {
+            anyhow::bail!("This config can't be applied in two phases: {}", reason);
+        }
+    }
 
-    println!("Generation {}:", gen.number);
-    println!("  Created at: {}", gen.created_at);
-    println!("  Active: {}", gen.active);
-    println!("  Config: {}", gen.config_path.display());
-    println!("  Symlinks:");
+    let symlink_manager = SymlinkManager::new()
+        .with_verbose(options.verbose)
+        .with_fs_identity(fs_identity)
+        .with_backup_suffix(config.backup_suffix.clone())
+        .with_backup_compression(config.backup_compression)
+        .with_timeout(options.timeout)
+        // A resumed generation has to record exactly what was applied, and
+        // staging has to undo exactly what was staged
+        .with_keep_going(options.keep_going || options.resume || two_phase)
+        .with_replace_blocking_files(options.force)
+        .with_confirm_each(options.confirm_each);
+    let generation_manager =
+        GenerationManager::new(config.generations_dir()?)?.with_format(config.state_format);
 
-    for symlink in &gen.symlinks {
-        println!(
-            "    {} -> {}",
-            symlink.target.display(),
-            symlink.source.display()
-        );
-        if let Some(backup) = &symlink.backup_path {
-            println!("      (backup: {})", backup.display());
+    warn_missing_capabilities(&symlinks)?;
+    if options.dry_run {
+        let active_gen = generation_manager.get_active_generation()?;
+        if options.compare_to_current {
+            print_changes_from(&symlinks, active_gen.as_ref());
         }
+        return preview_apply(
+            &config,
+            &symlink_manager,
+            &symlinks,
+            active_gen.as_ref(),
+            options.skip_validation,
+        );
+    }
+    if symlinks.iter().any(|s| s.is_directory) {
+        symlink_manager.probe_bind_mounts()?;
     }
 
-    Ok(())
-}
-
-fn switch_generation(config_path: &PathBuf, number: u64) -> Result<()> {
-    let state_dir = get_state_dir(config_path);
-    let generation_manager = GenerationManager::new(state_dir)?;
-    let symlink_manager = SymlinkManager::new();
+    let recorded_path = match options.git_source {
+        Some((source, _)) => PathBuf::from(source.to_string()),
+        None => recorded_config_path(config_path),
+    };
 
-    // Remove current generation's symlinks and mounts
-    if let Some(active_gen) = generation_manager.get_active_generation()? {
-        println!(
-            "Removing symlinks and mounts from generation {}...",
-            active_gen.number
+    if options.resume {
+        return resume_apply(
+            recorded_path,
+            &symlinks,
+            &symlink_manager,
+            &generation_manager,
         );
-        symlink_manager.remove(&active_gen.symlinks)?;
     }
 
-    // Switch to new generation
-    let new_gen = generation_manager.switch_generation(number)?;
+    if options.compare_to_current {
+        let active_gen = generation_manager.get_active_generation()?;
+        print_changes_from(&symlinks, active_gen.as_ref());
+    }
 
-    println!(
-        "\nApplying symlinks and mounts from generation {}...",
-        new_gen.number
-    );
+    if options.preview_backups {
+        let active_gen = generation_manager.get_active_generation()?;
+        return preview_backups(&symlink_manager, &symlinks, active_gen.as_ref());
+    }
 
-    // Recreate the symlinks and bind mounts
-    for gen_symlink in &new_gen.symlinks {
-        use nix::mount::{mount, MsFlags};
-        use std::os::unix::fs as unix_fs;
+    let next_gen = generation_manager.next_generation_number()?;
+    println!("\nCreating generation {}...", next_gen);
+    progress::emit(&Event::Start {
+        generation: next_gen,
+        entries: symlinks.len(),
+    });
+
+    // From here on a signal rolls back instead of leaving a partial apply behind
+    let _interrupt_guard = interrupt::install()?;
 
-        if let Some(parent) = gen_symlink.target.parent() {
-            std::fs::create_dir_all(parent)?;
+    // Remove old symlinks if there's an active generation, except for mounts
+    // that are replaced in place
+    let previous_gen = generation_manager.get_active_generation()?;
+    let remounts = if (options.atomic_remount || config.atomic_remount) && !two_phase {
+        in_place_remounts(&symlink_manager, &symlinks, previous_gen.as_ref())?
+    } else {
+        HashMap::new()
+    };
+    let removed: Vec<GenerationSymlink> = previous_gen
+        .iter()
+        .flat_map(|g| &g.symlinks)
+        .filter(|s| !remounts.contains_key(&s.target))
+        .cloned()
+        .collect();
+    let symlink_manager = symlink_manager.with_remounts(remounts);
+    let outcome = if two_phase {
+        two_phase_apply(
+            &symlink_manager,
+            &config,
+            &symlinks,
+            previous_gen.as_ref(),
+            next_gen,
+            options.strict_ownership,
+        )?
+    } else {
+        if let Some(active_gen) = &previous_gen {
+            println!("Removing symlinks from generation {}...", active_gen.number);
+            symlink_manager.remove(&removed)?;
         }
 
-        // Determine if this is a directory (bind mount) or file (symlink)
-        // by checking if the source is a directory
-        if gen_symlink.source.is_dir() {
-            // Create the target directory if it doesn't exist
-            if !gen_symlink.target.exists() {
-                std::fs::create_dir_all(&gen_symlink.target)?;
+        println!("\nApplying {} symlinks...", symlinks.len());
+        symlink_manager.apply(&symlinks)?
+    };
+
+    if outcome.interrupted || outcome.aborted {
+        println!(
+            "Generation {} will not be created; undoing the entries applied so far.{}",
+            next_gen,
+            if outcome.interrupted {
+                " Interrupt again to exit immediately."
+            } else {
+                ""
             }
+        );
+        rollback_apply(&symlink_manager, &outcome.symlinks, previous_gen.as_ref())?;
+        let what = if outcome.interrupted {
+            "interrupted"
+        } else {
+            "aborted"
+        };
+        anyhow::bail!(match &previous_gen {
+            Some(gen) => format!("Apply {}; generation {} is active again", what, gen.number),
+            None => format!("Apply {}; no generation is active", what),
+        });
+    }
 
-            // Create bind mount
-            mount(
-                Some(&gen_symlink.source),
-                &gen_symlink.target,
-                None::<&str>,
-                MsFlags::MS_BIND,
-                None::<&str>,
-            )?;
+    // Catch links and mounts that were created without error but aren't in
+    // place, before the generation is recorded as active
+    if !options.no_verify {
+        let errors = symlink_manager.verify(&outcome.symlinks)?;
+        if !errors.is_empty() {
             println!(
-                "  ✓ Created bind mount: {} -> {}",
-                gen_symlink.target.display(),
-                gen_symlink.source.display()
+                "\n✗ The applied links do not match the filesystem; generation {} was not created:",
+                next_gen
             );
-        } else {
-            // Create symlink for files
-            unix_fs::symlink(&gen_symlink.source, &gen_symlink.target)?;
-            println!(
-                "  ✓ Created symlink: {} -> {}",
-                gen_symlink.target.display(),
-                gen_symlink.source.display()
+            for error in &errors {
+                println!("  - {}", error);
+            }
+            rollback_apply(&symlink_manager, &outcome.symlinks, previous_gen.as_ref())?;
+            anyhow::bail!(
+                "{} problem(s) found after applying. Use --no-verify to keep the links anyway.",
+                errors.len()
             );
         }
     }
 
-    println!("\n✓ Switched to generation {}", number);
-
-    Ok(())
-}
-
-fn delete_generation(config_path: &PathBuf, number: u64, force: bool) -> Result<()> {
-    let state_dir = get_state_dir(config_path);
-    let generation_manager = GenerationManager::new(state_dir)?;
-
-    if !force {
-        print!(
-            "Are you sure you want to delete generation {}? (y/N): ",
-            number
-        );
-        use std::io::{self, Write};
-        io::stdout().flush()?;
+    // Ownership that was silently not achieved (e.g. a mount showing its
+    // source's owner rather than the one set on the mount point)
+    if options.strict_ownership {
+        let applied: Vec<config::Symlink> = symlinks
+            .iter()
+            .filter(|s| outcome.symlinks.iter().any(|g| g.target == s.target))
+            .cloned()
+            .collect();
+        let mismatches = symlink_manager.ownership_mismatches(&applied)?;
+        if !mismatches.is_empty() {
+            println!(
+                "\n✗ Requested ownership was not achieved; generation {} was not created:",
+                next_gen
+            );
+            for mismatch in &mismatches {
+                println!("  - {}", mismatch);
+            }
+            rollback_apply(&symlink_manager, &outcome.symlinks, previous_gen.as_ref())?;
+            anyhow::bail!(
+                "{} ownership problem(s) found with --strict-ownership",
+                mismatches.len()
+            );
+        }
+    }
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+    let generation = generation_manager.create_generation(recorded_path, outcome.symlinks)?;
+    if let Some((_, checkout)) = options.git_source {
+        generation_manager.record_config_commit(generation.number, checkout.commit.clone())?;
+    }
+    if !outcome.failures.is_empty() {
+        let failed = outcome.failures.iter().map(|(t, _)| t.clone()).collect();
+        generation_manager.record_failures(generation.number, failed)?;
+    }
+    generation_manager.record_operation(Operation::Apply {
+        previous: previous_gen.as_ref().map(|g| g.number),
+        created: generation.number,
+    })?;
 
-        if !input.trim().eq_ignore_ascii_case("y") {
-            println!("Aborted.");
-            return Ok(());
+    if let Some(max_backup_bytes) = config.max_backup_bytes {
+        let evicted =
+            backup::enforce_backup_limit(&generation_manager, max_backup_bytes, generation.number)?;
+        for backup in &evicted {
+            println!(
+                "  ℹ Evicted backup: {} ({} bytes)",
+                backup.path.display(),
+                backup.bytes
+            );
         }
     }
 
-    generation_manager.delete_generation(number)?;
-    println!("✓ Deleted generation {}", number);
-
-    Ok(())
-}
+    println!(
+        "\n✓ Successfully created and activated generation {}",
+        generation.number
+    );
+    println!("  Created at: {}", generation.created_at);
+    println!("  Symlinks: {}", generation.symlinks.len());
+    if !skipped.is_empty() {
+        println!(
+            "  Skipped: {} (missing sources, not part of this generation)",
+            skipped.len()
+        );
+    }
+    if !outcome.declined.is_empty() {
+        println!(
+            "  Skipped: {} (declined at the prompt, not part of this generation)",
+            outcome.declined.len()
+        );
+    }
+    if outcome.failures.is_empty() {
+        prune_generations(&generation_manager, &config.retention)?;
+    }
+
+    progress::emit(&Event::Finish {
+        generation: generation.number,
+        created: generation.symlinks.len(),
+        failed: outcome.failures.len(),
+    });
+
+    report_failures(&outcome.failures, symlinks.len(), generation.number)
+}
+
+/// Apply in two phases. Phase one creates every entry at a staging location
+/// and verifies it while the active generation stays as it is; any problem
+/// discards the staged entries and fails the apply. Phase two takes the
+/// active generation down and moves the staged entries to their targets,
+/// rolling back to the previous generation if a move fails.
+fn two_phase_apply(
+    symlink_manager: &SymlinkManager,
+    config: &Config,
+    symlinks: &[config::Symlink],
+    previous_gen: Option<&Generation>,
+    next_gen: u64,
+    strict_ownership: bool,
+) -> Result<ApplyOutcome> {
+    let staging = StagingArea::create(&config.generations_dir()?)?;
+    let staged = staging.staged(symlinks);
+
+    println!(
+        "\nStaging {} symlinks in {}...",
+        symlinks.len(),
+        staging.dir().display()
+    );
+    let outcome = symlink_manager.apply(&staged)?;
+    let mut problems: Vec<String> = outcome
+        .failures
+        .iter()
+        .map(|(staged_target, e)| {
+            let target = staged
+                .iter()
+                .zip(symlinks)
+                .find(|(s, _)| &s.target == staged_target)
+                .map_or(staged_target, |(_, symlink)| &symlink.target);
+            format!("{}: {:#}", target.display(), e)
+        })
+        .collect();
+    if !outcome.interrupted {
+        problems.extend(symlink_manager.verify(&outcome.symlinks)?);
+        if strict_ownership {
+            let applied: Vec<config::Symlink> = staged
+                .iter()
+                .filter(|s| outcome.symlinks.iter().any(|g| g.target == s.target))
+                .cloned()
+                .collect();
+            problems.extend(symlink_manager.ownership_mismatches(&applied)?);
+        }
+    }
+    if outcome.interrupted || !problems.is_empty() {
+        if !problems.is_empty() {
+            println!(
+                "\n✗ Staging failed; generation {} was not created:",
+                next_gen
+            );
+            for problem in &problems {
+                println!("  - {}", problem);
+            }
+        }
+        println!("\nDiscarding the staged entries...");
+        symlink_manager.undo(&outcome.symlinks)?;
+        let untouched = match previous_gen {
+            Some(gen) => format!("generation {} was left untouched", gen.number),
+            None => "nothing was changed".to_string(),
+        };
+        if outcome.interrupted {
+            anyhow::bail!("Apply interrupted while staging; {}", untouched);
+        }
+        anyhow::bail!(
+            "{} problem(s) found while staging; {}",
+            problems.len(),
+            untouched
+        );
+    }
+
+    println!(
+        "\nAll entries staged and verified; promoting generation {}...",
+        next_gen
+    );
+    if let Some(active_gen) = previous_gen {
+        println!("Removing symlinks from generation {}...", active_gen.number);
+        symlink_manager.remove(&active_gen.symlinks)?;
+    }
+    let mut promoted = Vec::new();
+    // Every staged entry succeeded, so they line up with `symlinks`
+    for (symlink, staged) in symlinks.iter().zip(&outcome.symlinks) {
+        match symlink_manager.promote(symlink, staged) {
+            Ok(gen_symlink) => promoted.push(gen_symlink),
+            Err(e) => {
+                println!("  ✗ Failed: {}: {:#}", symlink.target.display(), e);
+                symlink_manager.undo(&outcome.symlinks[promoted.len()..])?;
+                rollback_apply(symlink_manager, &promoted, previous_gen)?;
+                anyhow::bail!(match previous_gen {
+                    Some(gen) => format!(
+                        "Failed to promote the staged entries; generation {} is active again",
+                        gen.number
+                    ),
+                    None =>
+                        "Failed to promote the staged entries; no generation is active".to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(ApplyOutcome {
+        symlinks: promoted,
+        ..outcome
+    })
+}
+
+/// Targets the previous generation has bind mounted and the config bind
+/// mounts again, with the backup recorded for each. These are remounted in
+/// place rather than taken down with the rest of the previous generation.
+fn in_place_remounts(
+    symlink_manager: &SymlinkManager,
+    symlinks: &[config::Symlink],
+    previous_gen: Option<&Generation>,
+) -> Result<HashMap<PathBuf, Option<PathBuf>>> {
+    let mut remounts = HashMap::new();
+    for gen_symlink in previous_gen.into_iter().flat_map(|g| &g.symlinks) {
+        let mounted_again = symlinks
+            .iter()
+            .any(|s| s.target == gen_symlink.target && s.is_directory && s.device.is_none());
+        if gen_symlink.is_mount()
+            && !gen_symlink.is_device()
+            && mounted_again
+            && symlink_manager.is_mount_point(&gen_symlink.target)?
+        {
+            remounts.insert(gen_symlink.target.clone(), gen_symlink.backup_path.clone());
+        }
+    }
+    Ok(remounts)
+}
+
+/// List the backups an apply would take, using the same names as the backups
+/// themselves (with the current time)
+fn preview_backups(
+    symlink_manager: &SymlinkManager,
+    symlinks: &[config::Symlink],
+    active_gen: Option<&Generation>,
+) -> Result<()> {
+    let planned = symlink_manager.planned_backups(symlinks, active_gen, chrono::Utc::now())?;
+    if planned.is_empty() {
+        println!("\n✓ No targets would be backed up. Nothing was changed.");
+        return Ok(());
+    }
+
+    println!("\nPlanned backups:");
+    for (target, backup_path) in &planned {
+        println!("  {} -> {}", target.display(), backup_path.display());
+    }
+    println!(
+        "\n{} target(s) would be backed up. Nothing was changed.",
+        planned.len()
+    );
+    Ok(())
+}
+
+/// Describe what an apply would do to each entry, without doing any of it:
+/// how its source would be created if missing, and what would happen to
+/// anything at its target. A non-empty directory at a target is a conflict,
+/// since its content either disappears into a backup or is deleted. Problems
+/// are reported as `validate` reports them; none of this fails the command.
+fn preview_apply(
+    config: &Config,
+    symlink_manager: &SymlinkManager,
+    symlinks: &[config::Symlink],
+    active_gen: Option<&Generation>,
+    skip_validation: bool,
+) -> Result<()> {
+    let planned: HashMap<PathBuf, PathBuf> = symlink_manager
+        .planned_backups(symlinks, active_gen, chrono::Utc::now())?
+        .into_iter()
+        .collect();
+    let (mut created, mut backed_up, mut removed, mut conflicts) = (0, 0, 0, 0);
+
+    println!("\nDry run: {} entries would be applied", symlinks.len());
+    for symlink in symlinks {
+        let kind = if symlink.device.is_some() {
+            "device mount of"
+        } else if symlink.is_directory {
+            "bind mount of"
+        } else {
+            "symlink to"
+        };
+        println!(
+            "  {} ({} {})",
+            symlink.target.display(),
+            kind,
+            symlink.source.display()
+        );
+
+        let preserved = symlink.preserve_symlink && symlink.source.is_symlink();
+        let lazy = symlink.on_missing_source == config::MissingSource::Lazy;
+        if !symlink.source.exists() && lazy {
+            if symlink.target.is_dir()
+                && !symlink.target.is_symlink()
+                && std::fs::read_dir(&symlink.target)
+                    .context(format!(
+                        "Failed to read directory: {}",
+                        symlink.target.display()
+                    ))?
+                    .next()
+                    .is_some()
+            {
+                println!(
+                    "    source does not exist; the directory at the target would be moved \
+                     there and mounted (on_missing_source = \"lazy\")"
+                );
+                created += 1;
+                continue;
+            }
+            println!(
+                "    source does not exist; the target would wait for its first write \
+                 (on_missing_source = \"lazy\")"
+            );
+        } else if !symlink.source.exists() && !preserved {
+            let how = if symlink.link {
+                "it appears once the entry it points into is applied".to_string()
+            } else if symlink.device.is_some() {
+                "apply would fail".to_string()
+            } else if symlink.on_missing_source == config::MissingSource::Error {
+                "apply would fail (on_missing_source = \"error\")".to_string()
+            } else if symlink.is_directory && skip_validation && symlink.target.is_dir() {
+                "it would be created with the target's mode and owner".to_string()
+            } else if symlink.is_directory || !symlink.target.is_file() {
+                "it would be created empty".to_string()
+            } else {
+                format!("it would be created from {}", symlink.target.display())
+            };
+            println!("    source does not exist; {}", how);
+            if how.starts_with("it would be created") {
+                created += 1;
+            }
+        }
+
+        // What will be at the target once the active generation is gone
+        let previous = active_gen.and_then(|gen| {
+            let entry = gen.symlinks.iter().find(|s| s.target == symlink.target)?;
+            Some((gen.number, entry))
+        });
+        let existing = match previous {
+            Some((number, entry)) => {
+                println!("    replaces the entry of generation {}", number);
+                entry
+                    .backup_path
+                    .clone()
+                    .filter(|restored| entry.restore_backup && restored.symlink_metadata().is_ok())
+            }
+            None => Some(symlink.target.clone()).filter(|target| target.symlink_metadata().is_ok()),
+        };
+        let existing = match existing {
+            Some(existing) => existing,
+            None => continue,
+        };
+
+        let non_empty_dir = if backup::is_compressed(&existing) {
+            true
+        } else if existing.is_dir() && !existing.is_symlink() {
+            std::fs::read_dir(&existing)
+                .context(format!("Failed to read directory: {}", existing.display()))?
+                .next()
+                .is_some()
+        } else {
+            false
+        };
+        let what = if non_empty_dir {
+            "the non-empty directory at the target"
+        } else if existing.is_symlink() {
+            "the symlink at the target"
+        } else if existing.is_dir() {
+            "the empty directory at the target"
+        } else {
+            "the file at the target"
+        };
+        let fate = match planned.get(&symlink.target) {
+            Some(backup_path) => {
+                backed_up += 1;
+                format!("would be backed up to {}", backup_path.display())
+            }
+            None => {
+                removed += 1;
+                "would be deleted (backup = false)".to_string()
+            }
+        };
+        if non_empty_dir {
+            conflicts += 1;
+            println!("    ✗ Conflict: {} {}", what, fate);
+        } else {
+            println!("    {} {}", what, fate);
+        }
+    }
+
+    let mut problems = problems::problems(config);
+    if let Err(e) = config.validate_structure() {
+        problems.insert(0, problems::Problem::config(&e));
+    }
+    if !problems.is_empty() {
+        println!();
+        for problem in &problems {
+            print_problem(problem);
+        }
+    }
+
+    println!(
+        "\n{} source(s) to create, {} backup(s), {} deletion(s), {} conflict(s), {} problem(s). \
+         Nothing was changed.",
+        created,
+        backed_up,
+        removed,
+        conflicts,
+        problems.len()
+    );
+    Ok(())
+}
+
+/// Take down the links of an apply that failed verification and put the
+/// previously active generation's links back in place
+fn rollback_apply(
+    symlink_manager: &SymlinkManager,
+    applied: &[GenerationSymlink],
+    previous: Option<&Generation>,
+) -> Result<()> {
+    println!("\nRolling back...");
+    symlink_manager.undo(applied)?;
+
+    if let Some(previous) = previous {
+        println!(
+            "Restoring symlinks and mounts from generation {}...",
+            previous.number
+        );
+        for gen_symlink in &previous.symlinks {
+            // Removing the new links put back what they had backed up, which
+            // is what the previous generation had backed up in turn
+            symlink_manager.return_to_backup(gen_symlink)?;
+            symlink_manager.recreate(gen_symlink)?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete the generations the retention policy no longer keeps
+fn prune_generations(generation_manager: &GenerationManager, retention: &Retention) -> Result<()> {
+    let generations = generation_manager.list_generations()?;
+    let prunable = retention.prunable(&generations, chrono::Utc::now())?;
+    if prunable.is_empty() {
+        return Ok(());
+    }
+
+    generation_manager.delete_generations(&prunable)?;
+    let numbers: Vec<String> = prunable.iter().map(u64::to_string).collect();
+    println!(
+        "  Pruned: {} generation(s) by retention policy ({})",
+        prunable.len(),
+        numbers.join(", ")
+    );
+    Ok(())
+}
+
+/// Record an absolute config path so it can be found again from any
+/// directory; remote configs are recorded by their URL, and stdin as `-`
+fn recorded_config_path(config_path: &PathBuf) -> PathBuf {
+    if remote::as_url(config_path).is_some() || config_path.as_os_str() == config::STDIN_PATH {
+        config_path.clone()
+    } else {
+        // A pattern of merged configs doesn't exist as a file
+        std::fs::canonicalize(config_path)
+            .or_else(|_| std::path::absolute(config_path))
+            .unwrap_or_else(|_| config_path.clone())
+    }
+}
+
+/// List the entries that failed under --keep-going and fail the command
+fn report_failures(
+    failures: &[(PathBuf, anyhow::Error)],
+    total: usize,
+    generation: u64,
+) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    println!("\n✗ Could not apply {} target(s):", failures.len());
+    for (target, error) in failures {
+        println!("  {}: {:#}", target.display(), error);
+    }
+    anyhow::bail!(
+        "{} of {} entries failed to apply (the rest are active in generation {}). \
+         Fix them and run 'imp apply --resume' to apply only those.",
+        failures.len(),
+        total,
+        generation
+    )
+}
+
+/// Apply only the entries that failed in the active generation and add them
+/// to it, leaving the entries that were applied alone
+fn resume_apply(
+    config_path: PathBuf,
+    symlinks: &[config::Symlink],
+    symlink_manager: &SymlinkManager,
+    generation_manager: &GenerationManager,
+) -> Result<()> {
+    let active_gen = generation_manager
+        .get_active_generation()?
+        .ok_or_else(|| anyhow::anyhow!("Nothing to resume: there is no active generation"))?;
+    if active_gen.failed.is_empty() {
+        anyhow::bail!(
+            "Nothing to resume: generation {} has no failed entries",
+            active_gen.number
+        );
+    }
+    if active_gen.config_path != config_path {
+        anyhow::bail!(
+            "Cannot resume generation {}: it was applied from {}, not {}",
+            active_gen.number,
+            active_gen.config_path.display(),
+            config_path.display()
+        );
+    }
+
+    let pending: Vec<config::Symlink> = symlinks
+        .iter()
+        .filter(|s| active_gen.failed.contains(&s.target))
+        .cloned()
+        .collect();
+    for target in &active_gen.failed {
+        if !pending.iter().any(|s| &s.target == target) {
+            println!("  ℹ Dropping {}: no longer in the config", target.display());
+        }
+    }
+
+    println!(
+        "\nResuming generation {}: applying {} failed entries...",
+        active_gen.number,
+        pending.len()
+    );
+    progress::emit(&Event::Start {
+        generation: active_gen.number,
+        entries: pending.len(),
+    });
+
+    let _interrupt_guard = interrupt::install()?;
+    let outcome = symlink_manager.apply(&pending)?;
+    if outcome.interrupted {
+        println!("Removing the entries applied so far. Interrupt again to exit immediately.");
+        symlink_manager.undo(&outcome.symlinks)?;
+        anyhow::bail!(
+            "Resume interrupted; generation {} is unchanged",
+            active_gen.number
+        );
+    }
+    let applied = outcome.symlinks.len();
+    let failed = outcome.failures.iter().map(|(t, _)| t.clone()).collect();
+    let generation =
+        generation_manager.complete_generation(active_gen.number, outcome.symlinks, failed)?;
+    generation_manager.record_operation(Operation::Resume {
+        generation: generation.number,
+    })?;
+
+    println!(
+        "\n✓ Resumed generation {}: {} entries applied",
+        generation.number, applied
+    );
+    println!("  Symlinks: {}", generation.symlinks.len());
+
+    progress::emit(&Event::Finish {
+        generation: generation.number,
+        created: applied,
+        failed: outcome.failures.len(),
+    });
+
+    report_failures(&outcome.failures, pending.len(), generation.number)
+}
+
+/// Print the targets a config adds, removes or changes compared to a generation
+/// (the active one, or `None` if there is none)
+fn print_changes_from(symlinks: &[config::Symlink], active_gen: Option<&Generation>) {
+    let active_gen = match active_gen {
+        Some(gen) => gen,
+        None => {
+            println!(
+                "\nNo active generation: all {} target(s) are new",
+                symlinks.len()
+            );
+            return;
+        }
+    };
+
+    // Generations record canonical sources, so compare against those
+    let new: BTreeMap<&Path, PathBuf> = symlinks
+        .iter()
+        .map(|s| {
+            let source = std::fs::canonicalize(&s.source).unwrap_or_else(|_| s.source.clone());
+            (s.target.as_path(), source)
+        })
+        .collect();
+    let new: BTreeMap<&Path, &Path> = new.iter().map(|(t, s)| (*t, s.as_path())).collect();
+
+    println!("\nChanges compared to generation {}:", active_gen.number);
+    let (added, removed, changed) = print_target_changes(&generation_targets(active_gen), &new);
+    println!(
+        "  {} added, {} removed, {} changed, {} unchanged",
+        added,
+        removed,
+        changed,
+        new.len() - added - changed
+    );
+}
+
+/// A generation's targets mapped to their sources
+fn generation_targets(gen: &Generation) -> BTreeMap<&Path, &Path> {
+    gen.symlinks
+        .iter()
+        .map(|s| (s.target.as_path(), s.source.as_path()))
+        .collect()
+}
+
+/// Print the targets `new` adds (`+`), removes (`-`) or points at a different
+/// source (`~`) compared to `old`. Returns the (added, removed, changed) counts.
+fn print_target_changes(
+    old: &BTreeMap<&Path, &Path>,
+    new: &BTreeMap<&Path, &Path>,
+) -> (usize, usize, usize) {
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    for (target, source) in new {
+        match old.get(target) {
+            None => {
+                added += 1;
+                println!("  + {} -> {}", target.display(), source.display());
+            }
+            Some(old_source) if old_source != source => {
+                changed += 1;
+                println!(
+                    "  ~ {}: {} -> {}",
+                    target.display(),
+                    old_source.display(),
+                    source.display()
+                );
+            }
+            Some(_) => {}
+        }
+    }
+    for (target, source) in old {
+        if !new.contains_key(target) {
+            removed += 1;
+            println!("  - {} -> {}", target.display(), source.display());
+        }
+    }
+    (added, removed, changed)
+}
+
+/// Print every generation with the changes it made relative to the previous
+/// one that still exists, oldest first
+fn log_generations(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    local: bool,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let generations = generation_manager.list_generations()?;
+
+    if generations.is_empty() {
+        println!("No generations found.");
+        return Ok(());
+    }
+
+    let mut previous: Option<&Generation> = None;
+    for gen in &generations {
+        let active_marker = if gen.active { " (active)" } else { "" };
+        match previous {
+            Some(prev) if prev.number + 1 != gen.number => println!(
+                "Generation {} - {}{} (compared to generation {})",
+                gen.number,
+                format_created_at(gen, local),
+                active_marker,
+                prev.number
+            ),
+            _ => println!(
+                "Generation {} - {}{}",
+                gen.number,
+                format_created_at(gen, local),
+                active_marker
+            ),
+        }
+
+        let old = previous.map(generation_targets).unwrap_or_default();
+        if print_target_changes(&old, &generation_targets(gen)) == (0, 0, 0) {
+            println!("  (no changes)");
+        }
+        println!();
+        previous = Some(gen);
+    }
+
+    Ok(())
+}
+
+/// Print each generation's number on a line of its own, followed by a tab
+/// and the first line of its note if it has one (generations have no labels
+/// yet). Completion scripts show whatever this prints, so errors print nothing.
+fn complete_generations(config_path: &PathBuf, config_format: Option<ConfigFormat>) {
+    let generations =
+        match open_generations(config_path, config_format).and_then(|m| m.list_generations()) {
+            Ok(generations) => generations,
+            Err(_) => return,
+        };
+    for gen in &generations {
+        match gen.note.as_deref().and_then(|note| note.lines().next()) {
+            Some(note) => println!("{}\t{}", gen.number, note.replace('\t', " ")),
+            None => println!("{}", gen.number),
+        }
+    }
+}
+
+fn list_generations(
+    generation_manager: &GenerationManager,
+    format: Option<&str>,
+    local: bool,
+    count: bool,
+    notes: bool,
+    sort: ListSort,
+    reverse: bool,
+) -> Result<()> {
+    let mut generations = generation_manager.list_generations()?;
+    sort_generations(&mut generations, sort, reverse);
+
+    if count {
+        let active = generations
+            .iter()
+            .find(|g| g.active)
+            .map_or("none".to_string(), |g| g.number.to_string());
+        println!("total={} active={}", generations.len(), active);
+        return Ok(());
+    }
+
+    if let Some(template) = format {
+        for gen in &generations {
+            println!("{}", format_generation(template, gen, local)?);
+        }
+        return Ok(());
+    }
+
+    if generations.is_empty() {
+        println!("No generations found.");
+        return Ok(());
+    }
+
+    println!("Generations:");
+    print_generation_lines(&generations, local, notes);
+
+    Ok(())
+}
+
+/// One line per generation, as `list` prints them, each followed by its
+/// note if `notes` is set
+fn print_generation_lines(generations: &[Generation], local: bool, notes: bool) {
+    for gen in generations {
+        let active_marker = if gen.active { " (active)" } else { "" };
+        println!(
+            "  {} - {} - {} symlinks{}{}",
+            gen.number,
+            format_created_at(gen, local),
+            gen.symlinks.len(),
+            active_marker,
+            config_missing_note(gen)
+        );
+        if let (true, Some(note)) = (notes, &gen.note) {
+            for line in note.lines() {
+                println!("      {}", line);
+            }
+        }
+    }
+}
+
+/// Open the generations of every machine in the config's shared state dir
+fn open_machine_generations(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+) -> Result<Vec<(String, GenerationManager)>> {
+    let config = Config::from_file(config_path, config_format)?;
+    if !config.per_machine {
+        anyhow::bail!(
+            "--all-machines needs per_machine = true in {}",
+            config_path.display()
+        );
+    }
+
+    machine::all(&config.state_dir)?
+        .into_iter()
+        .map(|(id, state_dir)| {
+            let generation_manager =
+                GenerationManager::new(state_dir)?.with_format(config.state_format);
+            Ok((id, generation_manager))
+        })
+        .collect()
+}
+
+/// Marks the current machine among those sharing a state dir
+fn this_machine_note(id: &str) -> &'static str {
+    if machine::id().is_ok_and(|this| this == id) {
+        " (this machine)"
+    } else {
+        ""
+    }
+}
+
+/// `list --all-machines`: each machine's generations under its id
+fn list_machine_generations(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    local: bool,
+    notes: bool,
+    sort: ListSort,
+    reverse: bool,
+) -> Result<()> {
+    let machines = open_machine_generations(config_path, config_format)?;
+    if machines.is_empty() {
+        println!("No generations found.");
+        return Ok(());
+    }
+
+    for (i, (id, generation_manager)) in machines.iter().enumerate() {
+        let mut generations = generation_manager.list_generations()?;
+        sort_generations(&mut generations, sort, reverse);
+
+        if i > 0 {
+            println!();
+        }
+        println!("Machine {}{}:", id, this_machine_note(id));
+        if generations.is_empty() {
+            println!("  No generations found.");
+        }
+        print_generation_lines(&generations, local, notes);
+    }
+
+    Ok(())
+}
+
+/// Warn before touching anything if entries need capabilities this process
+/// lacks, naming the entries that will fail
+fn warn_missing_capabilities(symlinks: &[config::Symlink]) -> Result<()> {
+    let missing = caps::missing(symlinks, !symlink::no_mount_requested())?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    println!("⚠ Missing capabilities; these entries are likely to fail:");
+    for (capability, targets) in &missing {
+        let targets: Vec<String> = targets.iter().map(|t| t.display().to_string()).collect();
+        println!(
+            "  {} ({}): {}",
+            capability,
+            capability.purpose(),
+            targets.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Order generations for `list`. The state file keeps them in the order they
+/// were added, which isn't necessarily number order after an import.
+fn sort_generations(generations: &mut [Generation], sort: ListSort, reverse: bool) {
+    match sort {
+        ListSort::Number => generations.sort_by_key(|g| g.number),
+        ListSort::Date => generations.sort_by_key(|g| (g.created_at, g.number)),
+    }
+    if reverse {
+        generations.reverse();
+    }
+}
+
+/// Expand a `list --format` template for one generation
+fn format_generation(template: &str, gen: &Generation, local: bool) -> Result<String> {
+    let mut output = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .context(format!("Unclosed placeholder in format: {}", template))?;
+        let value = match &rest[start + 1..start + end] {
+            "number" => gen.number.to_string(),
+            "date" => format_created_at(gen, local),
+            "count" => gen.symlinks.len().to_string(),
+            "active" => if gen.active { "active" } else { "" }.to_string(),
+            // Joined into one line, so each generation stays on its own line
+            "note" => gen.note.as_deref().unwrap_or("").lines().collect::<Vec<_>>().join(" / "),
+            other => anyhow::bail!(
+                "Unknown placeholder {{{}}} in format. Available: {{number}}, {{date}}, {{count}}, {{active}}, {{note}}",
+                other
+            ),
+        };
+        output.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+/// Format a generation's creation time for display. Local times carry their
+/// UTC offset so they can't be mistaken for the stored UTC value.
+fn format_created_at(gen: &Generation, local: bool) -> String {
+    if local {
+        gen.created_at
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M:%S %:z")
+            .to_string()
+    } else {
+        gen.created_at.format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+}
+
+fn show_generation(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    number: u64,
+    local: bool,
+    diff_config: bool,
+    target_root: Option<&Path>,
+    env: Option<&str>,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let generations = generation_manager.list_generations()?;
+
+    let gen = generations
+        .iter()
+        .find(|g| g.number == number)
+        .ok_or_else(|| anyhow::anyhow!("Generation {} not found", number))?;
+
+    println!("Generation {}:", gen.number);
+    if local {
+        println!("  Created at: {}", format_created_at(gen, true));
+    } else {
+        println!("  Created at: {}", gen.created_at);
+    }
+    println!("  Active: {}", gen.active);
+    println!(
+        "  Config: {}{}",
+        gen.config_path.display(),
+        config_missing_note(gen)
+    );
+    if let Some(commit) = &gen.config_commit {
+        println!("  Config commit: {}", commit);
+    }
+    if let Some(last_verified) = gen.last_verified {
+        println!("  Last verified: {}", last_verified);
+    }
+    if let Some(note) = &gen.note {
+        println!("  Note:");
+        for line in note.lines() {
+            println!("    {}", line);
+        }
+    }
+    print_environment(&gen.environment);
+    println!("  Symlinks:");
+
+    for symlink in &gen.symlinks {
+        println!(
+            "    {} -> {}",
+            symlink.target.display(),
+            symlink.source.display()
+        );
+        if let Some(root) = &symlink.root {
+            println!("      (root: {})", root.display());
+        }
+        if let Some(backup) = &symlink.backup_path {
+            println!("      (backup: {})", backup.display());
+        }
+    }
+    if !gen.failed.is_empty() {
+        println!("  Failed (apply --resume to retry):");
+        for target in &gen.failed {
+            println!("    {}", target.display());
+        }
+    }
+
+    if diff_config {
+        // A config from git is compared with what its ref holds now
+        let checkout = match &gen.config_commit {
+            Some(_) => Some(gitsource::checkout(
+                &gen.config_path.to_string_lossy().parse()?,
+            )?),
+            None => None,
+        };
+        let config_path = checkout
+            .as_ref()
+            .map_or(&gen.config_path, |c| &c.config_path);
+        let config = Config::from_file(config_path, config_format)
+            .context(format!(
+                "Cannot compare with config file: {}",
+                gen.config_path.display()
+            ))?
+            .with_target_root(target_root.map(Path::to_path_buf))
+            .with_env(env)?;
+        print_changes_from(&config.to_symlinks(), Some(gen));
+    }
+
+    Ok(())
+}
+
+/// Print what was recorded about the environment a generation was applied in
+fn print_environment(environment: &environment::GenerationEnvironment) {
+    if environment.is_empty() {
+        return;
+    }
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+    println!("  Environment:");
+    if let Some(hostname) = &environment.hostname {
+        println!("    Hostname: {}", hostname);
+    }
+    if let Some(kernel) = &environment.kernel {
+        println!("    Kernel: {}", kernel);
+    }
+    if let Some(version) = &environment.imp_version {
+        println!("    imp version: {}", version);
+    }
+    if let Some(privileged) = environment.privileged {
+        println!("    Privileged (CAP_SYS_ADMIN): {}", yes_no(privileged));
+    }
+    if let Some(container) = environment.container {
+        println!("    Container: {}", yes_no(container));
+    }
+    if let Some(umask) = &environment.umask {
+        println!("    Umask: {}", umask);
+    }
+}
+
+fn switch_generation(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    number: u64,
+    no_verify: bool,
+    backup_on_switch: bool,
+    clean: bool,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let config = Config::from_file(config_path, config_format).ok();
+    let backup_on_switch = backup_on_switch || config.as_ref().is_some_and(|c| c.backup_on_switch);
+    let backup_compression = config
+        .as_ref()
+        .map(|c| c.backup_compression)
+        .unwrap_or_default();
+    let backup_suffix = config
+        .map(|c| c.backup_suffix)
+        .unwrap_or_else(|| symlink::DEFAULT_BACKUP_SUFFIX.to_string());
+    let symlink_manager = SymlinkManager::new()
+        .with_timeout(timeout)
+        .with_backup_suffix(backup_suffix)
+        .with_backup_compression(backup_compression);
+
+    // Remove current generation's symlinks and mounts
+    let active_gen = generation_manager.get_active_generation()?;
+    let previous = active_gen.as_ref().map(|g| g.number);
+    if let Some(mut active_gen) = active_gen {
+        if clean {
+            println!("Discarding backups of generation {}...", active_gen.number);
+            for backup_path in backup::discard_backups(&generation_manager, &active_gen)? {
+                println!("  ✓ Discarded backup: {}", backup_path.display());
+            }
+            for gen_symlink in &mut active_gen.symlinks {
+                gen_symlink.backup_path = None;
+            }
+        }
+        println!(
+            "Removing symlinks and mounts from generation {}...",
+            active_gen.number
+        );
+        symlink_manager.remove(&active_gen.symlinks)?;
+    }
+
+    // Switch to new generation
+    let new_gen = generation_manager.switch_generation(number)?;
+    generation_manager.record_operation(Operation::Switch {
+        previous,
+        to: number,
+    })?;
+
+    println!(
+        "\nApplying symlinks and mounts from generation {}...",
+        new_gen.number
+    );
+
+    // Recreate the symlinks and bind mounts
+    let mut backups = Vec::new();
+    let mut recreated = Ok(());
+    for gen_symlink in &new_gen.symlinks {
+        if backup_on_switch {
+            match symlink_manager.backup_existing_target(gen_symlink) {
+                Ok(Some(backup)) => backups.push((gen_symlink.target.clone(), backup)),
+                Ok(None) => {}
+                Err(e) => {
+                    recreated = Err(e);
+                    break;
+                }
+            }
+        }
+        if let Err(e) = symlink_manager.recreate(gen_symlink) {
+            recreated = Err(e);
+            break;
+        }
+    }
+
+    // Record backups even if a later entry failed, so they can still be restored
+    if !backups.is_empty() {
+        generation_manager.record_backups(number, &backups)?;
+    }
+    recreated?;
+
+    if !no_verify {
+        let errors = symlink_manager.verify(&new_gen.symlinks)?;
+        if !errors.is_empty() {
+            println!(
+                "\n✗ Generation {} is active but does not match the filesystem:",
+                number
+            );
+            for error in &errors {
+                println!("  - {}", error);
+            }
+            anyhow::bail!(
+                "{} problem(s) found after switching. Run 'imp verify --fix' to repair.",
+                errors.len()
+            );
+        }
+    }
+
+    println!("\n✓ Switched to generation {}", number);
+
+    Ok(())
+}
+
+fn delete_generation(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    number: u64,
+    force: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+
+    if dry_run {
+        return preview_delete(&generation_manager, number);
+    }
+
+    if !force {
+        print!(
+            "Are you sure you want to delete generation {}? (y/N): ",
+            number
+        );
+        use std::io::{self, Write};
+        io::stdout().flush()?;
+
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Aborted.");
+            return Ok(());
+        }
+    }
+
+    generation_manager.delete_generation(number)?;
+    generation_manager.record_operation(Operation::Delete {
+        deleted: vec![number],
+    })?;
+    println!("✓ Deleted generation {}", number);
+
+    Ok(())
+}
+
+fn note_generation(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    number: u64,
+    text: Option<String>,
+    append: bool,
+    clear: bool,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let generations = generation_manager.list_generations()?;
+    let gen = find_generation(&generations, number)?;
+
+    let note = match (text, &gen.note) {
+        _ if clear => None,
+        (Some(text), Some(existing)) if append => Some(format!("{}\n{}", existing, text)),
+        (text, _) => text,
+    };
+    generation_manager.set_note(number, note)?;
+
+    if clear {
+        println!("✓ Removed the note of generation {}", number);
+    } else {
+        println!("✓ Noted generation {}", number);
+    }
+    Ok(())
+}
+
+/// Revert the last apply or switch by switching back to the generation it
+/// replaced. An apply's generation is deleted afterwards.
+fn undo_last_operation(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let last = generation_manager
+        .last_operation()?
+        .context("Nothing to undo")?;
+    let (previous, current, created) = match last.operation {
+        Operation::Apply { previous, created } => (previous, created, Some(created)),
+        Operation::Switch { previous, to } => (previous, to, None),
+        ref operation => anyhow::bail!(
+            "The last operation ({}, at {}) can't be undone; only apply and switch can",
+            operation,
+            last.at
+        ),
+    };
+
+    // Undoing after something else changed the active generation would
+    // revert that change instead
+    let active_gen = generation_manager
+        .get_active_generation()?
+        .filter(|g| g.number == current)
+        .context(format!(
+            "Can't undo the {}: generation {} is no longer active",
+            last.operation, current
+        ))?;
+    if let Some(number) = previous {
+        if !generation_manager
+            .list_generations()?
+            .iter()
+            .any(|g| g.number == number)
+        {
+            anyhow::bail!(
+                "Can't undo the {}: generation {}, which it replaced, no longer exists",
+                last.operation,
+                number
+            );
+        }
+    }
+
+    println!("Undoing the {}...\n", last.operation);
+    match previous {
+        Some(number) => switch_generation(
+            config_path,
+            config_format,
+            number,
+            false,
+            false,
+            false,
+            timeout,
+        )?,
+        None => {
+            println!(
+                "Removing symlinks and mounts from generation {}...",
+                active_gen.number
+            );
+            SymlinkManager::new()
+                .with_timeout(timeout)
+                .remove(&active_gen.symlinks)?;
+            generation_manager.deactivate()?;
+            println!("\n✓ No generation is active");
+        }
+    }
+
+    // switch_generation saved the state through its own manager
+    let generation_manager = open_generations(config_path, config_format)?;
+    if let Some(number) = created {
+        generation_manager.delete_generation(number)?;
+        println!("✓ Deleted generation {}", number);
+    }
+    generation_manager.forget_operation()
+}
+
+/// Report groups of identical generations and optionally delete the redundant ones
+fn dedupe_generations(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    apply: bool,
+    keep_generations: &[u64],
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let generations = generation_manager.list_generations()?;
+
+    // Generations the retention policy protects are never redundant either
+    let mut protected = keep_generations.to_vec();
+    if let Ok(config) = Config::from_file(config_path, config_format) {
+        protected.extend(config.retention.keep_generations);
+    }
+    let clusters = dedupe::clusters(&generations, &protected);
+
+    if clusters.is_empty() {
+        println!("✓ No identical generations found");
+        return Ok(());
+    }
+
+    println!(
+        "Found {} group(s) of identical generations:",
+        clusters.len()
+    );
+    let mut redundant = Vec::new();
+    for cluster in &clusters {
+        let numbers: Vec<String> = cluster.numbers.iter().map(u64::to_string).collect();
+        let keep: Vec<String> = cluster.kept().map(|n| n.to_string()).collect();
+        let prune: Vec<String> = cluster.redundant().map(|n| n.to_string()).collect();
+        println!(
+            "  {} (keep {}; prune {})",
+            numbers.join(", "),
+            keep.join(", "),
+            prune.join(", ")
+        );
+        redundant.extend(cluster.redundant());
+    }
+    redundant.sort();
+
+    if redundant.is_empty() {
+        println!("\n✓ Every identical generation is protected; nothing to delete");
+        return Ok(());
+    }
+
+    if !apply {
+        println!(
+            "\nNothing was changed. Run with --apply to delete {} generation(s).",
+            redundant.len()
+        );
+        return Ok(());
+    }
+
+    generation_manager.delete_generations(&redundant)?;
+    generation_manager.record_operation(Operation::Delete {
+        deleted: redundant.clone(),
+    })?;
+    let numbers: Vec<String> = redundant.iter().map(u64::to_string).collect();
+    println!(
+        "\n✓ Deleted {} redundant generation(s) ({})",
+        redundant.len(),
+        numbers.join(", ")
+    );
+    Ok(())
+}
+
+/// Print what switching to generation `number` would remove and recreate
+fn preview_switch(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    number: u64,
+    clean: bool,
+    json: bool,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let generations = generation_manager.list_generations()?;
+    let target = generations
+        .iter()
+        .find(|g| g.number == number)
+        .ok_or_else(|| anyhow::anyhow!("Generation {} not found", number))?;
+    let active = generations.iter().find(|g| g.active);
+
+    let plan = switchplan::plan(active, target, clean);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+    } else {
+        println!("{}", plan);
+    }
+    Ok(())
+}
+
+/// Report what deleting a generation would do without mutating anything
+fn preview_delete(generation_manager: &GenerationManager, number: u64) -> Result<()> {
+    let generations = generation_manager.list_generations()?;
+
+    let gen = generations
+        .iter()
+        .find(|g| g.number == number)
+        .ok_or_else(|| anyhow::anyhow!("Generation {} not found", number))?;
+
+    if gen.active {
+        anyhow::bail!("Cannot delete active generation");
+    }
 
-fn verify_generation(config_path: &PathBuf) -> Result<()> {
-    let state_dir = get_state_dir(config_path);
-    let generation_manager = GenerationManager::new(state_dir)?;
-    let symlink_manager = SymlinkManager::new();
+    println!("Dry run: deleting generation {} would:", number);
+    println!(
+        "  - Remove generation {} ({}, {} symlinks) from generations.json",
+        gen.number,
+        gen.created_at.format("%Y-%m-%d %H:%M:%S"),
+        gen.symlinks.len()
+    );
+
+    let mut orphaned = 0;
+    for backup in gen.symlinks.iter().filter_map(|s| s.backup_path.as_ref()) {
+        let shared_with: Vec<String> = generations
+            .iter()
+            .filter(|g| g.number != number)
+            .filter(|g| {
+                g.symlinks
+                    .iter()
+                    .any(|s| s.backup_path.as_ref() == Some(backup))
+            })
+            .map(|g| g.number.to_string())
+            .collect();
+
+        if !shared_with.is_empty() {
+            println!(
+                "  - Keep backup {} (still referenced by generation {})",
+                backup.display(),
+                shared_with.join(", ")
+            );
+        } else if backup.exists() || backup.is_symlink() {
+            println!(
+                "  - Orphan backup {} (left on disk, no longer restorable)",
+                backup.display()
+            );
+            orphaned += 1;
+        }
+    }
+
+    println!(
+        "\nNothing was changed. {} backup(s) would be orphaned.",
+        orphaned
+    );
+
+    Ok(())
+}
+
+fn validate_config(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    json: bool,
+    against: Option<&Path>,
+) -> Result<()> {
+    let config = Config::from_file(config_path, config_format)?;
+
+    let mut problems = problems::problems(&config);
+    if let Err(e) = config.validate_structure() {
+        problems.insert(0, problems::Problem::config(&e));
+    }
+    if let Some(state_dir) = against {
+        if !state_dir.is_dir() {
+            anyhow::bail!("State directory does not exist: {}", state_dir.display());
+        }
+        let generations = GenerationManager::new(state_dir.to_path_buf())?
+            .with_format(config.state_format)
+            .list_generations()?;
+        problems.extend(problems::history_problems(
+            &config,
+            &generations,
+            chrono::Utc::now(),
+        )?);
+    }
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&problems::report(&problems))?
+        );
+    } else if problems.is_empty() {
+        match against {
+            Some(state_dir) => println!(
+                "✓ {} is valid and consistent with the generations in {}",
+                config_path.display(),
+                state_dir.display()
+            ),
+            None => println!("✓ {} is valid", config_path.display()),
+        }
+    } else {
+        for problem in &problems {
+            print_problem(problem);
+        }
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "{} problem(s) found in {}",
+            problems.len(),
+            config_path.display()
+        );
+    }
+    Ok(())
+}
+
+fn print_problem(problem: &problems::Problem) {
+    match (&problem.group, &problem.entry) {
+        (Some(group), Some(entry)) => println!(
+            "✗ [{}] {} in {}: {}",
+            problem.rule.name(),
+            entry,
+            group,
+            problem.message
+        ),
+        _ => println!("✗ [{}] {}", problem.rule.name(), problem.message),
+    }
+}
+
+/// Which entries `verify` checks
+#[derive(Clone, Copy, PartialEq)]
+enum VerifyScope {
+    /// Every entry, recording nothing (plain `verify`)
+    All,
+    /// Every entry, recording the time if nothing is wrong (`--full`)
+    Full,
+    /// Entries whose source changed since the recorded time (`--only-changed`)
+    OnlyChanged,
+}
+
+impl VerifyScope {
+    fn from_flags(only_changed: bool, full: bool) -> Self {
+        match (only_changed, full) {
+            (true, _) => VerifyScope::OnlyChanged,
+            (_, true) => VerifyScope::Full,
+            _ => VerifyScope::All,
+        }
+    }
+}
+
+fn verify_generation(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    fix: bool,
+    dry_run: bool,
+    strict: bool,
+    scope: VerifyScope,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let symlink_manager = SymlinkManager::new()
+        .with_strict(strict)
+        .with_timeout(timeout);
 
     let active_gen = generation_manager
         .get_active_generation()?
         .ok_or_else(|| anyhow::anyhow!("No active generation"))?;
 
-    println!("Verifying generation {}...", active_gen.number);
+    // Taken before looking at any source, so a change made while verifying
+    // is caught by the next run
+    let started = chrono::Utc::now();
+    let checked: Vec<GenerationSymlink> = match (scope, active_gen.last_verified) {
+        (VerifyScope::OnlyChanged, Some(last_verified)) => {
+            println!(
+                "Verifying generation {} (entries changed since {})...",
+                active_gen.number, last_verified
+            );
+            active_gen
+                .symlinks
+                .iter()
+                .filter(|s| s.source_changed_since(last_verified))
+                .cloned()
+                .collect()
+        }
+        (VerifyScope::OnlyChanged, None) => {
+            println!(
+                "Verifying generation {} (not verified with --only-changed or --full before, checking every entry)...",
+                active_gen.number
+            );
+            active_gen.symlinks.clone()
+        }
+        _ => {
+            println!("Verifying generation {}...", active_gen.number);
+            active_gen.symlinks.clone()
+        }
+    };
+    let skipped = active_gen.symlinks.len() - checked.len();
+    if skipped > 0 {
+        println!("  ℹ Skipped {} entries with unchanged sources", skipped);
+    }
 
-    let errors = symlink_manager.verify(&active_gen.symlinks)?;
+    let errors = symlink_manager.verify(&checked)?;
 
     if errors.is_empty() {
         println!("✓ All symlinks are correctly configured");
+        if scope != VerifyScope::All {
+            generation_manager.record_verified(active_gen.number, started)?;
+        }
+        return Ok(());
+    }
+
+    println!("✗ Found {} error(s):", errors.len());
+    for error in &errors {
+        println!("  - {}", error);
+    }
+
+    if !fix {
+        if strict {
+            anyhow::bail!("{} problem(s) found in strict mode", errors.len());
+        }
+        return Ok(());
+    }
+
+    if dry_run {
+        println!("\nRepair plan for generation {}:", active_gen.number);
+        for gen_symlink in &active_gen.symlinks {
+            if symlink_manager.verify_one(gen_symlink)?.is_none() {
+                continue;
+            }
+            match symlink_manager.plan_repair(gen_symlink) {
+                Ok(actions) => {
+                    for action in actions {
+                        println!("  - {}", action);
+                    }
+                }
+                Err(e) => println!(
+                    "  ✗ Cannot repair {}: {:#}",
+                    gen_symlink.target.display(),
+                    e
+                ),
+            }
+        }
+        println!("\nNothing was changed.");
+        return Ok(());
+    }
+
+    println!("\nRepairing generation {}...", active_gen.number);
+    for gen_symlink in &active_gen.symlinks {
+        if symlink_manager.verify_one(gen_symlink)?.is_none() {
+            continue;
+        }
+        if let Err(e) = symlink_manager.repair(gen_symlink) {
+            println!(
+                "  ✗ Failed to repair {}: {:#}",
+                gen_symlink.target.display(),
+                e
+            );
+        }
+    }
+
+    println!("\nRe-verifying generation {}...", active_gen.number);
+    let remaining = symlink_manager.verify(&active_gen.symlinks)?;
+    let fixed = errors.len().saturating_sub(remaining.len());
+
+    if remaining.is_empty() {
+        println!("✓ Fixed {} error(s)", fixed);
+        Ok(())
     } else {
-        println!("✗ Found {} error(s):", errors.len());
-        for error in errors {
+        println!(
+            "✗ Fixed {} error(s), {} still broken:",
+            fixed,
+            remaining.len()
+        );
+        for error in &remaining {
             println!("  - {}", error);
         }
+        anyhow::bail!("{} error(s) could not be repaired", remaining.len())
+    }
+}
+
+/// Persistence directories of the config, used to spot orphaned mounts.
+/// Without a readable config, only other generations can reveal orphans.
+fn persistence_dirs(config_path: &PathBuf, config_format: Option<ConfigFormat>) -> Vec<PathBuf> {
+    Config::from_file(config_path, config_format)
+        .and_then(|c| c.with_env(None))
+        .map(|c| {
+            c.persistence
+                .iter()
+                .flat_map(|(dir, p)| {
+                    if p.roots.is_empty() {
+                        vec![PathBuf::from(dir)]
+                    } else {
+                        p.roots.clone()
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Print the `+`/`-`/`~` lines between two target maps and a summary
+fn print_diff(old: &BTreeMap<&Path, &Path>, new: &BTreeMap<&Path, &Path>) {
+    match print_target_changes(old, new) {
+        (0, 0, 0) => println!("  (no differences)"),
+        (added, removed, changed) => println!(
+            "\n{} added, {} removed, {} changed",
+            added, removed, changed
+        ),
+    }
+}
+
+fn find_generation(generations: &[Generation], number: u64) -> Result<&Generation> {
+    generations
+        .iter()
+        .find(|g| g.number == number)
+        .ok_or_else(|| anyhow::anyhow!("Generation {} not found", number))
+}
+
+fn diff_generations(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    number: u64,
+    other: u64,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let generations = generation_manager.list_generations()?;
+    let old = find_generation(&generations, number)?;
+    let new = find_generation(&generations, other)?;
+
+    println!(
+        "Changes from generation {} to generation {}:",
+        number, other
+    );
+    print_diff(&generation_targets(old), &generation_targets(new));
+    Ok(())
+}
+
+/// Compare what a generation records with what its targets link to or are
+/// mounted from right now, plus orphans it doesn't record
+fn diff_filesystem(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    number: u64,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let symlink_manager = SymlinkManager::new().with_timeout(timeout);
+    let generations = generation_manager.list_generations()?;
+    let gen = find_generation(&generations, number)?;
+
+    let mut live: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for gen_symlink in &gen.symlinks {
+        if let Some(source) = symlink_manager.live_source(gen_symlink)? {
+            live.push((gen_symlink.target.clone(), source));
+        }
+    }
+    let orphans = fsck::orphans(
+        gen,
+        &generations,
+        &persistence_dirs(config_path, config_format),
+        &symlink_manager,
+    )?;
+    live.extend(orphans.into_iter().map(|o| (o.target, o.source)));
+
+    println!("Changes from generation {} to the filesystem:", number);
+    let live: BTreeMap<&Path, &Path> = live
+        .iter()
+        .map(|(target, source)| (target.as_path(), source.as_path()))
+        .collect();
+    print_diff(&generation_targets(gen), &live);
+    Ok(())
+}
+
+fn fsck_generation(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    fix: bool,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let symlink_manager = SymlinkManager::new().with_timeout(timeout);
+
+    let generations = generation_manager.list_generations()?;
+    let active_gen = generations
+        .iter()
+        .find(|g| g.active)
+        .ok_or_else(|| anyhow::anyhow!("No active generation"))?;
+
+    let persist_dirs = persistence_dirs(config_path, config_format);
+
+    println!(
+        "Checking generation {} against the filesystem...",
+        active_gen.number
+    );
+    let discrepancies = fsck::check(active_gen, &generations, &persist_dirs, &symlink_manager)?;
+
+    if discrepancies.is_empty() {
+        println!("✓ The filesystem matches generation {}", active_gen.number);
+        return Ok(());
+    }
+
+    println!(
+        "✗ Found {} discrepancy(ies) (- recorded but missing, ~ not as recorded, + present but not recorded):",
+        discrepancies.len()
+    );
+    for discrepancy in &discrepancies {
+        println!("  {}", discrepancy);
+    }
+
+    if !fix {
+        return Ok(());
+    }
+
+    println!("\nReconciling generation {}...", active_gen.number);
+    for discrepancy in &discrepancies {
+        if let Err(e) = fsck::fix(discrepancy, &symlink_manager) {
+            println!(
+                "  ✗ Failed to reconcile {}: {:#}",
+                discrepancy.target().display(),
+                e
+            );
+        }
+    }
+
+    println!("\nRe-checking generation {}...", active_gen.number);
+    let remaining = fsck::check(active_gen, &generations, &persist_dirs, &symlink_manager)?;
+    let fixed = discrepancies.len().saturating_sub(remaining.len());
+
+    if remaining.is_empty() {
+        println!("✓ Reconciled {} discrepancy(ies)", fixed);
+        Ok(())
+    } else {
+        println!(
+            "✗ Reconciled {} discrepancy(ies), {} remain:",
+            fixed,
+            remaining.len()
+        );
+        for discrepancy in &remaining {
+            println!("  {}", discrepancy);
+        }
+        anyhow::bail!(
+            "{} discrepancy(ies) could not be reconciled",
+            remaining.len()
+        )
+    }
+}
+
+fn write_metrics(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    output: Option<&Path>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let symlink_manager = SymlinkManager::new().with_timeout(timeout);
+    let rendered = metrics::collect(&generation_manager, &symlink_manager)?.render();
+
+    match output {
+        Some(path) => {
+            // The collector may read at any moment, so never expose a partial file
+            let temp_file = path.with_extension("tmp");
+            std::fs::write(&temp_file, rendered)
+                .context(format!("Failed to write metrics: {}", temp_file.display()))?;
+            std::fs::rename(&temp_file, path)
+                .context(format!("Failed to replace metrics: {}", path.display()))?;
+        }
+        None => print!("{}", rendered),
+    }
+
+    Ok(())
+}
+
+fn migrate_state(config_path: &PathBuf, config_format: Option<ConfigFormat>) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+
+    let (version, converted) = generation_manager.migrate_state()?;
+    if version == generation::STATE_VERSION {
+        println!(
+            "State is already at the current version ({})",
+            generation::STATE_VERSION
+        );
+    } else {
+        println!(
+            "✓ Migrated state from version {} to {}",
+            version,
+            generation::STATE_VERSION
+        );
+    }
+    if converted {
+        println!("✓ Converted state to {}", generation_manager.format());
     }
 
     Ok(())
 }
 
-fn show_current_generation(config_path: &PathBuf) -> Result<()> {
-    let state_dir = get_state_dir(config_path);
-    let generation_manager = GenerationManager::new(state_dir)?;
+fn show_current_generation(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    local: bool,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
 
     if let Some(gen) = generation_manager.get_active_generation()? {
         println!("Current generation: {}", gen.number);
-        println!("  Created at: {}", gen.created_at);
-        println!("  Config: {}", gen.config_path.display());
+        if local {
+            println!("  Created at: {}", format_created_at(&gen, true));
+        } else {
+            println!("  Created at: {}", gen.created_at);
+        }
+        println!(
+            "  Config: {}{}",
+            gen.config_path.display(),
+            config_missing_note(&gen)
+        );
         println!("  Symlinks: {}", gen.symlinks.len());
     } else {
         println!("No active generation");
@@ -323,3 +2902,282 @@ fn show_current_generation(config_path: &PathBuf) -> Result<()> {
 
     Ok(())
 }
+
+/// `current --all-machines`: the active generation of each machine
+fn show_machine_current_generations(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+) -> Result<()> {
+    let machines = open_machine_generations(config_path, config_format)?;
+    if machines.is_empty() {
+        println!("No active generation");
+        return Ok(());
+    }
+
+    for (id, generation_manager) in &machines {
+        match generation_manager.get_active_generation()? {
+            Some(gen) => println!(
+                "Machine {}{}: generation {} ({} symlinks)",
+                id,
+                this_machine_note(id),
+                gen.number,
+                gen.symlinks.len()
+            ),
+            None => println!(
+                "Machine {}{}: no active generation",
+                id,
+                this_machine_note(id)
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the active generation's mount entries that are mounted right now,
+/// with the source and options from the kernel's mount table
+fn list_mounts(config_path: &PathBuf, config_format: Option<ConfigFormat>) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let gen = generation_manager
+        .get_active_generation()?
+        .ok_or_else(|| anyhow::anyhow!("No active generation"))?;
+    let mounts = mounts::read_mountinfo()?;
+
+    println!("Mounts of generation {}:", gen.number);
+    let expected: Vec<&GenerationSymlink> = gen.symlinks.iter().filter(|s| s.is_mount()).collect();
+    let mut active = 0;
+    for gen_symlink in &expected {
+        let target = &gen_symlink.target;
+        match mounts::find_mount(&mounts, target) {
+            Some(mount) => {
+                let source = if gen_symlink.is_device() {
+                    mount.source.clone()
+                } else {
+                    mounts::mount_source(&mounts, mount)
+                };
+                println!(
+                    "  {} <- {} ({})",
+                    target.display(),
+                    source,
+                    mount.options.join(",")
+                );
+                active += 1;
+            }
+            None if gen_symlink.is_waiting() => println!(
+                "  {} (waiting for its first write to create {})",
+                target.display(),
+                gen_symlink.source.display()
+            ),
+            None => println!("  {} (not mounted)", target.display()),
+        }
+    }
+    println!("\n{} of {} mount(s) active", active, expected.len());
+
+    Ok(())
+}
+
+fn debug_dump(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    json: bool,
+    redact_paths: bool,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let dump = debugdump::collect(config_path, config_format, &generation_manager)?;
+
+    let mut report = if json {
+        serde_json::to_string_pretty(&dump)? + "\n"
+    } else {
+        dump.render()?
+    };
+    if redact_paths {
+        report = dump.redact_paths(&report);
+    }
+    print!("{}", report);
+
+    Ok(())
+}
+
+fn import_nix(file: &Path, output: Option<&PathBuf>) -> Result<()> {
+    let imported = nix_import::import_file(file)?;
+    let toml = toml::to_string_pretty(&imported).context("Failed to serialize config")?;
+
+    match output {
+        Some(output) => {
+            std::fs::write(output, &toml)
+                .context(format!("Failed to write config: {}", output.display()))?;
+            println!(
+                "✓ Imported {} persistence root(s) into {}",
+                imported.persistence.len(),
+                output.display()
+            );
+        }
+        None => print!("{}", toml),
+    }
+
+    Ok(())
+}
+
+fn archive_generation(
+    config_path: &PathBuf,
+    config_format: Option<ConfigFormat>,
+    number: u64,
+    output: &Path,
+) -> Result<()> {
+    let generation_manager = open_generations(config_path, config_format)?;
+    let generations = generation_manager.list_generations()?;
+
+    let gen = generations
+        .iter()
+        .find(|g| g.number == number)
+        .ok_or_else(|| anyhow::anyhow!("Generation {} not found", number))?;
+
+    let (manifest, missing) = archive::create_archive(gen, output)?;
+    for source in &missing {
+        println!("⚠ Skipping missing source: {}", source.display());
+    }
+    println!(
+        "✓ Archived {} source(s) of generation {} to {}",
+        manifest.entries.len(),
+        number,
+        output.display()
+    );
+
+    Ok(())
+}
+
+fn unarchive(archive: &Path, root: &Path) -> Result<()> {
+    let manifest = archive::extract_archive(archive, root)?;
+
+    println!(
+        "✓ Restored {} source(s) of generation {} into {}",
+        manifest.entries.len(),
+        manifest.generation,
+        root.display()
+    );
+    for entry in &manifest.entries {
+        println!(
+            "  {} <- {}",
+            entry.target.display(),
+            root.join(&entry.path).display()
+        );
+    }
+    println!(
+        "Use [persistence.\"{}\"] in your config to apply this data.",
+        root.display()
+    );
+
+    Ok(())
+}
+
+fn run_daemon(
+    config_path: &PathBuf,
+    verify_interval: Option<u64>,
+    no_namespace: bool,
+    socket: Option<&Path>,
+    options: &ApplyOptions,
+) -> Result<()> {
+    daemon::install_shutdown_handler()?;
+
+    if !no_namespace {
+        namespace::enter_private_mount_namespace()?;
+        println!("ℹ Using a private mount namespace held by this daemon");
+    }
+
+    apply_config(config_path, options)?;
+
+    let generation_manager = open_generations(config_path, options.config_format)?;
+    let symlink_manager = SymlinkManager::new()
+        .with_verbose(options.verbose)
+        .with_timeout(options.timeout);
+
+    let active_gen = generation_manager
+        .get_active_generation()?
+        .ok_or_else(|| anyhow::anyhow!("No active generation after apply"))?;
+
+    if let Some(socket) = socket {
+        if let Err(e) = daemon::serve_health(socket, active_gen.number, active_gen.symlinks.clone())
+        {
+            release_daemon_generation(&symlink_manager, &generation_manager, &active_gen)?;
+            return Err(e);
+        }
+        println!("ℹ Answering health checks on {}", socket.display());
+    }
+
+    println!(
+        "\nDaemon running (pid {}), holding generation {}",
+        std::process::id(),
+        active_gen.number
+    );
+
+    let interval = verify_interval.map(Duration::from_secs);
+    while !daemon::wait_for_shutdown(interval) {
+        for gen_symlink in &active_gen.symlinks {
+            // A failed check is retried on the next tick rather than ending
+            // the daemon without taking the generation down
+            let error = match symlink_manager.verify_one(gen_symlink) {
+                Ok(Some(error)) => error,
+                Ok(None) => continue,
+                Err(e) => {
+                    println!(
+                        "  ✗ Failed to verify {}: {:#}",
+                        gen_symlink.target.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            println!("  ⚠ {}", error);
+            if let Err(e) = symlink_manager.repair(gen_symlink) {
+                println!(
+                    "  ✗ Failed to repair {}: {:#}",
+                    gen_symlink.target.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    println!(
+        "\nShutdown requested, removing symlinks and mounts from generation {}...",
+        active_gen.number
+    );
+    if let Some(socket) = socket {
+        daemon::remove_health_socket(socket);
+    }
+    release_daemon_generation(&symlink_manager, &generation_manager, &active_gen)?;
+    println!("✓ Daemon stopped");
+
+    Ok(())
+}
+
+/// Take down the generation a daemon held and, unless another command
+/// activated a different one meanwhile, record that no generation is active
+fn release_daemon_generation(
+    symlink_manager: &SymlinkManager,
+    generation_manager: &GenerationManager,
+    active_gen: &Generation,
+) -> Result<()> {
+    symlink_manager.remove(&active_gen.symlinks)?;
+    let still_active = generation_manager
+        .get_active_generation()?
+        .is_some_and(|g| g.number == active_gen.number);
+    if still_active {
+        generation_manager.deactivate()?;
+    }
+    Ok(())
+}
+
+fn ping_daemon(socket: &PathBuf) -> Result<()> {
+    let reply = daemon::ping(socket)?;
+    println!("{}", reply);
+
+    if let Some(error) = reply.get("error").and_then(|e| e.as_str()) {
+        anyhow::bail!("Daemon replied with an error: {}", error);
+    }
+    if reply.get("healthy").and_then(|h| h.as_bool()) != Some(true) {
+        anyhow::bail!("Daemon reports problems with its generation");
+    }
+
+    Ok(())
+}
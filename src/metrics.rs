@@ -0,0 +1,98 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::backup;
+use crate::generation::GenerationManager;
+use crate::symlink::SymlinkManager;
+
+/// Gauges exported by `imp metrics`
+pub struct Metrics {
+    pub generations: usize,
+    /// Number of the active generation, if there is one
+    pub active_generation: Option<u64>,
+    /// Entries of the active generation
+    pub symlinks: usize,
+    /// Entries of the active generation that fail verification
+    pub broken_symlinks: usize,
+    /// Size of all backups still on disk, each counted once
+    pub backup_bytes: u64,
+}
+
+/// Gather the metrics, verifying the active generation along the way
+pub fn collect(
+    generation_manager: &GenerationManager,
+    symlink_manager: &SymlinkManager,
+) -> Result<Metrics> {
+    let generations = generation_manager.list_generations()?;
+    let active = generations.iter().find(|g| g.active);
+
+    let (symlinks, broken_symlinks) = match active {
+        Some(gen) => (
+            gen.symlinks.len(),
+            symlink_manager.verify(&gen.symlinks)?.len(),
+        ),
+        None => (0, 0),
+    };
+
+    let mut seen = HashSet::new();
+    let mut backup_bytes = 0;
+    let backups = generations
+        .iter()
+        .flat_map(|g| g.symlinks.iter().filter_map(|s| s.backup_path.as_ref()));
+    for backup_path in backups {
+        if seen.insert(backup_path) && backup_path.symlink_metadata().is_ok() {
+            backup_bytes += backup::backup_size(backup_path)?;
+        }
+    }
+
+    Ok(Metrics {
+        generations: generations.len(),
+        active_generation: active.map(|g| g.number),
+        symlinks,
+        broken_symlinks,
+        backup_bytes,
+    })
+}
+
+impl Metrics {
+    /// Render in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let gauges: [(&str, &str, u64); 5] = [
+            (
+                "imp_generations_total",
+                "Number of generations recorded",
+                self.generations as u64,
+            ),
+            (
+                "imp_active_generation",
+                "Number of the active generation (0 if none is active)",
+                self.active_generation.unwrap_or(0),
+            ),
+            (
+                "imp_symlinks_total",
+                "Symlinks and mounts in the active generation",
+                self.symlinks as u64,
+            ),
+            (
+                "imp_broken_symlinks",
+                "Entries of the active generation that fail verification",
+                self.broken_symlinks as u64,
+            ),
+            (
+                "imp_backup_bytes",
+                "Total size of the backups recorded by all generations",
+                self.backup_bytes,
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, help, value) in gauges {
+            // Writing to a String can't fail
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} gauge", name);
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+        output
+    }
+}
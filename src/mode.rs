@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+
+/// Permission and special bits a mode string can set
+const MODE_MASK: u32 = 0o7777;
+
+/// Bits belonging to each class, including its special bit
+const USER_BITS: u32 = 0o4700;
+const GROUP_BITS: u32 = 0o2070;
+const OTHER_BITS: u32 = 0o1007;
+
+/// Parse a permission mode, either octal (`0750`, `0o750`, `750`) or symbolic
+/// as understood by chmod (`u=rwx,g=rx,o=`, `a+x`, `go-w`).
+///
+/// Symbolic clauses are applied in order on top of `current`, the mode the
+/// path has now, so classes a clause doesn't name keep their permissions.
+/// Supported: classes `u`, `g`, `o` and `a` (also when none is given),
+/// operators `=`, `+` and `-` (several may follow one another, as in
+/// `u=rw+x`), and permissions `r`, `w`, `x`, `s` (setuid/setgid) and `t`
+/// (sticky). Copying from another class (`g=u`), `X` and the umask are not
+/// supported.
+pub fn parse(mode: &str, current: u32) -> Result<u32> {
+    if (!mode.is_empty() && mode.chars().all(|c| c.is_ascii_digit())) || mode.starts_with("0o") {
+        // Remove "0o" or "0" prefix if present
+        let digits = mode.trim_start_matches("0o").trim_start_matches('0');
+        if digits.is_empty() {
+            return Ok(0);
+        }
+        let bits =
+            u32::from_str_radix(digits, 8).context(format!("Invalid mode string: {}", mode))?;
+        if bits > MODE_MASK {
+            anyhow::bail!("Invalid mode string: {}: too many bits for a mode", mode);
+        }
+        return Ok(bits);
+    }
+
+    let mut bits = current & MODE_MASK;
+    for clause in mode.split(',') {
+        bits = apply_clause(clause, bits).context(format!("Invalid mode string: {}", mode))?;
+    }
+    Ok(bits)
+}
+
+/// Apply one symbolic clause such as `go-w` to `bits`
+fn apply_clause(clause: &str, mut bits: u32) -> Result<u32> {
+    let op_start = clause
+        .find(['=', '+', '-'])
+        .context(format!("{:?} has no operator (=, + or -)", clause))?;
+    let (who, mut rest) = clause.split_at(op_start);
+
+    let mut classes = 0;
+    for class in who.chars() {
+        classes |= match class {
+            'u' => USER_BITS,
+            'g' => GROUP_BITS,
+            'o' => OTHER_BITS,
+            'a' => USER_BITS | GROUP_BITS | OTHER_BITS,
+            _ => anyhow::bail!("unknown class {:?} in {:?}", class, clause),
+        };
+    }
+    if classes == 0 {
+        classes = USER_BITS | GROUP_BITS | OTHER_BITS;
+    }
+
+    while let Some(op) = rest.chars().next() {
+        let perms_end = rest[1..]
+            .find(['=', '+', '-'])
+            .map_or(rest.len(), |i| i + 1);
+        let perms = &rest[1..perms_end];
+        rest = &rest[perms_end..];
+
+        let mut value = 0;
+        for perm in perms.chars() {
+            value |= match perm {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                's' => 0o6000,
+                't' => 0o1000,
+                _ => anyhow::bail!("unknown permission {:?} in {:?}", perm, clause),
+            };
+        }
+        let value = value & classes;
+
+        bits = match op {
+            '=' => (bits & !classes) | value,
+            '+' => bits | value,
+            _ => bits & !value,
+        };
+    }
+
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn octal() {
+        assert_eq!(parse("0750", 0).unwrap(), 0o750);
+        assert_eq!(parse("0o750", 0).unwrap(), 0o750);
+        assert_eq!(parse("750", 0).unwrap(), 0o750);
+        assert_eq!(parse("4755", 0).unwrap(), 0o4755);
+        assert_eq!(parse("0", 0o777).unwrap(), 0);
+    }
+
+    #[test]
+    fn absolute_symbolic() {
+        assert_eq!(parse("u=rwx,g=rx,o=", 0o777).unwrap(), 0o750);
+        assert_eq!(parse("a=r", 0o777).unwrap(), 0o444);
+        assert_eq!(parse("=rx", 0).unwrap(), 0o555);
+        assert_eq!(parse("u=rw+x", 0).unwrap(), 0o700);
+        assert_eq!(parse("u=rwxs,o=t", 0).unwrap(), 0o5700);
+    }
+
+    #[test]
+    fn relative_symbolic() {
+        assert_eq!(parse("go-w", 0o777).unwrap(), 0o755);
+        assert_eq!(parse("a+x", 0o644).unwrap(), 0o755);
+        assert_eq!(parse("g+s", 0o755).unwrap(), 0o2755);
+        // Classes a clause doesn't name keep their permissions
+        assert_eq!(parse("o=", 0o755).unwrap(), 0o750);
+    }
+
+    #[test]
+    fn malformed() {
+        for mode in [
+            "", "rwx", "u=rwq", "z=r", "g=u", "u=X", "0o8", "99999", "u=r,",
+        ] {
+            assert!(parse(mode, 0).is_err(), "{:?} was accepted", mode);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Per-mount options that verify compares against what was recorded at apply time
+const TRACKED_OPTIONS: &[&str] = &["ro", "rw", "nosuid", "nodev", "noexec"];
+
+/// How a directory entry's mount updates access times, where it shouldn't
+/// just inherit the behavior of the source's filesystem
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Atime {
+    /// Never update access times
+    Noatime,
+    /// Only update an access time older than the last modification or
+    /// change, or more than a day old
+    Relatime,
+}
+
+impl Atime {
+    /// The option as mountinfo lists it
+    pub fn name(&self) -> &'static str {
+        match self {
+            Atime::Noatime => "noatime",
+            Atime::Relatime => "relatime",
+        }
+    }
+}
+
+/// A single entry from /proc/self/mountinfo
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    /// Device number of the mounted filesystem ("major:minor")
+    pub major_minor: String,
+    /// Path within the filesystem that forms the root of this mount
+    pub root: PathBuf,
+    /// Where the mount is attached
+    pub mount_point: PathBuf,
+    /// Per-mount options (e.g. "rw", "nosuid", "relatime")
+    pub options: Vec<String>,
+    /// Filesystem-specific source, usually the device (e.g. "/dev/sda1")
+    pub source: String,
+}
+
+/// Read and parse /proc/self/mountinfo
+pub fn read_mountinfo() -> Result<Vec<MountInfo>> {
+    let contents = fs::read_to_string("/proc/self/mountinfo")
+        .context("Failed to read /proc/self/mountinfo")?;
+    Ok(contents.lines().filter_map(parse_mountinfo_line).collect())
+}
+
+/// Read /proc/self/mountinfo, keeping each entry's line as the kernel wrote it
+pub fn read_mountinfo_lines() -> Result<Vec<(String, MountInfo)>> {
+    let contents = fs::read_to_string("/proc/self/mountinfo")
+        .context("Failed to read /proc/self/mountinfo")?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| Some((line.to_string(), parse_mountinfo_line(line)?)))
+        .collect())
+}
+
+fn parse_mountinfo_line(line: &str) -> Option<MountInfo> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    // Optional fields end with a lone "-" separator
+    let separator = fields.iter().position(|f| *f == "-")?;
+    if separator < 6 || fields.len() < separator + 3 {
+        return None;
+    }
+
+    Some(MountInfo {
+        major_minor: fields[2].to_string(),
+        root: PathBuf::from(unescape(fields[3])),
+        mount_point: PathBuf::from(unescape(fields[4])),
+        options: fields[5].split(',').map(String::from).collect(),
+        source: unescape(fields[separator + 2]),
+    })
+}
+
+impl MountInfo {
+    /// The subset of this mount's options that imp records and verifies
+    pub fn tracked_options(&self) -> Vec<String> {
+        self.options
+            .iter()
+            .filter(|o| TRACKED_OPTIONS.contains(&o.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Filesystem types the kernel can mount from a block device, in the order
+/// it lists them (those marked "nodev" in /proc/filesystems are left out)
+pub fn block_filesystems() -> Result<Vec<String>> {
+    let contents =
+        fs::read_to_string("/proc/filesystems").context("Failed to read /proc/filesystems")?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.starts_with("nodev"))
+        .map(|line| line.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect())
+}
+
+/// Decode the octal escapes (\040 for space, etc.) the kernel uses in mount tables
+pub fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() {
+            let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).ok();
+            if let Some(value) = digits.and_then(|d| u8::from_str_radix(d, 8).ok()) {
+                out.push(value);
+                i += 4;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The mount currently visible at `path` (the topmost one if mounts are stacked)
+pub fn find_mount<'a>(mounts: &'a [MountInfo], path: &Path) -> Option<&'a MountInfo> {
+    mounts.iter().rev().find(|m| m.mount_point == path)
+}
+
+/// The mount whose filesystem contains `path` (longest matching mount point)
+pub fn containing_mount<'a>(mounts: &'a [MountInfo], path: &Path) -> Option<&'a MountInfo> {
+    // max_by_key keeps the last maximum, i.e. the topmost of stacked mounts
+    mounts
+        .iter()
+        .filter(|m| path.starts_with(&m.mount_point))
+        .max_by_key(|m| m.mount_point.components().count())
+}
+
+/// Best-effort path of the directory that `mount` exposes.
+///
+/// Looks for another mount of the same filesystem whose root contains this
+/// mount's root and translates through it, preferring the mount closest to the
+/// filesystem root. Falls back to "device:root" when the filesystem isn't
+/// mounted anywhere else.
+pub fn mount_source(mounts: &[MountInfo], mount: &MountInfo) -> String {
+    let via = mounts
+        .iter()
+        .filter(|m| m.mount_point != mount.mount_point && m.major_minor == mount.major_minor)
+        .filter(|m| mount.root.starts_with(&m.root))
+        .min_by_key(|m| {
+            (
+                m.root.components().count(),
+                m.mount_point.components().count(),
+            )
+        });
+
+    match via {
+        Some(m) => {
+            let relative = mount.root.strip_prefix(&m.root).unwrap_or(&mount.root);
+            m.mount_point.join(relative).display().to_string()
+        }
+        None => format!("{}:{}", mount.source, mount.root.display()),
+    }
+}
+
+/// Whether `mount` exposes exactly the directory at `source` (an already canonical path)
+pub fn is_bind_of(mounts: &[MountInfo], mount: &MountInfo, source: &Path) -> bool {
+    let container = match containing_mount(mounts, source) {
+        Some(m) => m,
+        None => return false,
+    };
+    let relative = match source.strip_prefix(&container.mount_point) {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+
+    container.major_minor == mount.major_minor && container.root.join(relative) == mount.root
+}
@@ -0,0 +1,25 @@
+use anyhow::{Context, Result};
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+
+/// Move this process into a new, private mount namespace.
+///
+/// Mounts created afterwards are only visible to this process (and its
+/// children) and disappear when the namespace is torn down, i.e. when the last
+/// process in it exits. Propagation is set to private so nothing leaks back to
+/// the host and host mount events don't propagate in.
+pub fn enter_private_mount_namespace() -> Result<()> {
+    unshare(CloneFlags::CLONE_NEWNS)
+        .context("Failed to create a mount namespace. This requires root or CAP_SYS_ADMIN.")?;
+
+    mount(
+        None::<&str>,
+        "/",
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )
+    .context("Failed to make mounts private in the new namespace")?;
+
+    Ok(())
+}
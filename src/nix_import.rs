@@ -0,0 +1,493 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::config::{
+    DirectoryEntry, FileEntry, ParentDirectoryConfig, PersistenceConfig, SourceLayout,
+};
+use crate::mode;
+
+/// The parts of an imp config that an impermanence definition maps onto
+#[derive(Debug, Serialize)]
+pub struct ImportedConfig {
+    pub persistence: BTreeMap<String, PersistenceConfig>,
+}
+
+/// Read an impermanence `environment.persistence` definition, either as a
+/// Nix file or as JSON exported with `nix eval --json`, and convert it
+pub fn import_file(path: &Path) -> Result<ImportedConfig> {
+    let contents = std::fs::read_to_string(path)
+        .context(format!("Failed to read file: {}", path.display()))?;
+
+    let value = match serde_json::from_str(&contents) {
+        Ok(value) => value,
+        Err(_) => parse_nix(&contents).context(format!(
+            "Failed to parse {} as JSON or as the supported subset of Nix",
+            path.display()
+        ))?,
+    };
+
+    convert(persistence_section(&value)?)
+}
+
+/// Find the persistence attrset, accepting a whole module, a bare
+/// `environment.persistence` or the persistence attrset itself
+fn persistence_section(value: &Value) -> Result<&Map<String, Value>> {
+    let section = value
+        .pointer("/environment/persistence")
+        .or_else(|| value.get("persistence"))
+        .unwrap_or(value);
+
+    section
+        .as_object()
+        .context("Expected environment.persistence to be an attribute set")
+}
+
+fn convert(persistence: &Map<String, Value>) -> Result<ImportedConfig> {
+    let mut imported = BTreeMap::new();
+
+    for (root, definition) in persistence {
+        let definition = definition
+            .as_object()
+            .context(format!("Persistence root {} is not an attribute set", root))?;
+
+        let mut config = PersistenceConfig {
+            hide_mounts: definition
+                .get("hideMounts")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            base: None,
+            directories: Vec::new(),
+            files: Vec::new(),
+            source_layout: SourceLayout::default(),
+            strip_prefix: None,
+            roots: Vec::new(),
+            links: Vec::new(),
+        };
+
+        for entry in list(definition, "directories", root)? {
+            config
+                .directories
+                .push(directory_entry(entry, None, None, root)?);
+        }
+        for entry in list(definition, "files", root)? {
+            config.files.push(file_entry(entry, None, root)?);
+        }
+
+        // Per-user entries are relative to the user's home and owned by them
+        if let Some(users) = definition.get("users").and_then(Value::as_object) {
+            for (user, user_definition) in users {
+                let home = user_definition
+                    .get("home")
+                    .and_then(Value::as_str)
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| Path::new("/home").join(user));
+
+                let user_definition = user_definition
+                    .as_object()
+                    .context(format!("User {} in {} is not an attribute set", user, root))?;
+
+                for entry in list(user_definition, "directories", root)? {
+                    config
+                        .directories
+                        .push(directory_entry(entry, Some(&home), Some(user), root)?);
+                }
+                for entry in list(user_definition, "files", root)? {
+                    config.files.push(file_entry(entry, Some(&home), root)?);
+                }
+            }
+        }
+
+        // Exported configs may list user entries both per user and expanded
+        let mut seen = HashSet::new();
+        config
+            .directories
+            .retain(|entry| seen.insert(entry.path().to_string()));
+        let mut seen = HashSet::new();
+        config
+            .files
+            .retain(|entry| seen.insert(entry.path().to_string()));
+
+        imported.insert(root.clone(), config);
+    }
+
+    Ok(ImportedConfig {
+        persistence: imported,
+    })
+}
+
+fn list<'a>(definition: &'a Map<String, Value>, key: &str, root: &str) -> Result<&'a [Value]> {
+    match definition.get(key) {
+        None => Ok(&[]),
+        Some(value) => value
+            .as_array()
+            .map(Vec::as_slice)
+            .context(format!("{} of {} is not a list", key, root)),
+    }
+}
+
+fn entry_path(path: &str, home: Option<&Path>) -> String {
+    match home {
+        Some(home) => home
+            .join(path.trim_start_matches('/'))
+            .display()
+            .to_string(),
+        None => path.to_string(),
+    }
+}
+
+fn directory_entry(
+    entry: &Value,
+    home: Option<&Path>,
+    user: Option<&str>,
+    root: &str,
+) -> Result<DirectoryEntry> {
+    let (path, details) = match entry {
+        Value::String(path) => (path.as_str(), None),
+        Value::Object(details) => (
+            details
+                .get("directory")
+                .and_then(Value::as_str)
+                .context(format!("Directory entry in {} has no directory", root))?,
+            Some(details),
+        ),
+        _ => anyhow::bail!("Unsupported directory entry in {}: {}", root, entry),
+    };
+
+    let field = |name: &str| {
+        details
+            .and_then(|d| d.get(name))
+            .and_then(Value::as_str)
+            .map(String::from)
+    };
+    let owner = field("user").or_else(|| user.map(String::from));
+    let group = field("group").or_else(|| user.map(|_| "users".to_string()));
+    let mode = field("mode").map(|m| octal_mode(&m));
+    let path = entry_path(path, home);
+
+    if owner.is_none() && group.is_none() && mode.is_none() {
+        return Ok(DirectoryEntry::Simple(path));
+    }
+    Ok(DirectoryEntry::Detailed {
+        directory: path,
+        user: owner,
+        group,
+        mode,
+        mount_point_mode: None,
+        migrate: Vec::new(),
+        optional: false,
+        on_missing_source: None,
+        create_parents: None,
+        device: None,
+        image: None,
+        fstype: None,
+        root: None,
+        restore_backup: None,
+        atime: None,
+    })
+}
+
+fn file_entry(entry: &Value, home: Option<&Path>, root: &str) -> Result<FileEntry> {
+    let (path, details) = match entry {
+        Value::String(path) => (path.as_str(), None),
+        Value::Object(details) => (
+            details
+                .get("file")
+                .and_then(Value::as_str)
+                .context(format!("File entry in {} has no file", root))?,
+            Some(details),
+        ),
+        _ => anyhow::bail!("Unsupported file entry in {}: {}", root, entry),
+    };
+
+    let parent_mode = details
+        .and_then(|d| d.get("parentDirectory"))
+        .and_then(|p| p.get("mode"))
+        .and_then(Value::as_str)
+        .map(octal_mode);
+    let path = entry_path(path, home);
+
+    match parent_mode {
+        None => Ok(FileEntry::Simple(path)),
+        Some(mode) => Ok(FileEntry::Detailed {
+            file: path,
+            parent_directory: Some(ParentDirectoryConfig { mode: Some(mode) }),
+            preserve_symlink: false,
+            optional: false,
+            on_missing_source: None,
+            create_parents: None,
+            root: None,
+            restore_backup: None,
+        }),
+    }
+}
+
+/// Convert impermanence's symbolic modes (e.g. `u=rwx,g=rx,o=`) to octal, so
+/// the imported config doesn't depend on the permissions targets have now.
+/// Anything unparseable is left untouched for validation to report.
+fn octal_mode(mode: &str) -> String {
+    match mode::parse(mode, 0) {
+        Ok(bits) => format!("{:04o}", bits),
+        Err(_) => mode.to_string(),
+    }
+}
+
+/// Parse the subset of Nix used by persistence definitions: attribute sets
+/// (with dotted keys), lists, strings, booleans, integers and null, optionally
+/// wrapped in a module's `{ config, ... }:` header
+fn parse_nix(source: &str) -> Result<Value> {
+    let mut parser = NixParser {
+        chars: source.chars().collect(),
+        pos: 0,
+    };
+
+    parser.skip_module_header();
+    let value = parser.value()?;
+    parser.skip_trivia();
+    if parser.pos < parser.chars.len() {
+        return Err(parser.error("unexpected trailing input"));
+    }
+    Ok(value)
+}
+
+struct NixParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl NixParser {
+    fn error(&self, message: &str) -> anyhow::Error {
+        let line = self.chars[..self.pos]
+            .iter()
+            .filter(|c| **c == '\n')
+            .count()
+            + 1;
+        anyhow::anyhow!("line {}: {}", line, message)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_trivia(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => self.pos += 1,
+                Some('#') => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1;
+                    }
+                }
+                Some('/') if self.chars.get(self.pos + 1) == Some(&'*') => {
+                    self.pos += 2;
+                    while self.pos < self.chars.len()
+                        && !(self.chars[self.pos] == '*'
+                            && self.chars.get(self.pos + 1) == Some(&'/'))
+                    {
+                        self.pos += 1;
+                    }
+                    self.pos = (self.pos + 2).min(self.chars.len());
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_trivia();
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("expected '{}'", expected)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Skip a `{ config, pkgs, ... }:` function header if present
+    fn skip_module_header(&mut self) {
+        self.skip_trivia();
+        if self.peek() != Some('{') {
+            return;
+        }
+        let close = match self.chars[self.pos..].iter().position(|c| *c == '}') {
+            Some(offset) => self.pos + offset,
+            None => return,
+        };
+        let formals: String = self.chars[self.pos + 1..close].iter().collect();
+        if formals.contains(['=', '{', '"']) {
+            return;
+        }
+
+        let start = self.pos;
+        self.pos = close + 1;
+        self.skip_trivia();
+        if self.peek() == Some(':') {
+            self.pos += 1;
+        } else {
+            self.pos = start;
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.skip_trivia();
+        match self.peek() {
+            Some('{') => self.attrset(),
+            Some('[') => self.list(),
+            Some('"') => Ok(Value::String(self.string()?)),
+            Some(c) if c.is_ascii_digit() => self.integer(),
+            Some(c) if is_ident_start(c) => match self.identifier().as_str() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                "null" => Ok(Value::Null),
+                other => Err(self.error(&format!(
+                    "unsupported expression '{}' (only literal values are supported)",
+                    other
+                ))),
+            },
+            Some(c) => Err(self.error(&format!("unexpected '{}'", c))),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn attrset(&mut self) -> Result<Value> {
+        self.expect('{')?;
+        let mut attrs = Map::new();
+
+        loop {
+            self.skip_trivia();
+            if self.peek() == Some('}') {
+                self.pos += 1;
+                return Ok(Value::Object(attrs));
+            }
+
+            let path = self.attr_path()?;
+            self.expect('=')?;
+            let value = self.value()?;
+            self.expect(';')?;
+            insert_path(&mut attrs, &path, value).map_err(|e| self.error(&e))?;
+        }
+    }
+
+    fn attr_path(&mut self) -> Result<Vec<String>> {
+        let mut path = Vec::new();
+        loop {
+            self.skip_trivia();
+            match self.peek() {
+                Some('"') => path.push(self.string()?),
+                Some(c) if is_ident_start(c) => path.push(self.identifier()),
+                _ => return Err(self.error("expected attribute name")),
+            }
+            self.skip_trivia();
+            if self.peek() != Some('.') {
+                return Ok(path);
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn list(&mut self) -> Result<Value> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+
+        loop {
+            self.skip_trivia();
+            if self.peek() == Some(']') {
+                self.pos += 1;
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut out = String::new();
+
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated string")),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some('$') if self.chars.get(self.pos + 1) == Some(&'{') => {
+                    return Err(self.error("string interpolation is not supported"));
+                }
+                Some('\\') => {
+                    let escaped = self
+                        .chars
+                        .get(self.pos + 1)
+                        .copied()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    out.push(match escaped {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        other => other,
+                    });
+                    self.pos += 2;
+                }
+                Some(c) => {
+                    out.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn integer(&mut self) -> Result<Value> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        let number: i64 = digits.parse().map_err(|_| self.error("invalid integer"))?;
+        Ok(Value::from(number))
+    }
+
+    fn identifier(&mut self) -> String {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if is_ident_start(c) || c.is_ascii_digit() || c == '-' || c == '\'')
+        {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+/// Insert `value` at a dotted attribute path, merging with existing attrsets
+fn insert_path(
+    attrs: &mut Map<String, Value>,
+    path: &[String],
+    value: Value,
+) -> std::result::Result<(), String> {
+    let (last, parents) = path.split_last().expect("attribute paths are never empty");
+
+    let mut current = attrs;
+    for key in parents {
+        current = current
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .ok_or_else(|| format!("attribute '{}' is already defined", key))?;
+    }
+
+    match (current.get_mut(last), value) {
+        (Some(Value::Object(existing)), Value::Object(new)) => {
+            for (key, value) in new {
+                insert_path(existing, &[key], value)?;
+            }
+            Ok(())
+        }
+        (Some(_), _) => Err(format!("attribute '{}' is already defined", last)),
+        (None, value) => {
+            current.insert(last.clone(), value);
+            Ok(())
+        }
+    }
+}
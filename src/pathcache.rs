@@ -0,0 +1,118 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Symlinks followed while resolving one path before giving up (the kernel's limit)
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+/// Memoizes path canonicalization so entries that share ancestor directories
+/// only resolve those ancestors once.
+///
+/// Only successful resolutions are cached, so a path that didn't exist yet is
+/// looked up again next time. Anything renamed or replaced while the cache is in
+/// use must be passed to `invalidate`.
+#[derive(Debug, Default)]
+pub struct PathCache {
+    resolved: RefCell<HashMap<PathBuf, PathBuf>>,
+    lookups: Cell<usize>,
+}
+
+impl PathCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Equivalent to `fs::canonicalize`, reusing already resolved ancestors
+    pub fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let absolute = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            std::env::current_dir()?.join(path)
+        };
+
+        let mut follows = 0;
+        self.resolve(&absolute, &mut follows)
+    }
+
+    /// Forget `path` and everything cached beneath it
+    pub fn invalidate(&self, path: &Path) {
+        self.resolved
+            .borrow_mut()
+            .retain(|key, _| !key.starts_with(path));
+    }
+
+    /// Forget everything
+    pub fn clear(&self) {
+        self.resolved.borrow_mut().clear();
+        self.lookups.set(0);
+    }
+
+    /// Number of filesystem lookups performed since the cache was last cleared
+    pub fn lookups(&self) -> usize {
+        self.lookups.get()
+    }
+
+    fn resolve(&self, path: &Path, follows: &mut usize) -> io::Result<PathBuf> {
+        if let Some(resolved) = self.resolved.borrow().get(path) {
+            return Ok(resolved.clone());
+        }
+
+        let mut components = path.components();
+        let last = match components.next_back() {
+            Some(component) => component,
+            None => return Ok(PathBuf::from("/")),
+        };
+        let parent = components.as_path();
+
+        let resolved = match last {
+            Component::RootDir | Component::Prefix(_) => path.to_path_buf(),
+            Component::CurDir => self.resolve(parent, follows)?,
+            Component::ParentDir => {
+                let dir = self.resolve(parent, follows)?;
+                self.lookup(&dir)?;
+                if !fs::metadata(&dir)?.is_dir() {
+                    return Err(io::Error::from(io::ErrorKind::NotADirectory));
+                }
+                dir.parent().map(Path::to_path_buf).unwrap_or(dir)
+            }
+            Component::Normal(name) => self.resolve_entry(parent, name, follows)?,
+        };
+
+        self.resolved
+            .borrow_mut()
+            .insert(path.to_path_buf(), resolved.clone());
+        Ok(resolved)
+    }
+
+    /// Resolve `name` inside `parent`, following it if it is a symlink
+    fn resolve_entry(
+        &self,
+        parent: &Path,
+        name: &OsStr,
+        follows: &mut usize,
+    ) -> io::Result<PathBuf> {
+        let dir = self.resolve(parent, follows)?;
+        let candidate = dir.join(name);
+
+        if !self.lookup(&candidate)?.file_type().is_symlink() {
+            return Ok(candidate);
+        }
+
+        *follows += 1;
+        if *follows > MAX_SYMLINK_FOLLOWS {
+            return Err(io::Error::other("Too many levels of symbolic links"));
+        }
+
+        // An absolute link target replaces `dir` entirely when joined
+        let link_target = fs::read_link(&candidate)?;
+        self.resolve(&dir.join(link_target), follows)
+    }
+
+    fn lookup(&self, path: &Path) -> io::Result<fs::Metadata> {
+        self.lookups.set(self.lookups.get() + 1);
+        fs::symlink_metadata(path)
+    }
+}
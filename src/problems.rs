@@ -0,0 +1,317 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, MissingSource, Symlink};
+use crate::generation::Generation;
+use crate::mode;
+
+/// Version of the JSON document printed by `validate --json`, bumped on
+/// incompatible changes
+const REPORT_VERSION: u32 = 1;
+
+/// The rule a config entry breaks
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    /// The source is missing and the entry sets `on_missing_source = "error"`
+    MissingSource,
+    /// `mode` or `mount_point_mode` doesn't parse
+    InvalidMode,
+    /// Another entry persists the same target
+    Overlap,
+    /// The source and the target are the same path
+    SamePath,
+    /// A generation in the state checked against records the target as
+    /// another kind of entry or with another source
+    ConflictingTarget,
+    /// A backup only recorded by generations the retention policy would
+    /// prune after the next apply
+    OrphanedBackup,
+    /// A problem with the config as a whole rather than one entry
+    Config,
+}
+
+impl Rule {
+    /// The rule as it appears in the JSON output
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::MissingSource => "missing-source",
+            Rule::InvalidMode => "invalid-mode",
+            Rule::Overlap => "overlap",
+            Rule::SamePath => "same-path",
+            Rule::ConflictingTarget => "conflicting-target",
+            Rule::OrphanedBackup => "orphaned-backup",
+            Rule::Config => "config",
+        }
+    }
+
+    /// Whether apply refuses to run with this problem. Entries overlapping
+    /// each other are only reported: apply leaves the last one in place and
+    /// its verification flags the others. Problems with the generation
+    /// history are only found by `validate --against`.
+    pub fn blocks_apply(&self) -> bool {
+        !matches!(
+            self,
+            Rule::Overlap | Rule::ConflictingTarget | Rule::OrphanedBackup
+        )
+    }
+}
+
+/// A problem found in the config, with the entry it comes from
+#[derive(Serialize)]
+pub struct Problem {
+    pub rule: Rule,
+    /// Persistence group key, e.g. "/persist"
+    pub group: Option<String>,
+    /// The entry's path as written in the group
+    pub entry: Option<String>,
+    pub message: String,
+}
+
+impl Problem {
+    fn for_entry(rule: Rule, symlink: &Symlink, message: String) -> Self {
+        Problem {
+            rule,
+            group: Some(symlink.persist_dir.clone()),
+            entry: Some(symlink.entry.clone()),
+            message,
+        }
+    }
+
+    /// An error that can't be traced back to one entry
+    pub fn config(error: &anyhow::Error) -> Self {
+        Problem {
+            rule: Rule::Config,
+            group: None,
+            entry: None,
+            message: format!("{:#}", error),
+        }
+    }
+}
+
+/// The JSON document printed by `validate --json`
+#[derive(Serialize)]
+pub struct Report<'a> {
+    version: u32,
+    valid: bool,
+    problems: &'a [Problem],
+}
+
+pub fn report(problems: &[Problem]) -> Report<'_> {
+    Report {
+        version: REPORT_VERSION,
+        valid: problems.is_empty(),
+        problems,
+    }
+}
+
+/// Every problem with the config's entries, ordered by group and entry.
+/// Checking doesn't change anything on disk.
+pub fn problems(config: &Config) -> Vec<Problem> {
+    let symlinks = config.to_symlinks();
+    let mut problems = Vec::new();
+
+    let mut targets: HashMap<&Path, Vec<&Symlink>> = HashMap::new();
+    for symlink in &symlinks {
+        targets.entry(&symlink.target).or_default().push(symlink);
+    }
+
+    for symlink in &symlinks {
+        // Reported on each of the entries involved
+        for other in &targets[symlink.target.as_path()] {
+            if std::ptr::eq(*other, symlink) {
+                continue;
+            }
+            problems.push(Problem::for_entry(
+                Rule::Overlap,
+                symlink,
+                format!(
+                    "Target {} is also persisted by {} in {}",
+                    symlink.target.display(),
+                    other.entry,
+                    other.persist_dir
+                ),
+            ));
+        }
+
+        if symlink.source == symlink.target {
+            problems.push(Problem::for_entry(
+                Rule::SamePath,
+                symlink,
+                format!(
+                    "Source and target of {} are the same path",
+                    symlink.target.display()
+                ),
+            ));
+        }
+
+        // A malformed mode would otherwise only fail halfway through an apply
+        for mode in [&symlink.mode, &symlink.mount_point_mode]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(e) = mode::parse(mode, 0) {
+                problems.push(Problem::for_entry(
+                    Rule::InvalidMode,
+                    symlink,
+                    format!("Invalid mode for {}: {:#}", symlink.target.display(), e),
+                ));
+            }
+        }
+
+        let preserved = symlink.preserve_symlink && symlink.source.is_symlink();
+        if symlink.on_missing_source == MissingSource::Error
+            && !symlink.source.exists()
+            && !preserved
+        {
+            problems.push(Problem::for_entry(
+                Rule::MissingSource,
+                symlink,
+                format!(
+                    "Source of {} does not exist: {} (on_missing_source = \"error\")",
+                    symlink.target.display(),
+                    symlink.source.display()
+                ),
+            ));
+        }
+    }
+
+    problems.sort_by(|a, b| (&a.group, &a.entry).cmp(&(&b.group, &b.entry)));
+    problems
+}
+
+/// What an entry would be recorded as, named as in `GenerationSymlink::kind_name`
+fn kind_name(symlink: &Symlink) -> &'static str {
+    if symlink.device.is_some() {
+        "device"
+    } else if symlink.is_directory {
+        "mount"
+    } else {
+        "symlink"
+    }
+}
+
+/// The source an entry would be recorded with: resolved, except for links
+/// and preserved symlinks, which are recorded as they are
+fn recorded_source(symlink: &Symlink) -> PathBuf {
+    if symlink.link {
+        return symlink.source.clone();
+    }
+    if symlink.preserve_symlink && !symlink.is_directory {
+        if let Ok(link) = fs::read_link(&symlink.source) {
+            return link;
+        }
+    }
+    fs::canonicalize(&symlink.source).unwrap_or_else(|_| symlink.source.clone())
+}
+
+/// Problems with applying the config on top of recorded `generations`:
+/// targets a generation records differently, so switching between it and
+/// the config's generation replaces one entry with the other, and backups
+/// only recorded by generations the config's retention policy would prune
+/// after applying it, leaving nothing to restore or report them.
+/// Checking doesn't change anything on disk.
+pub fn history_problems(
+    config: &Config,
+    generations: &[Generation],
+    now: DateTime<Utc>,
+) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+
+    for symlink in config.to_symlinks() {
+        let kind = kind_name(&symlink);
+        let source = recorded_source(&symlink);
+
+        // Generation numbers by how they record the target instead
+        let mut conflicts: BTreeMap<(&str, &Path), Vec<String>> = BTreeMap::new();
+        for gen in generations {
+            for recorded in gen.symlinks.iter().filter(|s| s.target == symlink.target) {
+                if recorded.kind_name() != kind || recorded.source != source {
+                    conflicts
+                        .entry((recorded.kind_name(), &recorded.source))
+                        .or_default()
+                        .push(gen.number.to_string());
+                }
+            }
+        }
+        for ((other_kind, other_source), numbers) in conflicts {
+            problems.push(Problem::for_entry(
+                Rule::ConflictingTarget,
+                &symlink,
+                format!(
+                    "Target {} is a {} from {} in generation(s) {}, but a {} from {} in the config",
+                    symlink.target.display(),
+                    other_kind,
+                    other_source.display(),
+                    numbers.join(", "),
+                    kind,
+                    source.display()
+                ),
+            ));
+        }
+    }
+
+    // The history as it would be after applying: a new active generation
+    // on top of the others, then pruned by the retention policy
+    if let Some(latest) = generations.iter().max_by_key(|g| g.number) {
+        let mut after: Vec<Generation> = generations
+            .iter()
+            .cloned()
+            .map(|gen| Generation {
+                active: false,
+                ..gen
+            })
+            .collect();
+        after.push(Generation {
+            number: latest.number + 1,
+            created_at: now,
+            symlinks: Vec::new(),
+            active: true,
+            ..latest.clone()
+        });
+        let pruned = config.retention.prunable(&after, now)?;
+
+        let kept: HashSet<&Path> = generations
+            .iter()
+            .filter(|g| !pruned.contains(&g.number))
+            .flat_map(|g| &g.symlinks)
+            .filter_map(|s| s.backup_path.as_deref())
+            .collect();
+        let mut reported = HashSet::new();
+        for gen in generations.iter().filter(|g| pruned.contains(&g.number)) {
+            for recorded in &gen.symlinks {
+                // Put back at its target when the active generation is removed
+                if gen.active && recorded.restore_backup {
+                    continue;
+                }
+                let Some(backup) = recorded.backup_path.as_deref() else {
+                    continue;
+                };
+                if backup.symlink_metadata().is_ok()
+                    && !kept.contains(backup)
+                    && reported.insert(backup)
+                {
+                    problems.push(Problem {
+                        rule: Rule::OrphanedBackup,
+                        group: None,
+                        entry: None,
+                        message: format!(
+                            "Backup {} of {} is only recorded by generation {}, which the \
+                             retention policy would prune after applying the config",
+                            backup.display(),
+                            recorded.target.display(),
+                            gen.number
+                        ),
+                    });
+                }
+            }
+        }
+    }
+
+    problems.sort_by(|a, b| (&a.group, &a.entry).cmp(&(&b.group, &b.entry)));
+    Ok(problems)
+}
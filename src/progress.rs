@@ -0,0 +1,69 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+/// Where progress events are written once `enable_json_lines` has been called
+static EVENT_SINK: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// A step of `apply`, emitted as one JSON object per line
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event<'a> {
+    Start {
+        generation: u64,
+        entries: usize,
+    },
+    Skipped {
+        target: &'a Path,
+        source: &'a Path,
+    },
+    Created {
+        target: &'a Path,
+        source: &'a Path,
+        kind: &'static str,
+    },
+    Failed {
+        target: &'a Path,
+        error: String,
+    },
+    Finish {
+        generation: u64,
+        created: usize,
+        failed: usize,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Send events to stdout and everything else printed from here on to stderr,
+/// so stdout carries nothing but JSON lines
+pub fn enable_json_lines() -> Result<()> {
+    let stdout = std::io::stdout()
+        .as_fd()
+        .try_clone_to_owned()
+        .context("Failed to duplicate stdout")?;
+    nix::unistd::dup2(2, 1).context("Failed to redirect stdout to stderr")?;
+
+    // Only the first call takes effect; the sink never changes afterwards
+    let _ = EVENT_SINK.set(Mutex::new(File::from(stdout)));
+    Ok(())
+}
+
+/// Emit an event if JSON lines output is enabled
+pub fn emit(event: &Event) {
+    let Some(sink) = EVENT_SINK.get() else {
+        return;
+    };
+    let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+
+    // A reader that went away must not abort the apply halfway through
+    if let Ok(line) = serde_json::to_string(event) {
+        let _ = writeln!(sink, "{}", line);
+        let _ = sink.flush();
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::ConfigFormat;
+
+/// Environment variable holding an optional bearer token for remote configs
+pub const TOKEN_ENV: &str = "IMP_CONFIG_TOKEN";
+
+/// How long to wait for a remote config before falling back to the cached copy
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The URL `path` holds, if it is an http(s) URL rather than a local file
+pub fn as_url(path: &Path) -> Option<&str> {
+    let path = path.to_str()?;
+    (path.starts_with("https://") || path.starts_with("http://")).then_some(path)
+}
+
+/// Fetch a remote config, falling back to the last known good copy if the
+/// fetch fails
+pub fn fetch_config(url: &str, format: ConfigFormat) -> Result<String> {
+    // The token would be readable by anyone on the path, so don't send it,
+    // and don't quietly use the cached copy instead either
+    if url.starts_with("http://") && std::env::var_os(TOKEN_ENV).is_some() {
+        anyhow::bail!(
+            "Refusing to send {} over plain http: {} (use an https:// URL)",
+            TOKEN_ENV,
+            url
+        );
+    }
+
+    let error = match download(url) {
+        Ok(contents) => return Ok(contents),
+        Err(e) => e,
+    };
+
+    let cache_path = cache_path(url, format);
+    match fs::read_to_string(&cache_path) {
+        Ok(contents) => {
+            println!("⚠ {:#}", error);
+            println!("  Using cached config from: {}", cache_path.display());
+            Ok(contents)
+        }
+        Err(_) => Err(error.context("No cached copy of this config is available")),
+    }
+}
+
+/// Remember a config that fetched and parsed successfully
+pub fn cache_config(url: &str, format: ConfigFormat, contents: &str) -> Result<()> {
+    let cache_path = cache_path(url, format);
+    if let Some(parent) = cache_path.parent() {
+        fs::create_dir_all(parent).context(format!(
+            "Failed to create config cache directory: {}",
+            parent.display()
+        ))?;
+    }
+    fs::write(&cache_path, contents).context(format!(
+        "Failed to cache remote config: {}",
+        cache_path.display()
+    ))
+}
+
+fn download(url: &str) -> Result<String> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .context("Failed to create HTTP client")?;
+
+    let mut request = client.get(url);
+    if let Ok(token) = std::env::var(TOKEN_ENV) {
+        request = request.bearer_auth(token);
+    }
+
+    request
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .context(format!("Failed to fetch config: {}", url))
+}
+
+/// Where the last known good copy of a remote config is kept. This lives in
+/// the default state directory because the config's own `state_dir` isn't
+/// known until it has been fetched. The extension is that of the format the
+/// config is parsed as.
+fn cache_path(url: &str, format: ConfigFormat) -> PathBuf {
+    let file_name: String = url
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    dirs::data_local_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("imp")
+        .join("remote-configs")
+        .join(format!("{}.{}", file_name, format.extension()))
+}
@@ -0,0 +1,93 @@
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::libc;
+use nix::mount::{umount2, MntFlags};
+use std::ffi::CString;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Flags from <linux/mount.h>
+const OPEN_TREE_CLONE: libc::c_uint = 0x1;
+const OPEN_TREE_CLOEXEC: libc::c_uint = libc::O_CLOEXEC as libc::c_uint;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 0x4;
+/// Attach the mount beneath the one at the target (Linux 6.5)
+const MOVE_MOUNT_BENEATH: libc::c_uint = 0x200;
+
+/// Why a mount couldn't be replaced in place
+pub enum ReplaceError {
+    /// The kernel lacks the new mount API or `MOVE_MOUNT_BENEATH`; nothing
+    /// was changed, so the caller can fall back to unmounting first
+    Unsupported(Errno),
+    Failed(anyhow::Error),
+}
+
+fn path_cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .context(format!("Path contains a NUL byte: {}", path.display()))
+}
+
+/// A detached bind mount of `source`, not yet attached anywhere
+fn clone_tree(source: &Path) -> Result<OwnedFd, ReplaceError> {
+    let source_c = path_cstring(source).map_err(ReplaceError::Failed)?;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_open_tree,
+            libc::AT_FDCWD,
+            source_c.as_ptr(),
+            OPEN_TREE_CLONE | OPEN_TREE_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(match Errno::last() {
+            errno @ Errno::ENOSYS => ReplaceError::Unsupported(errno),
+            errno => ReplaceError::Failed(anyhow::Error::from(errno).context(format!(
+                "Failed to clone a bind mount of {}",
+                source.display()
+            ))),
+        });
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+/// Replace the mount at `target` with a bind mount of `source`, without a
+/// moment where nothing is mounted there: the new mount is attached beneath
+/// the old one, which is then detached. Lookups of `target` see the old
+/// content until the detach and the new content right after. Files the old
+/// mount still has open stay usable until they are closed.
+pub fn replace_mount(source: &Path, target: &Path) -> Result<(), ReplaceError> {
+    let tree = clone_tree(source)?;
+    let empty = CString::default();
+    let target_c = path_cstring(target).map_err(ReplaceError::Failed)?;
+
+    let moved = unsafe {
+        libc::syscall(
+            libc::SYS_move_mount,
+            tree.as_raw_fd(),
+            empty.as_ptr(),
+            libc::AT_FDCWD,
+            target_c.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH | MOVE_MOUNT_BENEATH,
+        )
+    };
+    if moved < 0 {
+        // Older kernels reject the unknown flag
+        return Err(match Errno::last() {
+            errno @ (Errno::ENOSYS | Errno::EINVAL) => ReplaceError::Unsupported(errno),
+            errno => ReplaceError::Failed(anyhow::Error::from(errno).context(format!(
+                "Failed to mount {} beneath the mount at {}",
+                source.display(),
+                target.display()
+            ))),
+        });
+    }
+
+    // The old mount is on top now; lazily, since services may still use it
+    umount2(target, MntFlags::MNT_DETACH)
+        .context(format!(
+            "Mounted {} beneath {}, but failed to detach the old mount above it",
+            source.display(),
+            target.display()
+        ))
+        .map_err(ReplaceError::Failed)
+}
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::generation::Generation;
+
+/// Which generations to keep after each apply. A generation is kept if any
+/// rule keeps it; the active generation is always kept.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, JsonSchema)]
+pub struct Retention {
+    /// Keep the N most recent generations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+
+    /// Keep generations created within this age, e.g. "30d" or "12h"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_within: Option<String>,
+
+    /// Never leave fewer than M generations: if the other rules keep fewer,
+    /// the newest of the rest are kept as well
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_minimum: Option<usize>,
+
+    /// Generations to keep regardless of the other rules, e.g. known-good
+    /// ones. On their own they don't turn pruning on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keep_generations: Vec<u64>,
+}
+
+impl Retention {
+    /// Whether any rule is set. Without rules nothing is pruned.
+    pub fn is_set(&self) -> bool {
+        self.keep_last.is_some() || self.keep_within.is_some() || self.keep_minimum.is_some()
+    }
+
+    /// Check that `keep_within` parses
+    pub fn validate(&self) -> Result<()> {
+        if let Some(age) = &self.keep_within {
+            parse_age(age).context("Invalid retention.keep_within")?;
+        }
+        Ok(())
+    }
+
+    /// Numbers of the generations none of the rules keep, oldest first
+    pub fn prunable(&self, generations: &[Generation], now: DateTime<Utc>) -> Result<Vec<u64>> {
+        if !self.is_set() {
+            return Ok(Vec::new());
+        }
+
+        // Newest first, so "the N most recent" is a prefix
+        let mut newest_first: Vec<&Generation> = generations.iter().collect();
+        newest_first.sort_by_key(|g| std::cmp::Reverse(g.number));
+
+        let cutoff = match &self.keep_within {
+            Some(age) => Some(now - parse_age(age).context("Invalid retention.keep_within")?),
+            None => None,
+        };
+
+        let mut keep: Vec<bool> = newest_first
+            .iter()
+            .enumerate()
+            .map(|(index, gen)| {
+                gen.active
+                    || self.keep_generations.contains(&gen.number)
+                    || self.keep_last.is_some_and(|n| index < n)
+                    || cutoff.is_some_and(|cutoff| gen.created_at >= cutoff)
+            })
+            .collect();
+
+        if let Some(minimum) = self.keep_minimum {
+            let missing = minimum.saturating_sub(keep.iter().filter(|k| **k).count());
+            for k in keep.iter_mut().filter(|k| !**k).take(missing) {
+                *k = true;
+            }
+        }
+
+        let mut prunable: Vec<u64> = newest_first
+            .iter()
+            .zip(&keep)
+            .filter(|(_, keep)| !**keep)
+            .map(|(gen, _)| gen.number)
+            .collect();
+        prunable.reverse();
+        Ok(prunable)
+    }
+}
+
+/// Parse an age such as "90s", "45m", "12h", "30d" or "2w"
+fn parse_age(age: &str) -> Result<Duration> {
+    let age = age.trim();
+    let split = age
+        .find(|c: char| !c.is_ascii_digit())
+        .context(format!("{:?} has no unit (use s, m, h, d or w)", age))?;
+    let (amount, unit) = age.split_at(split);
+    let amount: i64 = amount
+        .parse()
+        .context(format!("{:?} does not start with a number", age))?;
+
+    let duration = match unit {
+        "s" => Duration::try_seconds(amount),
+        "m" => Duration::try_minutes(amount),
+        "h" => Duration::try_hours(amount),
+        "d" => Duration::try_days(amount),
+        "w" => Duration::try_weeks(amount),
+        _ => anyhow::bail!("{:?} has an unknown unit (use s, m, h, d or w)", age),
+    };
+    duration.context(format!("{:?} is too long", age))
+}
@@ -0,0 +1,131 @@
+use crate::config::{MissingSource, Symlink};
+use crate::symlink::no_mount_requested;
+use anyhow::{Context, Result};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Suffix of the name a file entry's symlink is staged under, next to its target
+const STAGED_SUFFIX: &str = "imp-staged";
+
+/// Where phase one of a two-phase apply creates a generation's entries before
+/// they are moved to their targets. Directory entries are mounted in a
+/// private directory under the state directory; file entries are linked next
+/// to their target, so a rename on the same filesystem moves them into place.
+/// The staging directory is unmounted and removed when dropped.
+pub struct StagingArea {
+    dir: PathBuf,
+    mounted: bool,
+}
+
+impl StagingArea {
+    /// Create the staging directory in `state_dir` and make it a private
+    /// mount of its own: `MS_MOVE` refuses to move a mount whose parent mount
+    /// is shared, which the state directory's often is
+    pub fn create(state_dir: &Path) -> Result<Self> {
+        let dir = state_dir.join(format!("staging-{}", std::process::id()));
+        fs::create_dir_all(&dir).context(format!(
+            "Failed to create staging directory: {}",
+            dir.display()
+        ))?;
+        let mut staging = Self {
+            dir,
+            mounted: false,
+        };
+        if no_mount_requested() {
+            return Ok(staging);
+        }
+
+        mount(
+            Some(&staging.dir),
+            &staging.dir,
+            None::<&str>,
+            MsFlags::MS_BIND,
+            None::<&str>,
+        )
+        .context(format!(
+            "Failed to bind mount the staging directory {} onto itself",
+            staging.dir.display()
+        ))?;
+        staging.mounted = true;
+        mount(
+            None::<&str>,
+            &staging.dir,
+            None::<&str>,
+            MsFlags::MS_PRIVATE,
+            None::<&str>,
+        )
+        .context(format!(
+            "Failed to make the staging directory {} a private mount",
+            staging.dir.display()
+        ))?;
+        Ok(staging)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The entries retargeted at their staging locations. Nothing is backed
+    /// up there, since nothing exists there yet.
+    pub fn staged(&self, symlinks: &[Symlink]) -> Vec<Symlink> {
+        symlinks
+            .iter()
+            .enumerate()
+            .map(|(index, symlink)| Symlink {
+                target: self.staged_target(symlink, index),
+                backup: false,
+                ..symlink.clone()
+            })
+            .collect()
+    }
+
+    fn staged_target(&self, symlink: &Symlink, index: usize) -> PathBuf {
+        if symlink.is_directory {
+            return self.dir.join(index.to_string());
+        }
+        let mut name = OsString::from(".");
+        name.push(symlink.target.file_name().unwrap_or_default());
+        name.push(format!(".{}-{}", STAGED_SUFFIX, std::process::id()));
+        symlink.target.with_file_name(name)
+    }
+}
+
+impl Drop for StagingArea {
+    fn drop(&mut self) {
+        // Lazily, taking anything still mounted inside along with it
+        if self.mounted {
+            let _ = umount2(&self.dir, MntFlags::MNT_DETACH);
+        }
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
+
+/// Why a config can't be applied in two phases, if it can't
+pub fn unsupported(symlinks: &[Symlink]) -> Option<String> {
+    if let Some(symlink) = symlinks.iter().find(|s| s.link) {
+        return Some(format!(
+            "{} is a link into another entry's target, which only exists once that \
+             entry is in place",
+            symlink.target.display()
+        ));
+    }
+    if let Some(symlink) = symlinks.iter().find(|s| !s.migrate.is_empty()) {
+        return Some(format!(
+            "{} migrates existing target content, which would change the live target \
+             during staging",
+            symlink.target.display()
+        ));
+    }
+    if let Some(symlink) = symlinks
+        .iter()
+        .find(|s| s.on_missing_source == MissingSource::Lazy && !s.source.exists())
+    {
+        return Some(format!(
+            "{} has a lazy source that doesn't exist yet, so there is no mount to stage",
+            symlink.target.display()
+        ));
+    }
+    None
+}
@@ -0,0 +1,187 @@
+use serde::Serialize;
+use std::fmt;
+use std::path::Path;
+
+use crate::generation::{Generation, GenerationSymlink, LinkKind};
+
+/// Version of the JSON document printed by `switch --dry-run --json`, bumped
+/// on incompatible changes
+const PLAN_VERSION: u32 = 1;
+
+/// What `switch` would do, in order: take down the active generation's
+/// entries, then recreate the target generation's
+#[derive(Serialize)]
+pub struct SwitchPlan<'a> {
+    version: u32,
+    from: Option<u64>,
+    to: u64,
+    remove: Vec<Removal<'a>>,
+    recreate: Vec<Recreation<'a>>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum RemoveAction {
+    Unmount,
+    Unlink,
+}
+
+/// Taking down one entry of the active generation
+#[derive(Serialize)]
+struct Removal<'a> {
+    action: RemoveAction,
+    target: &'a Path,
+    source: &'a Path,
+    kind: LinkKind,
+    /// Backup moved back to the target afterwards
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restore_backup: Option<&'a Path>,
+    /// Backup deleted instead of restored (`--clean`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    discard_backup: Option<&'a Path>,
+    /// Backup left where it is (`restore_backup = false`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_backup: Option<&'a Path>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum RecreateAction {
+    Mount,
+    Link,
+}
+
+/// Putting one entry of the target generation in place
+#[derive(Serialize)]
+struct Recreation<'a> {
+    action: RecreateAction,
+    target: &'a Path,
+    source: &'a Path,
+    kind: LinkKind,
+}
+
+fn kind(entry: &GenerationSymlink) -> LinkKind {
+    if entry.is_device() {
+        LinkKind::Device
+    } else if entry.is_mount() {
+        LinkKind::Mount
+    } else {
+        LinkKind::Symlink
+    }
+}
+
+/// Plan switching from `active` to `target`, the way `switch` goes about it:
+/// entries already gone from the filesystem aren't removed again, and only
+/// backups that still exist are restored (or, with `clean`, discarded)
+pub fn plan<'a>(
+    active: Option<&'a Generation>,
+    target: &'a Generation,
+    clean: bool,
+) -> SwitchPlan<'a> {
+    let remove = active
+        .into_iter()
+        .flat_map(|g| &g.symlinks)
+        .filter(|entry| entry.target.symlink_metadata().is_ok())
+        .map(|entry| {
+            let backup = entry
+                .backup_path
+                .as_deref()
+                .filter(|p| p.symlink_metadata().is_ok());
+            Removal {
+                action: if entry.is_mount() {
+                    RemoveAction::Unmount
+                } else {
+                    RemoveAction::Unlink
+                },
+                target: &entry.target,
+                source: &entry.source,
+                kind: kind(entry),
+                restore_backup: backup.filter(|_| !clean && entry.restore_backup),
+                discard_backup: backup.filter(|_| clean),
+                keep_backup: backup.filter(|_| !clean && !entry.restore_backup),
+            }
+        })
+        .collect();
+
+    let recreate = target
+        .symlinks
+        .iter()
+        .map(|entry| Recreation {
+            action: if entry.is_mount() {
+                RecreateAction::Mount
+            } else {
+                RecreateAction::Link
+            },
+            target: &entry.target,
+            source: &entry.source,
+            kind: kind(entry),
+        })
+        .collect();
+
+    SwitchPlan {
+        version: PLAN_VERSION,
+        from: active.map(|g| g.number),
+        to: target.number,
+        remove,
+        recreate,
+    }
+}
+
+impl fmt::Display for SwitchPlan<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.from {
+            Some(from) => writeln!(
+                f,
+                "Dry run: switching from generation {} to generation {} would:",
+                from, self.to
+            )?,
+            None => writeln!(f, "Dry run: switching to generation {} would:", self.to)?,
+        }
+
+        for removal in &self.remove {
+            match removal.action {
+                RemoveAction::Unmount => write!(
+                    f,
+                    "  - Unmount {} (from {})",
+                    removal.target.display(),
+                    removal.source.display()
+                )?,
+                RemoveAction::Unlink => write!(
+                    f,
+                    "  - Remove symlink {} -> {}",
+                    removal.target.display(),
+                    removal.source.display()
+                )?,
+            }
+            if let Some(backup) = removal.restore_backup {
+                write!(f, ", restoring backup {}", backup.display())?;
+            }
+            if let Some(backup) = removal.discard_backup {
+                write!(f, ", discarding backup {}", backup.display())?;
+            }
+            if let Some(backup) = removal.keep_backup {
+                write!(f, ", keeping backup {}", backup.display())?;
+            }
+            writeln!(f)?;
+        }
+
+        for recreation in &self.recreate {
+            match recreation.action {
+                RecreateAction::Mount => writeln!(
+                    f,
+                    "  + Mount {} from {}",
+                    recreation.target.display(),
+                    recreation.source.display()
+                )?,
+                RecreateAction::Link => writeln!(
+                    f,
+                    "  + Link {} -> {}",
+                    recreation.target.display(),
+                    recreation.source.display()
+                )?,
+            }
+        }
+
+        write!(f, "\nNothing was changed.")
+    }
+}
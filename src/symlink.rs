@@ -1,27 +1,424 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use nix::mount::{mount, umount, MsFlags};
-use nix::unistd::{chown, Gid, Uid};
+use nix::sys::statvfs::{statvfs, FsFlags};
+use nix::unistd::{access, chown, AccessFlags, Gid, Uid};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::os::unix::fs as unix_fs;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use std::time::Duration;
 
-use crate::config::Symlink;
-use crate::generation::GenerationSymlink;
+use crate::backup::{self, BackupCompression};
+use crate::config::{DeviceMount, MissingSource, Symlink};
+use crate::generation::{Generation, GenerationSymlink, LinkKind};
+use crate::identity::{self, FsIdentity};
+use crate::interrupt;
+use crate::loopdev;
+use crate::mode;
+use crate::mounts::{self, Atime};
+use crate::pathcache::PathCache;
+use crate::progress::{self, Event};
+use crate::remount::{self, ReplaceError};
+
+/// Suffix used for backups when the config doesn't set `backup_suffix`
+pub const DEFAULT_BACKUP_SUFFIX: &str = "imp-backup";
+
+/// `user`/`group` value that takes the owner or group of the parent directory
+const INHERIT_OWNER: &str = "inherit";
+
+/// Set to 1 to skip all mount syscalls (testing only, see `no_mount`)
+pub const NO_MOUNT_VAR: &str = "IMP_NO_MOUNT";
+
+/// Whether the testing mode without real mounts was requested
+pub fn no_mount_requested() -> bool {
+    std::env::var(NO_MOUNT_VAR).is_ok_and(|v| v == "1")
+}
+
+/// Whether an unmount failed because the mount (or its mount point) no longer exists
+fn is_gone(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<nix::errno::Errno>(),
+        Some(nix::errno::Errno::EINVAL | nix::errno::Errno::ENOENT)
+    )
+}
+
+/// Result of the first bind mount probe, reused for the rest of the process
+static BIND_MOUNT_PROBE: OnceLock<Result<(), String>> = OnceLock::new();
 
 /// Manages symlink operations
-pub struct SymlinkManager;
+pub struct SymlinkManager {
+    /// Explain where path resolution breaks when a source can't be resolved
+    verbose: bool,
+
+    /// Create files, directories and symlinks as this user instead of the caller
+    fs_identity: Option<FsIdentity>,
+
+    /// Appended to a target's file name (before the timestamp) when backing it up
+    backup_suffix: String,
+
+    /// Whether directories are backed up as compressed archives
+    backup_compression: BackupCompression,
+
+    /// Also report missing sources and backups when verifying
+    strict: bool,
+
+    /// Canonicalized source paths, reset at the start of every `apply`
+    path_cache: PathCache,
+
+    /// Abandon mount/umount calls that take longer than this
+    timeout: Option<Duration>,
+
+    /// Carry on applying the remaining entries when one fails
+    keep_going: bool,
+
+    /// Back up and replace files sitting where a target's parent directory should be
+    replace_blocking_files: bool,
+
+    /// Ask on stdin before replacing anything that exists at a target
+    confirm_each: bool,
+
+    /// Targets still mounted by the previous generation that are remounted
+    /// in place instead of cleared, with the backup recorded for each
+    remounts: HashMap<PathBuf, Option<PathBuf>>,
+
+    /// Testing mode: never call mount/umount, and treat every directory target
+    /// as mounted. Generations are recorded as usual, but no real mounts exist
+    no_mount: bool,
+}
+
+/// What `apply` managed to put in place, and what it didn't
+pub struct ApplyOutcome {
+    pub symlinks: Vec<GenerationSymlink>,
+
+    /// Targets that failed under `keep_going`, with the reason
+    pub failures: Vec<(PathBuf, anyhow::Error)>,
+
+    /// Stopped early by SIGINT/SIGTERM; `symlinks` holds what was applied so far
+    pub interrupted: bool,
+
+    /// Targets the user chose to skip under `confirm_each`
+    pub declined: Vec<PathBuf>,
+
+    /// The user chose to abort under `confirm_each`; `symlinks` holds what
+    /// was applied so far
+    pub aborted: bool,
+}
+
+/// The answer to a `confirm_each` prompt
+enum Confirmation {
+    Apply,
+    Skip,
+    Abort,
+}
+
+/// One step of repairing a broken entry, as planned by `plan_repair`
+#[derive(Debug, Clone, PartialEq)]
+pub enum RepairAction {
+    /// Unmount whatever is mounted at the target
+    Unmount(PathBuf),
+
+    /// Remove the wrong or dangling symlink at the target
+    RemoveSymlink(PathBuf),
+
+    /// Move what was written to a lazy entry's target to its source
+    Materialize { target: PathBuf, source: PathBuf },
+
+    /// Create the directory a lazy entry waits at
+    CreateDir(PathBuf),
+
+    /// Recreate the entry as recorded in its generation
+    Recreate {
+        kind: LinkKind,
+        source: PathBuf,
+        target: PathBuf,
+    },
+}
+
+impl fmt::Display for RepairAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepairAction::Unmount(target) => write!(f, "unmount {}", target.display()),
+            RepairAction::RemoveSymlink(target) => {
+                write!(f, "remove symlink {}", target.display())
+            }
+            RepairAction::Materialize { target, source } => write!(
+                f,
+                "move what was written to {} to a new source {}",
+                target.display(),
+                source.display()
+            ),
+            RepairAction::CreateDir(target) => {
+                write!(f, "create directory {}", target.display())
+            }
+            RepairAction::Recreate {
+                kind: LinkKind::Device,
+                source,
+                target,
+            } => write!(
+                f,
+                "mount device {} at {}",
+                source.display(),
+                target.display()
+            ),
+            RepairAction::Recreate {
+                kind: LinkKind::Mount,
+                source,
+                target,
+            } => write!(f, "bind mount {} at {}", source.display(), target.display()),
+            RepairAction::Recreate {
+                kind: LinkKind::Symlink,
+                source,
+                target,
+            } => write!(
+                f,
+                "create symlink {} -> {}",
+                target.display(),
+                source.display()
+            ),
+        }
+    }
+}
 
 impl SymlinkManager {
     pub fn new() -> Self {
-        Self
+        Self {
+            verbose: false,
+            fs_identity: None,
+            backup_suffix: DEFAULT_BACKUP_SUFFIX.to_string(),
+            backup_compression: BackupCompression::None,
+            strict: false,
+            path_cache: PathCache::new(),
+            timeout: None,
+            keep_going: false,
+            replace_blocking_files: false,
+            confirm_each: false,
+            remounts: HashMap::new(),
+            no_mount: no_mount_requested(),
+        }
+    }
+
+    /// Move files that block a target's parent directories out of the way
+    pub fn with_replace_blocking_files(mut self, replace_blocking_files: bool) -> Self {
+        self.replace_blocking_files = replace_blocking_files;
+        self
+    }
+
+    /// Prompt before replacing whatever exists at a target
+    pub fn with_confirm_each(mut self, confirm_each: bool) -> Self {
+        self.confirm_each = confirm_each;
+        self
+    }
+
+    /// Remount these targets in place (see `remount::replace_mount`), keeping
+    /// their recorded backups
+    pub fn with_remounts(mut self, remounts: HashMap<PathBuf, Option<PathBuf>>) -> Self {
+        self.remounts = remounts;
+        self
+    }
+
+    /// Record failing entries and continue instead of stopping at the first one
+    pub fn with_keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Give up on mount/umount calls that don't finish within `timeout`
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Make `verify` also check the things a live link doesn't depend on
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Set the suffix used when naming backups
+    pub fn with_backup_suffix(mut self, backup_suffix: String) -> Self {
+        self.backup_suffix = backup_suffix;
+        self
     }
 
-    /// Parse a mode string (e.g., "0755") into a numeric mode
-    fn parse_mode(mode_str: &str) -> Result<u32> {
-        // Remove "0o" or "0" prefix if present
-        let mode_str = mode_str.trim_start_matches("0o").trim_start_matches("0");
-        u32::from_str_radix(mode_str, 8).context(format!("Invalid mode string: {}", mode_str))
+    /// Set how directories are stored when backed up
+    pub fn with_backup_compression(mut self, backup_compression: BackupCompression) -> Self {
+        self.backup_compression = backup_compression;
+        self
+    }
+
+    /// Create filesystem entries as the given identity (mounts still run as the caller)
+    pub fn with_fs_identity(mut self, fs_identity: Option<FsIdentity>) -> Self {
+        self.fs_identity = fs_identity;
+        self
+    }
+
+    /// Run a filesystem-creating operation under the configured identity
+    fn as_fs_user<T>(&self, f: impl FnOnce() -> T) -> T {
+        identity::run_as(self.fs_identity.as_ref(), f)
+    }
+
+    /// Enable verbose error reporting
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Canonicalize a source path, explaining where resolution breaks in verbose mode
+    fn resolve_source(&self, source: &Path) -> Result<PathBuf> {
+        self.path_cache.canonicalize(source).with_context(|| {
+            let mut message = format!("Failed to resolve source path: {}", source.display());
+            if self.verbose {
+                if let Some(trace) = path_resolution_trace(source) {
+                    message.push_str(&format!("\n  {}", trace));
+                }
+            }
+            message
+        })
+    }
+
+    /// Bind mount `source` onto `target`, giving up after the configured timeout
+    fn bind_mount(&self, source: &Path, target: &Path) -> Result<()> {
+        if self.no_mount {
+            return Ok(());
+        }
+        let (source, target) = (source.to_path_buf(), target.to_path_buf());
+        self.with_timeout_limit(move || {
+            mount(
+                Some(&source),
+                &target,
+                None::<&str>,
+                MsFlags::MS_BIND,
+                None::<&str>,
+            )
+        })
+    }
+
+    /// Move the mount at `from` to `target`, giving up after the configured timeout
+    fn move_mount(&self, from: &Path, target: &Path) -> Result<()> {
+        if self.no_mount {
+            return Ok(());
+        }
+        let (from, target) = (from.to_path_buf(), target.to_path_buf());
+        self.with_timeout_limit(move || {
+            mount(
+                Some(&from),
+                &target,
+                None::<&str>,
+                MsFlags::MS_MOVE,
+                None::<&str>,
+            )
+        })
+    }
+
+    /// Set how the mount at `target` updates access times. A bind mount
+    /// ignores atime flags, so this takes a second `MS_BIND | MS_REMOUNT`
+    /// mount, which replaces all of the mount's flags: the ones it has are
+    /// passed along so only the atime behavior changes.
+    fn set_atime(&self, target: &Path, atime: Option<Atime>) -> Result<()> {
+        let atime = match atime {
+            Some(atime) if !self.no_mount => atime,
+            _ => return Ok(()),
+        };
+        let current = statvfs(target)
+            .context(format!(
+                "Failed to read mount flags of: {}",
+                target.display()
+            ))?
+            .flags();
+        let mut flags = MsFlags::MS_BIND
+            | MsFlags::MS_REMOUNT
+            | match atime {
+                Atime::Noatime => MsFlags::MS_NOATIME,
+                Atime::Relatime => MsFlags::MS_RELATIME,
+            };
+        for (kept, flag) in [
+            (FsFlags::ST_RDONLY, MsFlags::MS_RDONLY),
+            (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+            (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+            (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+            (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        ] {
+            if current.contains(kept) {
+                flags |= flag;
+            }
+        }
+
+        let remounted = target.to_path_buf();
+        self.with_timeout_limit(move || {
+            mount(None::<&str>, &remounted, None::<&str>, flags, None::<&str>)
+        })
+        .context(format!(
+            "Failed to set {} on the mount at {}",
+            atime.name(),
+            target.display()
+        ))
+    }
+
+    /// Check once that bind mounts work at all, by bind mounting a scratch
+    /// directory onto itself, so directory entries fail up front with one
+    /// clear message instead of one error each
+    pub fn probe_bind_mounts(&self) -> Result<()> {
+        if self.no_mount {
+            return Ok(());
+        }
+        let probe = BIND_MOUNT_PROBE.get_or_init(|| {
+            let dir = std::env::temp_dir().join(format!("imp-mount-probe-{}", std::process::id()));
+            fs::create_dir_all(&dir).map_err(|e| format!("{}", e))?;
+            let result = self
+                .bind_mount(&dir, &dir)
+                .and_then(|()| self.unmount(&dir))
+                .map_err(|e| format!("{:#}", e));
+            let _ = fs::remove_dir(&dir);
+            result
+        });
+
+        probe.clone().map_err(|reason| {
+            anyhow::anyhow!(
+                "Bind mounts are not available here (probe mount failed: {}). \
+                 Directory entries are applied as bind mounts, which need root or CAP_SYS_ADMIN \
+                 in the current mount namespace. Run imp as root, or use `apply --links-only` \
+                 to apply only the file entries.",
+                reason
+            )
+        })
+    }
+
+    /// Unmount `target`, giving up after the configured timeout
+    fn unmount(&self, target: &Path) -> Result<()> {
+        if self.no_mount {
+            return Ok(());
+        }
+        let target = target.to_path_buf();
+        self.with_timeout_limit(move || umount(&target))
+    }
+
+    /// Run a mount syscall that may block indefinitely (e.g. on an unresponsive
+    /// network filesystem) on a worker thread, abandoning it after the timeout
+    fn with_timeout_limit(
+        &self,
+        operation: impl FnOnce() -> nix::Result<()> + Send + 'static,
+    ) -> Result<()> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Ok(operation()?),
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || sender.send(operation()));
+
+        match receiver.recv_timeout(timeout) {
+            Ok(result) => Ok(result?),
+            Err(_) => anyhow::bail!(
+                "Timed out after {}s. The operation was abandoned but may still complete \
+                 later; check the mount table and clean up any partial state manually.",
+                timeout.as_secs()
+            ),
+        }
     }
 
     /// Get UID from username
@@ -42,6 +439,47 @@ impl SymlinkManager {
             .context(format!("Group not found: {}", groupname))
     }
 
+    /// Resolve configured ownership for `path`: a user or group name, or
+    /// `inherit` for the owner or group of `path`'s parent directory
+    fn resolve_owner(
+        path: &Path,
+        user: Option<&str>,
+        group: Option<&str>,
+    ) -> Result<(Option<Uid>, Option<Gid>)> {
+        let parent_metadata = || {
+            let parent = path.parent().unwrap_or(Path::new("/"));
+            fs::metadata(parent).context(format!(
+                "Failed to get metadata for parent directory: {}",
+                parent.display()
+            ))
+        };
+
+        let uid = match user {
+            Some(INHERIT_OWNER) => Some(Uid::from_raw(parent_metadata()?.uid())),
+            Some(u) => Some(Self::get_uid(u)?),
+            None => None,
+        };
+        let gid = match group {
+            Some(INHERIT_OWNER) => Some(Gid::from_raw(parent_metadata()?.gid())),
+            Some(g) => Some(Self::get_gid(g)?),
+            None => None,
+        };
+        Ok((uid, gid))
+    }
+
+    /// `chown`, skipped when the path already has the requested owner and
+    /// group so unprivileged runs don't fail on a no-op change
+    fn chown_if_needed(path: &Path, uid: Option<Uid>, gid: Option<Gid>) -> nix::Result<()> {
+        if let Ok(metadata) = fs::metadata(path) {
+            let uid_matches = uid.is_none_or(|u| u.as_raw() == metadata.uid());
+            let gid_matches = gid.is_none_or(|g| g.as_raw() == metadata.gid());
+            if uid_matches && gid_matches {
+                return Ok(());
+            }
+        }
+        chown(path, uid, gid)
+    }
+
     /// Apply ownership and permissions to a path
     fn apply_ownership_and_permissions(
         &self,
@@ -52,25 +490,18 @@ impl SymlinkManager {
     ) -> Result<()> {
         // Apply ownership if specified
         if user.is_some() || group.is_some() {
-            let uid = if let Some(u) = user {
-                Some(Self::get_uid(u)?)
-            } else {
-                None
-            };
-
-            let gid = if let Some(g) = group {
-                Some(Self::get_gid(g)?)
-            } else {
-                None
-            };
-
-            chown(path, uid, gid)
+            let (uid, gid) = Self::resolve_owner(path, user, group)?;
+            Self::chown_if_needed(path, uid, gid)
                 .context(format!("Failed to change ownership of: {}", path.display()))?;
         }
 
         // Apply permissions if specified
         if let Some(mode_str) = mode {
-            let mode = Self::parse_mode(mode_str)?;
+            // Symbolic modes are relative to the permissions the path has now
+            let current = fs::metadata(path)
+                .context(format!("Failed to read permissions of: {}", path.display()))?
+                .mode();
+            let mode = mode::parse(mode_str, current)?;
             let permissions = fs::Permissions::from_mode(mode);
             fs::set_permissions(path, permissions)
                 .context(format!("Failed to set permissions on: {}", path.display()))?;
@@ -80,23 +511,186 @@ impl SymlinkManager {
     }
 
     /// Apply a list of symlinks
-    pub fn apply(&self, symlinks: &[Symlink]) -> Result<Vec<GenerationSymlink>> {
+    pub fn apply(&self, symlinks: &[Symlink]) -> Result<ApplyOutcome> {
         let mut generation_symlinks = Vec::new();
+        let mut failures = Vec::new();
+        let mut interrupted = false;
+        let mut declined = Vec::new();
+        let mut aborted = false;
+        self.path_cache.clear();
 
         for symlink in symlinks {
-            let gen_symlink = self.create_symlink(symlink)?;
-            generation_symlinks.push(gen_symlink);
+            // The entry in progress is always finished, so a signal never
+            // leaves one half-created
+            if interrupt::requested() {
+                println!(
+                    "\n⚠ Interrupted after {} of {} entries",
+                    generation_symlinks.len() + failures.len(),
+                    symlinks.len()
+                );
+                interrupted = true;
+                break;
+            }
+            if self.confirm_each {
+                match self.confirm(symlink)? {
+                    Confirmation::Apply => {}
+                    Confirmation::Skip => {
+                        println!("  ℹ Skipped: {}", symlink.target.display());
+                        declined.push(symlink.target.clone());
+                        continue;
+                    }
+                    Confirmation::Abort => {
+                        aborted = true;
+                        break;
+                    }
+                }
+            }
+            match self.create_symlink(symlink) {
+                Ok(gen_symlink) => {
+                    progress::emit(&Event::Created {
+                        target: &gen_symlink.target,
+                        source: &gen_symlink.source,
+                        kind: gen_symlink.kind_name(),
+                    });
+                    generation_symlinks.push(gen_symlink);
+                }
+                Err(e) if self.keep_going => {
+                    println!("  ✗ Failed: {}: {:#}", symlink.target.display(), e);
+                    progress::emit(&Event::Failed {
+                        target: &symlink.target,
+                        error: format!("{:#}", e),
+                    });
+                    failures.push((symlink.target.clone(), e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.verbose {
+            println!(
+                "  ℹ Resolved {} source(s) with {} filesystem lookup(s)",
+                symlinks.len(),
+                self.path_cache.lookups()
+            );
         }
 
-        Ok(generation_symlinks)
+        Ok(ApplyOutcome {
+            symlinks: generation_symlinks,
+            failures,
+            interrupted,
+            declined,
+            aborted,
+        })
+    }
+
+    /// Ask whether to replace what exists at an entry's target. Entries whose
+    /// target doesn't exist are applied without asking; end of input aborts.
+    fn confirm(&self, symlink: &Symlink) -> Result<Confirmation> {
+        let target = &symlink.target;
+        let metadata = match target.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(Confirmation::Apply),
+        };
+
+        let existing = if metadata.is_symlink() {
+            match fs::read_link(target) {
+                Ok(link) => format!("symlink to {}", link.display()),
+                Err(_) => "symlink".to_string(),
+            }
+        } else if metadata.is_dir() {
+            let entries = fs::read_dir(target).map(|d| d.count()).unwrap_or(0);
+            if self.is_mount_point(target)? {
+                format!("mount point with {} entries", entries)
+            } else {
+                format!("directory with {} entries", entries)
+            }
+        } else {
+            format!("file, {} bytes", metadata.len())
+        };
+        let fate = if symlink.backup {
+            let is_dir = metadata.is_dir() && !metadata.is_symlink();
+            format!(
+                "backed up to {}",
+                self.backup_path(target, is_dir, Utc::now())?.display()
+            )
+        } else {
+            "removed".to_string()
+        };
+        let kind = if symlink.is_directory {
+            "a bind mount"
+        } else {
+            "a symlink"
+        };
+
+        println!("\n? {} exists ({})", target.display(), existing);
+        println!(
+            "  It will be {} and replaced by {} to {}",
+            fate,
+            kind,
+            symlink.source.display()
+        );
+        loop {
+            print!("  [a]pply, [s]kip or a[b]ort? ");
+            std::io::Write::flush(&mut std::io::stdout())?;
+
+            let mut answer = String::new();
+            if std::io::stdin().read_line(&mut answer)? == 0 {
+                println!();
+                return Ok(Confirmation::Abort);
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "a" | "apply" => return Ok(Confirmation::Apply),
+                "s" | "skip" => return Ok(Confirmation::Skip),
+                "b" | "abort" => return Ok(Confirmation::Abort),
+                _ => println!("  Please answer a, s or b."),
+            }
+        }
     }
 
     /// Create a single symlink or bind mount
     fn create_symlink(&self, symlink: &Symlink) -> Result<GenerationSymlink> {
+        if let Some(device) = &symlink.device {
+            return self.create_device_mount(symlink, device);
+        }
+
         // Handle case where source doesn't exist but target does
         // In this case, create the source directory using target's permissions
-        let source = if !symlink.source.exists() && symlink.target.exists() && symlink.is_directory
-        {
+        let source = if symlink.link {
+            // Pointing into another entry, which has been applied by now
+            if symlink.source.symlink_metadata().is_err() {
+                anyhow::bail!(
+                    "Cannot link {} to {}: it doesn't exist",
+                    symlink.target.display(),
+                    symlink.source.display()
+                );
+            }
+            symlink.source.clone()
+        } else if symlink.preserve_symlink && !symlink.is_directory && symlink.source.is_symlink() {
+            // Copy the source symlink verbatim instead of resolving it
+            let link_target = fs::read_link(&symlink.source).context(format!(
+                "Failed to read source symlink: {}",
+                symlink.source.display()
+            ))?;
+            println!(
+                "  ℹ Preserving symlink {} -> {}",
+                symlink.source.display(),
+                link_target.display()
+            );
+            link_target
+        } else if !symlink.source.exists() && symlink.on_missing_source == MissingSource::Lazy {
+            if !self.was_written_to(&symlink.target)? {
+                return self.defer(symlink);
+            }
+            self.materialize(&symlink.target, &symlink.source)?;
+            self.resolve_source(&symlink.source)?
+        } else if !symlink.source.exists() && symlink.on_missing_source == MissingSource::Error {
+            // Reached when validation was skipped
+            anyhow::bail!(
+                "Source of {} does not exist: {} (on_missing_source = \"error\")",
+                symlink.target.display(),
+                symlink.source.display()
+            );
+        } else if !symlink.source.exists() && symlink.target.exists() && symlink.is_directory {
             println!(
                 "  ℹ Source {} doesn't exist but target {} does. Creating source from target.",
                 symlink.source.display(),
@@ -111,16 +705,18 @@ impl SymlinkManager {
 
             // Create source directory with target's permissions
             if let Some(parent) = symlink.source.parent() {
-                fs::create_dir_all(parent).context(format!(
-                    "Failed to create parent directories for source: {}",
-                    symlink.source.display()
-                ))?;
+                self.as_fs_user(|| fs::create_dir_all(parent))
+                    .context(format!(
+                        "Failed to create parent directories for source: {}",
+                        symlink.source.display()
+                    ))?;
             }
 
-            fs::create_dir_all(&symlink.source).context(format!(
-                "Failed to create source directory: {}",
-                symlink.source.display()
-            ))?;
+            self.as_fs_user(|| fs::create_dir_all(&symlink.source))
+                .context(format!(
+                    "Failed to create source directory: {}",
+                    symlink.source.display()
+                ))?;
 
             // Set permissions to match target
             let target_mode = target_metadata.mode();
@@ -130,268 +726,1262 @@ impl SymlinkManager {
                 symlink.source.display()
             ))?;
 
-            // Set ownership to match target
-            let target_uid = Uid::from_raw(target_metadata.uid());
-            let target_gid = Gid::from_raw(target_metadata.gid());
+            // Set ownership to match target, except where the entry
+            // inherits it from the source's parent instead
+            let inherit =
+                |value: Option<&str>| (value == Some(INHERIT_OWNER)).then_some(INHERIT_OWNER);
+            let (parent_uid, parent_gid) = Self::resolve_owner(
+                &symlink.source,
+                inherit(symlink.user.as_deref()),
+                inherit(symlink.group.as_deref()),
+            )?;
+            let source_uid = parent_uid.unwrap_or(Uid::from_raw(target_metadata.uid()));
+            let source_gid = parent_gid.unwrap_or(Gid::from_raw(target_metadata.gid()));
 
-            chown(&symlink.source, Some(target_uid), Some(target_gid)).context(format!(
-                "Failed to set ownership on source directory: {} (uid={}, gid={})",
-                symlink.source.display(),
-                target_uid,
-                target_gid
-            ))?;
+            Self::chown_if_needed(&symlink.source, Some(source_uid), Some(source_gid)).context(
+                format!(
+                    "Failed to set ownership on source directory: {} (uid={}, gid={})",
+                    symlink.source.display(),
+                    source_uid,
+                    source_gid
+                ),
+            )?;
 
             println!(
                 "  ✓ Created source directory: {} (from target: {})",
                 symlink.source.display(),
                 symlink.target.display()
             );
+            self.path_cache.invalidate(&symlink.source);
 
-            fs::canonicalize(&symlink.source).context(format!(
-                "Failed to resolve source path: {}",
-                symlink.source.display()
-            ))?
+            self.resolve_source(&symlink.source)?
         } else {
-            fs::canonicalize(&symlink.source).context(format!(
-                "Failed to resolve source path: {}",
-                symlink.source.display()
-            ))?
+            self.resolve_source(&symlink.source)?
         };
 
         let target = &symlink.target;
 
-        // Create parent directories if needed
-        if symlink.create_parents {
-            if let Some(parent) = target.parent() {
-                fs::create_dir_all(parent).context(format!(
-                    "Failed to create parent directories for: {}",
-                    target.display()
-                ))?;
+        self.prepare_parent(symlink)?;
+
+        if symlink.is_directory {
+            if let Some(backup_path) = self.remounts.get(target) {
+                return self.remount(symlink, &source, backup_path.clone());
             }
         }
 
-        // Handle existing target
-        let backup_path = if target.exists() || target.is_symlink() {
-            if symlink.backup {
-                Some(self.backup_target(target)?)
-            } else {
-                // Remove existing symlink or file
-                if target.is_symlink() {
-                    fs::remove_file(target).context(format!(
-                        "Failed to remove existing symlink: {}",
-                        target.display()
-                    ))?;
-                } else if target.is_dir() {
-                    // For directories, check if it's a mount point and unmount first
-                    if self.is_mount_point(target)? {
-                        umount(target).context(format!(
-                            "Failed to unmount existing mount point: {}",
-                            target.display()
-                        ))?;
-                    }
-                    fs::remove_dir_all(target).context(format!(
-                        "Failed to remove existing directory: {}",
+        // Move selected existing target content into the source so it survives
+        // the mount instead of being shadowed or removed
+        if symlink.is_directory
+            && !symlink.migrate.is_empty()
+            && target.is_dir()
+            && !target.is_symlink()
+            && !self.is_mount_point(target)?
+        {
+            self.migrate_target_content(target, &source, &symlink.migrate)?;
+            self.path_cache.invalidate(&source);
+        }
+
+        let backup_path = self.clear_target(target, symlink.backup)?;
+
+        // For directories, use bind mount; for files, use symlink
+        if symlink.is_directory {
+            self.create_mount_point(symlink, &source)?;
+
+            // Create bind mount
+            self.bind_mount(&source, target).context(format!(
+                "Failed to create bind mount from {} to {}. \
+                 This usually means insufficient privileges (need root or CAP_SYS_ADMIN), \
+                 or SELinux/AppArmor restrictions. Check that both source and target are accessible.",
+                source.display(),
+                target.display()
+            ))?;
+
+            println!(
+                "  ✓ Created bind mount: {} -> {}",
+                target.display(),
+                source.display()
+            );
+            self.set_atime(target, symlink.atime)?;
+
+            // `mode` also applies to the mounted content, i.e. the source's root
+            if let (Some(mode), false) = (symlink.mode.as_deref(), self.no_mount) {
+                self.apply_ownership_and_permissions(target, None, None, Some(mode))
+                    .context(format!(
+                        "Failed to set mode on mounted directory: {}",
                         target.display()
                     ))?;
-                } else {
-                    fs::remove_file(target).context(format!(
-                        "Failed to remove existing file: {}",
+            }
+        } else {
+            // Create the symlink for files
+            match self.as_fs_user(|| unix_fs::symlink(&source, target)) {
+                Ok(()) => {}
+                Err(e) if e.raw_os_error() == Some(nix::errno::Errno::EROFS as i32) => {
+                    return Err(e).context(format!(
+                        "Failed to create symlink from {} to {}: {} is read-only. \
+                         On an immutable root this is expected: with impermanence the directory \
+                         holding the target should itself be on a writable tmpfs \
+                         (e.g. / or /home mounted as tmpfs), so only the links imp creates persist.",
+                        source.display(),
+                        target.display(),
+                        Self::filesystem_of(target)
+                    ));
+                }
+                Err(e) => {
+                    return Err(e).context(format!(
+                        "Failed to create symlink from {} to {}",
+                        source.display(),
                         target.display()
-                    ))?;
+                    ));
                 }
-                None
             }
-        } else {
-            None
-        };
 
-        // For directories, use bind mount; for files, use symlink
-        if symlink.is_directory {
-            // Get source metadata first to copy permissions and ownership
-            let source_metadata = fs::metadata(&source).context(format!(
-                "Failed to get metadata for source: {}",
+            println!(
+                "  ✓ Created symlink: {} -> {}",
+                target.display(),
                 source.display()
-            ))?;
-
-            // Create the target directory if it doesn't exist
-            if !target.exists() {
-                fs::create_dir_all(target).context(format!(
-                    "Failed to create target directory: {}",
-                    target.display()
-                ))?;
+            );
+        }
 
-                // Set permissions on the newly created directory to match source
-                let source_mode = source_metadata.mode();
-                let permissions = fs::Permissions::from_mode(source_mode);
-                fs::set_permissions(target, permissions).context(format!(
-                    "Failed to set permissions on target directory: {}",
+        let mount_options = if symlink.is_directory {
+            Self::mount_options_at(target)?
+        } else {
+            Vec::new()
+        };
+
+        Ok(GenerationSymlink {
+            source: source.clone(),
+            target: target.clone(),
+            backup_path,
+            kind: Some(if symlink.is_directory {
+                LinkKind::Mount
+            } else {
+                LinkKind::Symlink
+            }),
+            mount_options,
+            fstype: None,
+            loop_device: None,
+            root: symlink.root.clone(),
+            restore_backup: symlink.restore_backup,
+            atime: symlink.atime,
+            lazy: symlink.on_missing_source == MissingSource::Lazy,
+        })
+    }
+
+    /// Replace the previous generation's bind mount at a target with one of
+    /// `source`. The mount point itself is left as it is.
+    fn remount(
+        &self,
+        symlink: &Symlink,
+        source: &Path,
+        backup_path: Option<PathBuf>,
+    ) -> Result<GenerationSymlink> {
+        let target = &symlink.target;
+
+        if !self.no_mount {
+            match remount::replace_mount(source, target) {
+                Ok(()) => {}
+                Err(ReplaceError::Unsupported(errno)) => {
+                    println!(
+                        "  ⚠ The kernel can't remount {} in place ({}; needs Linux 6.5 or later). \
+                         Unmounting it first",
+                        target.display(),
+                        errno
+                    );
+                    self.unmount(target)
+                        .context(format!("Failed to unmount: {}", target.display()))?;
+                    self.bind_mount(source, target).context(format!(
+                        "Failed to create bind mount from {} to {}",
+                        source.display(),
+                        target.display()
+                    ))?;
+                }
+                Err(ReplaceError::Failed(e)) => return Err(e),
+            }
+        }
+        println!(
+            "  ✓ Remounted in place: {} -> {}",
+            target.display(),
+            source.display()
+        );
+        self.set_atime(target, symlink.atime)?;
+
+        if let (Some(mode), false) = (symlink.mode.as_deref(), self.no_mount) {
+            self.apply_ownership_and_permissions(target, None, None, Some(mode))
+                .context(format!(
+                    "Failed to set mode on mounted directory: {}",
+                    target.display()
+                ))?;
+        }
+
+        Ok(GenerationSymlink {
+            source: source.to_path_buf(),
+            target: target.clone(),
+            backup_path,
+            kind: Some(LinkKind::Mount),
+            mount_options: Self::mount_options_at(target)?,
+            fstype: None,
+            loop_device: None,
+            root: symlink.root.clone(),
+            restore_backup: symlink.restore_backup,
+            atime: symlink.atime,
+            lazy: symlink.on_missing_source == MissingSource::Lazy,
+        })
+    }
+
+    /// Move an entry staged by phase one of a two-phase apply (see
+    /// `staging::StagingArea`) to its target: mounts with `MS_MOVE`, symlinks
+    /// with a rename. What is at the target is backed up or removed first, and
+    /// put back if the move fails.
+    pub fn promote(
+        &self,
+        symlink: &Symlink,
+        staged: &GenerationSymlink,
+    ) -> Result<GenerationSymlink> {
+        let target = &symlink.target;
+        self.prepare_parent(symlink)?;
+        let backup_path = self.clear_target(target, symlink.backup)?;
+        let mut promoted = GenerationSymlink {
+            target: target.clone(),
+            backup_path,
+            ..staged.clone()
+        };
+
+        let moved = if staged.is_mount() {
+            if symlink.device.is_some() {
+                self.create_device_mount_point(symlink)
+            } else {
+                self.create_mount_point(symlink, &staged.source)
+            }
+            .and_then(|()| {
+                self.move_mount(&staged.target, target).context(format!(
+                    "Failed to move the mount staged at {} to {}",
+                    staged.target.display(),
+                    target.display()
+                ))
+            })
+        } else {
+            fs::rename(&staged.target, target).context(format!(
+                "Failed to move the symlink staged at {} to {}",
+                staged.target.display(),
+                target.display()
+            ))
+        };
+        if let Err(e) = moved {
+            if staged.is_mount() {
+                let _ = fs::remove_dir(target);
+            }
+            self.restore_backup(&promoted, true)?;
+            return Err(e);
+        }
+
+        if staged.is_mount() {
+            // The staging location is an empty directory now
+            let _ = fs::remove_dir(&staged.target);
+            promoted.mount_options = Self::mount_options_at(target)?;
+        }
+        println!("  ✓ Moved into place: {}", target.display());
+        Ok(promoted)
+    }
+
+    /// Set up a lazy entry whose source doesn't exist yet: the target becomes
+    /// an empty directory with the entry's ownership and `mode`, and is left
+    /// unmounted until something is written to it
+    fn defer(&self, symlink: &Symlink) -> Result<GenerationSymlink> {
+        let target = &symlink.target;
+        self.prepare_parent(symlink)?;
+        let backup_path = self.clear_target(target, symlink.backup)?;
+
+        self.as_fs_user(|| fs::create_dir_all(target))
+            .context(format!(
+                "Failed to create target directory: {}",
+                target.display()
+            ))?;
+        let (user, group, mode) = (
+            symlink.user.as_deref(),
+            symlink.group.as_deref(),
+            symlink.mode.as_deref(),
+        );
+        if user.is_some() || group.is_some() || mode.is_some() {
+            self.apply_ownership_and_permissions(target, user, group, mode)
+                .context(format!(
+                    "Failed to apply explicit ownership/permissions on: {}",
+                    target.display()
+                ))?;
+        }
+        println!(
+            "  ℹ Waiting for the first write: {} (source {} doesn't exist yet)",
+            target.display(),
+            symlink.source.display()
+        );
+
+        Ok(GenerationSymlink {
+            source: symlink.source.clone(),
+            target: target.clone(),
+            backup_path,
+            kind: Some(LinkKind::Mount),
+            mount_options: Vec::new(),
+            fstype: None,
+            loop_device: None,
+            root: symlink.root.clone(),
+            restore_backup: symlink.restore_backup,
+            atime: symlink.atime,
+            lazy: true,
+        })
+    }
+
+    /// Whether something is really mounted at `path`. Unlike `is_mount_point`,
+    /// never true when mounts aren't performed, for the lazy entries that
+    /// aren't mounted on purpose.
+    fn is_mounted_over(&self, path: &Path) -> Result<bool> {
+        Ok(!self.no_mount && self.is_mount_point(path)?)
+    }
+
+    /// Whether a lazy entry's unmounted target has been written to: it is a
+    /// directory with something in it
+    fn was_written_to(&self, target: &Path) -> Result<bool> {
+        if !target.is_dir() || target.is_symlink() || self.is_mounted_over(target)? {
+            return Ok(false);
+        }
+        Ok(fs::read_dir(target)
+            .context(format!("Failed to read directory: {}", target.display()))?
+            .next()
+            .is_some())
+    }
+
+    /// Turn what was written to a lazy entry's target into its source, by
+    /// moving the target directory there with its content, mode and owner.
+    /// The target is gone afterwards, ready to be recreated as a mount point.
+    fn materialize(&self, target: &Path, source: &Path) -> Result<()> {
+        if let Some(parent) = source.parent() {
+            fs::create_dir_all(parent).context(format!(
+                "Failed to create parent directories for source: {}",
+                source.display()
+            ))?;
+        }
+        Self::move_path(target, source)?;
+        self.path_cache.invalidate(source);
+        println!(
+            "  ✓ Created source {} from what was written to {}",
+            source.display(),
+            target.display()
+        );
+        Ok(())
+    }
+
+    /// Create a bind mount's mount point at the entry's target if needed, with
+    /// the source's permissions and ownership, then apply the entry's own
+    fn create_mount_point(&self, symlink: &Symlink, source: &Path) -> Result<()> {
+        let target = &symlink.target;
+
+        // Get source metadata first to copy permissions and ownership
+        let source_metadata = fs::metadata(source).context(format!(
+            "Failed to get metadata for source: {}",
+            source.display()
+        ))?;
+
+        // Create the target directory if it doesn't exist
+        if !target.exists() {
+            self.as_fs_user(|| fs::create_dir_all(target))
+                .context(format!(
+                    "Failed to create target directory: {}",
+                    target.display()
+                ))?;
+
+            // Set permissions on the newly created directory to match source
+            let source_mode = source_metadata.mode();
+            let permissions = fs::Permissions::from_mode(source_mode);
+            fs::set_permissions(target, permissions).context(format!(
+                "Failed to set permissions on target directory: {}",
+                target.display()
+            ))?;
+
+            // Set ownership to match source
+            let source_uid = Uid::from_raw(source_metadata.uid());
+            let source_gid = Gid::from_raw(source_metadata.gid());
+
+            Self::chown_if_needed(target, Some(source_uid), Some(source_gid)).context(format!(
+                "Failed to set ownership on target directory: {} (uid={}, gid={}). \
+                 This usually means insufficient privileges. Try running as root or with CAP_CHOWN capability.",
+                target.display(),
+                source_uid,
+                source_gid
+            ))?;
+        }
+
+        // Apply any explicitly specified ownership and permissions (overrides source
+        // defaults). The mount point gets `mode` unless it has a mode of its own
+        let target_user = symlink.user.as_deref();
+        let target_group = symlink.group.as_deref();
+        let target_mode = symlink
+            .mount_point_mode
+            .as_deref()
+            .or(symlink.mode.as_deref());
+
+        if target_user.is_some() || target_group.is_some() || target_mode.is_some() {
+            self.apply_ownership_and_permissions(target, target_user, target_group, target_mode)
+                .context(format!(
+                    "Failed to apply explicit ownership/permissions on: {}",
+                    target.display()
+                ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Mount a block device or filesystem image at a directory target
+    fn create_device_mount(
+        &self,
+        symlink: &Symlink,
+        device: &DeviceMount,
+    ) -> Result<GenerationSymlink> {
+        let what = if device.is_image { "image" } else { "device" };
+        let device_path = fs::canonicalize(&device.path).context(format!(
+            "Failed to resolve {}: {}",
+            what,
+            device.path.display()
+        ))?;
+        let target = &symlink.target;
+
+        self.prepare_parent(symlink)?;
+        let backup_path = self.clear_target(target, symlink.backup)?;
+        self.create_device_mount_point(symlink)?;
+
+        let (fstype, loop_device) = self.mount_device(
+            &device_path,
+            device.is_image,
+            device.fstype.as_deref(),
+            target,
+        )?;
+        match &loop_device {
+            Some(loop_device) => println!(
+                "  ✓ Mounted {}: {} -> {} (via {}, {})",
+                what,
+                target.display(),
+                device_path.display(),
+                loop_device.display(),
+                fstype
+            ),
+            None => println!(
+                "  ✓ Mounted {}: {} -> {} ({})",
+                what,
+                target.display(),
+                device_path.display(),
+                fstype
+            ),
+        }
+        self.set_atime(target, symlink.atime)?;
+
+        // Explicit ownership and permissions apply to the root of the mounted filesystem
+        let (user, group, mode) = (
+            symlink.user.as_deref(),
+            symlink.group.as_deref(),
+            symlink.mode.as_deref(),
+        );
+        if user.is_some() || group.is_some() || mode.is_some() {
+            self.apply_ownership_and_permissions(target, user, group, mode)
+                .context(format!(
+                    "Failed to apply explicit ownership/permissions on: {}",
+                    target.display()
+                ))?;
+        }
+
+        Ok(GenerationSymlink {
+            source: device_path,
+            target: target.clone(),
+            backup_path,
+            kind: Some(LinkKind::Device),
+            mount_options: Self::mount_options_at(target)?,
+            fstype: Some(fstype),
+            loop_device,
+            root: None,
+            restore_backup: symlink.restore_backup,
+            atime: symlink.atime,
+            lazy: false,
+        })
+    }
+
+    /// Create a device mount's mount point at the entry's target if needed,
+    /// applying `mount_point_mode`
+    fn create_device_mount_point(&self, symlink: &Symlink) -> Result<()> {
+        let target = &symlink.target;
+        if !target.exists() {
+            self.as_fs_user(|| fs::create_dir_all(target))
+                .context(format!(
+                    "Failed to create target directory: {}",
+                    target.display()
+                ))?;
+        }
+        if let Some(mode) = symlink.mount_point_mode.as_deref() {
+            self.apply_ownership_and_permissions(target, None, None, Some(mode))
+                .context(format!(
+                    "Failed to set mount point mode on: {}",
+                    target.display()
+                ))?;
+        }
+        Ok(())
+    }
+
+    /// Mount `device` at `target`, attaching it to a loop device first if it is
+    /// an image. Without `fstype`, every block filesystem the kernel knows is
+    /// tried in turn. Returns the filesystem type and the loop device used.
+    fn mount_device(
+        &self,
+        device: &Path,
+        is_image: bool,
+        fstype: Option<&str>,
+        target: &Path,
+    ) -> Result<(String, Option<PathBuf>)> {
+        if self.no_mount {
+            return Ok((fstype.unwrap_or("auto").to_string(), None));
+        }
+
+        // Held until the mount exists, after which the loop device stays
+        // attached until it is unmounted
+        let loop_device = if is_image {
+            Some(loopdev::attach(device)?)
+        } else {
+            None
+        };
+        let source = loop_device.as_ref().map_or(device, |l| l.path.as_path());
+
+        let candidates = match fstype {
+            Some(fstype) => vec![fstype.to_string()],
+            None => mounts::block_filesystems()?,
+        };
+        let mut last_error = None;
+        for candidate in &candidates {
+            let (source, target, fstype) = (
+                source.to_path_buf(),
+                target.to_path_buf(),
+                candidate.clone(),
+            );
+            let result = self.with_timeout_limit(move || {
+                mount(
+                    Some(&source),
+                    &target,
+                    Some(fstype.as_str()),
+                    MsFlags::empty(),
+                    None::<&str>,
+                )
+            });
+            match result {
+                Ok(()) => return Ok((candidate.clone(), loop_device.map(|l| l.path))),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        match (fstype, last_error) {
+            (Some(_), Some(e)) => Err(e.context(format!(
+                "Failed to mount {} at {}",
+                source.display(),
+                target.display()
+            ))),
+            _ => anyhow::bail!(
+                "Failed to mount {} at {}: none of the filesystem types the kernel \
+                 supports ({}) recognized it. Set fstype for this entry.",
+                source.display(),
+                target.display(),
+                candidates.join(", ")
+            ),
+        }
+    }
+
+    /// Detach the loop device a removed image mount used, unless it already
+    /// went away with the mount
+    fn release_loop_device(&self, gen_symlink: &GenerationSymlink) -> Result<()> {
+        if self.no_mount {
+            return Ok(());
+        }
+        if let Some(loop_device) = &gen_symlink.loop_device {
+            if loopdev::detach_if_backed_by(loop_device, &gen_symlink.source)? {
+                println!("  ✓ Detached loop device: {}", loop_device.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure the parent directory of a target exists, creating it if the
+    /// entry allows
+    fn prepare_parent(&self, symlink: &Symlink) -> Result<()> {
+        let target = &symlink.target;
+        self.clear_parent_path(target)?;
+
+        // Create parent directories if needed
+        if let Some(parent) = target.parent() {
+            if symlink.create_parents {
+                self.as_fs_user(|| fs::create_dir_all(parent))
+                    .context(format!(
+                        "Failed to create parent directories for: {}",
+                        target.display()
+                    ))?;
+            } else if !parent.is_dir() {
+                anyhow::bail!(
+                    "Parent directory of {} does not exist: {} \
+                     (create_parents is off for this entry)",
+                    target.display(),
+                    parent.display()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Describe the filesystem `path` is on for error messages, e.g.
+    /// "the filesystem mounted at /"
+    fn filesystem_of(path: &Path) -> String {
+        let mounts = mounts::read_mountinfo().unwrap_or_default();
+        match mounts::containing_mount(&mounts, path) {
+            Some(mount) => format!("the filesystem mounted at {}", mount.mount_point.display()),
+            None => "its filesystem".to_string(),
+        }
+    }
+
+    /// Get whatever is at `target` out of the way, backing it up if requested.
+    /// Returns the backup's path.
+    fn clear_target(&self, target: &Path, backup: bool) -> Result<Option<PathBuf>> {
+        if !target.exists() && !target.is_symlink() {
+            return Ok(None);
+        }
+        if backup {
+            return Ok(Some(self.backup_target(target)?));
+        }
+
+        // Remove existing symlink or file
+        if target.is_symlink() {
+            fs::remove_file(target).context(format!(
+                "Failed to remove existing symlink: {}",
+                target.display()
+            ))?;
+        } else if target.is_dir() {
+            // For directories, check if it's a mount point and unmount first
+            if self.is_mount_point(target)? {
+                self.unmount(target).context(format!(
+                    "Failed to unmount existing mount point: {}",
                     target.display()
                 ))?;
+            }
+            fs::remove_dir_all(target).context(format!(
+                "Failed to remove existing directory: {}",
+                target.display()
+            ))?;
+        } else {
+            fs::remove_file(target).context(format!(
+                "Failed to remove existing file: {}",
+                target.display()
+            ))?;
+        }
+        Ok(None)
+    }
+
+    /// Make sure nothing but directories sits on the way to `target`'s parent.
+    /// A file there would otherwise surface as a confusing "Not a directory".
+    fn clear_parent_path(&self, target: &Path) -> Result<()> {
+        let parent = match target.parent() {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+
+        let mut ancestors: Vec<&Path> = parent.ancestors().collect();
+        ancestors.reverse();
+        let blocking = match ancestors.into_iter().find(|p| p.exists() && !p.is_dir()) {
+            Some(blocking) => blocking,
+            None => return Ok(()),
+        };
+
+        if !self.replace_blocking_files {
+            anyhow::bail!(
+                "Cannot create parent directory {}: a file exists at {}. \
+                 Move it away, or use --force to back it up and replace it.",
+                parent.display(),
+                blocking.display()
+            );
+        }
+
+        println!(
+            "  ℹ Replacing file in the way of {}: {}",
+            parent.display(),
+            blocking.display()
+        );
+        self.backup_target(blocking)?;
+        self.as_fs_user(|| fs::create_dir_all(parent))
+            .context(format!(
+                "Failed to create parent directories for: {}",
+                target.display()
+            ))?;
+
+        Ok(())
+    }
+
+    /// Tracked options of the mount currently at `target`
+    fn mount_options_at(target: &Path) -> Result<Vec<String>> {
+        let mounts = mounts::read_mountinfo()?;
+        let target = fs::canonicalize(target)?;
+        Ok(mounts::find_mount(&mounts, &target)
+            .map(|m| m.tracked_options())
+            .unwrap_or_default())
+    }
+
+    /// Move glob-matched content of an existing target directory into the source
+    fn migrate_target_content(
+        &self,
+        target: &Path,
+        source: &Path,
+        patterns: &[String],
+    ) -> Result<()> {
+        for pattern in patterns {
+            let full_pattern = format!(
+                "{}/{}",
+                glob::Pattern::escape(&target.to_string_lossy()),
+                pattern
+            );
+            let matches = glob::glob(&full_pattern)
+                .context(format!("Invalid migrate pattern: {}", pattern))?
+                .collect::<Result<Vec<_>, _>>()
+                .context(format!("Failed to expand migrate pattern: {}", pattern))?;
+
+            for path in matches {
+                // An earlier match may already have moved this path along with its parent
+                if !path.exists() && !path.is_symlink() {
+                    continue;
+                }
+
+                let relative = match path.strip_prefix(target) {
+                    Ok(relative) => relative,
+                    Err(_) => continue,
+                };
+                let destination = source.join(relative);
+
+                if destination.exists() || destination.is_symlink() {
+                    println!(
+                        "  ℹ Not migrating {}: {} already exists in source",
+                        path.display(),
+                        destination.display()
+                    );
+                    continue;
+                }
+
+                if let Some(parent) = destination.parent() {
+                    fs::create_dir_all(parent).context(format!(
+                        "Failed to create parent directories for: {}",
+                        destination.display()
+                    ))?;
+                }
+
+                Self::move_path(&path, &destination)?;
+                println!(
+                    "  ✓ Migrated {} -> {}",
+                    path.display(),
+                    destination.display()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move a file or directory, falling back to copy-and-delete across filesystems
+    fn move_path(from: &Path, to: &Path) -> Result<()> {
+        match fs::rename(from, to) {
+            Ok(()) => Ok(()),
+            Err(e) if e.raw_os_error() == Some(nix::errno::Errno::EXDEV as i32) => {
+                Self::copy_recursive(from, to)?;
+                if from.is_dir() && !from.is_symlink() {
+                    fs::remove_dir_all(from)
+                } else {
+                    fs::remove_file(from)
+                }
+                .context(format!(
+                    "Failed to remove after copying: {}",
+                    from.display()
+                ))
+            }
+            Err(e) => Err(e).context(format!(
+                "Failed to move {} to {}",
+                from.display(),
+                to.display()
+            )),
+        }
+    }
+
+    /// Recursively copy a path, preserving symlinks, permissions and ownership
+    fn copy_recursive(from: &Path, to: &Path) -> Result<()> {
+        let metadata = fs::symlink_metadata(from)
+            .context(format!("Failed to get metadata for: {}", from.display()))?;
+
+        if metadata.file_type().is_symlink() {
+            unix_fs::symlink(fs::read_link(from)?, to)
+                .context(format!("Failed to copy symlink: {}", from.display()))?;
+        } else if metadata.is_dir() {
+            fs::create_dir_all(to)
+                .context(format!("Failed to create directory: {}", to.display()))?;
+            fs::set_permissions(to, metadata.permissions())
+                .context(format!("Failed to set permissions on: {}", to.display()))?;
+            for entry in fs::read_dir(from)? {
+                let entry = entry?;
+                Self::copy_recursive(&entry.path(), &to.join(entry.file_name()))?;
+            }
+        } else {
+            fs::copy(from, to).context(format!(
+                "Failed to copy {} to {}",
+                from.display(),
+                to.display()
+            ))?;
+        }
+
+        unix_fs::lchown(to, Some(metadata.uid()), Some(metadata.gid()))
+            .context(format!("Failed to set ownership on: {}", to.display()))?;
+
+        Ok(())
+    }
+
+    /// Check if a path is a mount point
+    pub fn is_mount_point(&self, path: &Path) -> Result<bool> {
+        if self.no_mount {
+            return Ok(path.is_dir() && !path.is_symlink());
+        }
+        let canonical_path = match fs::canonicalize(path) {
+            Ok(p) => p,
+            Err(_) => return Ok(false), // If we can't canonicalize, it's probably not mounted
+        };
+
+        let mounts = mounts::read_mountinfo()?;
+        Ok(mounts::find_mount(&mounts, &canonical_path).is_some())
+    }
+
+    /// Backup an existing target
+    fn backup_target(&self, target: &Path) -> Result<PathBuf> {
+        let is_dir = target.is_dir() && !target.is_symlink();
+        let backup_path = self.backup_path(target, is_dir, Utc::now())?;
+
+        if target.is_symlink() {
+            // Read the symlink and create a new one
+            let link_target = fs::read_link(target)?;
+            fs::remove_file(target)?;
+            unix_fs::symlink(link_target, &backup_path)?;
+            println!("  ℹ Backed up to: {}", backup_path.display());
+            return Ok(backup_path);
+        }
+
+        if is_dir && self.backup_compression != BackupCompression::None {
+            backup::compress_dir(target, &backup_path, self.backup_compression)?;
+            fs::remove_dir_all(target).context(format!(
+                "Failed to remove backed up directory: {}",
+                target.display()
+            ))?;
+        } else {
+            fs::rename(target, &backup_path)?;
+        }
+
+        println!("  ℹ Backed up to: {}", backup_path.display());
+
+        Ok(backup_path)
+    }
+
+    /// Where a backup of `target` taken at `timestamp` goes: next to it, with
+    /// the suffix and time appended to the full file name so the original
+    /// extension is kept. `is_dir` is whether the backup is of a real
+    /// directory, which gets the archive extension if backups are compressed.
+    pub fn backup_path(
+        &self,
+        target: &Path,
+        is_dir: bool,
+        timestamp: DateTime<Utc>,
+    ) -> Result<PathBuf> {
+        let mut backup_name = target
+            .file_name()
+            .context(format!(
+                "Cannot back up path without a file name: {}",
+                target.display()
+            ))?
+            .to_os_string();
+        backup_name.push(format!(
+            ".{}.{}",
+            self.backup_suffix,
+            timestamp.format("%Y%m%d_%H%M%S")
+        ));
+        if is_dir {
+            backup_name.push(self.backup_compression.extension());
+        }
+        Ok(target.with_file_name(backup_name))
+    }
+
+    /// The backups applying `symlinks` would take if started at `timestamp`,
+    /// as (target, backup) pairs, after `previous` has been removed and its
+    /// own backups put back in place
+    pub fn planned_backups(
+        &self,
+        symlinks: &[Symlink],
+        previous: Option<&Generation>,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut planned = Vec::new();
+        for symlink in symlinks.iter().filter(|s| s.backup) {
+            let target = &symlink.target;
+            let previous_entry =
+                previous.and_then(|g| g.symlinks.iter().find(|s| s.target == *target));
+
+            // What will be at the target once the previous generation is gone
+            let is_dir = match previous_entry {
+                Some(entry) => match &entry.backup_path {
+                    Some(restored) if restored.symlink_metadata().is_ok() => {
+                        backup::is_compressed(restored)
+                            || (restored.is_dir() && !restored.is_symlink())
+                    }
+                    _ => continue,
+                },
+                None if target.symlink_metadata().is_ok() => {
+                    target.is_dir() && !target.is_symlink()
+                }
+                None => continue,
+            };
+
+            planned.push((target.clone(), self.backup_path(target, is_dir, timestamp)?));
+        }
+        Ok(planned)
+    }
+
+    /// Remove symlinks and unmount bind mounts from a generation. Backups are
+    /// restored except for entries set to `restore_backup = false`.
+    pub fn remove(&self, generation_symlinks: &[GenerationSymlink]) -> Result<()> {
+        self.remove_entries(generation_symlinks, false)
+    }
+
+    /// Take down entries of an apply that is being undone. They never became
+    /// a generation, so every backup they took goes back, whatever
+    /// `restore_backup` says.
+    pub fn undo(&self, applied: &[GenerationSymlink]) -> Result<()> {
+        self.remove_entries(applied, true)
+    }
+
+    fn remove_entries(&self, generation_symlinks: &[GenerationSymlink], undo: bool) -> Result<()> {
+        for gen_symlink in generation_symlinks {
+            let restore = undo || gen_symlink.restore_backup;
+            let target = &gen_symlink.target;
+
+            // Deleted by hand since the generation was applied; nothing to undo,
+            // and the backup stays where it is rather than taking the target's place
+            if target.symlink_metadata().is_err() {
+                println!("  ℹ Already removed: {}", target.display());
+                continue;
+            }
+
+            // Check if it's a lazy entry's directory, a mount point (directory
+            // bind mount) or symlink (file)
+            if gen_symlink.is_waiting() && target.is_dir() && !self.is_mounted_over(target)? {
+                // Keep what was written while waiting, as it would have been
+                // kept had the source existed
+                if self.was_written_to(target)? {
+                    self.materialize(target, &gen_symlink.source)?;
+                } else {
+                    fs::remove_dir(target)
+                        .context(format!("Failed to remove directory: {}", target.display()))?;
+                }
+                println!("  ✓ Removed waiting directory: {}", target.display());
+                self.restore_backup(gen_symlink, restore)?;
+            } else if self.is_mount_point(target)? {
+                // A bind mount or device (directory entries)
+                match self.unmount(target) {
+                    Ok(()) => println!("  ✓ Unmounted: {}", target.display()),
+                    // Unmounted or removed by something else in the meantime
+                    Err(e) if is_gone(&e) => {
+                        println!("  ℹ Already unmounted: {}", target.display())
+                    }
+                    Err(e) => {
+                        return Err(e.context(format!("Failed to unmount: {}", target.display())))
+                    }
+                }
+                self.release_loop_device(gen_symlink)?;
+
+                // Optionally remove the now-empty directory
+                if target.is_dir() {
+                    fs::remove_dir(target).ok(); // Ignore errors here
+                }
+
+                self.restore_backup(gen_symlink, restore)?;
+            } else if target.is_symlink() {
+                // Remove symlink (for files)
+                fs::remove_file(target)
+                    .context(format!("Failed to remove symlink: {}", target.display()))?;
+
+                println!("  ✓ Removed symlink: {}", target.display());
+
+                self.restore_backup(gen_symlink, restore)?;
+            } else if gen_symlink.is_mount() && target.is_dir() {
+                // Unmounted by hand; only the mount point is left
+                println!("  ℹ Already unmounted: {}", target.display());
+                self.release_loop_device(gen_symlink)?;
+                if fs::remove_dir(target).is_ok() {
+                    self.restore_backup(gen_symlink, restore)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move the backup of a removed entry back to its target, if there is one,
+    /// or leave it where it is if `restore` is false
+    fn restore_backup(&self, gen_symlink: &GenerationSymlink, restore: bool) -> Result<()> {
+        let backup_path = match &gen_symlink.backup_path {
+            Some(p) if p.symlink_metadata().is_ok() => p,
+            _ => return Ok(()),
+        };
+        if !restore {
+            println!(
+                "  ℹ Kept backup (restore_backup = false): {}",
+                backup_path.display()
+            );
+            return Ok(());
+        }
+
+        if backup::is_compressed(backup_path) {
+            backup::extract_dir(backup_path, &gen_symlink.target)?;
+            fs::remove_file(backup_path).context(format!(
+                "Failed to remove restored backup: {}",
+                backup_path.display()
+            ))?;
+        } else {
+            fs::rename(backup_path, &gen_symlink.target).context(format!(
+                "Failed to restore backup: {}",
+                backup_path.display()
+            ))?;
+        }
+        println!("  ℹ Restored backup: {}", gen_symlink.target.display());
+
+        Ok(())
+    }
 
-                // Set ownership to match source
-                let source_uid = Uid::from_raw(source_metadata.uid());
-                let source_gid = Gid::from_raw(source_metadata.gid());
+    /// Undo `restore_backup`: move the content restored at an entry's target
+    /// back to its recorded backup, so the entry can be recreated as it was
+    pub fn return_to_backup(&self, gen_symlink: &GenerationSymlink) -> Result<()> {
+        let target = &gen_symlink.target;
+        let backup_path = match &gen_symlink.backup_path {
+            Some(p) if p.symlink_metadata().is_err() => p,
+            _ => return Ok(()),
+        };
+        if target.symlink_metadata().is_err() {
+            return Ok(());
+        }
 
-                chown(target, Some(source_uid), Some(source_gid)).context(format!(
-                    "Failed to set ownership on target directory: {} (uid={}, gid={}). \
-                     This usually means insufficient privileges. Try running as root or with CAP_CHOWN capability.",
-                    target.display(),
-                    source_uid,
-                    source_gid
+        let compression = BackupCompression::of_backup(backup_path);
+        if compression != BackupCompression::None {
+            backup::compress_dir(target, backup_path, compression)?;
+            fs::remove_dir_all(target).context(format!(
+                "Failed to remove backed up directory: {}",
+                target.display()
+            ))?;
+        } else {
+            fs::rename(target, backup_path).context(format!(
+                "Failed to move {} back to its backup: {}",
+                target.display(),
+                backup_path.display()
+            ))?;
+        }
+        println!("  ℹ Backed up again: {}", target.display());
+
+        Ok(())
+    }
+
+    /// Recreate a recorded symlink or bind mount
+    pub fn recreate(&self, gen_symlink: &GenerationSymlink) -> Result<()> {
+        if let Some(parent) = gen_symlink.target.parent() {
+            fs::create_dir_all(parent).context(format!(
+                "Failed to create parent directories for: {}",
+                gen_symlink.target.display()
+            ))?;
+        }
+
+        if gen_symlink.is_device() {
+            if !gen_symlink.target.exists() {
+                fs::create_dir_all(&gen_symlink.target).context(format!(
+                    "Failed to create target directory: {}",
+                    gen_symlink.target.display()
                 ))?;
             }
 
-            // Apply any explicitly specified ownership and permissions (overrides source defaults)
-            let target_user = symlink.user.as_deref();
-            let target_group = symlink.group.as_deref();
-            let target_mode = symlink.mode.as_deref();
+            // An image gets a fresh loop device; the recorded one may be in use elsewhere
+            let (fstype, _) = self.mount_device(
+                &gen_symlink.source,
+                gen_symlink.loop_device.is_some(),
+                gen_symlink.fstype.as_deref(),
+                &gen_symlink.target,
+            )?;
+            println!(
+                "  ✓ Mounted device: {} -> {} ({})",
+                gen_symlink.target.display(),
+                gen_symlink.source.display(),
+                fstype
+            );
+            self.set_atime(&gen_symlink.target, gen_symlink.atime)?;
+        } else if gen_symlink.is_mount() {
+            if gen_symlink.is_waiting() {
+                if !self.was_written_to(&gen_symlink.target)? {
+                    fs::create_dir_all(&gen_symlink.target).context(format!(
+                        "Failed to create target directory: {}",
+                        gen_symlink.target.display()
+                    ))?;
+                    println!(
+                        "  ℹ Waiting for the first write: {}",
+                        gen_symlink.target.display()
+                    );
+                    return Ok(());
+                }
+                self.materialize(&gen_symlink.target, &gen_symlink.source)?;
+            }
 
-            if target_user.is_some() || target_group.is_some() || target_mode.is_some() {
-                self.apply_ownership_and_permissions(
-                    target,
-                    target_user,
-                    target_group,
-                    target_mode,
-                )
-                .context(format!(
-                    "Failed to apply explicit ownership/permissions on: {}",
-                    target.display()
+            // Create the target directory if it doesn't exist
+            if !gen_symlink.target.exists() {
+                fs::create_dir_all(&gen_symlink.target).context(format!(
+                    "Failed to create target directory: {}",
+                    gen_symlink.target.display()
                 ))?;
             }
 
-            // Create bind mount
-            mount(
-                Some(&source),
-                target,
-                None::<&str>,
-                MsFlags::MS_BIND,
-                None::<&str>,
-            )
-            .context(format!(
-                "Failed to create bind mount from {} to {}. \
-                 This usually means insufficient privileges (need root or CAP_SYS_ADMIN), \
-                 or SELinux/AppArmor restrictions. Check that both source and target are accessible.",
-                source.display(),
-                target.display()
-            ))?;
-
+            self.bind_mount(&gen_symlink.source, &gen_symlink.target)
+                .context(format!(
+                    "Failed to create bind mount from {} to {}",
+                    gen_symlink.source.display(),
+                    gen_symlink.target.display()
+                ))?;
             println!(
                 "  ✓ Created bind mount: {} -> {}",
-                target.display(),
-                source.display()
+                gen_symlink.target.display(),
+                gen_symlink.source.display()
             );
+            self.set_atime(&gen_symlink.target, gen_symlink.atime)?;
         } else {
-            // Create the symlink for files
-            unix_fs::symlink(&source, target).context(format!(
+            unix_fs::symlink(&gen_symlink.source, &gen_symlink.target).context(format!(
                 "Failed to create symlink from {} to {}",
-                source.display(),
-                target.display()
+                gen_symlink.source.display(),
+                gen_symlink.target.display()
             ))?;
-
             println!(
                 "  ✓ Created symlink: {} -> {}",
-                target.display(),
-                source.display()
+                gen_symlink.target.display(),
+                gen_symlink.source.display()
             );
         }
 
-        Ok(GenerationSymlink {
-            source: source.clone(),
-            target: target.clone(),
-            backup_path,
-        })
+        Ok(())
     }
 
-    /// Check if a path is a mount point
-    fn is_mount_point(&self, path: &Path) -> Result<bool> {
-        // Read /proc/mounts to check if the path is a mount point
-        let mounts = fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
-        let canonical_path = match fs::canonicalize(path) {
-            Ok(p) => p,
-            Err(_) => return Ok(false), // If we can't canonicalize, it's probably not mounted
-        };
+    /// Move whatever now occupies a recorded target out of the way before
+    /// recreating it, returning the backup's path if anything was there
+    pub fn backup_existing_target(
+        &self,
+        gen_symlink: &GenerationSymlink,
+    ) -> Result<Option<PathBuf>> {
+        let target = &gen_symlink.target;
+        if !target.exists() && !target.is_symlink() {
+            return Ok(None);
+        }
 
-        for line in mounts.lines() {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.len() >= 2 {
-                let mount_point = parts[1];
-                if Path::new(mount_point) == canonical_path {
-                    return Ok(true);
-                }
+        // An empty directory (e.g. a mount point left by the previous
+        // generation) holds nothing worth keeping
+        if target.is_dir() && !target.is_symlink() {
+            if self.is_mount_point(target)? {
+                return Ok(None);
+            }
+            let is_empty = fs::read_dir(target)
+                .context(format!("Failed to read directory: {}", target.display()))?
+                .next()
+                .is_none();
+            if is_empty {
+                return Ok(None);
             }
         }
-        Ok(false)
+
+        self.backup_target(target)
+            .context(format!("Failed to back up: {}", target.display()))
+            .map(Some)
     }
 
-    /// Backup an existing target
-    fn backup_target(&self, target: &Path) -> Result<PathBuf> {
-        let timestamp = chrono::Utc::now().format("%Y%m%d_%H%M%S");
-        let backup_path = target.with_extension(format!("backup.{}", timestamp));
+    /// Work out how `repair` would restore a broken entry, without touching
+    /// anything. Fails if the entry can't be repaired at all.
+    pub fn plan_repair(&self, gen_symlink: &GenerationSymlink) -> Result<Vec<RepairAction>> {
+        let target = &gen_symlink.target;
+        let mut actions = Vec::new();
 
-        if target.is_symlink() {
-            // Read the symlink and create a new one
-            let link_target = fs::read_link(target)?;
-            fs::remove_file(target)?;
-            unix_fs::symlink(link_target, &backup_path)?;
-        } else {
-            fs::rename(target, &backup_path)?;
+        if gen_symlink.is_waiting() {
+            if target.is_dir() && self.is_mounted_over(target)? {
+                // Whatever the mount hides is checked again on the next verify
+                actions.push(RepairAction::Unmount(target.clone()));
+            } else if self.was_written_to(target)? {
+                actions.push(RepairAction::Materialize {
+                    target: target.clone(),
+                    source: gen_symlink.source.clone(),
+                });
+                actions.push(RepairAction::Recreate {
+                    kind: LinkKind::Mount,
+                    source: gen_symlink.source.clone(),
+                    target: target.clone(),
+                });
+                return Ok(actions);
+            }
+            actions.push(RepairAction::CreateDir(target.clone()));
+            return Ok(actions);
         }
 
-        println!("  ℹ Backed up to: {}", backup_path.display());
+        if !gen_symlink.source.exists() {
+            anyhow::bail!("Source does not exist: {}", gen_symlink.source.display());
+        }
 
-        Ok(backup_path)
-    }
+        if gen_symlink.is_mount() {
+            // A mount from the wrong source has to go before we can remount
+            if target.is_dir() && self.is_mount_point(target)? {
+                actions.push(RepairAction::Unmount(target.clone()));
+            }
+        } else if target.is_symlink() {
+            actions.push(RepairAction::RemoveSymlink(target.clone()));
+        } else if target.exists() {
+            // Never clobber real data sitting where the symlink should be
+            anyhow::bail!(
+                "Refusing to replace existing file with symlink: {}",
+                target.display()
+            );
+        }
 
-    /// Remove symlinks and unmount bind mounts from a generation
-    pub fn remove(&self, generation_symlinks: &[GenerationSymlink]) -> Result<()> {
-        for gen_symlink in generation_symlinks {
-            // Check if it's a mount point (directory bind mount) or symlink (file)
-            if self.is_mount_point(&gen_symlink.target)? {
-                // Unmount the bind mount
-                umount(&gen_symlink.target).context(format!(
-                    "Failed to unmount: {}",
-                    gen_symlink.target.display()
-                ))?;
+        let kind = if gen_symlink.is_device() {
+            LinkKind::Device
+        } else if gen_symlink.is_mount() {
+            LinkKind::Mount
+        } else {
+            LinkKind::Symlink
+        };
+        actions.push(RepairAction::Recreate {
+            kind,
+            source: gen_symlink.source.clone(),
+            target: target.clone(),
+        });
 
-                println!("  ✓ Unmounted: {}", gen_symlink.target.display());
+        Ok(actions)
+    }
 
-                // Optionally remove the now-empty directory
-                if gen_symlink.target.is_dir() {
-                    fs::remove_dir(&gen_symlink.target).ok(); // Ignore errors here
+    /// Restore a broken entry to the state recorded in its generation
+    pub fn repair(&self, gen_symlink: &GenerationSymlink) -> Result<()> {
+        for action in self.plan_repair(gen_symlink)? {
+            match action {
+                RepairAction::Unmount(target) => {
+                    self.unmount(&target)
+                        .context(format!("Failed to unmount: {}", target.display()))?;
+                    println!("  ✓ Unmounted: {}", target.display());
                 }
-
-                // Restore backup if it exists
-                if let Some(backup_path) = &gen_symlink.backup_path {
-                    if backup_path.exists() {
-                        fs::rename(backup_path, &gen_symlink.target).context(format!(
-                            "Failed to restore backup: {}",
-                            backup_path.display()
-                        ))?;
-                        println!("  ℹ Restored backup: {}", gen_symlink.target.display());
-                    }
+                RepairAction::RemoveSymlink(target) => {
+                    fs::remove_file(&target)
+                        .context(format!("Failed to remove symlink: {}", target.display()))?;
                 }
-            } else if gen_symlink.target.is_symlink() {
-                // Remove symlink (for files)
-                fs::remove_file(&gen_symlink.target).context(format!(
-                    "Failed to remove symlink: {}",
-                    gen_symlink.target.display()
-                ))?;
-
-                println!("  ✓ Removed symlink: {}", gen_symlink.target.display());
-
-                // Restore backup if it exists
-                if let Some(backup_path) = &gen_symlink.backup_path {
-                    if backup_path.exists() {
-                        fs::rename(backup_path, &gen_symlink.target).context(format!(
-                            "Failed to restore backup: {}",
-                            backup_path.display()
-                        ))?;
-                        println!("  ℹ Restored backup: {}", gen_symlink.target.display());
-                    }
+                RepairAction::Materialize { target, source } => {
+                    self.materialize(&target, &source)?
                 }
+                RepairAction::CreateDir(target) => {
+                    fs::create_dir_all(&target)
+                        .context(format!("Failed to create directory: {}", target.display()))?;
+                    println!("  ✓ Created directory: {}", target.display());
+                }
+                RepairAction::Recreate { .. } => self.recreate(gen_symlink)?,
             }
         }
-
         Ok(())
     }
 
@@ -400,77 +1990,510 @@ impl SymlinkManager {
         let mut errors = Vec::new();
 
         for gen_symlink in generation_symlinks {
-            // Check if target is a directory (should be a mount point) or file (should be a symlink)
-            if gen_symlink.target.is_dir() {
-                // For directories, verify it's a mount point
-                if !self.is_mount_point(&gen_symlink.target)? {
-                    errors.push(format!(
-                        "Directory is not a mount point: {}",
-                        gen_symlink.target.display()
-                    ));
-                    continue;
+            if let Some(error) = self.verify_one(gen_symlink)? {
+                errors.push(error);
+            }
+            if self.strict {
+                for problem in Self::strict_problems(gen_symlink) {
+                    if !errors.contains(&problem) {
+                        errors.push(problem);
+                    }
                 }
+            }
+        }
 
-                // Verify it's mounted from the correct source
-                // We check this by reading /proc/mounts
-                let mounts = fs::read_to_string("/proc/mounts")?;
-                let canonical_target = fs::canonicalize(&gen_symlink.target)?;
-                let canonical_source = fs::canonicalize(&gen_symlink.source)?;
-
-                let mut found_correct_mount = false;
-                for line in mounts.lines() {
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    if parts.len() >= 2 {
-                        let mount_source = parts[0];
-                        let mount_point = parts[1];
-                        if Path::new(mount_point) == canonical_target
-                            && Path::new(mount_source) == canonical_source
-                        {
-                            found_correct_mount = true;
-                            break;
-                        }
-                    }
+        Ok(errors)
+    }
+
+    /// Problems that don't break the link right now but indicate drift
+    fn strict_problems(gen_symlink: &GenerationSymlink) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        // A lazy source only appears with the first write
+        if fs::symlink_metadata(gen_symlink.resolved_source()).is_err() && !gen_symlink.lazy {
+            problems.push(format!(
+                "Source does not exist: {}",
+                gen_symlink.source.display()
+            ));
+        }
+
+        if let Some(backup_path) = &gen_symlink.backup_path {
+            if fs::symlink_metadata(backup_path).is_err() {
+                problems.push(format!(
+                    "Backup is missing: {} (for {})",
+                    backup_path.display(),
+                    gen_symlink.target.display()
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Directory entries whose target, as programs see it once mounted, lacks
+    /// the owner, group or `mode` the config requests. Resolves names and
+    /// `inherit` the way apply does. File entries carry no ownership.
+    pub fn ownership_mismatches(&self, symlinks: &[Symlink]) -> Result<Vec<String>> {
+        let mut mismatches = Vec::new();
+
+        for symlink in symlinks.iter().filter(|s| s.is_directory) {
+            let target = &symlink.target;
+            // Without a mount the mount point is what is seen, and it gets
+            // `mount_point_mode` where set
+            let mode = if self.no_mount {
+                symlink
+                    .mount_point_mode
+                    .as_deref()
+                    .or(symlink.mode.as_deref())
+            } else {
+                symlink.mode.as_deref()
+            };
+            let (user, group) = (symlink.user.as_deref(), symlink.group.as_deref());
+            if user.is_none() && group.is_none() && mode.is_none() {
+                continue;
+            }
+
+            let metadata = fs::metadata(target)
+                .context(format!("Failed to get metadata for: {}", target.display()))?;
+            let (uid, gid) = Self::resolve_owner(target, user, group)?;
+            if let Some(uid) = uid.filter(|u| u.as_raw() != metadata.uid()) {
+                mismatches.push(format!(
+                    "Owner of {} is uid {} (expected {}, uid {})",
+                    target.display(),
+                    metadata.uid(),
+                    user.unwrap_or_default(),
+                    uid
+                ));
+            }
+            if let Some(gid) = gid.filter(|g| g.as_raw() != metadata.gid()) {
+                mismatches.push(format!(
+                    "Group of {} is gid {} (expected {}, gid {})",
+                    target.display(),
+                    metadata.gid(),
+                    group.unwrap_or_default(),
+                    gid
+                ));
+            }
+            if let Some(mode_str) = mode {
+                let current = metadata.mode() & 0o7777;
+                let expected = mode::parse(mode_str, current)?;
+                if current != expected {
+                    mismatches.push(format!(
+                        "Mode of {} is {:04o} (expected {}, {:04o})",
+                        target.display(),
+                        current,
+                        mode_str,
+                        expected
+                    ));
                 }
+            }
+        }
 
-                if !found_correct_mount {
-                    errors.push(format!(
-                        "Directory is mounted but from wrong source: {} (expected source: {})",
-                        gen_symlink.target.display(),
+        Ok(mismatches)
+    }
+
+    /// Check that the device mounted at a target is the recorded device or image
+    fn verify_device(&self, gen_symlink: &GenerationSymlink) -> Result<Option<String>> {
+        let mounts = mounts::read_mountinfo()?;
+        let canonical_target = fs::canonicalize(&gen_symlink.target)?;
+        let mount = match mounts::find_mount(&mounts, &canonical_target) {
+            Some(mount) => mount,
+            None => {
+                return Ok(Some(format!(
+                    "Directory is not a mount point: {}",
+                    gen_symlink.target.display()
+                )))
+            }
+        };
+
+        if Self::is_mount_of_device(gen_symlink, mount) {
+            Ok(Self::atime_problem(gen_symlink, mount))
+        } else {
+            Ok(Some(format!(
+                "Directory is mounted from the wrong device: {} (mounted from {}, expected {})",
+                gen_symlink.target.display(),
+                mount.source,
+                gen_symlink.source.display()
+            )))
+        }
+    }
+
+    /// Check a lazy entry whose source doesn't exist yet: its target should be
+    /// an empty, unmounted directory. Once something is written there, the
+    /// entry needs repairing, which creates the source from it.
+    fn verify_waiting(&self, gen_symlink: &GenerationSymlink) -> Result<Option<String>> {
+        let target = &gen_symlink.target;
+        if !target.is_dir() || target.is_symlink() {
+            return Ok(Some(format!(
+                "Directory waiting for its first write is missing: {}",
+                target.display()
+            )));
+        }
+        if self.is_mounted_over(target)? {
+            return Ok(Some(format!(
+                "Directory is mounted but its lazy source doesn't exist yet: {} (expected {})",
+                target.display(),
+                gen_symlink.source.display()
+            )));
+        }
+        if self.was_written_to(target)? {
+            return Ok(Some(format!(
+                "Directory was written to before its lazy source existed: {} \
+                 (`imp verify --fix` moves the content to {})",
+                target.display(),
+                gen_symlink.source.display()
+            )));
+        }
+        Ok(None)
+    }
+
+    /// The atime option recorded for an entry, if `mount` has lost it
+    fn atime_problem(gen_symlink: &GenerationSymlink, mount: &mounts::MountInfo) -> Option<String> {
+        let atime = gen_symlink.atime?;
+        (!mount.options.iter().any(|o| o == atime.name())).then(|| {
+            format!(
+                "Mount at {} lost {}: mounted with {}",
+                gen_symlink.target.display(),
+                atime.name(),
+                mount.options.join(",")
+            )
+        })
+    }
+
+    /// Whether `mount` is the recorded device or image of a device entry
+    fn is_mount_of_device(gen_symlink: &GenerationSymlink, mount: &mounts::MountInfo) -> bool {
+        if gen_symlink.loop_device.is_some() {
+            // Images are mounted through whichever loop device was free at the time
+            loopdev::backing_file(Path::new(&mount.source)).as_ref() == Some(&gen_symlink.source)
+        } else {
+            fs::metadata(&gen_symlink.source).is_ok_and(|m| {
+                let rdev = m.rdev();
+                format!(
+                    "{}:{}",
+                    nix::sys::stat::major(rdev),
+                    nix::sys::stat::minor(rdev)
+                ) == mount.major_minor
+            })
+        }
+    }
+
+    /// What a recorded entry's target links to or is mounted from right now,
+    /// or `None` if there is no symlink or mount there. A mount of the recorded
+    /// source is reported as the recorded path, whatever its options.
+    pub fn live_source(&self, gen_symlink: &GenerationSymlink) -> Result<Option<PathBuf>> {
+        let target = &gen_symlink.target;
+
+        if !gen_symlink.is_mount() {
+            if !target.is_symlink() {
+                return Ok(None);
+            }
+            let link = fs::read_link(target)
+                .context(format!("Failed to read symlink: {}", target.display()))?;
+            return Ok(Some(link));
+        }
+
+        if !target.is_dir() || !self.is_mount_point(target)? {
+            return Ok(None);
+        }
+        if self.no_mount {
+            return Ok(Some(gen_symlink.source.clone()));
+        }
+
+        let mounts = mounts::read_mountinfo()?;
+        let mount = match mounts::find_mount(&mounts, &fs::canonicalize(target)?) {
+            Some(mount) => mount,
+            None => return Ok(None),
+        };
+
+        let recorded = if gen_symlink.is_device() {
+            Self::is_mount_of_device(gen_symlink, mount)
+        } else {
+            fs::canonicalize(&gen_symlink.source)
+                .is_ok_and(|source| mounts::is_bind_of(&mounts, mount, &source))
+        };
+
+        Ok(Some(if recorded {
+            gen_symlink.source.clone()
+        } else if gen_symlink.is_device() {
+            PathBuf::from(&mount.source)
+        } else {
+            PathBuf::from(mounts::mount_source(&mounts, mount))
+        }))
+    }
+
+    /// Verify a single entry, returning a description of the problem if it is broken
+    pub fn verify_one(&self, gen_symlink: &GenerationSymlink) -> Result<Option<String>> {
+        if gen_symlink.is_waiting() {
+            return self.verify_waiting(gen_symlink);
+        }
+
+        // Check if target should be a directory (mount point) or file (symlink)
+        if gen_symlink.is_mount() {
+            // For directories, verify it's a mount point
+            if !gen_symlink.target.is_dir() || !self.is_mount_point(&gen_symlink.target)? {
+                return Ok(Some(format!(
+                    "Directory is not a mount point: {}",
+                    gen_symlink.target.display()
+                )));
+            }
+
+            if gen_symlink.is_device() && !self.no_mount {
+                return self.verify_device(gen_symlink);
+            }
+
+            // Nothing was really mounted, so there is no source to compare
+            if self.no_mount {
+                if !gen_symlink.source.exists() {
+                    return Ok(Some(format!(
+                        "Source does not exist: {}",
                         gen_symlink.source.display()
-                    ));
+                    )));
                 }
-            } else {
-                // For files, verify it's a symlink
-                if !gen_symlink.target.is_symlink() {
-                    errors.push(format!(
-                        "File is not a symlink: {}",
-                        gen_symlink.target.display()
-                    ));
-                    continue;
+                return Ok(None);
+            }
+
+            // Verify it's mounted from the correct source. The device column of
+            // /proc/mounts doesn't identify a bind mount's source directory, so
+            // compare the filesystem and root recorded in mountinfo instead
+            let mounts = mounts::read_mountinfo()?;
+            let canonical_target = fs::canonicalize(&gen_symlink.target)?;
+            let canonical_source = match fs::canonicalize(&gen_symlink.source) {
+                Ok(p) => p,
+                Err(_) => {
+                    return Ok(Some(format!(
+                        "Source does not exist: {}",
+                        gen_symlink.source.display()
+                    )))
                 }
+            };
 
-                match fs::read_link(&gen_symlink.target) {
-                    Ok(link_target) => {
-                        if link_target != gen_symlink.source {
-                            errors.push(format!(
-                                "Symlink points to wrong target: {} -> {} (expected: {})",
-                                gen_symlink.target.display(),
-                                link_target.display(),
-                                gen_symlink.source.display()
-                            ));
-                        }
+            let actual_source = match mounts::find_mount(&mounts, &canonical_target) {
+                Some(mount) if mounts::is_bind_of(&mounts, mount, &canonical_source) => {
+                    if let Some(problem) = Self::atime_problem(gen_symlink, mount) {
+                        return Ok(Some(problem));
                     }
-                    Err(e) => {
-                        errors.push(format!(
-                            "Failed to read symlink {}: {}",
+                    let options = mount.tracked_options();
+                    if gen_symlink.mount_options.is_empty() || options == gen_symlink.mount_options
+                    {
+                        return Ok(None);
+                    }
+                    return Ok(Some(format!(
+                        "Mount options drifted on {}: {} (expected {})",
+                        gen_symlink.target.display(),
+                        options.join(","),
+                        gen_symlink.mount_options.join(",")
+                    )));
+                }
+                Some(mount) => mounts::mount_source(&mounts, mount),
+                None => "unknown".to_string(),
+            };
+
+            Ok(Some(format!(
+                "Directory is mounted but from wrong source: {} (mounted from {}, expected {})",
+                gen_symlink.target.display(),
+                actual_source,
+                gen_symlink.source.display()
+            )))
+        } else {
+            // For files, verify it's a symlink
+            if !gen_symlink.target.is_symlink() {
+                return Ok(Some(format!(
+                    "File is not a symlink: {}",
+                    gen_symlink.target.display()
+                )));
+            }
+
+            match fs::read_link(&gen_symlink.target) {
+                Ok(link_target) => {
+                    if link_target != gen_symlink.source {
+                        return Ok(Some(format!(
+                            "Symlink points to wrong target: {} -> {} (expected: {})",
                             gen_symlink.target.display(),
-                            e
+                            link_target.display(),
+                            gen_symlink.source.display()
+                        )));
+                    }
+                    Ok(None)
+                }
+                Err(e) => Ok(Some(format!(
+                    "Failed to read symlink {}: {}",
+                    gen_symlink.target.display(),
+                    e
+                ))),
+            }
+        }
+    }
+}
+
+/// Walk a path component by component and describe the first one that can't be
+/// resolved, e.g. "path breaks at: /a/b (b does not exist)"
+pub fn path_resolution_trace(path: &Path) -> Option<String> {
+    let mut current = PathBuf::new();
+    let mut components = path.components().peekable();
+
+    while let Some(component) = components.next() {
+        current.push(component);
+        let name = component.as_os_str().to_string_lossy();
+        let has_more = components.peek().is_some();
+
+        let metadata = match fs::symlink_metadata(&current) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Some(format!(
+                    "path breaks at: {} ({} does not exist)",
+                    current.display(),
+                    name
+                ));
+            }
+            Err(e) => {
+                return Some(format!(
+                    "path breaks at: {} ({} is not accessible: {})",
+                    current.display(),
+                    name,
+                    e
+                ));
+            }
+        };
+
+        if metadata.file_type().is_symlink() {
+            if let Err(e) = fs::metadata(&current) {
+                let link_target = fs::read_link(&current)
+                    .map(|t| t.display().to_string())
+                    .unwrap_or_else(|_| "?".to_string());
+                return Some(format!(
+                    "path breaks at: {} ({} is a broken symlink to {}: {})",
+                    current.display(),
+                    name,
+                    link_target,
+                    e
+                ));
+            }
+        }
+
+        if has_more {
+            match fs::metadata(&current) {
+                Ok(m) if !m.is_dir() => {
+                    return Some(format!(
+                        "path breaks at: {} ({} is not a directory)",
+                        current.display(),
+                        name
+                    ));
+                }
+                Ok(_) => {
+                    // Traversing needs search (execute) permission, not read
+                    if let Err(e) = access(&current, AccessFlags::X_OK) {
+                        return Some(format!(
+                            "path breaks at: {} ({} is not traversable: {})",
+                            current.display(),
+                            name,
+                            e.desc()
                         ));
                     }
                 }
+                Err(e) => {
+                    return Some(format!(
+                        "path breaks at: {} ({} is not accessible: {})",
+                        current.display(),
+                        name,
+                        e
+                    ));
+                }
             }
         }
+    }
 
-        Ok(errors)
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_resolvable_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+
+        assert_eq!(path_resolution_trace(&dir.path().join("a")), None);
+    }
+
+    #[test]
+    fn trace_missing_component() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+
+        assert_eq!(
+            path_resolution_trace(&missing.join("file")),
+            Some(format!(
+                "path breaks at: {} (missing does not exist)",
+                missing.display()
+            ))
+        );
+    }
+
+    #[test]
+    fn trace_broken_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("link");
+        unix_fs::symlink("/nonexistent-imp-target", &link).unwrap();
+
+        let trace = path_resolution_trace(&link.join("file")).unwrap();
+        assert!(
+            trace.starts_with(&format!(
+                "path breaks at: {} (link is a broken symlink to /nonexistent-imp-target: ",
+                link.display()
+            )),
+            "{}",
+            trace
+        );
+    }
+
+    #[test]
+    fn trace_non_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+
+        assert_eq!(
+            path_resolution_trace(&file.join("child")),
+            Some(format!(
+                "path breaks at: {} (file is not a directory)",
+                file.display()
+            ))
+        );
+    }
+
+    #[test]
+    fn trace_search_only_directory_is_traversable() {
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        fs::create_dir_all(home.join("data")).unwrap();
+        fs::set_permissions(&home, fs::Permissions::from_mode(0o111)).unwrap();
+
+        let trace = path_resolution_trace(&home.join("data"));
+        fs::set_permissions(&home, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(trace, None);
+    }
+
+    #[test]
+    fn trace_read_only_directory_is_not_traversable() {
+        // Root may search any directory
+        if Uid::effective().is_root() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let home = dir.path().join("home");
+        fs::create_dir_all(home.join("data")).unwrap();
+        fs::set_permissions(&home, fs::Permissions::from_mode(0o444)).unwrap();
+
+        let trace = path_resolution_trace(&home.join("data"));
+        fs::set_permissions(&home, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            trace,
+            Some(format!(
+                "path breaks at: {} (home is not traversable: Permission denied)",
+                home.display()
+            ))
+        );
     }
 }
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::config::Symlink;
+use crate::generation::LinkKind;
+
+/// Version of the JSON document validators receive, bumped on incompatible changes
+const PLAN_VERSION: u32 = 1;
+
+/// The plan passed to validators on stdin
+#[derive(Serialize)]
+struct Plan<'a> {
+    version: u32,
+    entries: Vec<PlanEntry<'a>>,
+}
+
+/// One entry of the plan, as it is about to be applied
+#[derive(Serialize)]
+struct PlanEntry<'a> {
+    target: &'a Path,
+    source: &'a Path,
+    kind: LinkKind,
+    user: Option<&'a str>,
+    group: Option<&'a str>,
+    mode: Option<&'a str>,
+    root: Option<&'a Path>,
+}
+
+fn plan(symlinks: &[Symlink]) -> Plan<'_> {
+    let entries = symlinks
+        .iter()
+        .map(|s| PlanEntry {
+            target: &s.target,
+            source: &s.source,
+            kind: if s.device.is_some() {
+                LinkKind::Device
+            } else if s.is_directory {
+                LinkKind::Mount
+            } else {
+                LinkKind::Symlink
+            },
+            user: s.user.as_deref(),
+            group: s.group.as_deref(),
+            mode: s.mode.as_deref(),
+            root: s.root.as_deref(),
+        })
+        .collect();
+
+    Plan {
+        version: PLAN_VERSION,
+        entries,
+    }
+}
+
+/// Run each validator with the plan on stdin. The first one that exits
+/// non-zero fails validation with its stderr.
+pub fn run(validators: &[PathBuf], symlinks: &[Symlink]) -> Result<()> {
+    if validators.is_empty() {
+        return Ok(());
+    }
+    let plan = serde_json::to_vec(&plan(symlinks)).context("Failed to serialize the plan")?;
+
+    for validator in validators {
+        println!("Running validator: {}", validator.display());
+        let mut child = Command::new(validator)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context(format!("Failed to run validator: {}", validator.display()))?;
+
+        // A validator may decide without reading all of its input
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        match stdin.write_all(&plan) {
+            Err(e) if e.kind() != ErrorKind::BrokenPipe => {
+                return Err(e).context(format!(
+                    "Failed to pass the plan to validator: {}",
+                    validator.display()
+                ));
+            }
+            _ => drop(stdin),
+        }
+
+        let output = child
+            .wait_with_output()
+            .context(format!("Failed to run validator: {}", validator.display()))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim_end();
+        if !output.status.success() {
+            let status = match output.status.code() {
+                Some(code) => format!("exit status {}", code),
+                None => "killed by a signal".to_string(),
+            };
+            anyhow::bail!(
+                "Validator {} rejected the configuration ({}):\n{}",
+                validator.display(),
+                status,
+                stderr
+            );
+        }
+        for line in stderr.lines() {
+            println!("  ⚠ {}", line);
+        }
+    }
+
+    Ok(())
+}
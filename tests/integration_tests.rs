@@ -29,6 +29,55 @@ fn ensure_binary_built() {
     }
 }
 
+/// Run a bash script inside a privileged Ubuntu container with the imp binary
+/// mounted at /imp-bin, panicking with the script output if it fails
+fn run_in_privileged_container(name: &str, test_script: &str) -> String {
+    ensure_binary_built();
+    let binary_path = get_imp_binary_path();
+    let binary_dir = binary_path.parent().unwrap().to_str().unwrap();
+
+    // Create an Ubuntu container with privileged mode for mount operations
+    let image = GenericImage::new("ubuntu", "22.04")
+        .with_wait_for(WaitFor::Nothing)
+        .with_cmd(vec!["sleep", "infinity"])
+        .with_privileged(true)
+        .with_mount(Mount::bind_mount(binary_dir, "/imp-bin"));
+
+    let container = image.start().expect("Failed to start container");
+
+    // Write and execute the test script
+    let mut exec_result = container
+        .exec(testcontainers::core::ExecCommand::new(vec![
+            "bash",
+            "-c",
+            &format!("cat > /tmp/test.sh << 'EOFSCRIPT'\n{}\nEOFSCRIPT\nchmod +x /tmp/test.sh && /tmp/test.sh", test_script),
+        ]))
+        .expect("Failed to create and run test script");
+
+    // Check for success
+    let output =
+        String::from_utf8_lossy(&exec_result.stdout_to_vec().expect("Failed to get stdout"))
+            .to_string();
+    let errors =
+        String::from_utf8_lossy(&exec_result.stderr_to_vec().expect("Failed to get stderr"))
+            .to_string();
+
+    let exit_code = exec_result.exit_code().expect("Failed to get exit code");
+    if exit_code != Some(0) {
+        panic!(
+            "{} failed with exit code: {:?}\nStdout: {}\nStderr: {}",
+            name, exit_code, output, errors
+        );
+    }
+
+    println!("STDOUT:\n{}", output);
+    if !errors.is_empty() {
+        println!("STDERR:\n{}", errors);
+    }
+
+    output
+}
+
 #[test]
 fn test_bind_mounts_with_privileged_container() {
     ensure_binary_built();
@@ -318,3 +367,6036 @@ echo "✅ SQLite compatibility tests passed! No 'readonly database' errors!"
         "SQLite compatibility tests did not pass. See output above."
     );
 }
+
+#[test]
+fn test_verify_fix_repairs_mounts_and_symlinks() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/test-persist/home/tmp/test-repo/.cargo
+mkdir -p /tmp/test-persist/config/tmp/test-repo/.config
+mkdir -p /tmp/test-repo
+echo '[build]' > /tmp/test-persist/home/tmp/test-repo/.cargo/config.toml
+touch /tmp/test-persist/home/tmp/test-repo/.testfile
+
+cat > /tmp/test-repo/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/test-persist/home"]
+directories = ["/tmp/test-repo/.cargo"]
+files = ["/tmp/test-repo/.testfile"]
+
+[persistence."/tmp/test-persist/config"]
+directories = ["/tmp/test-repo/.config"]
+EOF
+$IMP --config /tmp/test-repo/imp.toml apply
+
+echo "=== A mount from the wrong source is detected ==="
+umount /tmp/test-repo/.cargo
+mount --bind /tmp/test-persist/config/tmp/test-repo/.config /tmp/test-repo/.cargo
+$IMP --config /tmp/test-repo/imp.toml verify | tee /tmp/verify-wrong-source.log
+grep "wrong source: /tmp/test-repo/.cargo" /tmp/verify-wrong-source.log || { echo "ERROR: wrong mount source not detected"; exit 1; }
+
+echo "=== verify --fix restores lost mounts and symlinks ==="
+rm /tmp/test-repo/.testfile
+$IMP --config /tmp/test-repo/imp.toml verify --fix | grep "Fixed 2 error(s)" || { echo "ERROR: verify --fix did not repair"; exit 1; }
+mount | grep "/tmp/test-repo/.cargo" || { echo "ERROR: .cargo bind mount not restored"; exit 1; }
+test -L /tmp/test-repo/.testfile || { echo "ERROR: .testfile symlink not restored"; exit 1; }
+test -f /tmp/test-repo/.cargo/config.toml || { echo "ERROR: .cargo mounted from wrong source after fix"; exit 1; }
+$IMP --config /tmp/test-repo/imp.toml verify | grep "All symlinks are correctly configured" || { echo "ERROR: still broken after fix"; exit 1; }
+
+echo ""
+echo "✅ Verify fix tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Verify fix tests", test_script);
+
+    assert!(
+        output.contains("✅ Verify fix tests passed!"),
+        "Verify fix tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_migrate_existing_target_content() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+echo "=== Seeding target with existing content ==="
+mkdir -p /var/lib/app/data /persist
+echo 'keep me' > /var/lib/app/app.db
+echo 'nested' > /var/lib/app/data/state.json
+echo 'scratch' > /var/lib/app/cache.tmp
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [
+    { directory = "/var/lib/app", migrate = ["*.db", "data"] },
+]
+EOF
+
+$IMP --config /tmp/imp.toml apply
+
+echo "=== Verifying migrated content landed in the source ==="
+grep 'keep me' /persist/var/lib/app/app.db || { echo "ERROR: app.db not migrated"; exit 1; }
+grep 'nested' /persist/var/lib/app/data/state.json || { echo "ERROR: data/ not migrated"; exit 1; }
+test ! -e /persist/var/lib/app/cache.tmp || { echo "ERROR: unmatched file was migrated"; exit 1; }
+
+echo "=== Verifying migrated content is visible through the mount ==="
+grep 'keep me' /var/lib/app/app.db || { echo "ERROR: app.db not visible through mount"; exit 1; }
+
+echo ""
+echo "✅ Migration tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Migration tests", test_script);
+
+    assert!(
+        output.contains("✅ Migration tests passed!"),
+        "Migration tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_missing_config_file_is_flagged() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/test-persist/home/tmp/test-repo /tmp/test-repo
+touch /tmp/test-persist/home/tmp/test-repo/.testfile
+
+cat > /tmp/test-repo/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/test-persist/home"]
+files = ["/tmp/test-repo/.testfile"]
+EOF
+$IMP --config /tmp/test-repo/imp.toml apply
+
+echo "=== Annotate generations whose config file is missing ==="
+cp /tmp/test-repo/imp.toml /tmp/test-repo/imp-temporary.toml
+$IMP --config /tmp/test-repo/imp-temporary.toml apply
+rm /tmp/test-repo/imp-temporary.toml
+$IMP --config /tmp/test-repo/imp.toml current | grep "(config file missing)" || { echo "ERROR: current did not flag missing config"; exit 1; }
+$IMP --config /tmp/test-repo/imp.toml show 2 | grep "(config file missing)" || { echo "ERROR: show did not flag missing config"; exit 1; }
+$IMP --config /tmp/test-repo/imp.toml list | grep "2 - .*(config file missing)" || { echo "ERROR: list did not flag missing config"; exit 1; }
+! $IMP --config /tmp/test-repo/imp.toml show 1 | grep "(config file missing)" || { echo "ERROR: existing config flagged as missing"; exit 1; }
+
+echo ""
+echo "✅ Missing config tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Missing config tests", test_script);
+
+    assert!(
+        output.contains("✅ Missing config tests passed!"),
+        "Missing config tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_backup_size_limit_evicts_oldest() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/bk /persist
+head -c 4096 /dev/zero > /tmp/bk/a.txt
+head -c 4096 /dev/zero > /tmp/bk/b.txt
+
+cat > /tmp/imp-a.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+max_backup_bytes = 6000
+
+[persistence."/persist"]
+files = ["/tmp/bk/a.txt"]
+EOF
+
+cat > /tmp/imp-b.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+max_backup_bytes = 6000
+
+[persistence."/persist"]
+files = ["/tmp/bk/b.txt"]
+EOF
+
+echo "=== Creating a backup in generation 1 ==="
+$IMP --config /tmp/imp-a.toml apply
+ls /tmp/bk/a.txt.imp-backup.* || { echo "ERROR: a.txt was not backed up"; exit 1; }
+
+# Drop the symlink out-of-band so switching away doesn't restore the backup
+rm /tmp/bk/a.txt
+
+echo "=== Exceeding the limit in generation 2 ==="
+$IMP --config /tmp/imp-b.toml apply | tee /tmp/apply.log
+grep "Evicted backup: /tmp/bk/a.txt.imp-backup" /tmp/apply.log || { echo "ERROR: eviction not reported"; exit 1; }
+! ls /tmp/bk/a.txt.imp-backup.* 2>/dev/null || { echo "ERROR: oldest backup not evicted"; exit 1; }
+ls /tmp/bk/b.txt.imp-backup.* || { echo "ERROR: new generation's backup was evicted"; exit 1; }
+! $IMP --config /tmp/imp-b.toml show 1 | grep "backup:" || { echo "ERROR: evicted backup still referenced"; exit 1; }
+
+echo ""
+echo "✅ Backup limit tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Backup limit tests", test_script);
+
+    assert!(
+        output.contains("✅ Backup limit tests passed!"),
+        "Backup limit tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_verbose_source_resolution_trace() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/persist /tmp/work
+ln -s /tmp/nowhere /tmp/persist/broken
+
+cat > /tmp/missing.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/persist/missing"]
+files = ["/tmp/work/file"]
+EOF
+
+cat > /tmp/broken.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/persist/broken"]
+files = ["/tmp/work/file"]
+EOF
+
+echo "=== Missing intermediate directory ==="
+$IMP --config /tmp/missing.toml --verbose apply --skip-validation 2>&1 | tee /tmp/out.log || true
+grep "path breaks at: /tmp/persist/missing (missing does not exist)" /tmp/out.log || { echo "ERROR: missing component not reported"; exit 1; }
+
+echo "=== Broken symlink in the path ==="
+$IMP --config /tmp/broken.toml --verbose apply --skip-validation 2>&1 | tee /tmp/out.log || true
+grep "path breaks at: /tmp/persist/broken (broken is a broken symlink to /tmp/nowhere" /tmp/out.log || { echo "ERROR: broken symlink not reported"; exit 1; }
+
+echo "=== No trace without --verbose ==="
+$IMP --config /tmp/missing.toml apply --skip-validation 2>&1 | tee /tmp/out.log || true
+! grep "path breaks at" /tmp/out.log || { echo "ERROR: trace shown without --verbose"; exit 1; }
+
+echo ""
+echo "✅ Verbose trace tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Verbose trace tests", test_script);
+
+    assert!(
+        output.contains("✅ Verbose trace tests passed!"),
+        "Verbose trace tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_apply_as_non_root_user() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+id impuser >/dev/null 2>&1 || useradd -m impuser
+mkdir -p /home/impuser/persist /home/impuser/work
+chown impuser:impuser /home/impuser/persist /home/impuser/work
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/home/impuser/persist"]
+directories = ["/home/impuser/work/data"]
+files = ["/home/impuser/work/notes.txt"]
+EOF
+
+$IMP --config /tmp/imp.toml apply --as-user impuser
+
+echo "=== Checking ownership of created paths ==="
+test "$(stat -c %U /home/impuser/persist/home/impuser/work/data)" = impuser || { echo "ERROR: source directory not owned by impuser"; exit 1; }
+test "$(stat -c %U /home/impuser/persist/home/impuser/work/notes.txt)" = impuser || { echo "ERROR: source file not owned by impuser"; exit 1; }
+test "$(stat -c %U /home/impuser/work/notes.txt)" = impuser || { echo "ERROR: symlink not owned by impuser"; exit 1; }
+mount | grep "/home/impuser/work/data" || { echo "ERROR: bind mount missing"; exit 1; }
+
+echo ""
+echo "✅ As-user tests passed!"
+"#;
+
+    let output = run_in_privileged_container("As-user tests", test_script);
+
+    assert!(
+        output.contains("✅ As-user tests passed!"),
+        "As-user tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_delete_dry_run() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/test-persist/home/tmp/test-repo /tmp/test-repo
+touch /tmp/test-persist/home/tmp/test-repo/.testfile
+touch /tmp/test-persist/home/tmp/test-repo/.env
+echo 'local' > /tmp/test-repo/.env
+
+cat > /tmp/test-repo/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/tmp/test-persist/home"]
+files = [
+    "/tmp/test-repo/.testfile",
+    { file = "/tmp/test-repo/.env", restore_backup = false },
+]
+EOF
+$IMP --config /tmp/test-repo/imp.toml apply
+BACKUP=$(ls /tmp/test-repo/.env.imp-backup.*)
+sed -i '/\/tmp\/test-repo\/.env/d' /tmp/test-repo/imp.toml
+$IMP --config /tmp/test-repo/imp.toml apply
+
+echo "=== delete --dry-run reports without deleting ==="
+$IMP --config /tmp/test-repo/imp.toml delete 1 --dry-run | tee /tmp/dry-run.log
+grep "Remove generation 1" /tmp/dry-run.log || { echo "ERROR: dry run did not describe deletion"; exit 1; }
+grep "Orphan backup $BACKUP" /tmp/dry-run.log || { echo "ERROR: orphaned backup not reported"; exit 1; }
+grep "1 backup(s) would be orphaned" /tmp/dry-run.log || { echo "ERROR: orphan count missing"; exit 1; }
+$IMP --config /tmp/test-repo/imp.toml list | grep "1 - " || { echo "ERROR: dry run deleted generation 1"; exit 1; }
+if $IMP --config /tmp/test-repo/imp.toml delete 2 --dry-run 2>&1 | grep -q "Cannot delete active generation"; then
+    echo "✓ Dry run refused active generation"
+else
+    echo "ERROR: dry run should refuse active generation"
+    exit 1
+fi
+
+echo ""
+echo "✅ Delete dry run tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Delete dry run tests", test_script);
+
+    assert!(
+        output.contains("✅ Delete dry run tests passed!"),
+        "Delete dry run tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_namespace_mode_leaves_host_mounts_untouched() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/persist/tmp/ns-target
+echo 'inside' > /tmp/persist/tmp/ns-target/marker
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/persist"]
+directories = ["/tmp/ns-target"]
+EOF
+
+$IMP --config /tmp/imp.toml apply --namespace | tee /tmp/apply.log
+grep "private mount namespace" /tmp/apply.log || { echo "ERROR: namespace mode not announced"; exit 1; }
+grep "Created bind mount: /tmp/ns-target" /tmp/apply.log || { echo "ERROR: mount not created inside namespace"; exit 1; }
+
+echo "=== Host mount table must not contain the namespaced mount ==="
+! grep " /tmp/ns-target " /proc/self/mountinfo || { echo "ERROR: namespaced mount leaked to host"; exit 1; }
+test ! -e /tmp/ns-target/marker || { echo "ERROR: persisted content visible on host"; exit 1; }
+
+echo ""
+echo "✅ Namespace tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Namespace tests", test_script);
+
+    assert!(
+        output.contains("✅ Namespace tests passed!"),
+        "Namespace tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_daemon_holds_mounts_until_sigterm() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/persist/tmp/daemon-target
+echo 'held' > /tmp/persist/tmp/daemon-target/marker
+echo 'file' > /tmp/persist/tmp/daemon-file
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/persist"]
+directories = ["/tmp/daemon-target"]
+files = ["/tmp/daemon-file"]
+EOF
+
+$IMP --config /tmp/imp.toml daemon --verify-interval 1 > /tmp/daemon.log 2>&1 &
+DAEMON_PID=$!
+
+for i in $(seq 1 50); do
+    grep -q "Daemon running" /tmp/daemon.log && break
+    sleep 0.1
+done
+cat /tmp/daemon.log
+grep "Daemon running (pid $DAEMON_PID)" /tmp/daemon.log || { echo "ERROR: daemon did not start"; exit 1; }
+
+echo "=== Mount is visible inside the daemon's namespace only ==="
+nsenter -t $DAEMON_PID -m cat /tmp/daemon-target/marker | grep held || { echo "ERROR: mount missing in daemon namespace"; exit 1; }
+! grep " /tmp/daemon-target " /proc/self/mountinfo || { echo "ERROR: daemon mount leaked to host"; exit 1; }
+test -L /tmp/daemon-file || { echo "ERROR: file symlink not created"; exit 1; }
+
+echo "=== Broken mount is repaired on the next verify tick ==="
+nsenter -t $DAEMON_PID -m umount /tmp/daemon-target
+sleep 2
+nsenter -t $DAEMON_PID -m cat /tmp/daemon-target/marker | grep held || { echo "ERROR: daemon did not repair mount"; exit 1; }
+
+echo "=== SIGTERM tears down the generation ==="
+kill -TERM $DAEMON_PID
+wait $DAEMON_PID
+cat /tmp/daemon.log
+grep "Daemon stopped" /tmp/daemon.log || { echo "ERROR: daemon did not shut down cleanly"; exit 1; }
+test ! -L /tmp/daemon-file || { echo "ERROR: file symlink left behind"; exit 1; }
+$IMP --config /tmp/imp.toml current | grep "No active generation" || { echo "ERROR: generation still active after shutdown"; exit 1; }
+
+echo "=== Without a namespace, shutdown leaves the host as verify expects ==="
+$IMP --config /tmp/imp.toml daemon --no-namespace > /tmp/daemon.log 2>&1 &
+DAEMON_PID=$!
+for i in $(seq 1 50); do
+    grep -q "Daemon running" /tmp/daemon.log && break
+    sleep 0.1
+done
+grep " /tmp/daemon-target " /proc/self/mountinfo || { echo "ERROR: host mount not created"; exit 1; }
+kill -TERM $DAEMON_PID
+wait $DAEMON_PID
+! grep " /tmp/daemon-target " /proc/self/mountinfo || { echo "ERROR: host mount left behind"; exit 1; }
+$IMP --config /tmp/imp.toml current | grep "No active generation" || { echo "ERROR: generation still active after shutdown"; exit 1; }
+
+echo ""
+echo "✅ Daemon tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Daemon tests", test_script);
+
+    assert!(
+        output.contains("✅ Daemon tests passed!"),
+        "Daemon tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_target_root_prefixes_targets() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/image
+mkdir -p /tmp/persist/etc/image-app
+echo 'persisted' > /tmp/persist/etc/image-app/data
+echo 'setting=1' > /tmp/persist/etc/image.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/persist"]
+directories = ["/etc/image-app"]
+files = ["/etc/image.conf"]
+EOF
+
+$IMP --config /tmp/imp.toml --target-root /tmp/image apply | tee /tmp/apply.log
+
+echo "=== Targets land under the target root ==="
+mountpoint -q /tmp/image/etc/image-app || { echo "ERROR: directory not mounted under target root"; exit 1; }
+grep persisted /tmp/image/etc/image-app/data || { echo "ERROR: mounted content wrong"; exit 1; }
+test -L /tmp/image/etc/image.conf || { echo "ERROR: file symlink not under target root"; exit 1; }
+test "$(readlink /tmp/image/etc/image.conf)" = "/tmp/persist/etc/image.conf" || { echo "ERROR: wrong symlink source"; exit 1; }
+
+echo "=== Real root is untouched ==="
+test ! -e /etc/image-app || { echo "ERROR: target created on real root"; exit 1; }
+test ! -e /etc/image.conf || { echo "ERROR: target created on real root"; exit 1; }
+
+echo "=== Recorded generation uses prefixed targets ==="
+$IMP --config /tmp/imp.toml show 1 | tee /tmp/show.log
+grep "/tmp/image/etc/image.conf" /tmp/show.log || { echo "ERROR: generation did not record prefixed target"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+
+echo ""
+echo "✅ Target root tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Target root tests", test_script);
+
+    assert!(
+        output.contains("✅ Target root tests passed!"),
+        "Target root tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_verify_shows_actual_mount_source() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/test-persist/home/tmp/test-repo/.cargo
+mkdir -p /tmp/test-persist/config/tmp/test-repo/.config
+mkdir -p /tmp/test-repo
+
+cat > /tmp/test-repo/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/test-persist/home"]
+directories = ["/tmp/test-repo/.cargo"]
+
+[persistence."/tmp/test-persist/config"]
+directories = ["/tmp/test-repo/.config"]
+EOF
+$IMP --config /tmp/test-repo/imp.toml apply
+
+echo "=== A wrong-source error names both sources ==="
+umount /tmp/test-repo/.cargo
+mount --bind /tmp/test-persist/config/tmp/test-repo/.config /tmp/test-repo/.cargo
+$IMP --config /tmp/test-repo/imp.toml verify | tee /tmp/verify-wrong-source.log
+grep "mounted from /tmp/test-persist/config/tmp/test-repo/.config, expected /tmp/test-persist/home/tmp/test-repo/.cargo" /tmp/verify-wrong-source.log || { echo "ERROR: verify did not show the actual mount source"; exit 1; }
+
+echo ""
+echo "✅ Mount source tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Mount source tests", test_script);
+
+    assert!(
+        output.contains("✅ Mount source tests passed!"),
+        "Mount source tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_backup_name_keeps_extension() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app /persist
+echo 'original = true' > /tmp/app/config.toml
+echo 'original' > /tmp/app/settings.json
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+files = ["/tmp/app/config.toml"]
+EOF
+
+echo "=== Default suffix keeps the original extension ==="
+$IMP --config /tmp/imp.toml apply
+ls /tmp/app/config.toml.imp-backup.* || { echo "ERROR: backup did not keep .toml"; exit 1; }
+! ls /tmp/app/config.backup.* 2>/dev/null || { echo "ERROR: extension was replaced"; exit 1; }
+
+echo "=== Custom suffix ==="
+cat > /tmp/imp-custom.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+backup_suffix = "orig"
+
+[persistence."/persist"]
+files = ["/tmp/app/settings.json"]
+EOF
+
+$IMP --config /tmp/imp-custom.toml apply
+ls /tmp/app/settings.json.orig.* || { echo "ERROR: custom backup suffix not used"; exit 1; }
+
+echo ""
+echo "✅ Backup naming tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Backup naming tests", test_script);
+
+    assert!(
+        output.contains("✅ Backup naming tests passed!"),
+        "Backup naming tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_switch_clean_discards_backups() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app /persist/tmp/app
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+files = []
+EOF
+$IMP --config /tmp/imp.toml apply
+
+# Generation 2 backs up what it replaces
+echo 'stale' > /tmp/app/clean.conf
+echo 'persisted' > /persist/tmp/app/clean.conf
+sed -i 's|files = \[\]|files = ["/tmp/app/clean.conf"]|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+BACKUP=$(ls /tmp/app/clean.conf.imp-backup.*)
+grep -F "$BACKUP" /tmp/imp-state/generations.json || { echo "ERROR: backup not recorded"; exit 1; }
+
+echo "=== switch --clean discards the backups instead of restoring them ==="
+$IMP --config /tmp/imp.toml switch 1 --clean | tee /tmp/switch.log
+grep "Discarded backup: $BACKUP" /tmp/switch.log || { echo "ERROR: discard not reported"; exit 1; }
+[ -e /tmp/app/clean.conf ] && { echo "ERROR: stale content restored: $(cat /tmp/app/clean.conf)"; exit 1; }
+[ -e "$BACKUP" ] && { echo "ERROR: backup still on disk"; exit 1; }
+grep -F "$BACKUP" /tmp/imp-state/generations.json && { echo "ERROR: backup still recorded"; exit 1; }
+[ "$(cat /persist/tmp/app/clean.conf)" = "persisted" ] || { echo "ERROR: persisted data touched"; exit 1; }
+
+echo "=== Switching back still works ==="
+$IMP --config /tmp/imp.toml switch 2
+[ "$(cat /tmp/app/clean.conf)" = "persisted" ] || { echo "ERROR: generation 2 not recreated"; exit 1; }
+
+echo "=== Without --clean the backup is restored ==="
+echo 'stale' > /tmp/app/other.conf
+sed -i 's|files = \["/tmp/app/clean.conf"\]|files = ["/tmp/app/other.conf"]|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+$IMP --config /tmp/imp.toml switch 1
+[ "$(cat /tmp/app/other.conf)" = "stale" ] || { echo "ERROR: backup not restored without --clean"; exit 1; }
+
+echo ""
+echo "✅ Switch clean tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Switch clean tests", test_script);
+
+    assert!(
+        output.contains("✅ Switch clean tests passed!"),
+        "Switch clean tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_restore_backup_per_entry() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app/keep /tmp/app/skip /persist/tmp/app/keep /persist/tmp/app/skip
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+files = []
+EOF
+$IMP --config /tmp/imp.toml apply
+
+# Generation 2 backs up content at four targets, two of which opt out of restoring
+echo 'old keep' > /tmp/app/keep.conf
+echo 'old skip' > /tmp/app/skip.conf
+echo 'old keep dir' > /tmp/app/keep/data
+echo 'old skip dir' > /tmp/app/skip/data
+echo 'persisted' > /persist/tmp/app/keep.conf
+echo 'persisted' > /persist/tmp/app/skip.conf
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+directories = [
+    "/tmp/app/keep",
+    { directory = "/tmp/app/skip", restore_backup = false },
+]
+files = [
+    "/tmp/app/keep.conf",
+    { file = "/tmp/app/skip.conf", restore_backup = false },
+]
+EOF
+$IMP --config /tmp/imp.toml apply
+FILE_BACKUP=$(ls /tmp/app/skip.conf.imp-backup.*)
+DIR_BACKUP=$(ls -d /tmp/app/skip.imp-backup.*)
+[ "$(grep -c '"restore_backup": false' /tmp/imp-state/generations.json)" = "2" ] || { echo "ERROR: restore_backup not recorded"; exit 1; }
+
+echo "=== The dry run shows which backups are kept ==="
+$IMP --config /tmp/imp.toml switch 1 --dry-run | tee /tmp/plan.log
+grep "/tmp/app/keep.conf .*restoring backup" /tmp/plan.log || { echo "ERROR: restore not planned"; exit 1; }
+grep "keeping backup $FILE_BACKUP" /tmp/plan.log || { echo "ERROR: kept file backup not planned"; exit 1; }
+grep "keeping backup $DIR_BACKUP" /tmp/plan.log || { echo "ERROR: kept dir backup not planned"; exit 1; }
+$IMP --config /tmp/imp.toml switch 1 --dry-run --json > /tmp/plan.json
+grep "\"keep_backup\": \"$FILE_BACKUP\"" /tmp/plan.json || { echo "ERROR: keep_backup missing from JSON"; exit 1; }
+
+echo "=== Switching away restores only the other entries' backups ==="
+$IMP --config /tmp/imp.toml switch 1 | tee /tmp/switch.log
+[ "$(cat /tmp/app/keep.conf)" = "old keep" ] || { echo "ERROR: file backup not restored"; exit 1; }
+[ "$(cat /tmp/app/keep/data)" = "old keep dir" ] || { echo "ERROR: dir backup not restored"; exit 1; }
+[ -e /tmp/app/skip.conf ] && { echo "ERROR: skipped file backup restored"; exit 1; }
+[ -e /tmp/app/skip/data ] && { echo "ERROR: skipped dir backup restored"; exit 1; }
+[ "$(cat "$FILE_BACKUP")" = "old skip" ] || { echo "ERROR: file backup not kept"; exit 1; }
+[ "$(cat "$DIR_BACKUP/data")" = "old skip dir" ] || { echo "ERROR: dir backup not kept"; exit 1; }
+grep "Kept backup (restore_backup = false): $FILE_BACKUP" /tmp/switch.log || { echo "ERROR: kept backup not reported"; exit 1; }
+grep -F "$FILE_BACKUP" /tmp/imp-state/generations.json || { echo "ERROR: kept backup no longer recorded"; exit 1; }
+
+echo "=== Switching back leaves the kept backups alone ==="
+$IMP --config /tmp/imp.toml switch 2 --backup-on-switch
+[ "$(cat /tmp/app/skip.conf)" = "persisted" ] || { echo "ERROR: generation 2 not recreated"; exit 1; }
+[ -e "$FILE_BACKUP" ] || { echo "ERROR: kept backup lost"; exit 1; }
+
+echo "=== A failed apply still restores every backup it took ==="
+# The mount shows the source's owner, so --strict-ownership fails and the apply is undone
+mkdir -p /persist2/tmp/own/data /persist2/tmp/app /tmp/own
+echo 'migrated' > /tmp/app/later.conf
+echo 'persisted' > /persist2/tmp/app/later.conf
+cat > /tmp/fail.toml <<'EOF'
+state_dir = "/tmp/imp-state-fail"
+backup = true
+
+[persistence."/persist2"]
+directories = [{ directory = "/tmp/own/data", user = "nobody" }]
+files = [{ file = "/tmp/app/later.conf", restore_backup = false }]
+EOF
+$IMP --config /tmp/fail.toml apply --strict-ownership && { echo "ERROR: apply should have failed"; exit 1; }
+[ "$(cat /tmp/app/later.conf)" = "migrated" ] || { echo "ERROR: backup of failed apply not restored"; exit 1; }
+
+echo ""
+echo "✅ Per-entry restore_backup tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Per-entry restore_backup tests", test_script);
+
+    assert!(
+        output.contains("✅ Per-entry restore_backup tests passed!"),
+        "Per-entry restore_backup tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_compressed_backup_round_trip() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app/data/nested /persist
+echo 'original' > /tmp/app/data/settings.conf
+echo 'deep' > /tmp/app/data/nested/file.txt
+ln -s settings.conf /tmp/app/data/link
+chmod 750 /tmp/app/data
+chmod 600 /tmp/app/data/settings.conf
+find /tmp/app/data -printf '%P %y %m %s\n' | sort > /tmp/before.txt
+
+for COMPRESSION in zstd:tar.zst gzip:tar.gz; do
+EXTENSION=${COMPRESSION#*:}
+COMPRESSION=${COMPRESSION%%:*}
+cat > /tmp/imp.toml <<EOF
+state_dir = "/tmp/imp-state"
+backup = true
+backup_compression = "$COMPRESSION"
+
+[persistence."/persist"]
+directories = ["/tmp/app/data"]
+EOF
+
+echo "=== Directory is backed up as a single $COMPRESSION archive ==="
+$IMP --config /tmp/imp.toml apply
+BACKUP=$(ls -d /tmp/app/data.imp-backup.*)
+case "$BACKUP" in *.$EXTENSION) ;; *) echo "ERROR: backup not compressed: $BACKUP"; exit 1 ;; esac
+[ -f "$BACKUP" ] || { echo "ERROR: backup is not a file"; exit 1; }
+grep -F "$BACKUP" /tmp/imp-state/generations.json || { echo "ERROR: archive not recorded"; exit 1; }
+[ -e /tmp/app/data/settings.conf ] && { echo "ERROR: old content still visible"; exit 1; }
+
+echo "=== Removing the generation restores the $COMPRESSION archive ==="
+sed -i 's|directories = \["/tmp/app/data"\]|directories = []|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+[ -e "$BACKUP" ] && { echo "ERROR: archive left behind after restore"; exit 1; }
+find /tmp/app/data -printf '%P %y %m %s\n' | sort > /tmp/after.txt
+diff /tmp/before.txt /tmp/after.txt || { echo "ERROR: restored content differs"; exit 1; }
+[ "$(readlink /tmp/app/data/link)" = "settings.conf" ] || { echo "ERROR: symlink not restored"; exit 1; }
+done
+
+echo "=== Unsupported compression is rejected ==="
+sed -i 's|"gzip"|"bzip2"|' /tmp/imp.toml
+if $IMP --config /tmp/imp.toml apply > /tmp/bad.log 2>&1; then
+    echo "ERROR: unknown compression accepted"; exit 1
+fi
+grep "backup_compression" /tmp/bad.log || { cat /tmp/bad.log; echo "ERROR: bad value not reported"; exit 1; }
+
+echo ""
+echo "✅ Compressed backup tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Compressed backup tests", test_script);
+
+    assert!(
+        output.contains("✅ Compressed backup tests passed!"),
+        "Compressed backup tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_strict_verify_reports_drift() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app /persist
+echo 'original' > /tmp/app/settings.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+files = ["/tmp/app/settings.conf"]
+EOF
+
+$IMP --config /tmp/imp.toml apply
+$IMP --config /tmp/imp.toml verify --strict || { echo "ERROR: strict verify failed on a clean generation"; exit 1; }
+
+echo "=== Missing backup only fails in strict mode ==="
+rm /tmp/app/settings.conf.imp-backup.*
+$IMP --config /tmp/imp.toml verify || { echo "ERROR: lenient verify should still pass"; exit 1; }
+if $IMP --config /tmp/imp.toml verify --strict > /tmp/strict.log 2>&1; then
+    echo "ERROR: strict verify should fail on a missing backup"; exit 1
+fi
+grep "Backup is missing" /tmp/strict.log || { echo "ERROR: missing backup not reported"; exit 1; }
+
+echo "=== Missing source is reported in strict mode ==="
+rm /persist/tmp/app/settings.conf
+if $IMP --config /tmp/imp.toml verify --strict > /tmp/strict.log 2>&1; then
+    echo "ERROR: strict verify should fail on a missing source"; exit 1
+fi
+grep "Source does not exist: /persist/tmp/app/settings.conf" /tmp/strict.log || { echo "ERROR: missing source not reported"; exit 1; }
+
+echo ""
+echo "✅ Strict verify tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Strict verify tests", test_script);
+
+    assert!(
+        output.contains("✅ Strict verify tests passed!"),
+        "Strict verify tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_preserve_symlinked_source() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/dotfiles /persist/tmp/home /tmp/home
+echo 'v2' > /persist/dotfiles/v2.conf
+ln -s /persist/dotfiles/v2.conf /persist/dotfiles/current.conf
+ln -s /persist/dotfiles/current.conf /persist/tmp/home/resolved.conf
+ln -s /persist/dotfiles/current.conf /persist/tmp/home/preserved.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = [
+    "/tmp/home/resolved.conf",
+    { file = "/tmp/home/preserved.conf", preserve_symlink = true },
+]
+EOF
+
+$IMP --config /tmp/imp.toml apply
+
+echo "=== Default mode links to the fully resolved source ==="
+test "$(readlink /tmp/home/resolved.conf)" = "/persist/dotfiles/v2.conf" || { echo "ERROR: default mode did not canonicalize"; exit 1; }
+
+echo "=== preserve_symlink recreates the source symlink verbatim ==="
+test "$(readlink /tmp/home/preserved.conf)" = "/persist/dotfiles/current.conf" || { echo "ERROR: symlink was not preserved"; exit 1; }
+grep v2 /tmp/home/preserved.conf || { echo "ERROR: preserved symlink does not resolve"; exit 1; }
+
+$IMP --config /tmp/imp.toml verify --strict
+
+echo ""
+echo "✅ Preserve symlink tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Preserve symlink tests", test_script);
+
+    assert!(
+        output.contains("✅ Preserve symlink tests passed!"),
+        "Preserve symlink tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_config_format_override() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app /persist/tmp/app
+echo 'toml' > /persist/tmp/app/toml.conf
+echo 'json' > /persist/tmp/app/json.conf
+
+cat > /tmp/imp-config <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/app/toml.conf"]
+EOF
+
+cat > /tmp/imp-config-json <<'EOF'
+{
+  "state_dir": "/tmp/imp-state",
+  "persistence": { "/persist": { "files": ["/tmp/app/json.conf"] } }
+}
+EOF
+
+echo "=== Forcing TOML on an extensionless file ==="
+$IMP --config /tmp/imp-config --config-format toml apply
+[ "$(cat /tmp/app/toml.conf)" = "toml" ] || { echo "ERROR: TOML config not applied"; exit 1; }
+
+echo "=== Forcing JSON on an extensionless file ==="
+if $IMP --config /tmp/imp-config-json apply > /tmp/bad.log 2>&1; then
+    echo "ERROR: JSON parsed as TOML without the flag"; exit 1
+fi
+$IMP --config /tmp/imp-config-json --config-format json apply
+[ "$(cat /tmp/app/json.conf)" = "json" ] || { echo "ERROR: JSON config not applied"; exit 1; }
+[ -e /tmp/app/toml.conf ] && { echo "ERROR: previous generation not replaced"; exit 1; }
+
+echo "=== A .json extension is detected ==="
+cp /tmp/imp-config-json /tmp/imp.json
+$IMP --config /tmp/imp.json apply
+
+echo "=== YAML by extension or flag ==="
+echo 'yaml' > /persist/tmp/app/yaml.conf
+cat > /tmp/imp-config-yaml <<'EOF'
+state_dir: /tmp/imp-state
+persistence:
+  /persist:
+    files:
+      - /tmp/app/yaml.conf
+EOF
+cp /tmp/imp-config-yaml /tmp/imp.yaml
+$IMP --config /tmp/imp.yaml apply
+[ "$(cat /tmp/app/yaml.conf)" = "yaml" ] || { echo "ERROR: YAML config not applied"; exit 1; }
+$IMP --config /tmp/imp-config-yaml --config-format yaml validate || { echo "ERROR: forced YAML not parsed"; exit 1; }
+
+echo "=== The flag wins over the extension ==="
+cp /tmp/imp-config-json /tmp/misnamed.toml
+$IMP --config /tmp/misnamed.toml --config-format json apply
+if $IMP --config /tmp/imp.json --config-format toml apply > /tmp/bad.log 2>&1; then
+    echo "ERROR: extension overrode the flag"; exit 1
+fi
+
+echo "=== Config from stdin ==="
+$IMP --config - --config-format toml apply < /tmp/imp-config
+[ "$(cat /tmp/app/toml.conf)" = "toml" ] || { echo "ERROR: stdin config not applied"; exit 1; }
+$IMP --config - --config-format json list < /tmp/imp-config-json | grep "5 - " || { echo "ERROR: stdin JSON not read"; exit 1; }
+
+echo "=== Unknown formats are rejected ==="
+if $IMP --config /tmp/imp-config --config-format ini apply > /tmp/bad.log 2>&1; then
+    echo "ERROR: unknown format accepted"; exit 1
+fi
+grep "possible values: toml, json, yaml" /tmp/bad.log || { cat /tmp/bad.log; echo "ERROR: choices not listed"; exit 1; }
+
+echo ""
+echo "✅ Config format tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Config format tests", test_script);
+
+    assert!(
+        output.contains("✅ Config format tests passed!"),
+        "Config format tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_remote_config_url_with_offline_cache() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+if ! command -v python3 > /dev/null; then
+    apt-get update -qq
+    apt-get install -y python3 > /dev/null 2>&1
+fi
+
+mkdir -p /tmp/www /tmp/remote-target
+cat > /tmp/www/host.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/persist"]
+files = ["/tmp/remote-target/app.conf"]
+EOF
+cat > /tmp/www/host.json <<'EOF'
+{"state_dir": "/tmp/imp-state-json", "persistence": {"/tmp/persist": {"files": ["/tmp/remote-target/json.conf"]}}}
+EOF
+
+python3 -m http.server 8765 --bind 127.0.0.1 --directory /tmp/www > /tmp/http.log 2>&1 &
+SERVER_PID=$!
+for i in $(seq 1 50); do
+    python3 -c "import urllib.request; urllib.request.urlopen('http://127.0.0.1:8765/host.toml')" 2>/dev/null && break
+    sleep 0.1
+done
+
+URL="http://127.0.0.1:8765/host.toml"
+
+echo "=== A token is never sent over plain HTTP ==="
+if IMP_CONFIG_TOKEN=secret $IMP --config "http://127.0.0.1:8765/token.toml" apply > /tmp/err.log 2>&1; then
+    echo "ERROR: token sent over http"; exit 1
+fi
+grep "Refusing to send IMP_CONFIG_TOKEN over plain http" /tmp/err.log || { cat /tmp/err.log; echo "ERROR: unclear token error"; exit 1; }
+! grep "GET /token.toml" /tmp/http.log || { echo "ERROR: config requested with a token"; exit 1; }
+
+echo "=== Apply a config fetched over HTTP ==="
+$IMP --config "$URL" apply
+test -L /tmp/remote-target/app.conf || { echo "ERROR: remote config was not applied"; exit 1; }
+$IMP --config "$URL" current | tee /tmp/current.log
+grep "Config: $URL" /tmp/current.log || { echo "ERROR: source URL not recorded"; exit 1; }
+! grep "config file missing" /tmp/current.log || { echo "ERROR: URL flagged as missing file"; exit 1; }
+
+echo "=== The cache keeps the config's format ==="
+$IMP --config "http://127.0.0.1:8765/host.json" apply > /dev/null
+ls ~/.local/share/imp/remote-configs/ | tee /tmp/cache.log
+grep -x "http___127_0_0_1_8765_host_toml.toml" /tmp/cache.log || { echo "ERROR: TOML config not cached"; exit 1; }
+grep -x "http___127_0_0_1_8765_host_json.json" /tmp/cache.log || { echo "ERROR: JSON config cached with the wrong extension"; exit 1; }
+
+echo "=== Fall back to the cached copy when offline ==="
+kill $SERVER_PID
+wait $SERVER_PID 2>/dev/null || true
+$IMP --config "$URL" list | tee /tmp/list.log
+grep "Using cached config" /tmp/list.log || { echo "ERROR: cached config not used"; exit 1; }
+grep "1 -" /tmp/list.log || { echo "ERROR: state dir from cached config not used"; exit 1; }
+
+echo "=== Unreachable URL without a cache fails cleanly ==="
+if $IMP --config "http://127.0.0.1:8765/other.toml" apply > /tmp/err.log 2>&1; then
+    echo "ERROR: apply should fail without network or cache"; exit 1
+fi
+grep "Failed to fetch config" /tmp/err.log || { echo "ERROR: unclear network error"; exit 1; }
+
+echo ""
+echo "✅ Remote config tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Remote config tests", test_script);
+
+    assert!(
+        output.contains("✅ Remote config tests passed!"),
+        "Remote config tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_apply_verifies_and_rolls_back() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app /persist/tmp/app /persist2/tmp/app
+echo 'first' > /persist/tmp/app/a.conf
+echo 'second' > /persist2/tmp/app/a.conf
+echo 'other' > /persist/tmp/app/b.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/app/b.conf"]
+EOF
+$IMP --config /tmp/imp.toml apply
+
+# Both sections claim a.conf: each link is created without error, but the
+# second replaces the first, so the first no longer matches afterwards
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/app/a.conf"]
+
+[persistence."/persist2"]
+files = ["/tmp/app/a.conf"]
+EOF
+
+echo "=== An inconsistent apply is rolled back ==="
+if $IMP --config /tmp/imp.toml apply > /tmp/apply.log 2>&1; then
+    cat /tmp/apply.log; echo "ERROR: apply succeeded despite the inconsistency"; exit 1
+fi
+cat /tmp/apply.log
+grep "generation 2 was not created" /tmp/apply.log || { echo "ERROR: verify failure not reported"; exit 1; }
+grep "problem(s) found after applying" /tmp/apply.log || { echo "ERROR: error not returned"; exit 1; }
+grep "Restoring symlinks and mounts from generation 1" /tmp/apply.log || { echo "ERROR: no rollback"; exit 1; }
+[ -e /tmp/app/a.conf ] && { echo "ERROR: links of the failed apply left behind"; exit 1; }
+[ "$(readlink /tmp/app/b.conf)" = "/persist/tmp/app/b.conf" ] || { echo "ERROR: previous generation not restored"; exit 1; }
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=1 active=1" ] || { echo "ERROR: generation recorded"; exit 1; }
+$IMP --config /tmp/imp.toml verify || { echo "ERROR: generation 1 broken after rollback"; exit 1; }
+
+echo "=== --no-verify keeps the links ==="
+$IMP --config /tmp/imp.toml apply --no-verify
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=2 active=2" ] || { echo "ERROR: --no-verify did not activate"; exit 1; }
+
+echo ""
+echo "✅ Apply verify tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Apply verify tests", test_script);
+
+    assert!(
+        output.contains("✅ Apply verify tests passed!"),
+        "Apply verify tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_apply_interrupt_rolls_back() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app /tmp/many /persist/tmp/app /persist/tmp/many
+echo 'kept' > /persist/tmp/app/keep.conf
+touch /persist/tmp/many/f{1..3000}
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/app/keep.conf"]
+EOF
+$IMP --config /tmp/imp.toml apply
+
+{
+    echo 'state_dir = "/tmp/imp-state"'
+    echo '[persistence."/persist"]'
+    echo 'directories = ["/tmp/many/dir"]'
+    echo 'files = ['
+    for i in $(seq 1 3000); do echo "'/tmp/many/f$i',"; done
+    echo ']'
+} > /tmp/imp.toml
+
+echo "=== SIGINT mid-apply rolls back ==="
+IMP_NO_MOUNT=1 $IMP --config /tmp/imp.toml apply > /tmp/apply.log 2>&1 &
+PID=$!
+for i in $(seq 1 500); do grep -q "Created symlink" /tmp/apply.log && break; sleep 0.01; done
+kill -INT $PID
+RC=0; wait $PID || RC=$?
+cat /tmp/apply.log | grep -v "Created symlink\|Removed symlink"
+[ $RC -ne 0 ] || { echo "ERROR: interrupted apply succeeded"; exit 1; }
+grep "Interrupted after" /tmp/apply.log || { echo "ERROR: interruption not reported"; exit 1; }
+CREATED=$(grep -c "Created symlink: /tmp/many" /tmp/apply.log)
+[ "$CREATED" -lt 3000 ] || { echo "ERROR: signal arrived too late to test anything"; exit 1; }
+grep "Generation 2 will not be created; undoing the entries applied so far" /tmp/apply.log || { echo "ERROR: no rollback message"; exit 1; }
+grep "Apply interrupted; generation 1 is active again" /tmp/apply.log || { echo "ERROR: wrong error"; exit 1; }
+
+LEFT=$(find /tmp/many -maxdepth 1 -type l | wc -l)
+[ "$LEFT" -eq 0 ] || { echo "ERROR: $LEFT symlinks of the partial apply left behind"; exit 1; }
+[ "$(cat /tmp/app/keep.conf)" = "kept" ] || { echo "ERROR: previous generation not restored"; exit 1; }
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=1 active=1" ] || { echo "ERROR: partial generation recorded"; exit 1; }
+
+echo ""
+echo "✅ Interrupt tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Interrupt tests", test_script);
+
+    assert!(
+        output.contains("✅ Interrupt tests passed!"),
+        "Interrupt tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_validator_plugins() {
+    let test_script = r##"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app /tmp/etc /persist/tmp/app /persist/tmp/etc
+echo 'ok' > /persist/tmp/app/ok.conf
+echo 'secret' > /persist/tmp/etc/shadow
+
+cat > /tmp/imp-policy <<'EOF'
+#!/bin/bash
+plan=$(cat)
+echo "$plan" > /tmp/plan.json
+if echo "$plan" | grep -q '"target":"/tmp/etc/'; then
+    echo "policy: no persistence target may be under /tmp/etc" >&2
+    exit 3
+fi
+EOF
+chmod +x /tmp/imp-policy
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+validators = ["/tmp/imp-policy"]
+
+[persistence."/persist"]
+directories = [{ directory = "/tmp/app/data", mode = "0750" }]
+files = ["/tmp/app/ok.conf"]
+EOF
+
+echo "=== An accepted plan is applied ==="
+$IMP --config /tmp/imp.toml apply | tee /tmp/apply.log
+grep "Running validator: /tmp/imp-policy" /tmp/apply.log || { echo "ERROR: validator not run"; exit 1; }
+grep -F '"version":1' /tmp/plan.json || { echo "ERROR: plan version missing"; exit 1; }
+grep -F '{"target":"/tmp/app/ok.conf","source":"/persist/tmp/app/ok.conf","kind":"symlink"' /tmp/plan.json || { cat /tmp/plan.json; echo "ERROR: file entry missing from plan"; exit 1; }
+grep -F '"target":"/tmp/app/data","source":"/persist/tmp/app/data","kind":"mount","user":null,"group":null,"mode":"0750"' /tmp/plan.json || { cat /tmp/plan.json; echo "ERROR: directory entry missing from plan"; exit 1; }
+
+echo "=== A rejected plan aborts the apply ==="
+sed -i 's|files = \["/tmp/app/ok.conf"\]|files = ["/tmp/app/ok.conf", "/tmp/etc/shadow"]|' /tmp/imp.toml
+if $IMP --config /tmp/imp.toml apply > /tmp/bad.log 2>&1; then
+    echo "ERROR: apply succeeded despite the validator"; exit 1
+fi
+cat /tmp/bad.log
+grep "Validator /tmp/imp-policy rejected the configuration (exit status 3):" /tmp/bad.log || { echo "ERROR: rejection not reported"; exit 1; }
+grep "policy: no persistence target may be under /tmp/etc" /tmp/bad.log || { echo "ERROR: stderr not surfaced"; exit 1; }
+[ -e /tmp/etc/shadow ] && { echo "ERROR: rejected entry applied"; exit 1; }
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=1 active=1" ] || { echo "ERROR: generation created"; exit 1; }
+
+echo "=== A missing validator fails validation ==="
+sed -i 's|/tmp/imp-policy|/tmp/no-such-validator|' /tmp/imp.toml
+if $IMP --config /tmp/imp.toml apply > /tmp/bad.log 2>&1; then
+    echo "ERROR: missing validator ignored"; exit 1
+fi
+grep "Failed to run validator: /tmp/no-such-validator" /tmp/bad.log || { cat /tmp/bad.log; echo "ERROR: wrong error"; exit 1; }
+
+echo ""
+echo "✅ Validator tests passed!"
+"##;
+
+    let output = run_in_privileged_container("Validator tests", test_script);
+
+    assert!(
+        output.contains("✅ Validator tests passed!"),
+        "Validator tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_apply_compare_to_current() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/cmp /persist /tmp/persist-new
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/cmp/kept.conf", "/tmp/cmp/dropped.conf", "/tmp/cmp/moved.conf"]
+EOF
+
+$IMP --config /tmp/imp.toml apply --compare-to-current | tee /tmp/first.log
+grep "No active generation: all 3 target(s) are new" /tmp/first.log || { echo "ERROR: first apply summary wrong"; exit 1; }
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/cmp/kept.conf", "/tmp/cmp/added.conf"]
+
+[persistence."/tmp/persist-new"]
+files = ["/tmp/cmp/moved.conf"]
+EOF
+
+$IMP --config /tmp/imp.toml apply --compare-to-current | tee /tmp/second.log
+grep "Changes compared to generation 1:" /tmp/second.log || { echo "ERROR: no comparison header"; exit 1; }
+grep "+ /tmp/cmp/added.conf -> /persist/tmp/cmp/added.conf" /tmp/second.log || { echo "ERROR: added target not shown"; exit 1; }
+grep "\- /tmp/cmp/dropped.conf -> /persist/tmp/cmp/dropped.conf" /tmp/second.log || { echo "ERROR: removed target not shown"; exit 1; }
+grep "~ /tmp/cmp/moved.conf: /persist/tmp/cmp/moved.conf -> /tmp/persist-new/tmp/cmp/moved.conf" /tmp/second.log || { echo "ERROR: changed target not shown"; exit 1; }
+grep "1 added, 1 removed, 1 changed, 1 unchanged" /tmp/second.log || { echo "ERROR: summary counts wrong"; exit 1; }
+
+echo ""
+echo "✅ Compare to current tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Compare to current tests", test_script);
+
+    assert!(
+        output.contains("✅ Compare to current tests passed!"),
+        "Compare to current tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_skip_missing_optional_sources() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/opt/present-dir /tmp/opt
+echo 'here' > /persist/tmp/opt/present.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [
+    "/tmp/opt/present-dir",
+    { directory = "/tmp/opt/staged-dir", optional = true },
+]
+files = [
+    "/tmp/opt/present.conf",
+    { file = "/tmp/opt/staged.conf", optional = true },
+]
+EOF
+
+echo "=== Optional entries with missing sources are skipped and reported ==="
+$IMP --config /tmp/imp.toml apply | tee /tmp/apply.log
+grep "Skipping /tmp/opt/staged-dir: source does not exist: /persist/tmp/opt/staged-dir" /tmp/apply.log || { echo "ERROR: skipped dir not reported"; exit 1; }
+grep "Skipping /tmp/opt/staged.conf: source does not exist: /persist/tmp/opt/staged.conf" /tmp/apply.log || { echo "ERROR: skipped file not reported"; exit 1; }
+grep "Skipped: 2" /tmp/apply.log || { echo "ERROR: skip summary missing"; exit 1; }
+test ! -e /persist/tmp/opt/staged-dir || { echo "ERROR: optional source was created"; exit 1; }
+test ! -e /tmp/opt/staged.conf || { echo "ERROR: skipped target was created"; exit 1; }
+mountpoint -q /tmp/opt/present-dir || { echo "ERROR: present dir not mounted"; exit 1; }
+test -L /tmp/opt/present.conf || { echo "ERROR: present file not linked"; exit 1; }
+$IMP --config /tmp/imp.toml show 1 | grep -c " -> " | grep -x 2 || { echo "ERROR: skipped entries recorded in generation"; exit 1; }
+
+echo "=== --skip-missing treats every entry as optional ==="
+cat > /tmp/imp-all.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/opt/present.conf", "/tmp/opt/not-yet.conf"]
+EOF
+$IMP --config /tmp/imp-all.toml apply --skip-missing | tee /tmp/apply-all.log
+grep "Skipping /tmp/opt/not-yet.conf" /tmp/apply-all.log || { echo "ERROR: --skip-missing did not skip"; exit 1; }
+test ! -e /persist/tmp/opt/not-yet.conf || { echo "ERROR: source created despite --skip-missing"; exit 1; }
+
+echo ""
+echo "✅ Skip missing tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Skip missing tests", test_script);
+
+    assert!(
+        output.contains("✅ Skip missing tests passed!"),
+        "Skip missing tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_verify_detects_mount_option_drift() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/opts-target
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/opts-target"]
+EOF
+
+$IMP --config /tmp/imp.toml apply
+grep '"mount_options"' /tmp/imp-state/generations.json || { echo "ERROR: mount options not recorded"; exit 1; }
+$IMP --config /tmp/imp.toml verify | grep "All symlinks are correctly configured" || { echo "ERROR: fresh mount flagged"; exit 1; }
+
+echo "=== Remounting read-only is reported as drift ==="
+mount -o remount,bind,ro /tmp/opts-target
+$IMP --config /tmp/imp.toml verify | tee /tmp/verify.log
+grep "Mount options drifted on /tmp/opts-target: ro" /tmp/verify.log || { echo "ERROR: option drift not detected"; exit 1; }
+grep "(expected rw" /tmp/verify.log || { echo "ERROR: expected options not shown"; exit 1; }
+
+echo "=== verify --fix restores the recorded options ==="
+$IMP --config /tmp/imp.toml verify --fix | grep "Fixed 1 error(s)" || { echo "ERROR: drift not repaired"; exit 1; }
+touch /tmp/opts-target/writable || { echo "ERROR: mount still read-only"; exit 1; }
+
+echo ""
+echo "✅ Mount option drift tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Mount option drift tests", test_script);
+
+    assert!(
+        output.contains("✅ Mount option drift tests passed!"),
+        "Mount option drift tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_verify_repair_dry_run() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/lost-mount /persist/tmp/wrong-mount /tmp/elsewhere
+echo data > /persist/tmp/repoint.conf
+echo other > /tmp/other.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/lost-mount", "/tmp/wrong-mount"]
+files = ["/tmp/repoint.conf"]
+EOF
+
+$IMP --config /tmp/imp.toml apply
+
+echo "=== Breaking every entry ==="
+umount /tmp/lost-mount
+umount /tmp/wrong-mount
+mount --bind /tmp/elsewhere /tmp/wrong-mount
+ln -sfn /tmp/other.conf /tmp/repoint.conf
+
+echo "=== The plan matches the broken state ==="
+$IMP --config /tmp/imp.toml verify --fix --dry-run | tee /tmp/plan.log
+grep "Repair plan for generation 1:" /tmp/plan.log || { echo "ERROR: no plan header"; exit 1; }
+grep -- "- bind mount /persist/tmp/lost-mount at /tmp/lost-mount" /tmp/plan.log || { echo "ERROR: lost mount not planned"; exit 1; }
+grep -- "- unmount /tmp/wrong-mount" /tmp/plan.log || { echo "ERROR: wrong mount not unmounted"; exit 1; }
+grep -- "- bind mount /persist/tmp/wrong-mount at /tmp/wrong-mount" /tmp/plan.log || { echo "ERROR: wrong mount not remounted"; exit 1; }
+grep -- "- remove symlink /tmp/repoint.conf" /tmp/plan.log || { echo "ERROR: wrong symlink not removed"; exit 1; }
+grep -- "- create symlink /tmp/repoint.conf -> /persist/tmp/repoint.conf" /tmp/plan.log || { echo "ERROR: symlink not recreated"; exit 1; }
+grep -- "- unmount /tmp/lost-mount" /tmp/plan.log && { echo "ERROR: planned to unmount something not mounted"; exit 1; }
+grep "Nothing was changed." /tmp/plan.log || { echo "ERROR: dry run not announced"; exit 1; }
+
+echo "=== Nothing was changed ==="
+mountpoint -q /tmp/lost-mount && { echo "ERROR: dry run mounted"; exit 1; }
+[ "$(readlink /tmp/repoint.conf)" = "/tmp/other.conf" ] || { echo "ERROR: dry run touched the symlink"; exit 1; }
+$IMP --config /tmp/imp.toml verify | grep "Found 3 error(s)" || { echo "ERROR: errors changed after dry run"; exit 1; }
+
+echo "=== --dry-run needs --fix ==="
+$IMP --config /tmp/imp.toml verify --dry-run 2>/dev/null && { echo "ERROR: --dry-run accepted without --fix"; exit 1; }
+
+echo "=== The real repair carries out the plan ==="
+$IMP --config /tmp/imp.toml verify --fix | grep "Fixed 3 error(s)" || { echo "ERROR: repair failed"; exit 1; }
+[ "$(readlink /tmp/repoint.conf)" = "/persist/tmp/repoint.conf" ] || { echo "ERROR: symlink not repaired"; exit 1; }
+
+echo ""
+echo "✅ Repair dry run tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Repair dry run tests", test_script);
+
+    assert!(
+        output.contains("✅ Repair dry run tests passed!"),
+        "Repair dry run tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_fsck_reconciles_state_and_filesystem() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/fsck-a /persist/tmp/fsck-old /persist/tmp/fsck-stray /tmp/fsck-stray /tmp/elsewhere
+echo data > /persist/tmp/fsck.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/fsck-a", "/tmp/fsck-old"]
+files = ["/tmp/fsck.conf"]
+EOF
+
+$IMP --config /tmp/imp.toml apply
+sed -i 's|, "/tmp/fsck-old"||' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+mountpoint -q /tmp/fsck-old && { echo "ERROR: old entry not removed by apply"; exit 1; }
+
+echo "=== A clean state matches ==="
+$IMP --config /tmp/imp.toml fsck | grep "The filesystem matches generation 2" || { echo "ERROR: clean state flagged"; exit 1; }
+
+echo "=== Diverging in every direction ==="
+umount /tmp/fsck-a
+ln -sfn /tmp/elsewhere /tmp/fsck.conf
+mkdir -p /tmp/fsck-old && mount --bind /persist/tmp/fsck-old /tmp/fsck-old
+mount --bind /persist/tmp/fsck-stray /tmp/fsck-stray
+
+$IMP --config /tmp/imp.toml fsck | tee /tmp/fsck.log
+grep "Found 4 discrepancy(ies)" /tmp/fsck.log || { echo "ERROR: wrong discrepancy count"; exit 1; }
+grep -- "- Directory is not a mount point: /tmp/fsck-a" /tmp/fsck.log || { echo "ERROR: missing mount not reported"; exit 1; }
+grep -- "~ Symlink points to wrong target: /tmp/fsck.conf" /tmp/fsck.log || { echo "ERROR: wrong symlink not reported"; exit 1; }
+grep -- "+ Orphaned mount: /tmp/fsck-old (from /persist/tmp/fsck-old)" /tmp/fsck.log || { echo "ERROR: leftover of generation 1 not reported"; exit 1; }
+grep -- "+ Orphaned mount: /tmp/fsck-stray (from /persist/tmp/fsck-stray)" /tmp/fsck.log || { echo "ERROR: stray mount not reported"; exit 1; }
+
+echo "=== Without --fix nothing changes ==="
+mountpoint -q /tmp/fsck-old || { echo "ERROR: fsck without --fix unmounted"; exit 1; }
+mountpoint -q /tmp/fsck-a && { echo "ERROR: fsck without --fix mounted"; exit 1; }
+
+echo "=== --fix reconciles both directions ==="
+$IMP --config /tmp/imp.toml fsck --fix | tee /tmp/fix.log
+grep "Reconciled 4 discrepancy(ies)" /tmp/fix.log || { echo "ERROR: not reconciled"; exit 1; }
+mountpoint -q /tmp/fsck-a || { echo "ERROR: missing mount not restored"; exit 1; }
+[ "$(readlink /tmp/fsck.conf)" = "/persist/tmp/fsck.conf" ] || { echo "ERROR: symlink not relinked"; exit 1; }
+mountpoint -q /tmp/fsck-old && { echo "ERROR: orphan of generation 1 still mounted"; exit 1; }
+mountpoint -q /tmp/fsck-stray && { echo "ERROR: stray mount still mounted"; exit 1; }
+[ -d /persist/tmp/fsck-stray ] || { echo "ERROR: persisted data removed"; exit 1; }
+$IMP --config /tmp/imp.toml fsck | grep "The filesystem matches generation 2" || { echo "ERROR: still diverged"; exit 1; }
+
+echo ""
+echo "✅ Fsck tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Fsck tests", test_script);
+
+    assert!(
+        output.contains("✅ Fsck tests passed!"),
+        "Fsck tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_mount_timeout_option() {
+    // Reproducing a hung backing store needs a stalling filesystem we can't
+    // build in the container, so this only covers that operations finishing
+    // within the timeout behave exactly as without it.
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/timeout-target
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/timeout-target"]
+EOF
+
+$IMP --config /tmp/imp.toml --timeout 5 apply
+mountpoint -q /tmp/timeout-target || { echo "ERROR: mount with timeout failed"; exit 1; }
+$IMP --config /tmp/imp.toml --timeout 5 apply
+$IMP --config /tmp/imp.toml --timeout 5 switch 1
+mountpoint -q /tmp/timeout-target || { echo "ERROR: switch with timeout failed"; exit 1; }
+umount /tmp/timeout-target
+$IMP --config /tmp/imp.toml --timeout 5 verify --fix | grep "Fixed 1 error(s)" || { echo "ERROR: repair with timeout failed"; exit 1; }
+
+echo ""
+echo "✅ Mount timeout tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Mount timeout tests", test_script);
+
+    assert!(
+        output.contains("✅ Mount timeout tests passed!"),
+        "Mount timeout tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_import_nix_persistence() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/import/log /persist/tmp/import/secrets
+echo "machine" > /persist/tmp/import/machine-id
+echo "key" > /persist/tmp/import/secrets/id
+
+cat > /tmp/persistence.nix <<'EOF'
+{ config, pkgs, ... }:
+{
+  # Kept across reboots
+  environment.persistence."/persist" = {
+    hideMounts = true;
+    directories = [
+      "/tmp/import/log"
+      { directory = "/tmp/import/data"; user = "root"; group = "root"; mode = "u=rwx,g=rx,o="; }
+    ];
+    files = [
+      "/tmp/import/machine-id"
+      { file = "/tmp/import/secrets/id"; parentDirectory = { mode = "u=rwx,g=,o="; }; }
+    ];
+  };
+}
+EOF
+
+echo "=== Importing a Nix module ==="
+$IMP import-nix /tmp/persistence.nix -o /tmp/imported.toml | grep "Imported 1 persistence root(s)" || { echo "ERROR: import failed"; exit 1; }
+cat /tmp/imported.toml
+grep 'mode = "0750"' /tmp/imported.toml || { echo "ERROR: symbolic mode not converted"; exit 1; }
+grep 'hideMounts = true' /tmp/imported.toml || { echo "ERROR: hideMounts not carried over"; exit 1; }
+
+echo "=== The imported config applies ==="
+(echo 'state_dir = "/tmp/imp-state"'; cat /tmp/imported.toml) > /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+mountpoint -q /tmp/import/log || { echo "ERROR: directory not mounted"; exit 1; }
+mountpoint -q /tmp/import/data || { echo "ERROR: detailed directory not mounted"; exit 1; }
+[ "$(readlink /tmp/import/machine-id)" = "/persist/tmp/import/machine-id" ] || { echo "ERROR: file not linked"; exit 1; }
+[ -L /tmp/import/secrets/id ] || { echo "ERROR: detailed file not linked"; exit 1; }
+
+echo "=== Importing a JSON export with users ==="
+cat > /tmp/persistence.json <<'EOF'
+{"/persist": {"hideMounts": false,
+  "directories": [{"directory": "/var/log", "user": "root", "group": "root", "mode": "0755"}],
+  "files": [],
+  "users": {"alice": {"directories": ["Documents"], "files": [".zsh_history"]},
+            "bob": {"home": "/srv/bob", "directories": [], "files": [".profile"]}}}}
+EOF
+$IMP import-nix /tmp/persistence.json > /tmp/json.toml
+cat /tmp/json.toml
+grep -A2 'directory = "/home/alice/Documents"' /tmp/json.toml | grep 'user = "alice"' || { echo "ERROR: user directory not resolved"; exit 1; }
+grep '/home/alice/.zsh_history' /tmp/json.toml || { echo "ERROR: user file not resolved"; exit 1; }
+grep '/srv/bob/.profile' /tmp/json.toml || { echo "ERROR: custom home ignored"; exit 1; }
+
+echo "=== Unsupported Nix is rejected with its line ==="
+printf '{\n  environment.persistence."/persist".directories = [ "${dir}" ];\n}\n' > /tmp/bad.nix
+if $IMP import-nix /tmp/bad.nix 2> /tmp/bad.log; then
+    echo "ERROR: interpolation was accepted"; exit 1
+fi
+grep "line 2" /tmp/bad.log || { echo "ERROR: error does not name the line"; exit 1; }
+
+echo ""
+echo "✅ Nix import tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Nix import tests", test_script);
+
+    assert!(
+        output.contains("✅ Nix import tests passed!"),
+        "Nix import tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_list_format_template() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/format-a /persist/tmp/format-b
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/format-a"]
+EOF
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=0 active=none" ] || { echo "ERROR: count without generations"; exit 1; }
+$IMP --config /tmp/imp.toml apply
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/format-a", "/tmp/format-b"]
+EOF
+$IMP --config /tmp/imp.toml apply
+
+echo "=== Default format is unchanged ==="
+$IMP --config /tmp/imp.toml list | grep -E "^  2 - .* - 2 symlinks \(active\)" || { echo "ERROR: default format changed"; exit 1; }
+
+echo "=== Custom template ==="
+$IMP --config /tmp/imp.toml list --format '{number}|{count}|{active}' > /tmp/list.txt
+cat /tmp/list.txt
+[ "$(cat /tmp/list.txt)" = "$(printf '1|1|\n2|2|active')" ] || { echo "ERROR: template not applied"; exit 1; }
+$IMP --config /tmp/imp.toml list --format '{date}' | grep -E "^[0-9]{4}-[0-9]{2}-[0-9]{2} [0-9:]{8}$" || { echo "ERROR: date placeholder"; exit 1; }
+
+echo "=== Unknown placeholders are rejected ==="
+if $IMP --config /tmp/imp.toml list --format '{nope}' 2> /tmp/err.log; then
+    echo "ERROR: unknown placeholder accepted"; exit 1
+fi
+grep "Unknown placeholder {nope}" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+if $IMP --config /tmp/imp.toml list --format '{label}' 2> /tmp/err.log; then
+    echo "ERROR: label placeholder accepted without labels"; exit 1
+fi
+grep "Unknown placeholder {label}" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+echo "=== Count summary ==="
+$IMP --config /tmp/imp.toml list --count
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=2 active=2" ] || { echo "ERROR: wrong count"; exit 1; }
+$IMP --config /tmp/imp.toml switch 1 > /dev/null
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=2 active=1" ] || { echo "ERROR: count after switch"; exit 1; }
+if $IMP --config /tmp/imp.toml list --count --format '{number}' 2> /dev/null; then
+    echo "ERROR: --count combined with --format"; exit 1
+fi
+
+echo ""
+echo "✅ List format tests passed!"
+"#;
+
+    let output = run_in_privileged_container("List format tests", test_script);
+
+    assert!(
+        output.contains("✅ List format tests passed!"),
+        "List format tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_dedupe_identical_generations() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app /persist/tmp/app
+echo 'a' > /persist/tmp/app/a.conf
+echo 'b' > /persist/tmp/app/b.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/app/a.conf"]
+EOF
+
+# 1, 2 and 4 are identical; 3 and 5 are identical; 6 is unique
+$IMP --config /tmp/imp.toml apply
+$IMP --config /tmp/imp.toml apply
+sed -i 's|files = \["/tmp/app/a.conf"\]|files = ["/tmp/app/b.conf"]|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+sed -i 's|files = \["/tmp/app/b.conf"\]|files = ["/tmp/app/a.conf"]|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+sed -i 's|files = \["/tmp/app/a.conf"\]|files = ["/tmp/app/b.conf"]|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+sed -i 's|files = \["/tmp/app/b.conf"\]|files = ["/tmp/app/a.conf", "/tmp/app/b.conf"]|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+$IMP --config /tmp/imp.toml switch 3
+
+echo "=== The default run only reports ==="
+$IMP --config /tmp/imp.toml dedupe --dry-run | tee /tmp/dedupe.log
+grep "Found 2 group(s) of identical generations" /tmp/dedupe.log || { echo "ERROR: groups not reported"; exit 1; }
+grep "  1, 2, 4 (keep 4; prune 1, 2)" /tmp/dedupe.log || { echo "ERROR: newest not kept"; exit 1; }
+grep "  3, 5 (keep 3; prune 5)" /tmp/dedupe.log || { echo "ERROR: active generation not kept"; exit 1; }
+grep "Nothing was changed. Run with --apply to delete 3 generation(s)." /tmp/dedupe.log || { echo "ERROR: summary missing"; exit 1; }
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=6 active=3" ] || { echo "ERROR: dry run deleted generations"; exit 1; }
+
+echo "=== --apply deletes the redundant generations ==="
+$IMP --config /tmp/imp.toml dedupe --apply | tee /tmp/dedupe.log
+grep "Deleted 3 redundant generation(s) (1, 2, 5)" /tmp/dedupe.log || { echo "ERROR: deletion not reported"; exit 1; }
+$IMP --config /tmp/imp.toml list > /tmp/list.log
+for n in 3 4 6; do
+    grep -q "^  $n - " /tmp/list.log || { cat /tmp/list.log; echo "ERROR: generation $n deleted"; exit 1; }
+done
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=3 active=3" ] || { echo "ERROR: wrong generations left"; exit 1; }
+$IMP --config /tmp/imp.toml current | grep "Current generation: 3" || { echo "ERROR: active generation changed"; exit 1; }
+
+$IMP --config /tmp/imp.toml dedupe | grep "No identical generations found" || { echo "ERROR: duplicates left"; exit 1; }
+
+echo ""
+echo "✅ Dedupe tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Dedupe tests", test_script);
+
+    assert!(
+        output.contains("✅ Dedupe tests passed!"),
+        "Dedupe tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_list_sort() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/sort-a
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/sort-a"]
+EOF
+$IMP --config /tmp/imp.toml apply
+$IMP --config /tmp/imp.toml apply
+$IMP --config /tmp/imp.toml apply
+
+# Move generation 1 into the future so date order differs from number order
+sed -i '0,/"created_at": "2[0-9]\{3\}/s//"created_at": "2999/' /tmp/imp-state/generations.json
+
+list() { $IMP --config /tmp/imp.toml list --format '{number}' "$@" | tr '\n' ' '; }
+
+echo "=== By number (default) ==="
+[ "$(list)" = "1 2 3 " ] || { echo "ERROR: default order: $(list)"; exit 1; }
+[ "$(list --sort number)" = "1 2 3 " ] || { echo "ERROR: number order"; exit 1; }
+[ "$(list --sort number --reverse)" = "3 2 1 " ] || { echo "ERROR: reversed number order"; exit 1; }
+
+echo "=== By date ==="
+[ "$(list --sort date)" = "2 3 1 " ] || { echo "ERROR: date order: $(list --sort date)"; exit 1; }
+[ "$(list --sort date --reverse)" = "1 3 2 " ] || { echo "ERROR: reversed date order"; exit 1; }
+$IMP --config /tmp/imp.toml list --sort date | sed -n 2p | grep "^  2 - " || { echo "ERROR: default output not sorted"; exit 1; }
+
+echo "=== Unknown keys are rejected ==="
+$IMP --config /tmp/imp.toml list --sort size 2> /dev/null && { echo "ERROR: unknown sort key accepted"; exit 1; }
+$IMP --config /tmp/imp.toml list --sort label 2> /dev/null && { echo "ERROR: label sort accepted without labels"; exit 1; }
+
+echo ""
+echo "✅ List sort tests passed!"
+"#;
+
+    let output = run_in_privileged_container("List sort tests", test_script);
+
+    assert!(
+        output.contains("✅ List sort tests passed!"),
+        "List sort tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_apply_keep_going() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/kg-dir /persist/tmp/kg-blocked
+echo "one" > /persist/tmp/kg-one
+echo "two" > /persist/tmp/kg-two
+echo "three" > /persist/tmp/kg-blocked/file
+# A regular file where a parent directory is needed makes one entry fail
+echo "in the way" > /tmp/kg-blocked
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/kg-dir"]
+files = ["/tmp/kg-one", "/tmp/kg-blocked/file", "/tmp/kg-two"]
+EOF
+
+echo "=== Without --keep-going the first failure aborts ==="
+if $IMP --config /tmp/imp.toml apply > /tmp/abort.log 2>&1; then
+    echo "ERROR: apply succeeded despite a failing entry"; exit 1
+fi
+[ -L /tmp/kg-two ] && { echo "ERROR: entries after the failure were applied"; exit 1; }
+$IMP --config /tmp/imp.toml list | grep "No generations found" || { echo "ERROR: generation recorded after abort"; exit 1; }
+umount /tmp/kg-dir
+rm -f /tmp/kg-one
+
+echo "=== With --keep-going the rest are applied and recorded ==="
+if $IMP --config /tmp/imp.toml apply --keep-going > /tmp/kg.log 2>&1; then
+    cat /tmp/kg.log
+    echo "ERROR: apply --keep-going exited zero with a failure"; exit 1
+fi
+cat /tmp/kg.log
+grep "✗ Failed: /tmp/kg-blocked/file" /tmp/kg.log || { echo "ERROR: failure not reported inline"; exit 1; }
+grep "Could not apply 1 target(s):" /tmp/kg.log || { echo "ERROR: failures not summarized"; exit 1; }
+grep "1 of 4 entries failed to apply (the rest are active in generation 1)" /tmp/kg.log || { echo "ERROR: error message"; exit 1; }
+mountpoint -q /tmp/kg-dir || { echo "ERROR: directory not mounted"; exit 1; }
+[ -L /tmp/kg-one ] && [ -L /tmp/kg-two ] || { echo "ERROR: files not linked"; exit 1; }
+$IMP --config /tmp/imp.toml list | grep -E "1 - .* - 3 symlinks \(active\)" || { echo "ERROR: successful entries not recorded"; exit 1; }
+
+echo ""
+echo "✅ Keep going tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Keep going tests", test_script);
+
+    assert!(
+        output.contains("✅ Keep going tests passed!"),
+        "Keep going tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_local_time_display() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/tz-target
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/tz-target"]
+EOF
+$IMP --config /tmp/imp.toml apply
+
+# A POSIX TZ string needs no tzdata in the container
+export TZ="IST-5:30"
+
+echo "=== UTC stays the default ==="
+$IMP --config /tmp/imp.toml show 1 | grep -E "Created at: .* UTC$" || { echo "ERROR: show default changed"; exit 1; }
+utc=$($IMP --config /tmp/imp.toml list --format '{date}')
+
+echo "=== --local converts and shows the offset ==="
+$IMP --config /tmp/imp.toml list --local | grep -E "^  1 - .* \+05:30 - 1 symlinks \(active\)" || { echo "ERROR: list --local"; exit 1; }
+$IMP --config /tmp/imp.toml show 1 --local | grep -E "Created at: .* \+05:30$" || { echo "ERROR: show --local"; exit 1; }
+$IMP --config /tmp/imp.toml current --local | grep -E "Created at: .* \+05:30$" || { echo "ERROR: current --local"; exit 1; }
+local_date=$($IMP --config /tmp/imp.toml list --local --format '{date}')
+echo "$utc -> $local_date"
+[ "$(date -u -d "$utc UTC" +%s)" = "$(date -d "$local_date" +%s)" ] || { echo "ERROR: local time is not the same instant"; exit 1; }
+
+echo "=== Stored value stays UTC ==="
+grep '"created_at": ".*Z"' /tmp/imp-state/generations.json || { echo "ERROR: stored time changed"; exit 1; }
+
+echo ""
+echo "✅ Local time tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Local time tests", test_script);
+
+    assert!(
+        output.contains("✅ Local time tests passed!"),
+        "Local time tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_switch_verifies_result() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/sv-dir
+echo "data" > /persist/tmp/sv-file
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/sv-dir"]
+files = ["/tmp/sv-file"]
+EOF
+$IMP --config /tmp/imp.toml apply
+$IMP --config /tmp/imp.toml apply
+
+echo "=== A clean switch verifies ==="
+$IMP --config /tmp/imp.toml switch 1 | grep "Switched to generation 1" || { echo "ERROR: switch failed"; exit 1; }
+
+echo "=== A switch that can't reproduce the generation fails ==="
+# Record options the recreated bind mount won't have
+sed -i 's/"rw"/"ro"/' /tmp/imp-state/generations.json
+if $IMP --config /tmp/imp.toml switch 2 > /tmp/switch.log 2>&1; then
+    cat /tmp/switch.log
+    echo "ERROR: switch succeeded despite a mismatch"; exit 1
+fi
+cat /tmp/switch.log
+grep "Generation 2 is active but does not match the filesystem" /tmp/switch.log || { echo "ERROR: mismatch not reported"; exit 1; }
+grep "Mount options drifted on /tmp/sv-dir" /tmp/switch.log || { echo "ERROR: discrepancy not listed"; exit 1; }
+
+echo "=== --no-verify skips the check ==="
+$IMP --config /tmp/imp.toml switch 2 --no-verify | grep "Switched to generation 2" || { echo "ERROR: --no-verify still verified"; exit 1; }
+
+echo ""
+echo "✅ Switch verification tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Switch verification tests", test_script);
+
+    assert!(
+        output.contains("✅ Switch verification tests passed!"),
+        "Switch verification tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_file_blocking_parent_directory() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/blocked/config/app
+echo "settings" > /persist/tmp/blocked/config/app/settings.toml
+# The target's parent /tmp/blocked/config should be a directory
+mkdir -p /tmp/blocked
+echo "stray" > /tmp/blocked/config
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/blocked/config/app"]
+EOF
+
+echo "=== A file in the way is reported clearly ==="
+if $IMP --config /tmp/imp.toml apply > /tmp/apply.log 2>&1; then
+    echo "ERROR: apply succeeded with a file in the way"; exit 1
+fi
+cat /tmp/apply.log
+grep "Cannot create parent directory /tmp/blocked/config: a file exists at /tmp/blocked/config" /tmp/apply.log || { echo "ERROR: unclear error"; exit 1; }
+[ "$(cat /tmp/blocked/config)" = "stray" ] || { echo "ERROR: file touched without --force"; exit 1; }
+
+echo "=== --force backs the file up and replaces it ==="
+$IMP --config /tmp/imp.toml apply --force | tee /tmp/force.log
+grep "Replacing file in the way of /tmp/blocked/config: /tmp/blocked/config" /tmp/force.log || { echo "ERROR: replacement not reported"; exit 1; }
+mountpoint -q /tmp/blocked/config/app || { echo "ERROR: target not mounted"; exit 1; }
+[ "$(cat /tmp/blocked/config.imp-backup.*)" = "stray" ] || { echo "ERROR: file not backed up"; exit 1; }
+
+echo ""
+echo "✅ Blocking file tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Blocking file tests", test_script);
+
+    assert!(
+        output.contains("✅ Blocking file tests passed!"),
+        "Blocking file tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_concurrent_reads_during_writes() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/conc-dir
+for i in $(seq 1 300); do echo "$i" > /persist/tmp/conc-file-$i; done
+
+{
+    echo 'state_dir = "/tmp/imp-state"'
+    echo ''
+    echo '[persistence."/persist"]'
+    echo 'directories = ["/tmp/conc-dir"]'
+    echo -n 'files = ['
+    for i in $(seq 1 300); do echo -n "\"/tmp/conc-file-$i\", "; done
+    echo ']'
+} > /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply > /dev/null
+
+echo "=== Readers never see a partial state file while apply writes it ==="
+(
+    for i in $(seq 1 15); do
+        $IMP --config /tmp/imp.toml apply > /dev/null
+    done
+    touch /tmp/writer-done
+) &
+writer=$!
+
+reads=0
+while [ ! -e /tmp/writer-done ]; do
+    $IMP --config /tmp/imp.toml list --format '{number}' > /tmp/list.out 2> /tmp/list.err || {
+        cat /tmp/list.err; echo "ERROR: list failed during apply"; kill $writer; exit 1
+    }
+    $IMP --config /tmp/imp.toml current > /dev/null 2> /tmp/current.err || {
+        cat /tmp/current.err; echo "ERROR: current failed during apply"; kill $writer; exit 1
+    }
+    reads=$((reads + 1))
+done
+wait $writer
+echo "Completed $reads concurrent reads"
+[ "$($IMP --config /tmp/imp.toml list --format '{number}' | tail -1)" = "16" ] || { echo "ERROR: writes were lost"; exit 1; }
+[ ! -e /tmp/imp-state/generations.json.tmp ] || { echo "ERROR: temporary state file left behind"; exit 1; }
+
+echo ""
+echo "✅ Concurrent read tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Concurrent read tests", test_script);
+
+    assert!(
+        output.contains("✅ Concurrent read tests passed!"),
+        "Concurrent read tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_damaged_state_file_recovery() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app /persist/tmp/app
+echo 'one' > /persist/tmp/app/one.conf
+echo 'two' > /persist/tmp/app/two.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/app/one.conf"]
+EOF
+STATE=/tmp/imp-state/generations.json
+
+echo "=== An empty state file counts as no generations ==="
+mkdir -p /tmp/imp-state
+: > $STATE
+$IMP --config /tmp/imp.toml list | tee /tmp/list.log
+grep -q "^  [0-9]* - " /tmp/list.log && { echo "ERROR: generations listed"; exit 1; }
+$IMP --config /tmp/imp.toml apply
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=1 active=1" ] || { echo "ERROR: apply after empty state"; exit 1; }
+[ -e $STATE.bak ] && { echo "ERROR: empty state backed up"; exit 1; }
+
+echo "=== Each save keeps the state it replaces ==="
+sed -i 's|"/tmp/app/one.conf"|"/tmp/app/one.conf", "/tmp/app/two.conf"|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+grep -q '"number": 1' $STATE.bak || { echo "ERROR: previous state not backed up"; exit 1; }
+grep -q '"number": 2' $STATE.bak && { echo "ERROR: backup is not the previous state"; exit 1; }
+ls /tmp/imp-state/*.tmp 2> /dev/null && { echo "ERROR: temporary file left behind"; exit 1; }
+cp $STATE /tmp/good.json
+
+echo "=== A whitespace-only state file counts as no generations, even with a backup ==="
+printf '  \n\t\n' > $STATE
+$IMP --config /tmp/imp.toml list 2> /tmp/err.log | tee /tmp/list.log
+grep "is corrupt" /tmp/err.log && { echo "ERROR: whitespace treated as corrupt"; exit 1; }
+grep -q "^  [0-9]* - " /tmp/list.log && { echo "ERROR: generations listed"; exit 1; }
+cp /tmp/good.json $STATE
+
+echo "=== A truncated state file is recovered from the backup ==="
+head -c 200 /tmp/good.json > $STATE
+$IMP --config /tmp/imp.toml list 2> /tmp/err.log | tee /tmp/list.log
+grep "is corrupt; using $STATE.bak instead" /tmp/err.log || { echo "ERROR: recovery not reported"; exit 1; }
+grep -E "^  1 - " /tmp/list.log || { echo "ERROR: backed up generation not listed"; exit 1; }
+
+echo "=== The next change rewrites the state file without losing the backup ==="
+$IMP --config /tmp/imp.toml switch 1 2> /tmp/err.log
+[ "$($IMP --config /tmp/imp.toml list --count 2> /tmp/err.log)" = "total=1 active=1" ] || { echo "ERROR: recovered state not saved"; exit 1; }
+grep "is corrupt" /tmp/err.log && { echo "ERROR: state not rewritten"; exit 1; }
+grep -q '"number": 1' $STATE.bak || { echo "ERROR: damaged state replaced the backup"; exit 1; }
+
+echo "=== migrate-state repairs a damaged state file ==="
+echo '{"version": 2, "generations": [' > $STATE
+$IMP --config /tmp/imp.toml migrate-state
+$IMP --config /tmp/imp.toml list 2> /tmp/err.log > /dev/null
+grep "is corrupt" /tmp/err.log && { echo "ERROR: state still damaged"; exit 1; }
+
+echo "=== Without a usable backup a corrupt state file is an error ==="
+echo '{"version": 2, "generations": [' > $STATE
+echo 'also broken' > $STATE.bak
+if $IMP --config /tmp/imp.toml list 2> /tmp/err.log; then
+    echo "ERROR: corrupt state accepted"; exit 1
+fi
+grep "Failed to parse state file: $STATE" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+echo ""
+echo "✅ State recovery tests passed!"
+"#;
+
+    let output = run_in_privileged_container("State recovery tests", test_script);
+
+    assert!(
+        output.contains("✅ State recovery tests passed!"),
+        "State recovery tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_apply_mount_only_and_links_only() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/filter-dir
+echo "data" > /persist/tmp/filter-file
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/filter-dir"]
+files = ["/tmp/filter-file"]
+EOF
+
+echo "=== --mount-only applies and records only bind mounts ==="
+$IMP --config /tmp/imp.toml apply --mount-only | grep "mount-only: applying 1 entries" || { echo "ERROR: filter not reported"; exit 1; }
+mountpoint -q /tmp/filter-dir || { echo "ERROR: directory not mounted"; exit 1; }
+[ -e /tmp/filter-file ] && { echo "ERROR: file linked with --mount-only"; exit 1; }
+$IMP --config /tmp/imp.toml show 1 | grep "/tmp/filter-file" && { echo "ERROR: file recorded"; exit 1; }
+
+echo "=== --links-only applies and records only symlinks ==="
+$IMP --config /tmp/imp.toml apply --links-only
+[ -L /tmp/filter-file ] || { echo "ERROR: file not linked"; exit 1; }
+mountpoint -q /tmp/filter-dir && { echo "ERROR: directory mounted with --links-only"; exit 1; }
+$IMP --config /tmp/imp.toml show 2 | grep "/tmp/filter-dir" && { echo "ERROR: directory recorded"; exit 1; }
+
+echo "=== The flags are mutually exclusive ==="
+if $IMP --config /tmp/imp.toml apply --mount-only --links-only 2> /dev/null; then
+    echo "ERROR: both filters accepted"; exit 1
+fi
+
+echo "=== An empty filtered set is refused ==="
+cat > /tmp/dirs.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/filter-dir"]
+EOF
+if $IMP --config /tmp/dirs.toml apply --links-only 2> /tmp/empty.err; then
+    echo "ERROR: empty filtered set applied"; exit 1
+fi
+grep "Nothing to apply: the config has no entries left after --links-only" /tmp/empty.err || { echo "ERROR: unclear error"; exit 1; }
+[ -L /tmp/filter-file ] || { echo "ERROR: refused apply touched the active generation"; exit 1; }
+
+echo ""
+echo "✅ Apply filter tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Apply filter tests", test_script);
+
+    assert!(
+        output.contains("✅ Apply filter tests passed!"),
+        "Apply filter tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_unprivileged_apply_with_matching_ownership() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+# An unprivileged user whose persisted data it already owns. Its own user
+# namespace provides mount rights, but only its own uid is mapped there, so
+# ownership can't be changed to anything else.
+mkdir -p /persist/tmp/own-data /tmp/own-existing
+chown -R 1000:1000 /persist/tmp /tmp/own-existing
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/own-state"
+
+[persistence."/persist"]
+directories = ["/tmp/own-data", "/tmp/own-existing"]
+EOF
+chmod 644 /tmp/imp.toml
+
+echo "=== Apply as uid 1000 without privileges ==="
+setpriv --reuid=1000 --regid=1000 --clear-groups \
+    unshare -r -m --propagation private \
+    sh -c "$IMP --config /tmp/imp.toml apply && mountpoint -q /tmp/own-data" > /tmp/apply.log 2>&1 || {
+    cat /tmp/apply.log; echo "ERROR: unprivileged apply failed"; exit 1
+}
+cat /tmp/apply.log
+mountpoint -q /tmp/own-data && { echo "ERROR: mount leaked out of the namespace"; exit 1; }
+[ "$(stat -c %u:%g /tmp/own-data)" = "1000:1000" ] || { echo "ERROR: target has wrong ownership"; exit 1; }
+[ "$(stat -c %u:%g /persist/tmp/own-existing)" = "1000:1000" ] || { echo "ERROR: ownership changed"; exit 1; }
+
+echo ""
+echo "✅ Unprivileged ownership tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Unprivileged ownership tests", test_script);
+
+    assert!(
+        output.contains("✅ Unprivileged ownership tests passed!"),
+        "Unprivileged ownership tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_apply_json_lines_output() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/jl-dir
+echo "data" > /persist/tmp/jl-file
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/jl-dir"]
+files = ["/tmp/jl-file", { file = "/tmp/jl-optional", optional = true }]
+EOF
+
+echo "=== stdout carries only JSON events ==="
+$IMP --config /tmp/imp.toml apply --output json-lines > /tmp/events.jsonl 2> /tmp/human.log
+cat /tmp/events.jsonl
+if grep -v '^{"event":"[a-z]*",.*}$' /tmp/events.jsonl; then
+    echo "ERROR: non-event output on stdout"; exit 1
+fi
+grep "Created bind mount: /tmp/jl-dir" /tmp/human.log || { echo "ERROR: human output not on stderr"; exit 1; }
+
+echo "=== Events arrive in order ==="
+events=$(sed 's/^{"event":"\([a-z]*\)".*/\1/' /tmp/events.jsonl | tr '\n' ' ')
+[ "$events" = "skipped start created created finish " ] || { echo "ERROR: unexpected events: $events"; exit 1; }
+grep -x '{"event":"skipped","target":"/tmp/jl-optional","source":"/persist/tmp/jl-optional"}' /tmp/events.jsonl || { echo "ERROR: skipped event"; exit 1; }
+grep -x '{"event":"start","generation":1,"entries":2}' /tmp/events.jsonl || { echo "ERROR: start event"; exit 1; }
+grep -x '{"event":"created","target":"/tmp/jl-dir","source":"/persist/tmp/jl-dir","kind":"mount"}' /tmp/events.jsonl || { echo "ERROR: mount event"; exit 1; }
+grep -x '{"event":"created","target":"/tmp/jl-file","source":"/persist/tmp/jl-file","kind":"symlink"}' /tmp/events.jsonl || { echo "ERROR: symlink event"; exit 1; }
+grep -x '{"event":"finish","generation":1,"created":2,"failed":0}' /tmp/events.jsonl || { echo "ERROR: finish event"; exit 1; }
+
+echo "=== Errors end the stream with an error event ==="
+if $IMP --config /tmp/missing.toml apply --output json-lines > /tmp/error.jsonl 2> /dev/null; then
+    echo "ERROR: apply with missing config succeeded"; exit 1
+fi
+cat /tmp/error.jsonl
+grep -x '{"event":"error","message":".*"}' /tmp/error.jsonl || { echo "ERROR: no error event"; exit 1; }
+
+echo ""
+echo "✅ JSON lines tests passed!"
+"#;
+
+    let output = run_in_privileged_container("JSON lines tests", test_script);
+
+    assert!(
+        output.contains("✅ JSON lines tests passed!"),
+        "JSON lines tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_relative_paths_with_base() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/home-me/.config/nvim /persist/tmp/abs-dir
+echo "set number" > /persist/tmp/home-me/.config/nvim/init.vim
+echo "export A=1" > /persist/tmp/home-me/.bashrc
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+base = "/tmp/home-me"
+directories = [".config/nvim", "/tmp/abs-dir"]
+files = [".bashrc"]
+EOF
+
+echo "=== Relative entries are placed under base ==="
+$IMP --config /tmp/imp.toml apply
+mountpoint -q /tmp/home-me/.config/nvim || { echo "ERROR: relative directory not mounted under base"; exit 1; }
+[ "$(cat /tmp/home-me/.config/nvim/init.vim)" = "set number" ] || { echo "ERROR: wrong source for relative directory"; exit 1; }
+[ "$(readlink /tmp/home-me/.bashrc)" = "/persist/tmp/home-me/.bashrc" ] || { echo "ERROR: relative file not linked under base"; exit 1; }
+
+echo "=== Absolute entries ignore base ==="
+mountpoint -q /tmp/abs-dir || { echo "ERROR: absolute directory not mounted at its path"; exit 1; }
+[ -e /tmp/home-me/tmp/abs-dir ] && { echo "ERROR: base applied to absolute path"; exit 1; }
+
+echo "=== A relative base is rejected ==="
+sed -i 's|base = "/tmp/home-me"|base = "home-me"|' /tmp/imp.toml
+if $IMP --config /tmp/imp.toml apply 2> /tmp/err.log; then
+    echo "ERROR: relative base accepted"; exit 1
+fi
+grep "Invalid base home-me in /persist: must be an absolute path" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+echo ""
+echo "✅ Base path tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Base path tests", test_script);
+
+    assert!(
+        output.contains("✅ Base path tests passed!"),
+        "Base path tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_tiered_roots_fallback() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /mnt/fast /mnt/slow /tmp/app
+mount -t tmpfs -o size=1m tmpfs /mnt/fast
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."tiered"]
+roots = ["/mnt/fast", "/mnt/slow"]
+directories = [
+  "/tmp/app/hot",
+  { directory = "/tmp/app/pinned", root = "/mnt/slow" },
+]
+EOF
+
+echo "=== Sources go to the first root, unless pinned ==="
+$IMP --config /tmp/imp.toml apply
+[ -d /mnt/fast/tmp/app/hot ] || { echo "ERROR: hot not placed on the first root"; exit 1; }
+[ -d /mnt/slow/tmp/app/pinned ] || { echo "ERROR: explicit root ignored"; exit 1; }
+[ -e /mnt/fast/tmp/app/pinned ] && { echo "ERROR: pinned entry placed on both roots"; exit 1; }
+grep '"root": "/mnt/fast"' /tmp/imp-state/generations.json || { echo "ERROR: chosen root not recorded"; exit 1; }
+$IMP --config /tmp/imp.toml show 1 | grep "(root: /mnt/slow)" || { echo "ERROR: root not shown"; exit 1; }
+
+echo "=== A full first root falls back to the next ==="
+echo 'hot data' > /tmp/app/hot/data
+dd if=/dev/zero of=/mnt/fast/fill bs=64k 2>/dev/null || true
+sed -i 's|  "/tmp/app/hot",|  "/tmp/app/hot",\n  "/tmp/app/cold",|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+[ -d /mnt/slow/tmp/app/cold ] || { echo "ERROR: cold not placed on the fallback root"; exit 1; }
+[ -e /mnt/fast/tmp/app/cold ] && { echo "ERROR: cold placed on the full root"; exit 1; }
+[ -e /mnt/slow/tmp/app/hot ] && { echo "ERROR: existing source moved to another root"; exit 1; }
+[ "$(cat /tmp/app/hot/data)" = "hot data" ] || { echo "ERROR: hot data lost"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+
+echo "=== An explicit root must be one of the roots ==="
+sed -i 's|root = "/mnt/slow"|root = "/mnt/other"|' /tmp/imp.toml
+if $IMP --config /tmp/imp.toml apply > /tmp/bad.log 2>&1; then
+    echo "ERROR: unknown root accepted"; exit 1
+fi
+grep "sets root /mnt/other, which is not one of the group's roots" /tmp/bad.log || { cat /tmp/bad.log; echo "ERROR: wrong error"; exit 1; }
+
+echo ""
+echo "✅ Tiered roots tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Tiered roots tests", test_script);
+
+    assert!(
+        output.contains("✅ Tiered roots tests passed!"),
+        "Tiered roots tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_source_layout() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist/mirror"]
+directories = ["/tmp/layout/mirror-dir"]
+files = ["/tmp/layout/mirror.conf"]
+
+[persistence."/persist/flat"]
+sourceLayout = "flat"
+directories = ["/tmp/layout/deep/flat-dir"]
+files = ["/tmp/layout/deep/flat.conf"]
+
+[persistence."/persist/stripped"]
+stripPrefix = "/tmp/layout/home"
+directories = ["/tmp/layout/home/.config"]
+EOF
+
+$IMP --config /tmp/imp.toml apply
+
+echo "=== The default layout mirrors the target path ==="
+[ -d /persist/mirror/tmp/layout/mirror-dir ] || { echo "ERROR: mirrored source not created"; exit 1; }
+[ "$(readlink /tmp/layout/mirror.conf)" = "/persist/mirror/tmp/layout/mirror.conf" ] || { echo "ERROR: mirrored file source wrong"; exit 1; }
+
+echo "=== The flat layout uses only the file name ==="
+[ -d /persist/flat/flat-dir ] || { echo "ERROR: flat source not created"; exit 1; }
+mountpoint -q /tmp/layout/deep/flat-dir || { echo "ERROR: flat directory not mounted"; exit 1; }
+[ "$(readlink /tmp/layout/deep/flat.conf)" = "/persist/flat/flat.conf" ] || { echo "ERROR: flat file source wrong"; exit 1; }
+[ -e /persist/flat/tmp ] && { echo "ERROR: flat layout created nested sources"; exit 1; }
+
+echo "=== stripPrefix removes the prefix before mirroring ==="
+[ -d /persist/stripped/.config ] || { echo "ERROR: stripped source not created"; exit 1; }
+grep '"/persist/stripped/.config"' /tmp/imp-state/generations.json || { echo "ERROR: stripped source not recorded"; exit 1; }
+
+echo "=== Flat entries sharing a name are rejected ==="
+sed -i 's|files = \["/tmp/layout/deep/flat.conf"\]|files = ["/tmp/layout/deep/flat.conf", "/tmp/layout/other/flat.conf"]|' /tmp/imp.toml
+if $IMP --config /tmp/imp.toml apply 2> /tmp/err.log; then
+    echo "ERROR: colliding flat sources accepted"; exit 1
+fi
+grep "/tmp/layout/deep/flat.conf and /tmp/layout/other/flat.conf in /persist/flat would both use source /persist/flat/flat.conf" /tmp/err.log || { echo "ERROR: unclear collision error"; exit 1; }
+
+echo "=== Entries outside stripPrefix are rejected ==="
+sed -i 's|, "/tmp/layout/other/flat.conf"||' /tmp/imp.toml
+sed -i 's|directories = \["/tmp/layout/home/.config"\]|directories = ["/tmp/layout/home/.config", "/tmp/layout/elsewhere"]|' /tmp/imp.toml
+if $IMP --config /tmp/imp.toml apply 2> /tmp/err.log; then
+    echo "ERROR: entry outside stripPrefix accepted"; exit 1
+fi
+grep "/tmp/layout/elsewhere in /persist/stripped is not under stripPrefix /tmp/layout/home" /tmp/err.log || { echo "ERROR: unclear stripPrefix error"; exit 1; }
+
+echo ""
+echo "✅ Source layout tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Source layout tests", test_script);
+
+    assert!(
+        output.contains("✅ Source layout tests passed!"),
+        "Source layout tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_bind_mount_probe_fails_fast() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/probe-a /persist/tmp/probe-b
+echo "data" > /persist/tmp/probe-file
+chown -R 1000:1000 /persist/tmp
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/probe-state"
+
+[persistence."/persist"]
+directories = ["/tmp/probe-a", "/tmp/probe-b"]
+files = ["/tmp/probe-file"]
+EOF
+chmod 644 /tmp/imp.toml
+
+echo "=== Without mount privileges apply stops before touching anything ==="
+if setpriv --reuid=1000 --regid=1000 --clear-groups $IMP --config /tmp/imp.toml apply > /tmp/apply.log 2>&1; then
+    echo "ERROR: unprivileged apply succeeded"; exit 1
+fi
+cat /tmp/apply.log
+grep "Bind mounts are not available here (probe mount failed: EPERM" /tmp/apply.log || { echo "ERROR: probe message missing"; exit 1; }
+grep "apply --links-only" /tmp/apply.log || { echo "ERROR: no suggestion"; exit 1; }
+grep -c "Bind mounts are not available" /tmp/apply.log | grep -x 1 || { echo "ERROR: reported more than once"; exit 1; }
+grep "Applying" /tmp/apply.log && { echo "ERROR: entries were attempted"; exit 1; }
+[ -e /tmp/probe-file ] && { echo "ERROR: file linked after failed probe"; exit 1; }
+
+echo "=== File-only applies don't need the probe ==="
+setpriv --reuid=1000 --regid=1000 --clear-groups $IMP --config /tmp/imp.toml apply --links-only
+[ -L /tmp/probe-file ] || { echo "ERROR: file not linked"; exit 1; }
+
+echo ""
+echo "✅ Bind mount probe tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Bind mount probe tests", test_script);
+
+    assert!(
+        output.contains("✅ Bind mount probe tests passed!"),
+        "Bind mount probe tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_capability_precheck() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/caps-plain /persist/tmp/caps-owned
+echo "data" > /persist/tmp/caps-file
+chown -R 1000:1000 /persist/tmp
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/caps-state"
+
+[persistence."/persist"]
+directories = ["/tmp/caps-plain", { directory = "/tmp/caps-owned", user = "nobody" }]
+files = ["/tmp/caps-file"]
+EOF
+chmod 644 /tmp/imp.toml
+
+echo "=== Without privileges every missing capability is listed up front ==="
+setpriv --reuid=1000 --regid=1000 --clear-groups $IMP --config /tmp/imp.toml apply > /tmp/apply.log 2>&1 || true
+cat /tmp/apply.log
+grep "Missing capabilities; these entries are likely to fail:" /tmp/apply.log || { echo "ERROR: no warning"; exit 1; }
+grep "CAP_SYS_ADMIN (mounts): /tmp/caps-plain, /tmp/caps-owned$" /tmp/apply.log || { echo "ERROR: mount entries not listed"; exit 1; }
+grep "CAP_CHOWN (ownership): /tmp/caps-owned$" /tmp/apply.log || { echo "ERROR: owned entry not listed"; exit 1; }
+grep "caps-file" /tmp/apply.log | grep CAP_ && { echo "ERROR: plain file listed"; exit 1; }
+[ "$(grep -n "Missing capabilities" /tmp/apply.log | cut -d: -f1)" -lt "$(grep -n "Bind mounts are not available" /tmp/apply.log | cut -d: -f1)" ] || { echo "ERROR: warning not up front"; exit 1; }
+
+echo "=== Only the capabilities actually missing are listed ==="
+setpriv --bounding-set=-chown $IMP --config /tmp/imp.toml apply > /tmp/apply.log 2>&1 || true
+grep "CAP_CHOWN (ownership): /tmp/caps-owned$" /tmp/apply.log || { echo "ERROR: dropped CAP_CHOWN not reported"; exit 1; }
+grep "CAP_SYS_ADMIN" /tmp/apply.log && { echo "ERROR: CAP_SYS_ADMIN reported while held"; exit 1; }
+
+echo "=== Links-only applies need no capabilities ==="
+setpriv --reuid=1000 --regid=1000 --clear-groups $IMP --config /tmp/imp.toml apply --links-only > /tmp/apply.log 2>&1
+grep "Missing capabilities" /tmp/apply.log && { echo "ERROR: warning for file entries"; exit 1; }
+
+echo "=== Root gets no warning ==="
+$IMP --config /tmp/imp.toml apply > /tmp/apply.log 2>&1
+grep "Missing capabilities" /tmp/apply.log && { echo "ERROR: warning as root"; exit 1; }
+
+echo ""
+echo "✅ Capability precheck tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Capability precheck tests", test_script);
+
+    assert!(
+        output.contains("✅ Capability precheck tests passed!"),
+        "Capability precheck tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_show_diff_config() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/dc-keep /persist/tmp/dc-drop /persist/tmp/dc-new /tmp/persist-alt/tmp/dc-keep
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/dc-keep", "/tmp/dc-drop"]
+EOF
+$IMP --config /tmp/imp.toml apply
+
+echo "=== An unchanged config shows no drift ==="
+$IMP --config /tmp/imp.toml show 1 --diff-config | grep "0 added, 0 removed, 0 changed, 2 unchanged" || { echo "ERROR: unexpected drift"; exit 1; }
+
+echo "=== Edits since the generation are listed ==="
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/persist-alt"]
+directories = ["/tmp/dc-keep"]
+
+[persistence."/persist"]
+directories = ["/tmp/dc-new"]
+EOF
+$IMP --config /tmp/imp.toml show 1 --diff-config | tee /tmp/diff.log
+grep "  + /tmp/dc-new -> /persist/tmp/dc-new" /tmp/diff.log || { echo "ERROR: added entry"; exit 1; }
+grep "  - /tmp/dc-drop -> /persist/tmp/dc-drop" /tmp/diff.log || { echo "ERROR: removed entry"; exit 1; }
+grep "  ~ /tmp/dc-keep: /persist/tmp/dc-keep -> /tmp/persist-alt/tmp/dc-keep" /tmp/diff.log || { echo "ERROR: changed entry"; exit 1; }
+grep "1 added, 1 removed, 1 changed, 0 unchanged" /tmp/diff.log || { echo "ERROR: summary"; exit 1; }
+mountpoint -q /tmp/dc-drop || { echo "ERROR: show changed the filesystem"; exit 1; }
+
+echo "=== A missing config file is reported ==="
+mv /tmp/imp.toml /tmp/moved.toml
+if $IMP --config /tmp/moved.toml show 1 --diff-config 2> /tmp/err.log; then
+    echo "ERROR: diff without config succeeded"; exit 1
+fi
+grep "Cannot compare with config file: /tmp/imp.toml" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+echo ""
+echo "✅ Show diff config tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Show diff config tests", test_script);
+
+    assert!(
+        output.contains("✅ Show diff config tests passed!"),
+        "Show diff config tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_environment_sections() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/env-base /persist/tmp/env-shared /tmp/persist-web/tmp/env-web /tmp/persist-host/tmp/env-host
+
+host=$(cat /proc/sys/kernel/hostname)
+cat > /tmp/imp.toml <<EOF
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/env-base", "/tmp/env-shared"]
+
+[env.web.persistence."/persist"]
+directories = ["/tmp/env-shared"]
+
+[env.web.persistence."/tmp/persist-web"]
+directories = ["/tmp/env-web"]
+
+[env."$host".persistence."/tmp/persist-host"]
+directories = ["/tmp/env-host"]
+EOF
+
+echo "=== Base only when no environment matches ==="
+mv /tmp/imp.toml /tmp/full.toml
+grep -v "env.\"$host\"" /tmp/full.toml | grep -v 'env-host' > /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply | tee /tmp/base.log
+grep "Using environment" /tmp/base.log && { echo "ERROR: environment selected"; exit 1; }
+mountpoint -q /tmp/env-base && mountpoint -q /tmp/env-shared || { echo "ERROR: base not applied"; exit 1; }
+mountpoint -q /tmp/env-web && { echo "ERROR: env section applied without selection"; exit 1; }
+
+echo "=== --env merges its section over the base ==="
+$IMP --config /tmp/imp.toml --env web apply | tee /tmp/web.log
+grep "Using environment: web" /tmp/web.log || { echo "ERROR: environment not reported"; exit 1; }
+mountpoint -q /tmp/env-web || { echo "ERROR: env-only directory not applied"; exit 1; }
+mountpoint -q /tmp/env-shared || { echo "ERROR: overridden directory missing"; exit 1; }
+mountpoint -q /tmp/env-base && { echo "ERROR: overridden persistence entry not replaced"; exit 1; }
+
+echo "=== IMP_ENV selects the same way ==="
+IMP_ENV=web $IMP --config /tmp/imp.toml apply | grep "Using environment: web" || { echo "ERROR: IMP_ENV ignored"; exit 1; }
+
+echo "=== The hostname is the default environment ==="
+cp /tmp/full.toml /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply | grep "Using environment: $host" || { echo "ERROR: hostname not used"; exit 1; }
+mountpoint -q /tmp/env-host || { echo "ERROR: host section not applied"; exit 1; }
+
+echo "=== Unknown environments are rejected ==="
+if $IMP --config /tmp/imp.toml --env staging apply 2> /tmp/err.log; then
+    echo "ERROR: unknown environment accepted"; exit 1
+fi
+grep "Unknown environment \"staging\": the config defines $host, web" /tmp/err.log || grep "Unknown environment \"staging\": the config defines web, $host" /tmp/err.log || { cat /tmp/err.log; echo "ERROR: unclear error"; exit 1; }
+mountpoint -q /tmp/env-host || { echo "ERROR: rejected apply changed the active generation"; exit 1; }
+
+echo ""
+echo "✅ Environment section tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Environment section tests", test_script);
+
+    assert!(
+        output.contains("✅ Environment section tests passed!"),
+        "Environment section tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_switch_backs_up_existing_targets() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/bs-dir /persist/tmp/bs-other
+echo "persisted" > /persist/tmp/bs-file
+echo "persisted" > /persist/tmp/bs-dir/data
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/bs-dir"]
+files = ["/tmp/bs-file"]
+EOF
+$IMP --config /tmp/imp.toml apply
+
+cat > /tmp/other.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/bs-other"]
+EOF
+$IMP --config /tmp/other.toml apply
+
+# Manual changes at generation 1's targets while generation 2 is active
+echo "manual" > /tmp/bs-file
+mkdir -p /tmp/bs-dir && echo "manual" > /tmp/bs-dir/notes
+
+echo "=== Without the option an occupied target stops the switch ==="
+if $IMP --config /tmp/imp.toml switch 1 > /tmp/plain.log 2>&1; then
+    echo "ERROR: switch overwrote an existing file"; exit 1
+fi
+[ "$(cat /tmp/bs-file)" = "manual" ] || { echo "ERROR: manual file lost"; exit 1; }
+$IMP --config /tmp/other.toml switch 2 --no-verify > /dev/null 2>&1 || true
+echo "manual" > /tmp/bs-file
+
+echo "=== --backup-on-switch moves existing content aside and records it ==="
+$IMP --config /tmp/imp.toml switch 1 --backup-on-switch | tee /tmp/switch.log
+[ "$(grep -c 'Backed up to' /tmp/switch.log)" = "2" ] || { echo "ERROR: expected two backups"; exit 1; }
+[ "$(readlink /tmp/bs-file)" = "/persist/tmp/bs-file" ] || { echo "ERROR: file not linked"; exit 1; }
+mountpoint -q /tmp/bs-dir || { echo "ERROR: directory not mounted"; exit 1; }
+[ "$($IMP --config /tmp/imp.toml show 1 | grep -c '(backup: ')" = "2" ] || { echo "ERROR: backups not recorded"; exit 1; }
+
+echo "=== Leaving the generation restores the backed up content ==="
+$IMP --config /tmp/other.toml switch 2
+[ "$(cat /tmp/bs-file)" = "manual" ] || { echo "ERROR: file backup not restored"; exit 1; }
+[ "$(cat /tmp/bs-dir/notes)" = "manual" ] || { echo "ERROR: directory backup not restored"; exit 1; }
+
+echo "=== The config can turn it on by default ==="
+echo "persisted" > /persist/tmp/bs-file
+sed -i '1a backup_on_switch = true' /tmp/imp.toml
+$IMP --config /tmp/imp.toml switch 1 | grep "Backed up to: /tmp/bs-file" || { echo "ERROR: config default ignored"; exit 1; }
+
+echo ""
+echo "✅ Backup on switch tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Backup on switch tests", test_script);
+
+    assert!(
+        output.contains("✅ Backup on switch tests passed!"),
+        "Backup on switch tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_state_format_migration() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/mig-dir /tmp/mig-dir /tmp/imp-state
+echo "data" > /persist/tmp/mig-file
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/mig-dir"]
+files = ["/tmp/mig-file"]
+EOF
+
+echo "=== Version 1 without kinds (the original format) ==="
+cat > /tmp/imp-state/generations.json <<'EOF'
+[
+  {
+    "number": 1,
+    "created_at": "2024-01-01T00:00:00Z",
+    "config_path": "/tmp/imp.toml",
+    "symlinks": [
+      { "source": "/persist/tmp/mig-dir", "target": "/tmp/mig-dir", "backup_path": null },
+      { "source": "/persist/tmp/mig-file", "target": "/tmp/mig-file", "backup_path": null }
+    ],
+    "active": true
+  }
+]
+EOF
+$IMP --config /tmp/imp.toml list | grep -E "1 - 2024-01-01 00:00:00 - 2 symlinks \(active\)" || { echo "ERROR: v1 state not readable"; exit 1; }
+$IMP --config /tmp/imp.toml migrate-state | grep "Migrated state from version 1 to 2" || { echo "ERROR: v1 not migrated"; exit 1; }
+cat /tmp/imp-state/generations.json
+grep '"version": 2' /tmp/imp-state/generations.json || { echo "ERROR: version not written"; exit 1; }
+grep -B3 '"kind": "mount"' /tmp/imp-state/generations.json | grep '/tmp/mig-dir' || { echo "ERROR: directory kind not settled"; exit 1; }
+grep -B3 '"kind": "symlink"' /tmp/imp-state/generations.json | grep '/tmp/mig-file' || { echo "ERROR: file kind not settled"; exit 1; }
+$IMP --config /tmp/imp.toml migrate-state | grep "already at the current version (2)" || { echo "ERROR: migrated twice"; exit 1; }
+
+echo "=== Version 1 with kinds and mount options ==="
+cat > /tmp/imp-state/generations.json <<'EOF'
+[
+  {
+    "number": 4,
+    "created_at": "2024-02-01T00:00:00Z",
+    "config_path": "/tmp/imp.toml",
+    "symlinks": [
+      { "source": "/persist/tmp/mig-dir", "target": "/tmp/mig-dir", "backup_path": null, "kind": "mount", "mount_options": ["rw"] }
+    ],
+    "active": true
+  }
+]
+EOF
+$IMP --config /tmp/imp.toml show 4 | grep "/tmp/mig-dir -> /persist/tmp/mig-dir" || { echo "ERROR: state not readable"; exit 1; }
+
+echo "=== Writes upgrade the file without migrate-state ==="
+$IMP --config /tmp/imp.toml apply
+grep '"version": 2' /tmp/imp-state/generations.json || { echo "ERROR: apply did not write the current version"; exit 1; }
+$IMP --config /tmp/imp.toml list | grep -E "^  5 - .* \(active\)" || { echo "ERROR: numbering lost"; exit 1; }
+grep '"mount_options"' /tmp/imp-state/generations.json | head -1 | grep -q . || { echo "ERROR: old fields lost"; exit 1; }
+
+echo "=== Newer versions are refused ==="
+sed -i 's/"version": 2/"version": 99/' /tmp/imp-state/generations.json
+if $IMP --config /tmp/imp.toml list 2> /tmp/err.log; then
+    echo "ERROR: newer state accepted"; exit 1
+fi
+grep "has version 99, but this imp only understands up to 2" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+echo ""
+echo "✅ State migration tests passed!"
+"#;
+
+    let output = run_in_privileged_container("State migration tests", test_script);
+
+    assert!(
+        output.contains("✅ State migration tests passed!"),
+        "State migration tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_archive_round_trip() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/arc-dir/nested /tmp/arc-dir /persist/tmp/arc-conf /tmp/arc-conf
+echo "hello" > /persist/tmp/arc-dir/nested/data.txt
+chmod 600 /persist/tmp/arc-dir/nested/data.txt
+ln -s nested/data.txt /persist/tmp/arc-dir/link
+echo "setting=1" > /persist/tmp/arc-conf/app.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/arc-dir"]
+files = ["/tmp/arc-conf/app.conf", "/tmp/arc-missing"]
+EOF
+
+$IMP --config /tmp/imp.toml apply --skip-missing
+$IMP --config /tmp/imp.toml apply
+# A source that disappeared after the generation was created
+rm /persist/tmp/arc-missing
+
+echo "=== Archive a generation ==="
+$IMP --config /tmp/imp.toml archive 2 /tmp/gen2.tar.zst | tee /tmp/archive.log
+grep "Archived 2 source(s) of generation 2" /tmp/archive.log || { echo "ERROR: wrong summary"; exit 1; }
+grep "Skipping missing source: /persist/tmp/arc-missing" /tmp/archive.log || { echo "ERROR: missing source not reported"; exit 1; }
+test -s /tmp/gen2.tar.zst || { echo "ERROR: archive not written"; exit 1; }
+
+echo "=== Unarchive into a new root ==="
+$IMP unarchive /tmp/gen2.tar.zst /tmp/restored | tee /tmp/unarchive.log
+grep "Restored 2 source(s) of generation 2 into /tmp/restored" /tmp/unarchive.log || { echo "ERROR: wrong summary"; exit 1; }
+grep "/tmp/arc-dir <- /tmp/restored/tmp/arc-dir" /tmp/unarchive.log || { echo "ERROR: mapping not shown"; exit 1; }
+diff -r /persist/tmp/arc-dir /tmp/restored/tmp/arc-dir || { echo "ERROR: directory differs"; exit 1; }
+diff /persist/tmp/arc-conf/app.conf /tmp/restored/tmp/arc-conf/app.conf || { echo "ERROR: file differs"; exit 1; }
+test -L /tmp/restored/tmp/arc-dir/link || { echo "ERROR: symlink not preserved"; exit 1; }
+test "$(stat -c %a /tmp/restored/tmp/arc-dir/nested/data.txt)" = "600" || { echo "ERROR: mode not preserved"; exit 1; }
+test ! -e /tmp/restored/.imp-unarchive || { echo "ERROR: staging left behind"; exit 1; }
+
+echo "=== The restored root works as a persistence root ==="
+cat > /tmp/imp-restored.toml <<'EOF'
+state_dir = "/tmp/imp-state-restored"
+
+[persistence."/tmp/restored"]
+directories = ["/tmp/arc-dir"]
+files = ["/tmp/arc-conf/app.conf"]
+EOF
+$IMP --config /tmp/imp.toml switch 1 > /dev/null
+umount /tmp/arc-dir 2>/dev/null || true
+$IMP --config /tmp/imp-restored.toml apply
+grep hello /tmp/arc-dir/nested/data.txt || { echo "ERROR: restored data not applied"; exit 1; }
+
+echo "=== Refuses a non-empty root ==="
+if $IMP unarchive /tmp/gen2.tar.zst /tmp/restored 2> /tmp/err.log; then
+    echo "ERROR: restored over existing data"; exit 1
+fi
+grep "the directory is not empty" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+echo ""
+echo "✅ Archive tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Archive tests", test_script);
+
+    assert!(
+        output.contains("✅ Archive tests passed!"),
+        "Archive tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_remove_targets_deleted_out_of_band() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/oob-dir /persist/tmp/oob-conf /tmp/oob-dir /tmp/oob-conf
+echo "persisted" > /persist/tmp/oob-file
+echo "persisted" > /persist/tmp/oob-conf/app.conf
+echo "original" > /tmp/oob-conf/app.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+directories = ["/tmp/oob-dir"]
+files = ["/tmp/oob-file", "/tmp/oob-conf/app.conf"]
+EOF
+
+cat > /tmp/imp-empty.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = []
+files = []
+EOF
+
+$IMP --config /tmp/imp.toml apply
+ls /tmp/oob-conf/app.conf.imp-backup.* > /dev/null || { echo "ERROR: no backup created"; exit 1; }
+
+echo "=== Delete targets by hand ==="
+rm /tmp/oob-file
+umount /tmp/oob-dir
+rmdir /tmp/oob-dir
+rm /tmp/oob-conf/app.conf
+
+echo "=== Applying another config removes the rest without errors ==="
+$IMP --config /tmp/imp-empty.toml apply | tee /tmp/apply.log
+grep "Already removed: /tmp/oob-file" /tmp/apply.log || { echo "ERROR: missing symlink not reported"; exit 1; }
+grep "Already removed: /tmp/oob-dir" /tmp/apply.log || { echo "ERROR: missing mount point not reported"; exit 1; }
+test ! -e /tmp/oob-conf/app.conf || { echo "ERROR: backup restored over a target removed by hand"; exit 1; }
+ls /tmp/oob-conf/app.conf.imp-backup.* > /dev/null || { echo "ERROR: backup lost"; exit 1; }
+
+echo "=== A mount point unmounted by hand ==="
+$IMP --config /tmp/imp.toml apply
+umount /tmp/oob-dir
+$IMP --config /tmp/imp-empty.toml apply | tee /tmp/apply.log
+grep "Already unmounted: /tmp/oob-dir" /tmp/apply.log || { echo "ERROR: unmounted dir not reported"; exit 1; }
+test ! -e /tmp/oob-dir || { echo "ERROR: empty mount point left behind"; exit 1; }
+
+echo "=== Switching away from a hand-modified generation ==="
+$IMP --config /tmp/imp.toml apply
+rm /tmp/oob-file
+$IMP --config /tmp/imp.toml switch 2 --no-verify | tee /tmp/switch.log
+grep "Already removed: /tmp/oob-file" /tmp/switch.log || { echo "ERROR: switch did not tolerate the missing target"; exit 1; }
+
+echo ""
+echo "✅ Out-of-band removal tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Out-of-band removal tests", test_script);
+
+    assert!(
+        output.contains("✅ Out-of-band removal tests passed!"),
+        "Out-of-band removal tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_no_mount_mode_without_privileges() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+AS_USER="setpriv --reuid=1000 --regid=1000 --clear-groups"
+
+mkdir -p /tmp/nm/persist/tmp/nm/data /tmp/nm/state
+echo "persisted" > /tmp/nm/persist/tmp/nm/data/file.txt
+echo "persisted" > /tmp/nm/persist/tmp/nm/app.conf
+chown -R 1000:1000 /tmp/nm
+
+cat > /tmp/nm/imp.toml <<'EOF'
+state_dir = "/tmp/nm/state"
+
+[persistence."/tmp/nm/persist"]
+directories = ["/tmp/nm/data"]
+files = ["/tmp/nm/app.conf"]
+EOF
+
+echo "=== Without privileges, real mounts fail ==="
+if $AS_USER $IMP --config /tmp/nm/imp.toml apply 2> /tmp/err.log; then
+    echo "ERROR: unprivileged apply mounted"; exit 1
+fi
+
+echo "=== IMP_NO_MOUNT=1 records the generation without mounting ==="
+IMP_NO_MOUNT=1 $AS_USER $IMP --config /tmp/nm/imp.toml apply 2> /tmp/warn.log
+grep "IMP_NO_MOUNT is set: bind mounts are recorded but not performed" /tmp/warn.log || { echo "ERROR: no warning"; exit 1; }
+grep '"kind": "mount"' /tmp/nm/state/generations.json || { echo "ERROR: mount not recorded"; exit 1; }
+test -d /tmp/nm/data || { echo "ERROR: mount point not created"; exit 1; }
+test ! -e /tmp/nm/data/file.txt || { echo "ERROR: something was really mounted"; exit 1; }
+test -L /tmp/nm/app.conf || { echo "ERROR: file symlink not created"; exit 1; }
+IMP_NO_MOUNT=1 $AS_USER $IMP --config /tmp/nm/imp.toml verify
+
+echo "=== The generation lifecycle works with --no-mount ==="
+$AS_USER $IMP --no-mount --config /tmp/nm/imp.toml apply 2> /dev/null
+$AS_USER $IMP --no-mount --config /tmp/nm/imp.toml switch 1 2> /dev/null
+$AS_USER $IMP --config /tmp/nm/imp.toml current | grep "Current generation: 1" || { echo "ERROR: switch not recorded"; exit 1; }
+$AS_USER $IMP --no-mount --config /tmp/nm/imp.toml delete 2 --force 2> /dev/null
+$AS_USER $IMP --config /tmp/nm/imp.toml list | grep -c "symlinks" | grep -x 1 || { echo "ERROR: delete not recorded"; exit 1; }
+
+echo ""
+echo "✅ No-mount mode tests passed!"
+"#;
+
+    let output = run_in_privileged_container("No-mount mode tests", test_script);
+
+    assert!(
+        output.contains("✅ No-mount mode tests passed!"),
+        "No-mount mode tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_create_parents_override() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/cp/nested/dir /persist/tmp/cp/deep
+echo "data" > /persist/tmp/cp/deep/file.conf
+
+echo "=== create_parents = true on a file without parent_directory ==="
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = [{ file = "/tmp/cp/deep/file.conf", create_parents = true }]
+EOF
+$IMP --config /tmp/imp.toml apply
+test -L /tmp/cp/deep/file.conf || { echo "ERROR: parents not created for file"; exit 1; }
+rm -r /tmp/cp
+
+echo "=== create_parents = false on a directory fails loudly ==="
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [{ directory = "/tmp/cp/nested/dir", create_parents = false }]
+EOF
+if $IMP --config /tmp/imp.toml apply 2> /tmp/err.log; then
+    echo "ERROR: missing parent was created"; exit 1
+fi
+cat /tmp/err.log
+grep "Parent directory of /tmp/cp/nested/dir does not exist: /tmp/cp/nested" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+test ! -e /tmp/cp || { echo "ERROR: parent created anyway"; exit 1; }
+
+echo "=== create_parents = false is fine when the parent exists ==="
+mkdir -p /tmp/cp/nested
+$IMP --config /tmp/imp.toml apply
+mountpoint -q /tmp/cp/nested/dir || { echo "ERROR: not mounted"; exit 1; }
+
+echo "=== Defaults are unchanged ==="
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/cp2/nested/dir"]
+files = ["/tmp/cp3/deep/file.conf"]
+EOF
+mkdir -p /persist/tmp/cp2/nested/dir /persist/tmp/cp3/deep
+echo "data" > /persist/tmp/cp3/deep/file.conf
+if $IMP --config /tmp/imp.toml apply 2> /tmp/err.log; then
+    echo "ERROR: file parents created by default"; exit 1
+fi
+grep "create_parents is off" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+mkdir -p /tmp/cp3/deep
+$IMP --config /tmp/imp.toml apply
+mountpoint -q /tmp/cp2/nested/dir || { echo "ERROR: directory parents not created by default"; exit 1; }
+
+echo ""
+echo "✅ Create parents tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Create parents tests", test_script);
+
+    assert!(
+        output.contains("✅ Create parents tests passed!"),
+        "Create parents tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_daemon_health_socket() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/persist/tmp/health-dir
+echo 'file' > /tmp/persist/tmp/health-file
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/tmp/persist"]
+directories = ["/tmp/health-dir"]
+files = ["/tmp/health-file"]
+EOF
+
+echo "=== Without a daemon, ping fails ==="
+if $IMP ping /tmp/imp.sock 2> /tmp/err.log; then
+    echo "ERROR: ping succeeded without a daemon"; exit 1
+fi
+grep "Failed to connect to /tmp/imp.sock" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+$IMP --config /tmp/imp.toml daemon --socket /tmp/imp.sock > /tmp/daemon.log 2>&1 &
+DAEMON_PID=$!
+for i in $(seq 1 50); do
+    grep -q "Daemon running" /tmp/daemon.log && break
+    sleep 0.1
+done
+cat /tmp/daemon.log
+grep "Answering health checks on /tmp/imp.sock" /tmp/daemon.log || { echo "ERROR: socket not announced"; exit 1; }
+
+echo "=== A healthy daemon ==="
+$IMP ping /tmp/imp.sock | tee /tmp/ping.log
+grep "\"pid\":$DAEMON_PID" /tmp/ping.log || { echo "ERROR: wrong pid"; exit 1; }
+grep '"generation":1' /tmp/ping.log || { echo "ERROR: wrong generation"; exit 1; }
+grep '"healthy":true' /tmp/ping.log || { echo "ERROR: not healthy"; exit 1; }
+grep '"problems":\[\]' /tmp/ping.log || { echo "ERROR: unexpected problems"; exit 1; }
+
+echo "=== Problems are reported ==="
+nsenter -t $DAEMON_PID -m umount /tmp/health-dir
+if $IMP ping /tmp/imp.sock > /tmp/ping.log; then
+    echo "ERROR: ping succeeded with a broken mount"; exit 1
+fi
+cat /tmp/ping.log
+grep '"healthy":false' /tmp/ping.log || { echo "ERROR: still healthy"; exit 1; }
+grep 'Directory is not a mount point: /tmp/health-dir' /tmp/ping.log || { echo "ERROR: problem not listed"; exit 1; }
+
+echo "=== The socket goes away with the daemon ==="
+kill -TERM $DAEMON_PID
+wait $DAEMON_PID
+test ! -e /tmp/imp.sock || { echo "ERROR: socket left behind"; exit 1; }
+
+echo ""
+echo "✅ Daemon health socket tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Daemon health socket tests", test_script);
+
+    assert!(
+        output.contains("✅ Daemon health socket tests passed!"),
+        "Daemon health socket tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_apply_resume() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/rs-dir /persist/tmp/rs-blocked
+echo "one" > /persist/tmp/rs-one
+echo "two" > /persist/tmp/rs-blocked/file
+# A regular file where a parent directory is needed makes one entry fail
+echo "in the way" > /tmp/rs-blocked
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/rs-dir"]
+files = ["/tmp/rs-one", "/tmp/rs-blocked/file"]
+EOF
+
+echo "=== Nothing to resume without a partial generation ==="
+if $IMP --config /tmp/imp.toml apply --resume 2> /tmp/err.log; then
+    echo "ERROR: resumed without a generation"; exit 1
+fi
+grep "Nothing to resume: there is no active generation" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+echo "=== A partial apply records the failed entry ==="
+if $IMP --config /tmp/imp.toml apply --keep-going > /tmp/kg.log 2>&1; then
+    echo "ERROR: apply succeeded"; exit 1
+fi
+grep "Fix them and run 'imp apply --resume'" /tmp/kg.log || { echo "ERROR: no resume hint"; exit 1; }
+$IMP --config /tmp/imp.toml show 1 | tee /tmp/show.log
+grep -A1 "Failed (apply --resume to retry):" /tmp/show.log | grep "/tmp/rs-blocked/file" || { echo "ERROR: failure not recorded"; exit 1; }
+
+echo "=== Fix the entry and resume ==="
+INODE_BEFORE=$(stat -c %i /tmp/rs-one)
+MOUNT_BEFORE=$(grep " /tmp/rs-dir " /proc/self/mountinfo | cut -d' ' -f1)
+rm /tmp/rs-blocked && mkdir /tmp/rs-blocked
+$IMP --config /tmp/imp.toml apply --resume | tee /tmp/resume.log
+grep "Resuming generation 1: applying 1 failed entries" /tmp/resume.log || { echo "ERROR: wrong entries resumed"; exit 1; }
+grep "Removing symlinks" /tmp/resume.log && { echo "ERROR: applied entries were churned"; exit 1; }
+test "$(stat -c %i /tmp/rs-one)" = "$INODE_BEFORE" || { echo "ERROR: symlink recreated"; exit 1; }
+test "$(grep " /tmp/rs-dir " /proc/self/mountinfo | cut -d' ' -f1)" = "$MOUNT_BEFORE" || { echo "ERROR: mount recreated"; exit 1; }
+test -L /tmp/rs-blocked/file || { echo "ERROR: fixed entry not applied"; exit 1; }
+
+$IMP --config /tmp/imp.toml list | tee /tmp/list.log
+grep -E "1 - .* - 3 symlinks \(active\)" /tmp/list.log || { echo "ERROR: entry not merged into generation 1"; exit 1; }
+grep -E "^  2 " /tmp/list.log && { echo "ERROR: a new generation was created"; exit 1; }
+$IMP --config /tmp/imp.toml show 1 | grep "Failed" && { echo "ERROR: failure still recorded"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+
+echo "=== Nothing left to resume ==="
+if $IMP --config /tmp/imp.toml apply --resume 2> /tmp/err.log; then
+    echo "ERROR: resumed a complete generation"; exit 1
+fi
+grep "generation 1 has no failed entries" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+echo ""
+echo "✅ Resume tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Resume tests", test_script);
+
+    assert!(
+        output.contains("✅ Resume tests passed!"),
+        "Resume tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_device_mount_loopback_image() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+truncate -s 32M /tmp/persist.img
+mkfs.ext4 -q -F /tmp/persist.img
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [{ directory = "/tmp/img-target", image = "/tmp/persist.img" }]
+EOF
+
+cat > /tmp/imp-empty.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = []
+EOF
+
+echo "=== Mount an image through a loop device ==="
+$IMP --config /tmp/imp.toml apply | tee /tmp/apply.log
+grep -E "Mounted image: /tmp/img-target -> /tmp/persist.img \(via /dev/loop[0-9]+, ext4\)" /tmp/apply.log || { echo "ERROR: image not mounted"; exit 1; }
+grep " /tmp/img-target " /proc/self/mountinfo | grep ext4 || { echo "ERROR: no ext4 mount at target"; exit 1; }
+test ! -e /persist/tmp/img-target || { echo "ERROR: a bind source was created"; exit 1; }
+echo "on the image" > /tmp/img-target/hello
+cat /tmp/imp-state/generations.json
+grep '"kind": "device"' /tmp/imp-state/generations.json || { echo "ERROR: kind not recorded"; exit 1; }
+grep '"fstype": "ext4"' /tmp/imp-state/generations.json || { echo "ERROR: fstype not recorded"; exit 1; }
+LOOP=$(grep -o '"loop_device": "[^"]*"' /tmp/imp-state/generations.json | cut -d'"' -f4)
+test -n "$LOOP" || { echo "ERROR: loop device not recorded"; exit 1; }
+losetup "$LOOP" | grep /tmp/persist.img || { echo "ERROR: loop device not backed by the image"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+
+echo "=== Teardown unmounts and detaches ==="
+$IMP --config /tmp/imp-empty.toml apply | tee /tmp/apply.log
+grep "Unmounted: /tmp/img-target" /tmp/apply.log || { echo "ERROR: not unmounted"; exit 1; }
+! grep " /tmp/img-target " /proc/self/mountinfo || { echo "ERROR: still mounted"; exit 1; }
+test -z "$(losetup -j /tmp/persist.img)" || { echo "ERROR: loop device still attached"; exit 1; }
+
+echo "=== Data persists on the image; switching remounts it ==="
+sed -i 's|image = "/tmp/persist.img"|image = "/tmp/persist.img", fstype = "ext4"|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+grep "on the image" /tmp/img-target/hello || { echo "ERROR: data lost"; exit 1; }
+$IMP --config /tmp/imp.toml switch 2
+! grep " /tmp/img-target " /proc/self/mountinfo || { echo "ERROR: still mounted after switch"; exit 1; }
+$IMP --config /tmp/imp.toml switch 3 | tee /tmp/switch.log
+grep "on the image" /tmp/img-target/hello || { echo "ERROR: not remounted by switch"; exit 1; }
+
+echo "=== A wrong fstype fails clearly ==="
+$IMP --config /tmp/imp-empty.toml apply > /dev/null
+sed -i 's|fstype = "ext4"|fstype = "xfs"|' /tmp/imp.toml
+if $IMP --config /tmp/imp.toml apply 2> /tmp/err.log; then
+    echo "ERROR: mounted with the wrong fstype"; exit 1
+fi
+grep "Failed to mount /dev/loop" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+test -z "$(losetup -j /tmp/persist.img)" || { echo "ERROR: loop device leaked after a failed mount"; exit 1; }
+
+echo "=== device and image are exclusive ==="
+cat > /tmp/imp-bad.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [{ directory = "/tmp/img-target", image = "/tmp/persist.img", device = "/dev/loop0" }]
+EOF
+if $IMP --config /tmp/imp-bad.toml apply 2> /tmp/err.log; then
+    echo "ERROR: accepted both device and image"; exit 1
+fi
+grep "sets both device and image" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+echo ""
+echo "✅ Device mount tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Device mount tests", test_script);
+
+    assert!(
+        output.contains("✅ Device mount tests passed!"),
+        "Device mount tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_log_generations() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+mkdir -p /persist /persist-b
+
+cat > /tmp/imp1.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/log-a", "/tmp/log-b"]
+EOF
+
+cat > /tmp/imp2.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/log-a", "/tmp/log-c"]
+EOF
+
+cat > /tmp/imp3.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/log-c"]
+
+[persistence."/persist-b"]
+directories = ["/tmp/log-a"]
+EOF
+
+echo "=== Empty state ==="
+$IMP --config /tmp/imp1.toml log | grep "No generations found." || { echo "ERROR: empty log"; exit 1; }
+
+$IMP --config /tmp/imp1.toml apply > /dev/null
+$IMP --config /tmp/imp2.toml apply > /dev/null
+$IMP --config /tmp/imp2.toml apply > /dev/null
+$IMP --config /tmp/imp3.toml apply > /dev/null
+
+echo "=== Per-generation changes ==="
+$IMP --config /tmp/imp3.toml log | tee /tmp/log.txt
+grep -A3 "^Generation 1 - " /tmp/log.txt | grep -F "+ /tmp/log-a -> /persist/tmp/log-a" || { echo "ERROR: gen 1 additions"; exit 1; }
+grep -A3 "^Generation 1 - " /tmp/log.txt | grep -F "+ /tmp/log-b -> /persist/tmp/log-b" || { echo "ERROR: gen 1 additions"; exit 1; }
+grep -A3 "^Generation 2 - " /tmp/log.txt | grep -F "+ /tmp/log-c -> /persist/tmp/log-c" || { echo "ERROR: gen 2 addition"; exit 1; }
+grep -A3 "^Generation 2 - " /tmp/log.txt | grep -F -- "- /tmp/log-b -> /persist/tmp/log-b" || { echo "ERROR: gen 2 removal"; exit 1; }
+grep -A1 "^Generation 3 - " /tmp/log.txt | grep -F "(no changes)" || { echo "ERROR: gen 3 should be unchanged"; exit 1; }
+grep "^Generation 4 - .*(active)" /tmp/log.txt || { echo "ERROR: active marker"; exit 1; }
+grep -A2 "^Generation 4 - " /tmp/log.txt | grep -F "~ /tmp/log-a: /persist/tmp/log-a -> /persist-b/tmp/log-a" || { echo "ERROR: gen 4 change"; exit 1; }
+
+echo "=== Deleted generations are skipped ==="
+$IMP --config /tmp/imp3.toml delete 3 --force > /dev/null
+$IMP --config /tmp/imp3.toml log | tee /tmp/log.txt
+! grep "^Generation 3 " /tmp/log.txt || { echo "ERROR: deleted generation shown"; exit 1; }
+grep "^Generation 4 - .*(compared to generation 2)" /tmp/log.txt || { echo "ERROR: predecessor not named"; exit 1; }
+
+echo ""
+echo "✅ Log tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Log tests", test_script);
+
+    assert!(
+        output.contains("✅ Log tests passed!"),
+        "Log tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_mounts_lists_active_mounts() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/app "/persist/tmp/app/with space" /persist/tmp/app/data
+echo 'x' > /persist/tmp/app/file.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/app/data", "/tmp/app/with space"]
+files = ["/tmp/app/file.conf"]
+EOF
+$IMP --config /tmp/imp.toml apply
+
+echo "=== Mounts match the kernel's mount table ==="
+$IMP --config /tmp/imp.toml mounts | tee /tmp/mounts.log
+grep "Mounts of generation 1:" /tmp/mounts.log || { echo "ERROR: header missing"; exit 1; }
+for target in /tmp/app/data "/tmp/app/with space"; do
+    options=$(findmnt -n -o VFS-OPTIONS --mountpoint "$target")
+    grep -F "  $target <- /persist$target ($options)" /tmp/mounts.log || { echo "ERROR: $target not listed as findmnt reports it"; exit 1; }
+done
+grep "file.conf" /tmp/mounts.log && { echo "ERROR: symlink listed as a mount"; exit 1; }
+grep "2 of 2 mount(s) active" /tmp/mounts.log || { echo "ERROR: wrong summary"; exit 1; }
+
+echo "=== A mount that is gone is reported ==="
+umount /tmp/app/data
+$IMP --config /tmp/imp.toml mounts | tee /tmp/mounts.log
+grep "  /tmp/app/data (not mounted)" /tmp/mounts.log || { echo "ERROR: missing mount not reported"; exit 1; }
+grep "1 of 2 mount(s) active" /tmp/mounts.log || { echo "ERROR: wrong summary"; exit 1; }
+
+echo ""
+echo "✅ Mounts tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Mounts tests", test_script);
+
+    assert!(
+        output.contains("✅ Mounts tests passed!"),
+        "Mounts tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_diff_generations_and_filesystem() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/diff-a /persist/tmp/diff-b /persist/tmp/diff-d /tmp/elsewhere-dir
+echo data > /persist/tmp/diff.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/diff-a", "/tmp/diff-b"]
+files = ["/tmp/diff.conf"]
+EOF
+$IMP --config /tmp/imp.toml apply
+sed -i 's|"/tmp/diff-b"|"/tmp/diff-d"|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply
+
+echo "=== Two generations ==="
+$IMP --config /tmp/imp.toml diff 1 2 | tee /tmp/diff.log
+grep "Changes from generation 1 to generation 2:" /tmp/diff.log || { echo "ERROR: no header"; exit 1; }
+grep -- "+ /tmp/diff-d -> /persist/tmp/diff-d" /tmp/diff.log || { echo "ERROR: addition missing"; exit 1; }
+grep -- "- /tmp/diff-b -> /persist/tmp/diff-b" /tmp/diff.log || { echo "ERROR: removal missing"; exit 1; }
+grep "1 added, 1 removed, 0 changed" /tmp/diff.log || { echo "ERROR: wrong summary"; exit 1; }
+
+echo "=== A generation that matches the filesystem ==="
+$IMP --config /tmp/imp.toml diff 2 --fs | grep "(no differences)" || { echo "ERROR: clean state differs"; exit 1; }
+
+echo "=== Drift shows up as added, removed and changed ==="
+umount /tmp/diff-a
+ln -sfn /tmp/elsewhere.conf /tmp/diff.conf
+umount /tmp/diff-d
+mount --bind /tmp/elsewhere-dir /tmp/diff-d
+mkdir -p /tmp/diff-b && mount --bind /persist/tmp/diff-b /tmp/diff-b
+
+$IMP --config /tmp/imp.toml diff 2 --fs | tee /tmp/diff.log
+grep "Changes from generation 2 to the filesystem:" /tmp/diff.log || { echo "ERROR: no header"; exit 1; }
+grep -- "- /tmp/diff-a -> /persist/tmp/diff-a" /tmp/diff.log || { echo "ERROR: lost mount not removed"; exit 1; }
+grep -- "~ /tmp/diff.conf: /persist/tmp/diff.conf -> /tmp/elsewhere.conf" /tmp/diff.log || { echo "ERROR: repointed symlink not changed"; exit 1; }
+grep -- "~ /tmp/diff-d: /persist/tmp/diff-d -> /tmp/elsewhere-dir" /tmp/diff.log || { echo "ERROR: wrong mount source not changed"; exit 1; }
+grep -- "+ /tmp/diff-b -> /persist/tmp/diff-b" /tmp/diff.log || { echo "ERROR: orphan not added"; exit 1; }
+grep "1 added, 1 removed, 2 changed" /tmp/diff.log || { echo "ERROR: wrong summary"; exit 1; }
+
+echo "=== Bad arguments ==="
+$IMP --config /tmp/imp.toml diff 2 2> /dev/null && { echo "ERROR: diff without a second generation or --fs accepted"; exit 1; }
+$IMP --config /tmp/imp.toml diff 9 --fs 2>&1 | grep "Generation 9 not found" || { echo "ERROR: unknown generation"; exit 1; }
+
+echo ""
+echo "✅ Diff tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Diff tests", test_script);
+
+    assert!(
+        output.contains("✅ Diff tests passed!"),
+        "Diff tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_toml_state_format_round_trip() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+mkdir -p /persist/tmp/fmt /tmp/fmt
+echo "existing" > /tmp/fmt/file.txt
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+directories = ["/tmp/fmt/dir"]
+files = ["/tmp/fmt/file.txt"]
+EOF
+
+echo "=== Generations in JSON ==="
+$IMP --config /tmp/imp.toml apply > /dev/null
+$IMP --config /tmp/imp.toml apply > /dev/null
+grep '"backup_path": "/tmp/fmt/file.txt.imp-backup' /tmp/imp-state/generations.json || { echo "ERROR: expected a recorded backup"; exit 1; }
+cp /tmp/imp-state/generations.json /tmp/original.json
+for n in 1 2; do $IMP --config /tmp/imp.toml show $n > /tmp/json-show-$n.txt; done
+$IMP --config /tmp/imp.toml list > /tmp/json-list.txt
+
+echo "=== Convert to TOML ==="
+sed -i 's|^backup = true|backup = true\nstate_format = "toml"|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml migrate-state | tee /tmp/migrate.log
+grep "Converted state to toml" /tmp/migrate.log || { echo "ERROR: conversion not reported"; exit 1; }
+test ! -e /tmp/imp-state/generations.json || { echo "ERROR: JSON state left behind"; exit 1; }
+cat /tmp/imp-state/generations.toml
+head -1 /tmp/imp-state/generations.toml | grep "^# imp generation state" || { echo "ERROR: missing header"; exit 1; }
+grep "^version = 2" /tmp/imp-state/generations.toml || { echo "ERROR: missing version"; exit 1; }
+grep "^\[\[generations\]\]" /tmp/imp-state/generations.toml || { echo "ERROR: not TOML tables"; exit 1; }
+
+echo "=== TOML state reads back identically ==="
+for n in 1 2; do
+    $IMP --config /tmp/imp.toml show $n > /tmp/toml-show-$n.txt
+    diff /tmp/json-show-$n.txt /tmp/toml-show-$n.txt || { echo "ERROR: generation $n differs"; exit 1; }
+done
+$IMP --config /tmp/imp.toml list > /tmp/toml-list.txt
+diff /tmp/json-list.txt /tmp/toml-list.txt || { echo "ERROR: list differs"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+$IMP --config /tmp/imp.toml migrate-state | grep "already at the current version" || { echo "ERROR: reconverted"; exit 1; }
+! $IMP --config /tmp/imp.toml migrate-state | grep "Converted" || { echo "ERROR: reconverted"; exit 1; }
+
+echo "=== Converting back to JSON reproduces the original file ==="
+sed -i '/^state_format/d' /tmp/imp.toml
+$IMP --config /tmp/imp.toml list | grep "2 - " || { echo "ERROR: TOML state not read with the json setting"; exit 1; }
+$IMP --config /tmp/imp.toml migrate-state | grep "Converted state to json" || { echo "ERROR: conversion back not reported"; exit 1; }
+test ! -e /tmp/imp-state/generations.toml || { echo "ERROR: TOML state left behind"; exit 1; }
+cmp /tmp/original.json /tmp/imp-state/generations.json || { echo "ERROR: JSON round trip changed the state"; exit 1; }
+
+echo "=== Hand-edited TOML state ==="
+sed -i 's|^backup = true|backup = true\nstate_format = "toml"|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply > /dev/null
+test -e /tmp/imp-state/generations.toml || { echo "ERROR: apply did not write TOML"; exit 1; }
+$IMP --config /tmp/imp.toml list | grep "3 - .*(active)" || { echo "ERROR: generation 3 missing"; exit 1; }
+sed -i 's|^config_path = "/tmp/imp.toml"|config_path = "/tmp/edited.toml"|' /tmp/imp-state/generations.toml
+$IMP --config /tmp/imp.toml show 1 | grep "Config: /tmp/edited.toml" || { echo "ERROR: hand edit not read"; exit 1; }
+# With no backup to recover from, a corrupt state file is an error
+rm -f /tmp/imp-state/generations.toml.bak
+echo "not = [valid" >> /tmp/imp-state/generations.toml
+if $IMP --config /tmp/imp.toml list 2> /tmp/err.log; then
+    echo "ERROR: corrupt state accepted"; exit 1
+fi
+cat /tmp/err.log
+grep "Failed to parse state file: /tmp/imp-state/generations.toml" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+grep "TOML parse error" /tmp/err.log || { echo "ERROR: expected the TOML error"; exit 1; }
+
+echo ""
+echo "✅ TOML state tests passed!"
+"#;
+
+    let output = run_in_privileged_container("TOML state tests", test_script);
+
+    assert!(
+        output.contains("✅ TOML state tests passed!"),
+        "TOML state tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_inherit_ownership_from_parent() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /home/inherit-owner /persist
+chown 1234:2345 /home/inherit-owner
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [
+    { directory = "/home/inherit-owner/data", user = "inherit", group = "inherit" },
+    { directory = "/home/inherit-owner/group-only", group = "inherit" },
+]
+EOF
+
+echo "=== Targets inherit their parent's owner ==="
+$IMP --config /tmp/imp.toml apply
+# A non-recursive bind of /home shows the mount points themselves, not what is mounted on them
+mkdir -p /mnt/view
+mount --bind /home /mnt/view
+stat -c '%u:%g %n' /mnt/view/inherit-owner/*
+[ "$(stat -c %u:%g /mnt/view/inherit-owner/data)" = "1234:2345" ] || { echo "ERROR: owner not inherited"; exit 1; }
+[ "$(stat -c %u:%g /mnt/view/inherit-owner/group-only)" = "0:2345" ] || { echo "ERROR: only the group should be inherited"; exit 1; }
+umount /mnt/view
+
+echo "=== A source created from its target inherits the source's parent ==="
+mkdir -p /home/inherit-owner/existing /persist/home/inherit-owner
+echo "kept" > /home/inherit-owner/existing/file
+chown 3456:4567 /persist/home/inherit-owner
+sed -i 's|^]$|    { directory = "/home/inherit-owner/existing", user = "inherit", group = "inherit" },\n]|' /tmp/imp.toml
+cat /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply --skip-validation | tee /tmp/apply.log
+grep "Creating source from target" /tmp/apply.log || { echo "ERROR: source not created from target"; exit 1; }
+[ "$(stat -c %u:%g /persist/home/inherit-owner/existing)" = "3456:4567" ] || { echo "ERROR: source owner not inherited"; exit 1; }
+
+echo "=== Without inherit the source still copies the target ==="
+mkdir -p /home/inherit-owner/copied
+chown 5678:6789 /home/inherit-owner/copied
+sed -i 's|^]$|    "/home/inherit-owner/copied",\n]|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply --skip-validation > /dev/null
+[ "$(stat -c %u:%g /persist/home/inherit-owner/copied)" = "5678:6789" ] || { echo "ERROR: source should copy the target's owner"; exit 1; }
+
+echo ""
+echo "✅ Inherit ownership tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Inherit ownership tests", test_script);
+
+    assert!(
+        output.contains("✅ Inherit ownership tests passed!"),
+        "Inherit ownership tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_mount_point_mode() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [
+    { directory = "/home/mpm/both", mode = "0700", mount_point_mode = "0755" },
+    { directory = "/home/mpm/content-only", mode = "0750" },
+    { directory = "/home/mpm/point-only", mount_point_mode = "0711" },
+]
+EOF
+
+$IMP --config /tmp/imp.toml apply
+# A non-recursive bind of /home shows the mount points themselves, not what is mounted on them
+mkdir -p /mnt/view
+mount --bind /home /mnt/view
+stat -c '%a %n' /mnt/view/mpm/* /home/mpm/*
+
+echo "=== mount_point_mode and mode set independently ==="
+[ "$(stat -c %a /mnt/view/mpm/both)" = "755" ] || { echo "ERROR: mount point mode not applied"; exit 1; }
+[ "$(stat -c %a /home/mpm/both)" = "700" ] || { echo "ERROR: content mode not applied"; exit 1; }
+[ "$(stat -c %a /persist/home/mpm/both)" = "700" ] || { echo "ERROR: source mode not applied"; exit 1; }
+
+echo "=== Without mount_point_mode the mount point gets mode ==="
+[ "$(stat -c %a /mnt/view/mpm/content-only)" = "750" ] || { echo "ERROR: mount point should default to mode"; exit 1; }
+[ "$(stat -c %a /home/mpm/content-only)" = "750" ] || { echo "ERROR: content mode not applied"; exit 1; }
+
+echo "=== mount_point_mode alone leaves the content alone ==="
+[ "$(stat -c %a /mnt/view/mpm/point-only)" = "711" ] || { echo "ERROR: mount point mode not applied"; exit 1; }
+[ "$(stat -c %a /home/mpm/point-only)" = "755" ] || { echo "ERROR: content mode changed"; exit 1; }
+umount /mnt/view
+
+echo ""
+echo "✅ Mount point mode tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Mount point mode tests", test_script);
+
+    assert!(
+        output.contains("✅ Mount point mode tests passed!"),
+        "Mount point mode tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_read_only_target_filesystem() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist /mnt/ro
+mount -t tmpfs tmpfs /mnt/ro
+mkdir -p /mnt/ro/etc
+mount -o remount,ro /mnt/ro
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/mnt/ro/etc/machine-id"]
+EOF
+
+echo "=== Linking into a read-only filesystem explains why it fails ==="
+if $IMP --config /tmp/imp.toml apply 2> /tmp/err.log; then
+    echo "ERROR: apply succeeded on a read-only filesystem"; exit 1
+fi
+cat /tmp/err.log
+grep "Failed to create symlink from /persist/mnt/ro/etc/machine-id to /mnt/ro/etc/machine-id: the filesystem mounted at /mnt/ro is read-only" /tmp/err.log || { echo "ERROR: read-only filesystem not explained"; exit 1; }
+grep "writable tmpfs" /tmp/err.log || { echo "ERROR: no hint about tmpfs"; exit 1; }
+grep "Read-only file system" /tmp/err.log || { echo "ERROR: underlying error missing"; exit 1; }
+
+echo "=== Works once the filesystem is writable ==="
+mount -o remount,rw /mnt/ro
+$IMP --config /tmp/imp.toml apply
+test -L /mnt/ro/etc/machine-id || { echo "ERROR: symlink not created"; exit 1; }
+
+echo ""
+echo "✅ Read-only target tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Read-only target tests", test_script);
+
+    assert!(
+        output.contains("✅ Read-only target tests passed!"),
+        "Read-only target tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_retention_policy() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+mkdir -p /persist
+
+# Write a config with the given [retention] rules and start from empty state
+setup() {
+    rm -rf /tmp/imp-state
+    cat > /tmp/imp.toml <<EOF
+state_dir = "/tmp/imp-state"
+
+[retention]
+$1
+
+[persistence."/persist"]
+directories = ["/tmp/retained"]
+EOF
+}
+
+# Move the oldest generation not yet backdated into 1999
+backdate() {
+    sed -i '0,/"created_at": "2[0-9]\{3\}/s//"created_at": "1999/' /tmp/imp-state/generations.json
+}
+
+generations() {
+    $IMP --config /tmp/imp.toml list --format '{number}' | tr '\n' ' ' | sed 's/ $//'
+}
+
+echo "=== keep_last ==="
+setup "keep_last = 2"
+$IMP --config /tmp/imp.toml apply > /dev/null
+$IMP --config /tmp/imp.toml apply > /tmp/apply.log
+! grep "Pruned" /tmp/apply.log || { echo "ERROR: pruned within the limit"; exit 1; }
+$IMP --config /tmp/imp.toml apply | tee /tmp/apply.log
+grep "Pruned: 1 generation(s) by retention policy (1)" /tmp/apply.log || { echo "ERROR: prune not reported"; exit 1; }
+$IMP --config /tmp/imp.toml apply > /dev/null
+[ "$(generations)" = "3 4" ] || { echo "ERROR: keep_last kept $(generations)"; exit 1; }
+
+echo "=== keep_within ==="
+setup 'keep_within = "30d"'
+for i in 1 2 3; do $IMP --config /tmp/imp.toml apply > /dev/null; done
+backdate; backdate
+$IMP --config /tmp/imp.toml apply | tee /tmp/apply.log
+grep "Pruned: 2 generation(s) by retention policy (1, 2)" /tmp/apply.log || { echo "ERROR: old generations not pruned"; exit 1; }
+[ "$(generations)" = "3 4" ] || { echo "ERROR: keep_within kept $(generations)"; exit 1; }
+
+echo "=== keep_minimum ==="
+setup 'keep_within = "30d"
+keep_minimum = 4'
+for i in 1 2 3; do $IMP --config /tmp/imp.toml apply > /dev/null; done
+backdate; backdate; backdate
+$IMP --config /tmp/imp.toml apply > /tmp/apply.log
+! grep "Pruned" /tmp/apply.log || { echo "ERROR: pruned below the minimum"; exit 1; }
+[ "$(generations)" = "1 2 3 4" ] || { echo "ERROR: keep_minimum kept $(generations)"; exit 1; }
+sed -i 's/keep_minimum = 4/keep_minimum = 2/' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply > /dev/null
+[ "$(generations)" = "4 5" ] || { echo "ERROR: lower minimum kept $(generations)"; exit 1; }
+
+echo "=== Rules combine as a union ==="
+setup 'keep_last = 1
+keep_within = "30d"
+keep_minimum = 4'
+for i in 1 2 3 4 5 6; do $IMP --config /tmp/imp.toml apply > /dev/null; done
+backdate; backdate; backdate; backdate
+$IMP --config /tmp/imp.toml apply | tee /tmp/apply.log
+grep "Pruned: 3 generation(s) by retention policy (1, 2, 3)" /tmp/apply.log || { echo "ERROR: wrong union"; exit 1; }
+[ "$(generations)" = "4 5 6 7" ] || { echo "ERROR: union kept $(generations)"; exit 1; }
+$IMP --config /tmp/imp.toml current | grep "Current generation: 7" || { echo "ERROR: active generation changed"; exit 1; }
+
+echo "=== Invalid ages are rejected ==="
+setup 'keep_within = "30 days"'
+if $IMP --config /tmp/imp.toml apply 2> /tmp/err.log; then
+    echo "ERROR: invalid keep_within accepted"; exit 1
+fi
+grep "Invalid retention.keep_within" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+
+echo ""
+echo "✅ Retention tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Retention tests", test_script);
+
+    assert!(
+        output.contains("✅ Retention tests passed!"),
+        "Retention tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_protected_generations() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+mkdir -p /persist
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[retention]
+keep_last = 2
+keep_generations = [1]
+
+[persistence."/persist"]
+directories = ["/tmp/retained"]
+EOF
+
+generations() {
+    $IMP --config /tmp/imp.toml list --format '{number}' | tr '\n' ' ' | sed 's/ $//'
+}
+
+echo "=== Retention keeps a protected generation outside the window ==="
+for i in 1 2 3; do $IMP --config /tmp/imp.toml apply > /dev/null; done
+$IMP --config /tmp/imp.toml apply | tee /tmp/apply.log
+grep "Pruned: 1 generation(s) by retention policy (2)" /tmp/apply.log || { echo "ERROR: wrong generations pruned"; exit 1; }
+[ "$(generations)" = "1 3 4" ] || { echo "ERROR: retention kept $(generations)"; exit 1; }
+
+echo "=== Protection alone prunes nothing ==="
+sed -i '/^keep_last/d' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply > /tmp/apply.log
+! grep "Pruned" /tmp/apply.log || { echo "ERROR: keep_generations alone pruned"; exit 1; }
+
+echo "=== Dedupe keeps protected generations ==="
+# 1, 3, 4 and 5 are identical; 5 is active and 1 is protected by the config
+$IMP --config /tmp/imp.toml dedupe --keep-generation 3 | tee /tmp/dedupe.log
+grep "  1, 3, 4, 5 (keep 1, 3, 5; prune 4)" /tmp/dedupe.log || { echo "ERROR: protection not reported"; exit 1; }
+$IMP --config /tmp/imp.toml dedupe --apply --keep-generation 3 | tee /tmp/dedupe.log
+grep "Deleted 1 redundant generation(s) (4)" /tmp/dedupe.log || { echo "ERROR: protected generation deleted"; exit 1; }
+[ "$(generations)" = "1 3 5" ] || { echo "ERROR: dedupe kept $(generations)"; exit 1; }
+$IMP --config /tmp/imp.toml dedupe --apply --keep-generation 3 | tee /tmp/dedupe.log
+grep "Every identical generation is protected; nothing to delete" /tmp/dedupe.log || { echo "ERROR: empty deletion not reported"; exit 1; }
+
+echo ""
+echo "✅ Protected generation tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Protected generation tests", test_script);
+
+    assert!(
+        output.contains("✅ Protected generation tests passed!"),
+        "Protected generation tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_apply_multiple_configs() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+mkdir -p /persist /persist-other /tmp/separate /tmp/shared /tmp/conflict
+
+cat > /tmp/separate/imp-a.toml <<'EOF'
+state_dir = "/tmp/state-a"
+
+[persistence."/persist"]
+directories = ["/tmp/multi-a"]
+EOF
+cat > /tmp/separate/imp-b.toml <<'EOF'
+state_dir = "/tmp/state-b"
+
+[persistence."/persist-other"]
+directories = ["/tmp/multi-b"]
+EOF
+
+echo "=== One generation per config ==="
+$IMP --config '/tmp/separate/imp-*.toml' apply | tee /tmp/apply.log
+grep "Applying 2 configs matching /tmp/separate/imp-\*.toml, one generation each" /tmp/apply.log || { echo "ERROR: per-config mode not announced"; exit 1; }
+mountpoint -q /tmp/multi-a || { echo "ERROR: first config not applied"; exit 1; }
+mountpoint -q /tmp/multi-b || { echo "ERROR: second config not applied"; exit 1; }
+$IMP --config '/tmp/separate/imp-*.toml' apply > /dev/null
+mountpoint -q /tmp/multi-a || { echo "ERROR: a later config removed an earlier config's mount"; exit 1; }
+[ "$($IMP --config /tmp/separate/imp-a.toml list --count)" = "total=2 active=2" ] || { echo "ERROR: wrong generations for a"; exit 1; }
+[ "$($IMP --config /tmp/separate/imp-b.toml list --count)" = "total=2 active=2" ] || { echo "ERROR: wrong generations for b"; exit 1; }
+
+echo "=== Configs sharing a state_dir need --merge ==="
+cat > /tmp/shared/imp-x.toml <<'EOF'
+state_dir = "/tmp/state-shared"
+backup = true
+
+[persistence."/persist"]
+directories = ["/tmp/multi-x"]
+EOF
+cat > /tmp/shared/imp-y.toml <<'EOF'
+state_dir = "/tmp/state-shared"
+
+[persistence."/persist-other"]
+files = ["/tmp/multi-y.txt"]
+EOF
+if $IMP --config '/tmp/shared/imp-*.toml' apply 2> /tmp/err.log; then
+    echo "ERROR: shared state_dir accepted"; exit 1
+fi
+grep "/tmp/shared/imp-x.toml and /tmp/shared/imp-y.toml both use state_dir /tmp/state-shared" /tmp/err.log || { echo "ERROR: unclear error"; exit 1; }
+test ! -e /tmp/state-shared/generations.json || { echo "ERROR: applied before refusing"; exit 1; }
+
+echo "=== Merged into one generation ==="
+$IMP --config '/tmp/shared/imp-*.toml' apply --merge
+mountpoint -q /tmp/multi-x || { echo "ERROR: merged directory missing"; exit 1; }
+test -L /tmp/multi-y.txt || { echo "ERROR: merged file missing"; exit 1; }
+[ "$($IMP --config '/tmp/shared/imp-*.toml' list --count)" = "total=1 active=1" ] || { echo "ERROR: expected one generation"; exit 1; }
+$IMP --config '/tmp/shared/imp-*.toml' show 1 | tee /tmp/show.txt
+grep "Config: /tmp/shared/imp-\*.toml$" /tmp/show.txt || { echo "ERROR: pattern not recorded"; exit 1; }
+$IMP --config '/tmp/shared/imp-*.toml' show 1 --diff-config | grep "0 added, 0 removed, 0 changed, 2 unchanged" || { echo "ERROR: diff-config on merged configs"; exit 1; }
+
+echo "=== Merge conflicts are refused ==="
+cat > /tmp/conflict/imp-1.toml <<'EOF'
+state_dir = "/tmp/state-conflict"
+
+[persistence."/persist"]
+directories = ["/tmp/multi-dup"]
+EOF
+cat > /tmp/conflict/imp-2.toml <<'EOF'
+state_dir = "/tmp/state-conflict"
+
+[persistence."/persist-other"]
+directories = ["/tmp/multi-dup"]
+EOF
+if $IMP --config '/tmp/conflict/imp-*.toml' apply --merge 2> /tmp/err.log; then
+    echo "ERROR: duplicate target accepted"; exit 1
+fi
+grep "both persist /tmp/multi-dup" /tmp/err.log || { echo "ERROR: unclear duplicate error"; exit 1; }
+sed -i 's|/tmp/multi-dup|/tmp/multi-other|; s|/persist-other|/persist|' /tmp/conflict/imp-2.toml
+if $IMP --config '/tmp/conflict/imp-*.toml' apply --merge 2> /tmp/err.log; then
+    echo "ERROR: persistence directory defined twice"; exit 1
+fi
+grep "persistence directory /persist is already defined" /tmp/err.log || { echo "ERROR: unclear persistence error"; exit 1; }
+sed -i 's|state_dir = "/tmp/state-conflict"|state_dir = "/tmp/state-elsewhere"|; s|"/persist"|"/persist-other"|' /tmp/conflict/imp-2.toml
+if $IMP --config '/tmp/conflict/imp-*.toml' apply --merge 2> /tmp/err.log; then
+    echo "ERROR: different state_dirs merged"; exit 1
+fi
+grep "they use different state_dir" /tmp/err.log || { echo "ERROR: unclear state_dir error"; exit 1; }
+
+if $IMP --config '/tmp/nothing/imp-*.toml' apply 2> /tmp/err.log; then
+    echo "ERROR: empty pattern accepted"; exit 1
+fi
+grep "No config files match: /tmp/nothing/imp-\*.toml" /tmp/err.log || { echo "ERROR: unclear no-match error"; exit 1; }
+
+echo ""
+echo "✅ Multiple config tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Multiple config tests", test_script);
+
+    assert!(
+        output.contains("✅ Multiple config tests passed!"),
+        "Multiple config tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_prometheus_metrics() {
+    let test_script = r##"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+mkdir -p /persist /tmp/metrics /var/lib/node_exporter
+printf '0123456789' > /tmp/metrics/existing.txt
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+directories = ["/tmp/metrics/dir"]
+files = ["/tmp/metrics/existing.txt", "/tmp/metrics/other.txt"]
+EOF
+
+echo "=== Without generations ==="
+$IMP --config /tmp/imp.toml metrics | tee /tmp/metrics.txt
+grep -x "imp_generations_total 0" /tmp/metrics.txt || { echo "ERROR: generations"; exit 1; }
+grep -x "imp_active_generation 0" /tmp/metrics.txt || { echo "ERROR: active generation"; exit 1; }
+
+echo "=== After applying ==="
+$IMP --config /tmp/imp.toml apply > /dev/null
+$IMP --config /tmp/imp.toml apply > /dev/null
+$IMP --config /tmp/imp.toml metrics | tee /tmp/metrics.txt
+grep -x "# HELP imp_generations_total Number of generations recorded" /tmp/metrics.txt || { echo "ERROR: missing HELP"; exit 1; }
+grep -x "# TYPE imp_generations_total gauge" /tmp/metrics.txt || { echo "ERROR: missing TYPE"; exit 1; }
+grep -x "imp_generations_total 2" /tmp/metrics.txt || { echo "ERROR: generations"; exit 1; }
+grep -x "imp_active_generation 2" /tmp/metrics.txt || { echo "ERROR: active generation"; exit 1; }
+grep -x "imp_symlinks_total 3" /tmp/metrics.txt || { echo "ERROR: symlinks"; exit 1; }
+grep -x "imp_broken_symlinks 0" /tmp/metrics.txt || { echo "ERROR: broken symlinks"; exit 1; }
+grep -x "imp_backup_bytes 10" /tmp/metrics.txt || { echo "ERROR: backup bytes"; exit 1; }
+[ "$(grep -c '^imp_' /tmp/metrics.txt)" = "5" ] || { echo "ERROR: expected 5 metrics"; exit 1; }
+
+echo "=== Broken entries are counted ==="
+rm /tmp/metrics/other.txt
+$IMP --config /tmp/imp.toml metrics | grep -x "imp_broken_symlinks 1" || { echo "ERROR: broken symlink not counted"; exit 1; }
+
+echo "=== Textfile collector output ==="
+$IMP --config /tmp/imp.toml metrics --output /var/lib/node_exporter/imp.prom
+grep -x "imp_broken_symlinks 1" /var/lib/node_exporter/imp.prom || { echo "ERROR: output file"; exit 1; }
+[ "$(ls /var/lib/node_exporter)" = "imp.prom" ] || { echo "ERROR: temporary file left behind"; exit 1; }
+
+echo ""
+echo "✅ Metrics tests passed!"
+"##;
+
+    let output = run_in_privileged_container("Metrics tests", test_script);
+
+    assert!(
+        output.contains("✅ Metrics tests passed!"),
+        "Metrics tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_env_vars_in_ownership_fields() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+mkdir -p /persist /home/svc
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [
+    { directory = "/home/svc/data", user = "$SERVICE_USER", group = "${SERVICE_GROUP}", mode = "0${SERVICE_MODE}" },
+]
+EOF
+
+echo "=== Defined variables are expanded ==="
+SERVICE_USER=daemon SERVICE_GROUP=nogroup SERVICE_MODE=750 $IMP --config /tmp/imp.toml apply
+# A non-recursive bind of /home shows the mount point itself
+mkdir -p /mnt/view
+mount --bind /home /mnt/view
+stat -c '%U:%G %a' /mnt/view/svc/data
+[ "$(stat -c '%U:%G %a' /mnt/view/svc/data)" = "daemon:nogroup 750" ] || { echo "ERROR: variables not expanded"; exit 1; }
+umount /mnt/view
+
+echo "=== Undefined variables are an error ==="
+unset SERVICE_USER
+if SERVICE_GROUP=nogroup SERVICE_MODE=750 $IMP --config /tmp/imp.toml apply 2> /tmp/err.log; then
+    echo "ERROR: undefined variable accepted"; exit 1
+fi
+cat /tmp/err.log
+grep "Invalid user for /home/svc/data" /tmp/err.log || { echo "ERROR: entry not named"; exit 1; }
+grep '"$SERVICE_USER" uses environment variable SERVICE_USER which is not set' /tmp/err.log || { echo "ERROR: variable not named"; exit 1; }
+[ "$(grep -c '"number"' /tmp/imp-state/generations.json)" = "1" ] || { echo "ERROR: applied anyway"; exit 1; }
+
+echo ""
+echo "✅ Ownership variable tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Ownership variable tests", test_script);
+
+    assert!(
+        output.contains("✅ Ownership variable tests passed!"),
+        "Ownership variable tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_links_into_mounted_directories() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/data/app /tmp/var
+echo 'state' > /persist/tmp/data/app/state.db
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/data"]
+links = [{ link = "/tmp/var/app", to = "/tmp/data/app" }]
+EOF
+$IMP --config /tmp/imp.toml apply
+
+echo "=== The link points into the mount, not the persistence directory ==="
+[ "$(readlink /tmp/var/app)" = "/tmp/data/app" ] || { echo "ERROR: wrong link target: $(readlink /tmp/var/app)"; exit 1; }
+[ "$(cat /tmp/var/app/state.db)" = "state" ] || { echo "ERROR: content not reachable through the link"; exit 1; }
+echo 'new' > /tmp/var/app/new.txt
+[ -f /persist/tmp/data/app/new.txt ] || { echo "ERROR: write through the link not persisted"; exit 1; }
+
+echo "=== The link is recorded as a symlink entry ==="
+grep -A3 '"target": "/tmp/var/app"' /tmp/imp-state/generations.json | grep '"kind": "symlink"' || { cat /tmp/imp-state/generations.json; echo "ERROR: link not recorded as a symlink"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+
+echo "=== A link outside any persisted location is rejected ==="
+cat > /tmp/bad.toml <<'EOF'
+state_dir = "/tmp/imp-state-bad"
+
+[persistence."/persist"]
+directories = ["/tmp/data"]
+links = [{ link = "/tmp/var/other", to = "/tmp/elsewhere" }]
+EOF
+if $IMP --config /tmp/bad.toml apply 2>/tmp/bad.log; then
+    echo "ERROR: link outside persisted locations accepted"; exit 1
+fi
+grep "not inside a persisted directory or file" /tmp/bad.log || { cat /tmp/bad.log; echo "ERROR: wrong error"; exit 1; }
+
+echo ""
+echo "✅ Links tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Links tests", test_script);
+
+    assert!(
+        output.contains("✅ Links tests passed!"),
+        "Links tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_state_read_once_per_command() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+apt-get update -qq
+apt-get install -y strace > /dev/null 2>&1
+
+mkdir -p /persist/tmp/app/data
+echo 'x' > /persist/tmp/app/file.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/app/data"]
+files = ["/tmp/app/file.conf"]
+EOF
+
+# Number of times a command opens the state file
+state_reads() {
+    strace -f -e trace=open,openat -o /tmp/trace.log $IMP --config /tmp/imp.toml "$@" > /dev/null
+    grep -c 'generations\.json"' /tmp/trace.log || true
+}
+
+echo "=== The first apply has no state to read ==="
+reads=$(state_reads apply)
+echo "apply: $reads read(s)"
+[ "$reads" -eq 0 ] || { echo "ERROR: first apply read the state $reads times"; exit 1; }
+
+echo "=== Every command reads the state at most once ==="
+for cmd in "apply" "apply" "list" "show 1" "current" "verify" "switch 1" "delete 2 --force"; do
+    reads=$(state_reads $cmd)
+    echo "$cmd: $reads read(s)"
+    [ "$reads" -eq 1 ] || { cat /tmp/trace.log | grep generations; echo "ERROR: '$cmd' read the state $reads times"; exit 1; }
+done
+
+echo "=== Changes made from the cached state are all saved ==="
+$IMP --config /tmp/imp.toml list --count | grep "total=2 active=1" || { echo "ERROR: wrong generation count"; exit 1; }
+$IMP --config /tmp/imp.toml current | grep "Current generation: 1" || { echo "ERROR: wrong active generation"; exit 1; }
+
+echo "=== A change made by another process during an apply is kept ==="
+# Compressing a large backup keeps the apply busy after it has read the state
+mkdir -p /tmp/app/big
+head -c 200M /dev/urandom > /tmp/app/big/blob
+sed -i 's|^state_dir.*|&\nbackup = true\nbackup_compression = "gzip"|; s|^directories = .*|directories = ["/tmp/app/data", "/tmp/app/big"]|' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply > /tmp/apply.log 2>&1 &
+APPLY=$!
+until grep -q "Creating generation" /tmp/apply.log; do sleep 0.1; done
+$IMP --config /tmp/imp.toml note 1 "noted during apply"
+kill -0 $APPLY 2> /dev/null || { echo "ERROR: apply finished before the note was made"; exit 1; }
+wait $APPLY || { cat /tmp/apply.log; echo "ERROR: apply failed"; exit 1; }
+$IMP --config /tmp/imp.toml list --notes | grep "noted during apply" || { echo "ERROR: apply overwrote the note"; exit 1; }
+$IMP --config /tmp/imp.toml current | grep "Current generation: 4" || { echo "ERROR: apply not recorded"; exit 1; }
+
+echo ""
+echo "✅ State cache tests passed!"
+"#;
+
+    let output = run_in_privileged_container("State cache tests", test_script);
+
+    assert!(
+        output.contains("✅ State cache tests passed!"),
+        "State cache tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_preview_backups_match_actual_backups() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/app/data /tmp/app/data /tmp/app/cache
+echo 'persisted' > /persist/tmp/app/file.conf
+echo 'local' > /tmp/app/file.conf
+echo 'local' > /tmp/app/data/old.txt
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+backup_compression = "zstd"
+
+[persistence."/persist"]
+directories = ["/tmp/app/data", "/tmp/app/cache"]
+files = ["/tmp/app/file.conf", "/tmp/app/new.conf"]
+EOF
+
+# Backup paths with the timestamp masked, sorted
+normalize() {
+    sed 's/[0-9]\{8\}_[0-9]\{6\}/TIMESTAMP/' | sort
+}
+
+check_round() {
+    $IMP --config /tmp/imp.toml apply --preview-backups | tee /tmp/preview.log
+    grep "Nothing was changed" /tmp/preview.log || { echo "ERROR: preview summary missing"; exit 1; }
+    grep -o '  /tmp/app/[^ ]* -> .*' /tmp/preview.log | sed 's/.* -> //' | normalize > /tmp/previewed.txt
+
+    $IMP --config /tmp/imp.toml apply | tee /tmp/apply.log
+    grep -o 'Backed up to: .*' /tmp/apply.log | sed 's/Backed up to: //' | normalize > /tmp/actual.txt
+
+    echo "previewed:"; cat /tmp/previewed.txt
+    echo "actual:"; cat /tmp/actual.txt
+    [ -s /tmp/actual.txt ] || { echo "ERROR: apply took no backups"; exit 1; }
+    diff /tmp/previewed.txt /tmp/actual.txt || { echo "ERROR: preview doesn't match the backups taken"; exit 1; }
+}
+
+echo "=== Existing targets are previewed without changing anything ==="
+check_round
+grep -x "/tmp/app/file.conf.imp-backup.TIMESTAMP" /tmp/previewed.txt || { echo "ERROR: file backup not previewed"; exit 1; }
+grep -x "/tmp/app/data.imp-backup.TIMESTAMP.tar.zst" /tmp/previewed.txt || { echo "ERROR: compressed directory backup not previewed"; exit 1; }
+grep "new.conf" /tmp/previewed.txt && { echo "ERROR: missing target previewed"; exit 1; }
+
+echo "=== Backups restored from the previous generation are previewed again ==="
+check_round
+[ "$(wc -l < /tmp/previewed.txt)" -eq 3 ] || { echo "ERROR: expected 3 backups"; exit 1; }
+
+echo "=== Nothing to back up ==="
+sed -i 's/^backup = true/backup = false/' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply --preview-backups | grep "No targets would be backed up" || { echo "ERROR: empty preview"; exit 1; }
+$IMP --config /tmp/imp.toml list --count | grep "total=2" || { echo "ERROR: preview created a generation"; exit 1; }
+
+echo ""
+echo "✅ Backup preview tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Backup preview tests", test_script);
+
+    assert!(
+        output.contains("✅ Backup preview tests passed!"),
+        "Backup preview tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_per_machine_generations() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/app /tmp/app
+echo 'x' > /persist/tmp/app/file.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/shared-state"
+per_machine = true
+
+[persistence."/persist"]
+files = ["/tmp/app/file.conf"]
+EOF
+
+echo "=== Each machine numbers its own generations ==="
+IMP_MACHINE_ID=aaaa $IMP --config /tmp/imp.toml apply
+IMP_MACHINE_ID=aaaa $IMP --config /tmp/imp.toml apply
+IMP_MACHINE_ID=bbbb $IMP --config /tmp/imp.toml apply | tee /tmp/apply-b.log
+grep "Creating generation 1" /tmp/apply-b.log || { echo "ERROR: second machine did not start at generation 1"; exit 1; }
+[ -f /tmp/shared-state/machines/aaaa/generations.json ] || { echo "ERROR: no state for aaaa"; exit 1; }
+[ -f /tmp/shared-state/machines/bbbb/generations.json ] || { echo "ERROR: no state for bbbb"; exit 1; }
+[ -f /tmp/shared-state/generations.json ] && { echo "ERROR: state written outside the machine directories"; exit 1; }
+
+echo "=== Commands only see the current machine's generations ==="
+IMP_MACHINE_ID=aaaa $IMP --config /tmp/imp.toml list --count | grep "total=2 active=2" || { echo "ERROR: wrong generations for aaaa"; exit 1; }
+IMP_MACHINE_ID=bbbb $IMP --config /tmp/imp.toml list --count | grep "total=1 active=1" || { echo "ERROR: wrong generations for bbbb"; exit 1; }
+IMP_MACHINE_ID=cccc $IMP --config /tmp/imp.toml current | grep "No active generation" || { echo "ERROR: new machine sees another's generation"; exit 1; }
+
+echo "=== --all-machines shows every machine ==="
+IMP_MACHINE_ID=bbbb $IMP --config /tmp/imp.toml list --all-machines | tee /tmp/all.log
+grep -x "Machine aaaa:" /tmp/all.log || { echo "ERROR: aaaa missing"; exit 1; }
+grep -x "Machine bbbb (this machine):" /tmp/all.log || { echo "ERROR: bbbb missing or not marked"; exit 1; }
+[ "$(grep -c ' symlinks' /tmp/all.log)" -eq 3 ] || { echo "ERROR: expected 3 generations in total"; exit 1; }
+IMP_MACHINE_ID=aaaa $IMP --config /tmp/imp.toml current --all-machines | tee /tmp/current.log
+grep -x "Machine aaaa (this machine): generation 2 (1 symlinks)" /tmp/current.log || { echo "ERROR: aaaa current"; exit 1; }
+grep -x "Machine bbbb: generation 1 (1 symlinks)" /tmp/current.log || { echo "ERROR: bbbb current"; exit 1; }
+
+echo "=== --all-machines needs per_machine ==="
+sed -i '/^per_machine/d' /tmp/imp.toml
+if $IMP --config /tmp/imp.toml list --all-machines 2>/tmp/err.log; then
+    echo "ERROR: --all-machines accepted without per_machine"; exit 1
+fi
+grep "needs per_machine = true" /tmp/err.log || { cat /tmp/err.log; echo "ERROR: wrong error"; exit 1; }
+
+echo ""
+echo "✅ Per-machine tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Per-machine tests", test_script);
+
+    assert!(
+        output.contains("✅ Per-machine tests passed!"),
+        "Per-machine tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_symbolic_modes() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/modes/absolute /persist/tmp/modes/partial /persist/tmp/modes/added \
+         /persist/tmp/modes/removed /persist/tmp/modes/sticky /persist/tmp/modes/octal
+chmod 0644 /persist/tmp/modes/partial /persist/tmp/modes/added
+chmod 0777 /persist/tmp/modes/removed
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [
+  { directory = "/tmp/modes/absolute", mode = "u=rwx,g=rx,o=" },
+  { directory = "/tmp/modes/partial", mode = "u=rwx" },
+  { directory = "/tmp/modes/added", mode = "a+x" },
+  { directory = "/tmp/modes/removed", mode = "go-w" },
+  { directory = "/tmp/modes/sticky", mode = "u=rwx,go=rx,+t" },
+  { directory = "/tmp/modes/octal", mode = "0o710" },
+]
+EOF
+
+check_modes() {
+    for expected in absolute:750 partial:744 added:755 removed:755 sticky:1755 octal:710; do
+        dir=${expected%%:*}
+        mode=${expected#*:}
+        actual=$(stat -c %a /tmp/modes/$dir)
+        [ "$actual" = "$mode" ] || { echo "ERROR: /tmp/modes/$dir has mode $actual, expected $mode"; exit 1; }
+        echo "  /tmp/modes/$dir: $actual"
+    done
+}
+
+echo "=== Symbolic modes are applied like chmod ==="
+$IMP --config /tmp/imp.toml apply
+check_modes
+
+echo "=== Relative modes are stable across applies ==="
+$IMP --config /tmp/imp.toml apply
+check_modes
+
+echo "=== Malformed modes are rejected during validation ==="
+for bad in "u=rwq" "z+x" "u" "rwx" "0999" "77777" ""; do
+    cat > /tmp/bad.toml <<EOF
+state_dir = "/tmp/imp-state-bad"
+
+[persistence."/persist"]
+directories = [{ directory = "/tmp/modes/absolute", mode = "$bad" }]
+EOF
+    if $IMP --config /tmp/bad.toml apply > /tmp/bad.log 2>&1; then
+        echo "ERROR: mode '$bad' accepted"; exit 1
+    fi
+    grep "Invalid mode for /tmp/modes/absolute" /tmp/bad.log || { cat /tmp/bad.log; echo "ERROR: mode '$bad' not rejected by validation"; exit 1; }
+    grep "Creating generation" /tmp/bad.log && { echo "ERROR: '$bad' got past validation"; exit 1; }
+done
+
+echo ""
+echo "✅ Symbolic mode tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Symbolic mode tests", test_script);
+
+    assert!(
+        output.contains("✅ Symbolic mode tests passed!"),
+        "Symbolic mode tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_confirm_each_prompts() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/app/data /tmp/app/data
+for f in a.conf b.conf c.conf; do echo "persisted $f" > /persist/tmp/app/$f; done
+echo 'local a' > /tmp/app/a.conf
+echo 'local b' > /tmp/app/b.conf
+echo 'local data' > /tmp/app/data/old.txt
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+directories = ["/tmp/app/data"]
+files = ["/tmp/app/a.conf", "/tmp/app/b.conf", "/tmp/app/c.conf"]
+EOF
+
+# Run imp on a terminal, answering its prompts from the given lines
+run_on_tty() {
+    local answers=$1
+    shift
+    printf "$answers" | script -qec "$IMP $*" /dev/null
+}
+
+echo "=== Without a terminal, --confirm-each refuses to start ==="
+if echo a | $IMP --config /tmp/imp.toml apply --confirm-each > /tmp/notty.log 2>&1; then
+    echo "ERROR: --confirm-each ran without a terminal"; exit 1
+fi
+grep "needs a terminal" /tmp/notty.log || { cat /tmp/notty.log; echo "ERROR: wrong error"; exit 1; }
+[ -d /tmp/imp-state ] && [ -n "$(ls /tmp/imp-state)" ] && { echo "ERROR: state touched"; exit 1; }
+
+echo "=== Skip, retry on a bad answer, apply ==="
+run_on_tty 's\nx\na\ns\n' --config /tmp/imp.toml apply --confirm-each | tee /tmp/confirm.log
+grep "? /tmp/app/data exists (directory with 1 entries)" /tmp/confirm.log || { echo "ERROR: directory prompt"; exit 1; }
+grep "? /tmp/app/a.conf exists (file, 8 bytes)" /tmp/confirm.log || { echo "ERROR: file prompt"; exit 1; }
+grep "It will be backed up to /tmp/app/a.conf.imp-backup" /tmp/confirm.log || { echo "ERROR: backup path not shown"; exit 1; }
+grep "Please answer a, s or b" /tmp/confirm.log || { echo "ERROR: bad answer accepted"; exit 1; }
+grep "c.conf exists" /tmp/confirm.log && { echo "ERROR: prompted for a missing target"; exit 1; }
+grep "Skipped: 2 (declined at the prompt" /tmp/confirm.log || { echo "ERROR: summary"; exit 1; }
+
+[ "$(cat /tmp/app/data/old.txt)" = "local data" ] || { echo "ERROR: skipped directory was replaced"; exit 1; }
+[ "$(cat /tmp/app/b.conf)" = "local b" ] || { echo "ERROR: skipped file was replaced"; exit 1; }
+[ -L /tmp/app/a.conf ] && [ -L /tmp/app/c.conf ] || { echo "ERROR: applied entries missing"; exit 1; }
+ls /tmp/app/a.conf.imp-backup.* > /dev/null || { echo "ERROR: a.conf not backed up"; exit 1; }
+$IMP --config /tmp/imp.toml show 1 | tee /tmp/show.log
+grep "b.conf\|/tmp/app/data" /tmp/show.log && { echo "ERROR: skipped entries recorded"; exit 1; }
+
+echo "=== Abort rolls back to the active generation ==="
+echo 'local d' > /tmp/app/d.conf
+echo "persisted d.conf" > /persist/tmp/app/d.conf
+sed -i 's|"/tmp/app/c.conf"\]|"/tmp/app/c.conf", "/tmp/app/d.conf"]|' /tmp/imp.toml
+if run_on_tty 'a\na\nb\n' --config /tmp/imp.toml apply --confirm-each > /tmp/abort.log; then
+    :
+fi
+cat /tmp/abort.log
+grep "Apply aborted; generation 1 is active again" /tmp/abort.log || { echo "ERROR: abort not reported"; exit 1; }
+$IMP --config /tmp/imp.toml current | grep "Current generation: 1" || { echo "ERROR: generation 1 not active"; exit 1; }
+[ "$(readlink /tmp/app/a.conf)" = "/persist/tmp/app/a.conf" ] || { echo "ERROR: a.conf not restored"; exit 1; }
+[ "$(cat /tmp/app/d.conf)" = "local d" ] || { echo "ERROR: d.conf touched"; exit 1; }
+$IMP --config /tmp/imp.toml list --count | grep "total=1" || { echo "ERROR: aborted generation recorded"; exit 1; }
+
+echo ""
+echo "✅ Confirm-each tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Confirm-each tests", test_script);
+
+    assert!(
+        output.contains("✅ Confirm-each tests passed!"),
+        "Confirm-each tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_case_insensitive_collisions() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/ci/Data /persist/tmp/ci/data /persist/tmp/ci/Cache /persist/tmp/ci/other
+echo 'x' > /persist/tmp/ci/cache.conf
+mkdir -p /tmp/ci
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/ci/Data", "/tmp/ci/data"]
+EOF
+
+echo "=== Targets differing in case are fine on a case-sensitive filesystem ==="
+$IMP --config /tmp/imp.toml apply
+mountpoint -q /tmp/ci/Data && mountpoint -q /tmp/ci/data || { echo "ERROR: both targets should be mounted"; exit 1; }
+ls -a /tmp/ci | grep imp-case-probe && { echo "ERROR: probe file left behind"; exit 1; }
+
+echo "=== The colliding pair is reported on a case-insensitive filesystem ==="
+if IMP_CASE_INSENSITIVE=1 $IMP --config /tmp/imp.toml apply 2>/tmp/err.log; then
+    echo "ERROR: colliding targets accepted"; exit 1
+fi
+cat /tmp/err.log
+grep "Targets /tmp/ci/Data and /tmp/ci/data collide: /tmp/ci/Data and /tmp/ci/data differ only in case, and /tmp/ci is on a case-insensitive filesystem" /tmp/err.log || { echo "ERROR: wrong error"; exit 1; }
+$IMP --config /tmp/imp.toml list --count | grep "total=1 active=1" || { echo "ERROR: a generation was created"; exit 1; }
+
+echo "=== A collision in a parent directory is reported ==="
+cat > /tmp/nested.toml <<'EOF'
+state_dir = "/tmp/imp-state-nested"
+
+[persistence."/persist"]
+directories = ["/tmp/ci/Cache"]
+files = ["/tmp/ci/cache.conf", "/tmp/ci/cache/app.conf"]
+EOF
+if IMP_CASE_INSENSITIVE=1 $IMP --config /tmp/nested.toml apply 2>/tmp/err.log; then
+    echo "ERROR: colliding parent accepted"; exit 1
+fi
+cat /tmp/err.log
+grep "Targets /tmp/ci/Cache and /tmp/ci/cache/app.conf collide: /tmp/ci/Cache and /tmp/ci/cache differ only in case" /tmp/err.log || { echo "ERROR: wrong error"; exit 1; }
+
+echo "=== Targets that don't collide pass ==="
+cat > /tmp/ok.toml <<'EOF'
+state_dir = "/tmp/imp-state-ok"
+
+[persistence."/persist"]
+directories = ["/tmp/ci/other", "/tmp/ci/Cache"]
+files = ["/tmp/ci/cache.conf"]
+EOF
+IMP_CASE_INSENSITIVE=1 $IMP --config /tmp/ok.toml apply
+
+echo ""
+echo "✅ Case collision tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Case collision tests", test_script);
+
+    assert!(
+        output.contains("✅ Case collision tests passed!"),
+        "Case collision tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_switch_dry_run_json() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/app/a /persist/tmp/app/b /tmp/app
+echo 'x' > /persist/tmp/app/x.conf
+echo 'y' > /persist/tmp/app/y.conf
+echo 'local y' > /tmp/app/y.conf
+
+cat > /tmp/one.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+directories = ["/tmp/app/a"]
+files = ["/tmp/app/x.conf"]
+EOF
+cat > /tmp/two.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup = true
+
+[persistence."/persist"]
+directories = ["/tmp/app/a", "/tmp/app/b"]
+files = ["/tmp/app/y.conf"]
+EOF
+$IMP --config /tmp/one.toml apply
+$IMP --config /tmp/two.toml apply
+backup=$(ls -d /tmp/app/y.conf.imp-backup.*)
+
+echo "=== The human-readable plan lists removals and recreations ==="
+$IMP --config /tmp/two.toml switch 1 --dry-run | tee /tmp/plan.log
+grep "Dry run: switching from generation 2 to generation 1 would:" /tmp/plan.log || { echo "ERROR: header"; exit 1; }
+grep -x "  - Unmount /tmp/app/a (from /persist/tmp/app/a)" /tmp/plan.log || { echo "ERROR: unmount a"; exit 1; }
+grep -x "  - Unmount /tmp/app/b (from /persist/tmp/app/b)" /tmp/plan.log || { echo "ERROR: unmount b"; exit 1; }
+grep -x "  - Remove symlink /tmp/app/y.conf -> /persist/tmp/app/y.conf, restoring backup $backup" /tmp/plan.log || { echo "ERROR: unlink y"; exit 1; }
+grep -x "  + Mount /tmp/app/a from /persist/tmp/app/a" /tmp/plan.log || { echo "ERROR: mount a"; exit 1; }
+grep -x "  + Link /tmp/app/x.conf -> /persist/tmp/app/x.conf" /tmp/plan.log || { echo "ERROR: link x"; exit 1; }
+grep "Nothing was changed." /tmp/plan.log || { echo "ERROR: summary"; exit 1; }
+
+echo "=== The JSON plan carries the same actions ==="
+$IMP --config /tmp/two.toml switch 1 --dry-run --json | tee /tmp/plan.json
+grep -x '  "version": 1,' /tmp/plan.json || { echo "ERROR: version"; exit 1; }
+grep -x '  "from": 2,' /tmp/plan.json || { echo "ERROR: from"; exit 1; }
+grep -x '  "to": 1,' /tmp/plan.json || { echo "ERROR: to"; exit 1; }
+[ "$(grep -c '"action": "unmount"' /tmp/plan.json)" -eq 2 ] || { echo "ERROR: unmount actions"; exit 1; }
+[ "$(grep -c '"action": "unlink"' /tmp/plan.json)" -eq 1 ] || { echo "ERROR: unlink actions"; exit 1; }
+[ "$(grep -c '"action": "mount"' /tmp/plan.json)" -eq 1 ] || { echo "ERROR: mount actions"; exit 1; }
+[ "$(grep -c '"action": "link"' /tmp/plan.json)" -eq 1 ] || { echo "ERROR: link actions"; exit 1; }
+grep -A5 '"target": "/tmp/app/y.conf"' /tmp/plan.json | grep "\"restore_backup\": \"$backup\"" || { echo "ERROR: restored backup"; exit 1; }
+grep '"source": "/persist/tmp/app/x.conf"' /tmp/plan.json || { echo "ERROR: source of x"; exit 1; }
+
+echo "=== --clean discards instead of restoring ==="
+$IMP --config /tmp/two.toml switch 1 --dry-run --json --clean > /tmp/clean.json
+grep "\"discard_backup\": \"$backup\"" /tmp/clean.json || { echo "ERROR: discard"; exit 1; }
+grep restore_backup /tmp/clean.json && { echo "ERROR: restore with --clean"; exit 1; }
+
+echo "=== Nothing was changed ==="
+$IMP --config /tmp/two.toml current | grep "Current generation: 2" || { echo "ERROR: switched"; exit 1; }
+mountpoint -q /tmp/app/b || { echo "ERROR: /tmp/app/b unmounted"; exit 1; }
+[ -L /tmp/app/y.conf ] && [ -e "$backup" ] || { echo "ERROR: y.conf changed"; exit 1; }
+
+echo "=== --json needs --dry-run ==="
+if $IMP --config /tmp/two.toml switch 1 --json 2>/dev/null; then
+    echo "ERROR: --json without --dry-run accepted"; exit 1
+fi
+
+echo ""
+echo "✅ Switch dry-run tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Switch dry-run tests", test_script);
+
+    assert!(
+        output.contains("✅ Switch dry-run tests passed!"),
+        "Switch dry-run tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_on_missing_source_policies() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/oms/existing-dir /tmp/oms
+echo 'from target' > /tmp/oms/seeded.conf
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [
+    { directory = "/tmp/oms/existing-dir", on_missing_source = "create" },
+    { directory = "/tmp/oms/skipped-dir", on_missing_source = "skip" },
+]
+files = [
+    { file = "/tmp/oms/seeded.conf", on_missing_source = "create" },
+    { file = "/tmp/oms/skipped.conf", on_missing_source = "skip" },
+]
+EOF
+
+echo "=== create makes the source, skip leaves the entry out ==="
+$IMP --config /tmp/imp.toml apply | tee /tmp/apply.log
+test -d /persist/tmp/oms/existing-dir || { echo "ERROR: directory source not created"; exit 1; }
+grep -x 'from target' /persist/tmp/oms/seeded.conf || { echo "ERROR: file source not seeded from target"; exit 1; }
+grep "Skipping /tmp/oms/skipped-dir" /tmp/apply.log || { echo "ERROR: skipped dir not reported"; exit 1; }
+grep "Skipping /tmp/oms/skipped.conf" /tmp/apply.log || { echo "ERROR: skipped file not reported"; exit 1; }
+test ! -e /persist/tmp/oms/skipped-dir || { echo "ERROR: skip created a source"; exit 1; }
+$IMP --config /tmp/imp.toml show 1 | grep -c " -> " | grep -x 2 || { echo "ERROR: skipped entries recorded"; exit 1; }
+
+echo "=== error fails validation without creating anything ==="
+cat > /tmp/imp-error.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [
+    "/tmp/oms/other-dir",
+    { directory = "/tmp/oms/required-dir", on_missing_source = "error" },
+]
+files = [{ file = "/tmp/oms/required.conf", on_missing_source = "error" }]
+EOF
+if $IMP --config /tmp/imp-error.toml apply 2>/tmp/error.log; then
+    echo "ERROR: apply succeeded with a missing required source"; exit 1
+fi
+cat /tmp/error.log
+grep 'Source of /tmp/oms/required-dir does not exist: /persist/tmp/oms/required-dir (on_missing_source = "error")' /tmp/error.log || { echo "ERROR: wrong error message"; exit 1; }
+test ! -e /persist/tmp/oms/required-dir || { echo "ERROR: error policy created a source"; exit 1; }
+$IMP --config /tmp/imp-error.toml current | grep "Current generation: 1" || { echo "ERROR: failed apply changed the generation"; exit 1; }
+
+echo "=== error also holds with --skip-missing and --skip-validation ==="
+if $IMP --config /tmp/imp-error.toml apply --skip-missing 2>/tmp/error.log; then
+    echo "ERROR: --skip-missing overrode on_missing_source = error"; exit 1
+fi
+grep 'on_missing_source = "error"' /tmp/error.log || { echo "ERROR: wrong error with --skip-missing"; exit 1; }
+test ! -e /persist/tmp/oms/other-dir || { echo "ERROR: failed validation created other sources"; exit 1; }
+mkdir -p /persist/tmp/oms/other-dir
+if $IMP --config /tmp/imp-error.toml apply --skip-validation 2>/tmp/error.log; then
+    echo "ERROR: --skip-validation bypassed on_missing_source = error"; exit 1
+fi
+grep 'on_missing_source = "error"' /tmp/error.log || { echo "ERROR: wrong error with --skip-validation"; exit 1; }
+test ! -e /persist/tmp/oms/required-dir || { echo "ERROR: source created from target"; exit 1; }
+
+echo "=== error passes once the sources are restored ==="
+mkdir -p /persist/tmp/oms/required-dir
+touch /persist/tmp/oms/required.conf
+$IMP --config /tmp/imp-error.toml apply
+mountpoint -q /tmp/oms/required-dir || { echo "ERROR: required dir not mounted"; exit 1; }
+test -L /tmp/oms/required.conf || { echo "ERROR: required file not linked"; exit 1; }
+
+echo "=== optional = true contradicts any policy but skip ==="
+cat > /tmp/imp-bad.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = [{ file = "/tmp/oms/x.conf", optional = true, on_missing_source = "error" }]
+EOF
+if $IMP --config /tmp/imp-bad.toml apply 2>/tmp/bad.log; then
+    echo "ERROR: contradictory entry accepted"; exit 1
+fi
+grep 'sets optional = true, which contradicts on_missing_source = "error"' /tmp/bad.log || { echo "ERROR: wrong contradiction message"; exit 1; }
+
+echo ""
+echo "✅ Missing source policy tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Missing source policy tests", test_script);
+
+    assert!(
+        output.contains("✅ Missing source policy tests passed!"),
+        "Missing source policy tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_validate_json_attribution() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/v /persist-v2
+
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [
+    "/tmp/v/fine",
+    { directory = "/tmp/v/badmode", mode = "u=zz" },
+    { directory = "/tmp/v/required", on_missing_source = "error" },
+    "/tmp/v/shared",
+]
+
+[persistence."/persist-v2"]
+directories = ["/tmp/v/shared"]
+
+[persistence."/"]
+files = ["/tmp/v/self.conf"]
+EOF
+
+# attribution RULE GROUP ENTRY: the report has a problem for that entry
+attribution() {
+    grep -A2 "\"rule\": \"$1\"" /tmp/report.json | tr -d '\n' \
+        | grep -q "\"group\": \"$2\",.*\"entry\": \"$3\"" \
+        || { cat /tmp/report.json; echo "ERROR: no $1 problem for $3 in $2"; exit 1; }
+}
+
+echo "=== Every problem is reported with its entry ==="
+if $IMP --config /tmp/imp.toml validate --json > /tmp/report.json 2>/tmp/validate.err; then
+    echo "ERROR: validate succeeded on a broken config"; exit 1
+fi
+cat /tmp/report.json
+grep '"valid": false' /tmp/report.json || { echo "ERROR: not marked invalid"; exit 1; }
+grep "5 problem(s) found" /tmp/validate.err || { cat /tmp/validate.err; echo "ERROR: problems not all collected"; exit 1; }
+attribution invalid-mode /persist /tmp/v/badmode
+attribution missing-source /persist /tmp/v/required
+attribution overlap /persist /tmp/v/shared
+attribution overlap /persist-v2 /tmp/v/shared
+attribution same-path / /tmp/v/self.conf
+grep '"message": "Source of /tmp/v/required does not exist: /persist/tmp/v/required (on_missing_source = \\"error\\")"' /tmp/report.json || { echo "ERROR: missing-source message"; exit 1; }
+
+echo "=== validate changes nothing ==="
+test ! -e /persist/tmp/v/fine || { echo "ERROR: validate created a source"; exit 1; }
+test ! -e /tmp/imp-state/generations.json || { echo "ERROR: validate recorded a generation"; exit 1; }
+
+echo "=== Human output names the rule and entry ==="
+$IMP --config /tmp/imp.toml validate > /tmp/human.log 2>&1 || true
+cat /tmp/human.log
+grep -F "✗ [invalid-mode] /tmp/v/badmode in /persist: Invalid mode for /tmp/v/badmode" /tmp/human.log || { echo "ERROR: human output"; exit 1; }
+
+echo "=== apply stops at the same problems ==="
+if $IMP --config /tmp/imp.toml apply 2>/tmp/apply.err; then
+    echo "ERROR: apply succeeded on a broken config"; exit 1
+fi
+cat /tmp/apply.err
+
+echo "=== Config-wide problems have no entry ==="
+cat > /tmp/suffix.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+backup_suffix = ""
+
+[persistence."/persist"]
+directories = ["/tmp/v/fine"]
+EOF
+$IMP --config /tmp/suffix.toml validate --json > /tmp/report.json 2>/dev/null || true
+cat /tmp/report.json
+grep -A2 '"rule": "config"' /tmp/report.json | grep '"group": null' || { echo "ERROR: config problem attributed to a group"; exit 1; }
+
+echo "=== A valid config passes ==="
+cat > /tmp/good.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/v/fine"]
+EOF
+$IMP --config /tmp/good.toml validate --json | tee /tmp/report.json
+grep '"valid": true' /tmp/report.json || { echo "ERROR: valid config rejected"; exit 1; }
+$IMP --config /tmp/good.toml validate | grep "✓ /tmp/good.toml is valid" || { echo "ERROR: human success line"; exit 1; }
+
+echo ""
+echo "✅ Validate JSON tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Validate JSON tests", test_script);
+
+    assert!(
+        output.contains("✅ Validate JSON tests passed!"),
+        "Validate JSON tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_undo_apply_and_switch() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/undo /persist/tmp/undo
+echo 'a' > /persist/tmp/undo/a.conf
+echo 'b' > /persist/tmp/undo/b.conf
+
+cat > /tmp/a.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/undo/a.conf"]
+EOF
+cat > /tmp/b.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/undo/b.conf"]
+EOF
+
+echo "=== Undoing the first apply leaves no generation ==="
+$IMP --config /tmp/a.toml apply
+$IMP --config /tmp/a.toml undo | tee /tmp/undo.log
+grep "Undoing the apply of generation 1" /tmp/undo.log || { echo "ERROR: undo not announced"; exit 1; }
+[ ! -e /tmp/undo/a.conf ] || { echo "ERROR: link of the undone apply left behind"; exit 1; }
+$IMP --config /tmp/a.toml current | grep "No active generation" || { echo "ERROR: generation still active"; exit 1; }
+[ "$($IMP --config /tmp/a.toml list --count)" = "total=0 active=none" ] || { echo "ERROR: generation not deleted"; exit 1; }
+
+echo "=== Undoing an apply switches back and deletes the new generation ==="
+$IMP --config /tmp/a.toml apply
+$IMP --config /tmp/b.toml apply
+[ -L /tmp/undo/b.conf ] && [ ! -e /tmp/undo/a.conf ] || { echo "ERROR: second apply not in place"; exit 1; }
+$IMP --config /tmp/b.toml undo | tee /tmp/undo.log
+grep "Undoing the apply of generation 2" /tmp/undo.log || { echo "ERROR: undo not announced"; exit 1; }
+grep "✓ Deleted generation 2" /tmp/undo.log || { echo "ERROR: deletion not reported"; exit 1; }
+$IMP --config /tmp/a.toml current | grep "Current generation: 1" || { echo "ERROR: previous generation not active"; exit 1; }
+[ "$($IMP --config /tmp/a.toml list --count)" = "total=1 active=1" ] || { echo "ERROR: undone generation kept"; exit 1; }
+[ "$(readlink /tmp/undo/a.conf)" = "/persist/tmp/undo/a.conf" ] || { echo "ERROR: previous link not restored"; exit 1; }
+[ ! -e /tmp/undo/b.conf ] || { echo "ERROR: undone link left behind"; exit 1; }
+$IMP --config /tmp/a.toml verify
+
+echo "=== There is nothing left to undo ==="
+if $IMP --config /tmp/a.toml undo 2>/tmp/undo.err; then
+    echo "ERROR: second undo succeeded"; exit 1
+fi
+grep "Nothing to undo" /tmp/undo.err || { cat /tmp/undo.err; echo "ERROR: wrong message"; exit 1; }
+
+echo "=== Undoing a switch switches back ==="
+$IMP --config /tmp/b.toml apply
+$IMP --config /tmp/b.toml switch 1
+$IMP --config /tmp/b.toml current | grep "Current generation: 1"
+$IMP --config /tmp/b.toml undo | tee /tmp/undo.log
+grep "Undoing the switch to generation 1" /tmp/undo.log || { echo "ERROR: undo not announced"; exit 1; }
+$IMP --config /tmp/b.toml current | grep "Current generation: 2" || { echo "ERROR: switch not undone"; exit 1; }
+[ "$($IMP --config /tmp/b.toml list --count)" = "total=2 active=2" ] || { echo "ERROR: undoing a switch deleted a generation"; exit 1; }
+[ -L /tmp/undo/b.conf ] && [ ! -e /tmp/undo/a.conf ] || { echo "ERROR: links of generation 2 not back"; exit 1; }
+
+echo "=== A delete can't be undone ==="
+$IMP --config /tmp/b.toml delete 1 --force
+if $IMP --config /tmp/b.toml undo 2>/tmp/undo.err; then
+    echo "ERROR: undo of a delete succeeded"; exit 1
+fi
+grep "The last operation (deletion of generation(s) 1, at .*) can't be undone; only apply and switch can" /tmp/undo.err || { cat /tmp/undo.err; echo "ERROR: wrong message"; exit 1; }
+[ "$($IMP --config /tmp/b.toml list --count)" = "total=1 active=2" ] || { echo "ERROR: failed undo changed state"; exit 1; }
+
+echo ""
+echo "✅ Undo tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Undo tests", test_script);
+
+    assert!(
+        output.contains("✅ Undo tests passed!"),
+        "Undo tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_atomic_remount() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/svc /persist/tmp/svc /mnt/new/tmp/svc
+echo 'old' > /persist/tmp/svc/marker
+echo 'new' > /mnt/new/tmp/svc/marker
+
+cat > /tmp/old.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+atomic_remount = true
+
+[persistence."/persist"]
+directories = ["/tmp/svc", "/tmp/other"]
+EOF
+cat > /tmp/new.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+atomic_remount = true
+
+[persistence."/mnt/new"]
+directories = ["/tmp/svc"]
+EOF
+
+$IMP --config /tmp/old.toml apply
+grep -x old /tmp/svc/marker
+
+mounts_at() {
+    awk -v t="$1" '$5 == t' /proc/self/mountinfo | wc -l
+}
+
+echo "=== The target stays readable throughout the remount ==="
+# Hold a file of the old mount open, like a running service would
+exec 3< /tmp/svc/marker
+( while [ ! -e /tmp/stop ]; do cat /tmp/svc/marker >> /tmp/reads 2>&1 || echo MISSING >> /tmp/reads; done ) &
+reader=$!
+sleep 0.2
+$IMP --config /tmp/new.toml apply | tee /tmp/apply.log
+sleep 0.2
+touch /tmp/stop
+wait $reader
+
+grep "Remounted in place: /tmp/svc -> /mnt/new/tmp/svc" /tmp/apply.log || { echo "ERROR: not remounted in place"; exit 1; }
+grep -q "Unmounted: /tmp/svc" /tmp/apply.log && { echo "ERROR: target was unmounted first"; exit 1; }
+grep "Unmounted: /tmp/other" /tmp/apply.log || { echo "ERROR: other mounts not taken down"; exit 1; }
+if grep -v -x -e old -e new /tmp/reads | head -5 | grep .; then
+    echo "ERROR: the target was briefly missing its content"; exit 1
+fi
+grep -qx old /tmp/reads && grep -qx new /tmp/reads || { echo "ERROR: reader did not see the switch"; exit 1; }
+grep -x new /tmp/svc/marker || { echo "ERROR: new source not mounted"; exit 1; }
+[ "$(mounts_at /tmp/svc)" = 1 ] || { cat /proc/self/mountinfo; echo "ERROR: old mount not detached"; exit 1; }
+read -r held <&3
+[ "$held" = old ] || { echo "ERROR: open file of the old mount broken"; exit 1; }
+exec 3<&-
+$IMP --config /tmp/new.toml verify
+
+echo "=== Switching back and undoing still work ==="
+$IMP --config /tmp/new.toml switch 1
+grep -x old /tmp/svc/marker || { echo "ERROR: switch back failed"; exit 1; }
+[ "$(mounts_at /tmp/svc)" = 1 ] || { echo "ERROR: stacked mounts after switch"; exit 1; }
+
+echo "=== Without atomic_remount the target is unmounted first ==="
+sed -i '/atomic_remount/d' /tmp/new.toml
+$IMP --config /tmp/new.toml apply | tee /tmp/apply.log
+grep "Unmounted: /tmp/svc" /tmp/apply.log || { echo "ERROR: default behaviour changed"; exit 1; }
+grep -x new /tmp/svc/marker
+
+echo "=== --atomic-remount enables it for one apply ==="
+$IMP --config /tmp/old.toml apply --atomic-remount | tee /tmp/apply.log
+grep "Remounted in place: /tmp/svc -> /persist/tmp/svc" /tmp/apply.log || { echo "ERROR: flag ignored"; exit 1; }
+grep -x old /tmp/svc/marker
+[ "$(mounts_at /tmp/svc)" = 1 ] || { echo "ERROR: stacked mounts"; exit 1; }
+
+echo ""
+echo "✅ Atomic remount tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Atomic remount tests", test_script);
+
+    assert!(
+        output.contains("✅ Atomic remount tests passed!"),
+        "Atomic remount tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_generation_notes() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/notes
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/notes/data"]
+EOF
+$IMP --config /tmp/imp.toml apply
+$IMP --config /tmp/imp.toml apply
+
+echo "=== A note is shown by show and list --notes ==="
+$IMP --config /tmp/imp.toml note 1 "this one broke audio"
+$IMP --config /tmp/imp.toml show 1 | tee /tmp/show.log
+grep -A1 "^  Note:" /tmp/show.log | grep -x "    this one broke audio" || { echo "ERROR: note not shown"; exit 1; }
+$IMP --config /tmp/imp.toml show 2 | grep -q "Note:" && { echo "ERROR: note on the wrong generation"; exit 1; }
+$IMP --config /tmp/imp.toml list --notes | tee /tmp/list.log
+grep -A1 "^  1 - " /tmp/list.log | grep -x "      this one broke audio" || { echo "ERROR: note not listed"; exit 1; }
+$IMP --config /tmp/imp.toml list | grep -q "broke audio" && { echo "ERROR: notes listed without --notes"; exit 1; }
+
+echo "=== Notes can be appended to, replaced and cleared ==="
+$IMP --config /tmp/imp.toml note 1 --append "keep for bisecting"
+$IMP --config /tmp/imp.toml show 1 | grep -A2 "^  Note:" | tail -1 | grep -x "    keep for bisecting" || { echo "ERROR: append failed"; exit 1; }
+[ "$($IMP --config /tmp/imp.toml list --format '{number}:{note}' | head -1)" = "1:this one broke audio / keep for bisecting" ] || { echo "ERROR: {note} placeholder"; exit 1; }
+$IMP --config /tmp/imp.toml note 1 "fixed by generation 2"
+$IMP --config /tmp/imp.toml show 1 | grep -A1 "^  Note:" | grep -x "    fixed by generation 2" || { echo "ERROR: replace failed"; exit 1; }
+$IMP --config /tmp/imp.toml note 1 --clear
+$IMP --config /tmp/imp.toml show 1 | grep -q "Note:" && { echo "ERROR: clear failed"; exit 1; }
+
+echo "=== Notes don't change anything else ==="
+$IMP --config /tmp/imp.toml note 2 "current"
+$IMP --config /tmp/imp.toml current | grep "Current generation: 2"
+$IMP --config /tmp/imp.toml verify
+if $IMP --config /tmp/imp.toml note 9 "nope" 2>/tmp/err.log; then
+    echo "ERROR: noted a missing generation"; exit 1
+fi
+grep "Generation 9 not found" /tmp/err.log
+
+echo ""
+echo "✅ Generation note tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Generation note tests", test_script);
+
+    assert!(
+        output.contains("✅ Generation note tests passed!"),
+        "Generation note tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_strict_ownership() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/own/data /tmp/own
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [{ directory = "/tmp/own/data", user = "nobody", mode = "0750" }]
+EOF
+
+echo "=== Without --strict-ownership the mount shows the source's owner ==="
+$IMP --config /tmp/imp.toml apply
+[ "$(stat -c %U /tmp/own/data)" = "root" ] || { echo "ERROR: expected the source's owner"; exit 1; }
+
+echo "=== --strict-ownership fails and rolls back ==="
+if $IMP --config /tmp/imp.toml apply --strict-ownership > /tmp/strict.log 2>&1; then
+    cat /tmp/strict.log
+    echo "ERROR: apply succeeded although the owner doesn't match"; exit 1
+fi
+cat /tmp/strict.log
+grep "Requested ownership was not achieved" /tmp/strict.log
+grep "Owner of /tmp/own/data is uid 0 (expected nobody" /tmp/strict.log
+grep "ownership problem(s) found with --strict-ownership" /tmp/strict.log
+$IMP --config /tmp/imp.toml current | grep "Current generation: 1"
+$IMP --config /tmp/imp.toml list | grep -q "^  2 - " && { echo "ERROR: generation 2 recorded"; exit 1; }
+$IMP --config /tmp/imp.toml verify | grep "All symlinks are correctly configured"
+
+echo "=== Once the source has the owner, --strict-ownership succeeds ==="
+chown nobody /persist/tmp/own/data
+$IMP --config /tmp/imp.toml apply --strict-ownership
+$IMP --config /tmp/imp.toml current | grep "Current generation: 2"
+[ "$(stat -c %U:%a /tmp/own/data)" = "nobody:750" ] || { echo "ERROR: wrong ownership"; exit 1; }
+
+echo ""
+echo "✅ Strict ownership tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Strict ownership tests", test_script);
+
+    assert!(
+        output.contains("✅ Strict ownership tests passed!"),
+        "Strict ownership tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_config_from_git_ref() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+apt-get update -qq
+apt-get install -y git > /dev/null 2>&1
+
+IMP="/imp-bin/imp"
+export GIT_AUTHOR_NAME=test GIT_AUTHOR_EMAIL=test@example.com
+export GIT_COMMITTER_NAME=test GIT_COMMITTER_EMAIL=test@example.com
+
+mkdir -p /srv/configs/hosts /tmp/git-app
+cd /srv/configs
+git init -q -b main
+cat > hosts/web.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/git-app/data"]
+EOF
+git add . && git commit -qm "first"
+git tag v1
+FIRST=$(git rev-parse HEAD)
+cd /
+
+echo "=== Applying from a branch records the commit ==="
+$IMP apply --config-git "/srv/configs#main:hosts/web.toml" | tee /tmp/apply.log
+grep "Checked out /srv/configs#main:hosts/web.toml at $FIRST" /tmp/apply.log
+mount | grep "/tmp/git-app/data" || { echo "ERROR: mount missing"; exit 1; }
+$IMP --config /srv/configs/hosts/web.toml show 1 | tee /tmp/show.log
+grep "Config: /srv/configs#main:hosts/web.toml$" /tmp/show.log
+grep "Config commit: $FIRST" /tmp/show.log
+ls /tmp | grep -q imp-git-config && { echo "ERROR: checkout left behind"; exit 1; }
+
+echo "=== A new commit is picked up; a tag stays pinned ==="
+cd /srv/configs
+sed -i 's|directories = \["/tmp/git-app/data"\]|directories = ["/tmp/git-app/data", "/tmp/git-app/cache"]|' hosts/web.toml
+git commit -qam "second"
+SECOND=$(git rev-parse HEAD)
+cd /
+$IMP --config /srv/configs/hosts/web.toml show 1 --diff-config | grep "+ /tmp/git-app/cache"
+$IMP apply --config-git "/srv/configs#main:hosts/web.toml"
+$IMP --config /srv/configs/hosts/web.toml show 2 | grep "Config commit: $SECOND"
+$IMP apply --config-git "/srv/configs#v1:hosts/web.toml"
+$IMP --config /srv/configs/hosts/web.toml show 3 | tee /tmp/show.log
+grep "Config commit: $FIRST" /tmp/show.log
+grep -q "/tmp/git-app/cache" /tmp/show.log && { echo "ERROR: tag not pinned"; exit 1; }
+
+echo "=== Bad refs and paths fail before anything changes ==="
+if $IMP apply --config-git "/srv/configs#nope:hosts/web.toml" 2>/tmp/err.log; then
+    echo "ERROR: applied a missing ref"; exit 1
+fi
+grep "Failed to fetch nope from /srv/configs" /tmp/err.log
+if $IMP apply --config-git "/srv/configs#main:hosts/db.toml" 2>/tmp/err.log; then
+    echo "ERROR: applied a missing file"; exit 1
+fi
+grep "hosts/db.toml does not exist at main" /tmp/err.log
+if $IMP apply --config-git "/srv/configs" 2>/tmp/err.log; then
+    echo "ERROR: accepted a source without ref and path"; exit 1
+fi
+grep "expected <repo>#<ref>:<path>" /tmp/err.log
+for spec in "--upload-pack=touch /tmp/pwned#main:hosts/web.toml" "/srv/configs#--upload-pack=touch /tmp/pwned:hosts/web.toml"; do
+    if $IMP apply --config-git="$spec" 2>/tmp/err.log; then
+        echo "ERROR: accepted an option as repository or ref"; exit 1
+    fi
+    grep "the repository and ref can't start with '-'" /tmp/err.log || { cat /tmp/err.log; echo "ERROR: unclear error"; exit 1; }
+done
+[ ! -e /tmp/pwned ] || { echo "ERROR: git ran an injected command"; exit 1; }
+$IMP --config /srv/configs/hosts/web.toml current | grep "Current generation: 3"
+
+echo ""
+echo "✅ Git config source tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Git config source tests", test_script);
+
+    assert!(
+        output.contains("✅ Git config source tests passed!"),
+        "Git config source tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_config_schema() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+apt-get update -qq
+apt-get install -y python3-jsonschema > /dev/null 2>&1
+
+IMP="/imp-bin/imp"
+
+$IMP gen-config-schema > /tmp/imp.schema.json
+$IMP --help | grep -q "gen-config-schema" && { echo "ERROR: schema command not hidden"; exit 1; }
+
+cat > /tmp/check.py <<'EOF'
+import json, sys, jsonschema
+schema = json.load(open("/tmp/imp.schema.json"))
+jsonschema.Draft7Validator.check_schema(schema)
+try:
+    jsonschema.validate(json.load(open(sys.argv[1])), schema)
+except jsonschema.ValidationError as e:
+    print("invalid:", e.message)
+    sys.exit(1)
+print("valid")
+EOF
+
+cat > /tmp/good.json <<'EOF'
+{
+  "state_dir": "/tmp/imp-state",
+  "backup": true,
+  "backup_compression": "zstd",
+  "retention": { "keep_last": 5 },
+  "persistence": {
+    "/persist": {
+      "hideMounts": true,
+      "sourceLayout": "mirror",
+      "directories": [
+        "/var/log",
+        { "directory": "/var/lib/app", "user": "root", "mode": "0750", "on_missing_source": "create" }
+      ],
+      "files": [
+        "/etc/machine-id",
+        { "file": "/etc/app.conf", "parentDirectory": { "mode": "0755" }, "optional": true }
+      ],
+      "links": [{ "link": "/srv/app", "to": "/var/lib/app" }]
+    }
+  },
+  "env": { "web": { "persistence": { "/persist-web": { "directories": ["/srv/www"] } } } }
+}
+EOF
+
+echo "=== A known-good config validates against the schema and in imp ==="
+python3 /tmp/check.py /tmp/good.json | grep -x "valid"
+$IMP --config /tmp/good.json validate
+
+echo "=== Known-bad configs are rejected ==="
+reject() {
+    echo "$1" > /tmp/bad.json
+    if python3 /tmp/check.py /tmp/bad.json > /tmp/check.log; then
+        echo "ERROR: schema accepted $1"; exit 1
+    fi
+    cat /tmp/check.log
+    grep -q "$2" /tmp/check.log || { echo "ERROR: unexpected message for $1"; exit 1; }
+}
+reject '{"persistence": {"/p": {"directories": [{"path": "/var/log"}]}}}' "'directory' is a required property"
+reject '{"persistence": {"/p": {"files": [42]}}}' "42 is not valid"
+reject '{"state_format": "yaml"}' "'yaml' is not valid"
+reject '{"persistence": {"/p": {"directories": [{"directory": "/x", "on_missing_source": "maybe"}]}}}' "'maybe' is not valid"
+reject '{"retention": {"keep_last": -1}}' "less than the minimum"
+
+echo ""
+echo "✅ Config schema tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Config schema tests", test_script);
+
+    assert!(
+        output.contains("✅ Config schema tests passed!"),
+        "Config schema tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_generation_environment() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/envapp
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/envapp"]
+EOF
+
+echo "=== The environment is recorded when a generation is created ==="
+umask 0027
+$IMP --config /tmp/imp.toml apply
+umask 0022
+$IMP --config /tmp/imp.toml show 1 | tee /tmp/show.log
+grep "^  Environment:" /tmp/show.log
+grep "^    Hostname: $(cat /proc/sys/kernel/hostname)$" /tmp/show.log || { echo "ERROR: hostname"; exit 1; }
+grep "^    Kernel: $(uname -r)$" /tmp/show.log || { echo "ERROR: kernel"; exit 1; }
+grep -E "^    imp version: [0-9]+\.[0-9]+\.[0-9]+" /tmp/show.log || { echo "ERROR: imp version"; exit 1; }
+grep "^    Privileged (CAP_SYS_ADMIN): yes$" /tmp/show.log || { echo "ERROR: privilege"; exit 1; }
+grep "^    Container: yes$" /tmp/show.log || { echo "ERROR: container"; exit 1; }
+grep "^    Umask: 0027$" /tmp/show.log || { echo "ERROR: umask"; exit 1; }
+grep '"environment"' /tmp/imp-state/generations.json || { echo "ERROR: environment not stored"; exit 1; }
+
+echo "=== Generations from before the environment was recorded still load ==="
+perl -0pi -e 's/,\s*"environment": \{[^}]*\}//' /tmp/imp-state/generations.json
+grep '"environment"' /tmp/imp-state/generations.json && { echo "ERROR: environment not stripped"; exit 1; }
+$IMP --config /tmp/imp.toml show 1 > /tmp/show.log
+cat /tmp/show.log
+grep "Environment:" /tmp/show.log && { echo "ERROR: environment shown for an old generation"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+
+echo ""
+echo "✅ Generation environment tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Generation environment tests", test_script);
+
+    assert!(
+        output.contains("✅ Generation environment tests passed!"),
+        "Generation environment tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_verify_only_changed() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/oc /persist/tmp/oc/dir
+for i in $(seq 1 50); do echo "$i" > /persist/tmp/oc/file-$i; done
+{
+    echo 'state_dir = "/tmp/imp-state"'
+    echo ''
+    echo '[persistence."/persist"]'
+    echo 'directories = ["/tmp/oc/dir"]'
+    echo -n 'files = ['
+    for i in $(seq 1 50); do echo -n "\"/tmp/oc/file-$i\", "; done
+    echo ']'
+} > /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply > /dev/null
+
+echo "=== Without a recorded verification every entry is checked ==="
+$IMP --config /tmp/imp.toml verify --only-changed | tee /tmp/verify.log
+grep "checking every entry" /tmp/verify.log || { echo "ERROR: first run not full"; exit 1; }
+grep "Skipped" /tmp/verify.log && { echo "ERROR: entries skipped without a baseline"; exit 1; }
+$IMP --config /tmp/imp.toml show 1 | grep "Last verified:" || { echo "ERROR: verification time not recorded"; exit 1; }
+
+echo "=== Entries with unchanged sources are skipped ==="
+sleep 1
+$IMP --config /tmp/imp.toml verify --only-changed | tee /tmp/verify.log
+grep "Skipped 51 entries with unchanged sources" /tmp/verify.log || { echo "ERROR: unchanged entries checked"; exit 1; }
+
+echo "=== A changed source is checked, and a problem keeps it checked ==="
+sleep 1
+echo 'changed' > /persist/tmp/oc/file-7
+rm /tmp/oc/file-7
+ln -s /elsewhere /tmp/oc/file-7
+$IMP --config /tmp/imp.toml verify --only-changed | tee /tmp/verify.log
+grep "Skipped 50 entries" /tmp/verify.log || { echo "ERROR: changed entry not checked"; exit 1; }
+grep "/tmp/oc/file-7" /tmp/verify.log || { echo "ERROR: broken entry not reported"; exit 1; }
+$IMP --config /tmp/imp.toml verify --only-changed | grep "/tmp/oc/file-7" || { echo "ERROR: problem forgotten"; exit 1; }
+rm /tmp/oc/file-7
+ln -s /persist/tmp/oc/file-7 /tmp/oc/file-7
+$IMP --config /tmp/imp.toml verify --only-changed | grep "All symlinks are correctly configured"
+
+echo "=== Breakage that leaves the source alone is only caught by --full ==="
+sleep 1
+rm /tmp/oc/file-3
+$IMP --config /tmp/imp.toml verify --only-changed | tee /tmp/verify.log
+grep "Skipped 51 entries" /tmp/verify.log || { echo "ERROR: expected mtime to be trusted"; exit 1; }
+$IMP --config /tmp/imp.toml verify --full | tee /tmp/verify.log
+grep "Skipped" /tmp/verify.log && { echo "ERROR: --full skipped entries"; exit 1; }
+grep "/tmp/oc/file-3" /tmp/verify.log || { echo "ERROR: --full missed the broken entry"; exit 1; }
+$IMP --config /tmp/imp.toml verify --only-changed --full | grep "/tmp/oc/file-3" || { echo "ERROR: the later flag should win"; exit 1; }
+$IMP --config /tmp/imp.toml verify --fix > /dev/null
+
+echo "=== --only-changed can't be combined with --fix ==="
+if $IMP --config /tmp/imp.toml verify --only-changed --fix 2> /tmp/err.log; then
+    echo "ERROR: --only-changed --fix accepted"; exit 1
+fi
+grep "cannot be used with" /tmp/err.log
+
+echo "=== Timing: skipping unchanged entries beats checking all of them ==="
+$IMP --config /tmp/imp.toml verify --full > /dev/null
+start=$(date +%s%N)
+for i in $(seq 1 20); do $IMP --config /tmp/imp.toml verify --full > /dev/null; done
+full=$(( $(date +%s%N) - start ))
+start=$(date +%s%N)
+for i in $(seq 1 20); do $IMP --config /tmp/imp.toml verify --only-changed > /dev/null; done
+only_changed=$(( $(date +%s%N) - start ))
+echo "20 runs: --full ${full}ns, --only-changed ${only_changed}ns"
+
+echo ""
+echo "✅ Verify only-changed tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Verify only-changed tests", test_script);
+
+    assert!(
+        output.contains("✅ Verify only-changed tests passed!"),
+        "Verify only-changed tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_two_phase_apply() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/tp /persist/tmp/tp/data /persist/tmp/tp/new
+echo 'generation 1' > /persist/tmp/tp/data/marker
+echo 'config 1' > /persist/tmp/tp/app.conf
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/tp/data"]
+files = ["/tmp/tp/app.conf"]
+EOF
+
+echo "=== A two-phase apply stages, verifies and then promotes ==="
+$IMP --config /tmp/imp.toml apply --two-phase | tee /tmp/apply.log
+grep "Staging 2 symlinks in /tmp/imp-state/staging-" /tmp/apply.log || { echo "ERROR: nothing staged"; exit 1; }
+grep "Moved into place: /tmp/tp/data" /tmp/apply.log || { echo "ERROR: mount not promoted"; exit 1; }
+mountpoint -q /tmp/tp/data || { echo "ERROR: /tmp/tp/data is not mounted"; exit 1; }
+[ "$(cat /tmp/tp/data/marker)" = "generation 1" ] || { echo "ERROR: wrong mount content"; exit 1; }
+[ "$(readlink /tmp/tp/app.conf)" = "/persist/tmp/tp/app.conf" ] || { echo "ERROR: symlink not promoted"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+
+# The mount's ID changes if it is ever unmounted and mounted again
+MOUNT_ID=$(awk '$5 == "/tmp/tp/data" { print $1 }' /proc/self/mountinfo)
+
+echo "=== A config that fails staging leaves the active generation alone ==="
+# The mount shows the source's owner, so --strict-ownership fails while staging
+cat > /tmp/bad.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/tp/data", { directory = "/tmp/tp/new", user = "nobody" }]
+files = ["/tmp/tp/app.conf"]
+EOF
+if $IMP --config /tmp/bad.toml apply --two-phase --strict-ownership > /tmp/bad.log 2>&1; then
+    cat /tmp/bad.log; echo "ERROR: bad config applied"; exit 1
+fi
+cat /tmp/bad.log
+grep "found while staging; generation 1 was left untouched" /tmp/bad.log || { echo "ERROR: wrong failure"; exit 1; }
+grep "Removing symlinks from generation 1" /tmp/bad.log && { echo "ERROR: generation 1 taken down"; exit 1; }
+[ "$(awk '$5 == "/tmp/tp/data" { print $1 }' /proc/self/mountinfo)" = "$MOUNT_ID" ] || { echo "ERROR: live mount was replaced"; exit 1; }
+[ "$(cat /tmp/tp/data/marker)" = "generation 1" ] || { echo "ERROR: live mount content changed"; exit 1; }
+[ "$(readlink /tmp/tp/app.conf)" = "/persist/tmp/tp/app.conf" ] || { echo "ERROR: live symlink changed"; exit 1; }
+[ -e /tmp/tp/new ] && { echo "ERROR: new target created"; exit 1; }
+ls -a /tmp/tp | grep imp-staged && { echo "ERROR: staged symlink left behind"; exit 1; }
+ls /tmp/imp-state | grep staging && { echo "ERROR: staging directory left behind"; exit 1; }
+grep -c "/tmp/imp-state/staging" /proc/self/mountinfo && { echo "ERROR: staging mounts left behind"; exit 1; }
+$IMP --config /tmp/imp.toml list | grep "1 - .*(active)" || { echo "ERROR: generation 1 not active"; exit 1; }
+$IMP --config /tmp/imp.toml list --count | grep "total=1" || { echo "ERROR: generation recorded"; exit 1; }
+
+echo "=== A good config replaces the active generation ==="
+echo 'generation 2' > /persist/tmp/tp/new/marker
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+two_phase = true
+
+[persistence."/persist"]
+directories = ["/tmp/tp/data", "/tmp/tp/new"]
+files = ["/tmp/tp/app.conf"]
+EOF
+$IMP --config /tmp/imp.toml apply | tee /tmp/apply.log
+grep "Staging 3 symlinks" /tmp/apply.log || { echo "ERROR: two_phase in the config ignored"; exit 1; }
+[ "$(cat /tmp/tp/new/marker)" = "generation 2" ] || { echo "ERROR: new mount not promoted"; exit 1; }
+[ "$(cat /tmp/tp/data/marker)" = "generation 1" ] || { echo "ERROR: remounted content wrong"; exit 1; }
+[ "$(findmnt -n -o SOURCE /tmp/tp/data | grep -c /persist/tmp/tp/data)" = "1" ] || { echo "ERROR: mount stacked or missing"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+$IMP --config /tmp/imp.toml list | grep "2 - .*(active)" || { echo "ERROR: generation 2 not active"; exit 1; }
+grep -c "/tmp/imp-state/staging" /proc/self/mountinfo && { echo "ERROR: staging mounts left behind"; exit 1; }
+
+echo "=== Links can't be staged ==="
+cat > /tmp/links.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/tp/data"]
+links = [{ link = "/tmp/tp/link", to = "/tmp/tp/data/marker" }]
+EOF
+if $IMP --config /tmp/links.toml apply --two-phase 2> /tmp/err.log; then
+    echo "ERROR: links staged"; exit 1
+fi
+grep "can't be applied in two phases" /tmp/err.log || { echo "ERROR: wrong refusal"; exit 1; }
+
+echo ""
+echo "✅ Two-phase apply tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Two-phase apply tests", test_script);
+
+    assert!(
+        output.contains("✅ Two-phase apply tests passed!"),
+        "Two-phase apply tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_validate_against_generations() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+# Synthetic history: generation 1 mounted the data directory from an older
+# persistence directory and took a backup of app.conf; generation 2 is active
+mkdir -p /tmp/va/state /tmp/va/backups /tmp/va/old/tmp/va/srv/data /tmp/va/persist/tmp/va/srv/data
+echo 'persisted' > /tmp/va/persist/tmp/va/srv/app.conf
+echo 'original' > /tmp/va/backups/app.conf.imp-backup.1
+cat > /tmp/va/state/generations.json <<'EOF'
+{
+  "version": 2,
+  "generations": [
+    {
+      "number": 1,
+      "created_at": "2026-01-01T00:00:00Z",
+      "config_path": "/tmp/va/old.toml",
+      "symlinks": [
+        { "source": "/tmp/va/old/tmp/va/srv/data", "target": "/tmp/va/srv/data", "backup_path": null, "kind": "mount" },
+        { "source": "/tmp/va/persist/tmp/va/srv/app.conf", "target": "/tmp/va/srv/app.conf", "backup_path": "/tmp/va/backups/app.conf.imp-backup.1", "kind": "symlink" }
+      ],
+      "active": false
+    },
+    {
+      "number": 2,
+      "created_at": "2026-02-01T00:00:00Z",
+      "config_path": "/tmp/va/imp.toml",
+      "symlinks": [
+        { "source": "/tmp/va/persist/tmp/va/srv/data", "target": "/tmp/va/srv/data", "backup_path": null, "kind": "mount" },
+        { "source": "/tmp/va/persist/tmp/va/srv/app.conf", "target": "/tmp/va/srv/app.conf", "backup_path": null, "kind": "symlink" }
+      ],
+      "active": true
+    }
+  ]
+}
+EOF
+cp /tmp/va/state/generations.json /tmp/va/generations.before
+
+echo "=== A config consistent with the history is valid ==="
+cat > /tmp/va/clean.toml <<'EOF'
+state_dir = "/tmp/va/state"
+
+# Leaves out the data directory, which generation 1 persisted elsewhere
+[persistence."/tmp/va/persist"]
+files = ["/tmp/va/srv/app.conf"]
+EOF
+$IMP --config /tmp/va/clean.toml validate --against /tmp/va/state | tee /tmp/va/clean.log
+grep "is valid and consistent with the generations in /tmp/va/state" /tmp/va/clean.log || { echo "ERROR: clean config rejected"; exit 1; }
+
+echo "=== Conflicting targets and orphaned backups are reported ==="
+cat > /tmp/va/conflict.toml <<'EOF'
+state_dir = "/tmp/va/state"
+
+[retention]
+keep_last = 1
+
+[persistence."/tmp/va/persist"]
+directories = ["/tmp/va/srv/data", "/tmp/va/srv/app.conf"]
+EOF
+if $IMP --config /tmp/va/conflict.toml validate --against /tmp/va/state > /tmp/va/conflict.log; then
+    cat /tmp/va/conflict.log; echo "ERROR: conflicting config accepted"; exit 1
+fi
+cat /tmp/va/conflict.log
+grep "\[conflicting-target\] /tmp/va/srv/data in /tmp/va/persist: Target /tmp/va/srv/data is a mount from /tmp/va/old/tmp/va/srv/data in generation(s) 1" /tmp/va/conflict.log \
+    || { echo "ERROR: source conflict not reported"; exit 1; }
+grep "\[conflicting-target\] /tmp/va/srv/app.conf in /tmp/va/persist: Target /tmp/va/srv/app.conf is a symlink from .* in generation(s) 1, 2, but a mount" /tmp/va/conflict.log \
+    || { echo "ERROR: kind conflict not reported"; exit 1; }
+grep "\[orphaned-backup\] Backup /tmp/va/backups/app.conf.imp-backup.1 of /tmp/va/srv/app.conf is only recorded by generation 1" /tmp/va/conflict.log \
+    || { echo "ERROR: orphaned backup not reported"; exit 1; }
+[ "$(grep -c '^✗' /tmp/va/conflict.log)" = "3" ] || { echo "ERROR: unexpected problems"; exit 1; }
+
+$IMP --config /tmp/va/conflict.toml validate --against /tmp/va/state --json > /tmp/va/conflict.json || true
+python3 - <<'PYEOF'
+import json
+report = json.load(open("/tmp/va/conflict.json"))
+assert not report["valid"], report
+rules = sorted(p["rule"] for p in report["problems"])
+assert rules == ["conflicting-target", "conflicting-target", "orphaned-backup"], rules
+PYEOF
+
+echo "=== A backup that a kept generation records isn't orphaned ==="
+sed -i 's/keep_last = 1/keep_last = 3/' /tmp/va/conflict.toml
+$IMP --config /tmp/va/conflict.toml validate --against /tmp/va/state > /tmp/va/kept.log || true
+grep "orphaned-backup" /tmp/va/kept.log && { echo "ERROR: kept backup reported"; exit 1; }
+
+echo "=== Checking changes nothing ==="
+cmp /tmp/va/state/generations.json /tmp/va/generations.before || { echo "ERROR: state changed"; exit 1; }
+[ -e /tmp/va/backups/app.conf.imp-backup.1 ] || { echo "ERROR: backup removed"; exit 1; }
+if $IMP --config /tmp/va/clean.toml validate --against /tmp/va/missing 2> /tmp/va/err.log; then
+    echo "ERROR: missing state directory accepted"; exit 1
+fi
+grep "State directory does not exist: /tmp/va/missing" /tmp/va/err.log
+
+echo ""
+echo "✅ Validate against generations tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Validate against generations tests", test_script);
+
+    assert!(
+        output.contains("✅ Validate against generations tests passed!"),
+        "Validate against generations tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_complete_generations() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/cg /persist/tmp/cg
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = ["/tmp/cg/app.conf"]
+EOF
+
+echo "=== Without any state nothing is printed ==="
+[ -z "$($IMP --config /tmp/imp.toml __complete-generations)" ] || { echo "ERROR: output without generations"; exit 1; }
+
+for i in 1 2 3 4; do $IMP --config /tmp/imp.toml apply > /dev/null; done
+$IMP --config /tmp/imp.toml delete 2 --force > /dev/null
+$IMP --config /tmp/imp.toml note 3 $'known good\nsecond line'
+
+echo "=== The numbers are those list reports ==="
+$IMP --config /tmp/imp.toml __complete-generations | tee /tmp/complete.txt
+diff <(cut -f1 /tmp/complete.txt) <($IMP --config /tmp/imp.toml list --format '{number}') \
+    || { echo "ERROR: completion differs from list"; exit 1; }
+[ "$(grep -c . /tmp/complete.txt)" = "3" ] || { echo "ERROR: wrong number of lines"; exit 1; }
+
+echo "=== A note follows its generation's number after a tab ==="
+grep -qx $'3\tknown good' /tmp/complete.txt || { echo "ERROR: note missing"; exit 1; }
+grep -qx '1' /tmp/complete.txt || { echo "ERROR: generation without a note has extra output"; exit 1; }
+
+echo "=== The command is hidden ==="
+$IMP --help | grep -q "complete-generations" && { echo "ERROR: listed in --help"; exit 1; }
+
+echo ""
+echo "✅ Complete generations tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Complete generations tests", test_script);
+
+    assert!(
+        output.contains("✅ Complete generations tests passed!"),
+        "Complete generations tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_atime_mount_options() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+# Sources on a nosuid tmpfs, so the bind mounts start out with nosuid too
+mkdir -p /persist-at
+mount -t tmpfs -o nosuid tmpfs /persist-at
+mkdir -p /persist-at/tmp/at/cache /persist-at/tmp/at/db /persist-at/tmp/at/plain
+echo 'cached' > /persist-at/tmp/at/cache/data
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist-at"]
+directories = [
+    { directory = "/tmp/at/cache", atime = "noatime" },
+    { directory = "/tmp/at/db", atime = "relatime" },
+    "/tmp/at/plain",
+]
+EOF
+$IMP --config /tmp/imp.toml apply
+
+echo "=== The mounts carry the requested atime option ==="
+findmnt -n -o OPTIONS /tmp/at/cache
+findmnt -n -o OPTIONS /tmp/at/cache | tr ',' '\n' | grep -qx noatime || { echo "ERROR: noatime missing"; exit 1; }
+findmnt -n -o OPTIONS /tmp/at/db | tr ',' '\n' | grep -qx relatime || { echo "ERROR: relatime missing"; exit 1; }
+findmnt -n -o OPTIONS /tmp/at/cache | tr ',' '\n' | grep -qx nosuid || { echo "ERROR: remount dropped nosuid"; exit 1; }
+findmnt -n -o OPTIONS /tmp/at/plain | tr ',' '\n' | grep -qx noatime && { echo "ERROR: noatime without atime"; exit 1; }
+
+echo "=== Reading doesn't update access times ==="
+touch -a -d '2000-01-01' /tmp/at/cache/data
+before=$(stat -c %X /tmp/at/cache/data)
+cat /tmp/at/cache/data > /dev/null
+[ "$(stat -c %X /tmp/at/cache/data)" = "$before" ] || { echo "ERROR: access time updated"; exit 1; }
+
+echo "=== The option is recorded and verified ==="
+grep -q '"atime": "noatime"' /tmp/imp-state/generations.json || { echo "ERROR: noatime not recorded"; exit 1; }
+grep -q '"atime": "relatime"' /tmp/imp-state/generations.json || { echo "ERROR: relatime not recorded"; exit 1; }
+[ "$(grep -c '"atime"' /tmp/imp-state/generations.json)" = "2" ] || { echo "ERROR: atime recorded for plain entry"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+
+mount -o remount,bind,strictatime,nosuid /tmp/at/cache
+if $IMP --config /tmp/imp.toml verify --strict > /tmp/verify.log; then
+    cat /tmp/verify.log; echo "ERROR: lost noatime not detected"; exit 1
+fi
+cat /tmp/verify.log
+grep "Mount at /tmp/at/cache lost noatime" /tmp/verify.log || { echo "ERROR: wrong problem"; exit 1; }
+$IMP --config /tmp/imp.toml verify --fix
+findmnt -n -o OPTIONS /tmp/at/cache | tr ',' '\n' | grep -qx noatime || { echo "ERROR: --fix didn't restore noatime"; exit 1; }
+$IMP --config /tmp/imp.toml verify
+
+echo "=== Switching back sets it again ==="
+$IMP --config /tmp/imp.toml apply > /dev/null
+$IMP --config /tmp/imp.toml switch 1 > /dev/null
+findmnt -n -o OPTIONS /tmp/at/cache | tr ',' '\n' | grep -qx noatime || { echo "ERROR: switch lost noatime"; exit 1; }
+
+echo ""
+echo "✅ Atime mount option tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Atime mount option tests", test_script);
+
+    assert!(
+        output.contains("✅ Atime mount option tests passed!"),
+        "Atime mount option tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_debug_dump() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist/tmp/dd/cache /tmp/dd
+echo 'setting=1' > /persist/tmp/dd/app.conf
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = ["/tmp/dd/cache"]
+files = ["/tmp/dd/app.conf"]
+EOF
+
+echo "=== Without an active generation ==="
+$IMP --config /tmp/imp.toml debug-dump > /tmp/dump.txt
+cat /tmp/dump.txt
+grep -A1 '^=== active generation ===$' /tmp/dump.txt | grep -qx none || { echo "ERROR: expected no generation"; exit 1; }
+
+$IMP --config /tmp/imp.toml apply > /dev/null
+
+echo "=== The report has every section ==="
+$IMP --config /tmp/imp.toml debug-dump > /tmp/dump.txt
+cat /tmp/dump.txt
+for section in imp environment capabilities 'active generation' mountinfo config; do
+    grep -qx "=== $section ===" /tmp/dump.txt || { echo "ERROR: missing section $section"; exit 1; }
+done
+grep -qx "version: [0-9.]*" /tmp/dump.txt || { echo "ERROR: no version"; exit 1; }
+grep -qx "container: yes" /tmp/dump.txt || { echo "ERROR: container not detected"; exit 1; }
+grep -qx "CAP_SYS_ADMIN: yes" /tmp/dump.txt || { echo "ERROR: capability missing"; exit 1; }
+grep -q '"target": "/tmp/dd/cache"' /tmp/dump.txt || { echo "ERROR: generation missing"; exit 1; }
+sed -n '/^=== mountinfo ===$/,/^=== config ===$/p' /tmp/dump.txt | grep -q ' /tmp/dd/cache ' || { echo "ERROR: mount line missing"; exit 1; }
+sed -n '/^=== config ===$/,$p' /tmp/dump.txt | grep -qF 'files = ["/tmp/dd/app.conf"]' || { echo "ERROR: config missing"; exit 1; }
+
+echo "=== As JSON ==="
+$IMP --config /tmp/imp.toml debug-dump --json > /tmp/dump.json
+for key in imp_version config_path config state_dir environment capabilities active_generation mountinfo; do
+    grep -q "^  \"$key\": " /tmp/dump.json || { echo "ERROR: missing key $key"; exit 1; }
+done
+
+echo "=== With --redact-paths ==="
+$IMP --config /tmp/imp.toml debug-dump --redact-paths > /tmp/redacted.txt
+cat /tmp/redacted.txt
+if grep -E '/tmp/dd|/persist|/tmp/imp' /tmp/redacted.txt; then
+    echo "ERROR: paths left in the redacted report"; exit 1
+fi
+grep -q '"target": "<path-[0-9]*>"' /tmp/redacted.txt || { echo "ERROR: no placeholders"; exit 1; }
+sed -n '/^=== mountinfo ===$/,/^=== config ===$/p' /tmp/redacted.txt | grep -q ' <path-[0-9]*> ' || { echo "ERROR: mount line not redacted"; exit 1; }
+$IMP --config /tmp/imp.toml debug-dump --json --redact-paths | grep -q '<path-' || { echo "ERROR: JSON not redacted"; exit 1; }
+
+echo ""
+echo "✅ Debug dump tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Debug dump tests", test_script);
+
+    assert!(
+        output.contains("✅ Debug dump tests passed!"),
+        "Debug dump tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_apply_dry_run() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /tmp/dr/full /tmp/dr/empty /persist/tmp/dr
+echo 'data' > /tmp/dr/full/file
+echo 'conf' > /tmp/dr/app.conf
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [
+    "/tmp/dr/full",
+    "/tmp/dr/empty",
+    { directory = "/tmp/dr/restored", on_missing_source = "error" },
+]
+files = ["/tmp/dr/app.conf"]
+EOF
+
+snapshot() { find /tmp/dr /persist/tmp/dr | sort; cat /proc/self/mountinfo | grep /tmp/dr || true; }
+snapshot > /tmp/before.txt
+
+echo "=== The plan reports conflicts, missing sources and problems ==="
+$IMP --config /tmp/imp.toml apply --dry-run > /tmp/plan.txt || { cat /tmp/plan.txt; echo "ERROR: dry run failed"; exit 1; }
+cat /tmp/plan.txt
+grep -q '/tmp/dr/full (bind mount of /persist/tmp/dr/full)' /tmp/plan.txt || { echo "ERROR: entry missing"; exit 1; }
+grep -q '/tmp/dr/app.conf (symlink to /persist/tmp/dr/app.conf)' /tmp/plan.txt || { echo "ERROR: file entry missing"; exit 1; }
+grep -q 'Conflict: the non-empty directory at the target would be deleted' /tmp/plan.txt || { echo "ERROR: conflict not reported"; exit 1; }
+grep -q 'the empty directory at the target would be deleted' /tmp/plan.txt || { echo "ERROR: empty target not reported"; exit 1; }
+grep -q 'source does not exist; it would be created from /tmp/dr/app.conf' /tmp/plan.txt || { echo "ERROR: seeded source not reported"; exit 1; }
+grep -q '✗ \[missing-source\] /tmp/dr/restored in /persist' /tmp/plan.txt || { echo "ERROR: missing source problem not reported"; exit 1; }
+grep -q '^3 source(s) to create, 0 backup(s), 3 deletion(s), 1 conflict(s), 1 problem(s). Nothing was changed.$' /tmp/plan.txt || { echo "ERROR: wrong summary"; exit 1; }
+
+echo "=== Nothing was changed ==="
+snapshot > /tmp/after.txt
+diff /tmp/before.txt /tmp/after.txt || { echo "ERROR: dry run changed the filesystem"; exit 1; }
+if $IMP --config /tmp/imp.toml list | grep -q 'symlinks'; then
+    echo "ERROR: dry run recorded a generation"; exit 1
+fi
+
+echo "=== Against an active generation with backups ==="
+sed -i 's/^state_dir.*/&\nbackup = true/; /restored/d' /tmp/imp.toml
+$IMP --config /tmp/imp.toml apply > /dev/null
+$IMP --config /tmp/imp.toml apply --dry-run > /tmp/plan.txt
+cat /tmp/plan.txt
+grep -q 'replaces the entry of generation 1' /tmp/plan.txt || { echo "ERROR: replaced entry not reported"; exit 1; }
+grep -q 'Conflict: the non-empty directory at the target would be backed up to /tmp/dr/full.imp-backup.' /tmp/plan.txt || { echo "ERROR: backup of restored content not reported"; exit 1; }
+[ "$($IMP --config /tmp/imp.toml list --count)" = "total=1 active=1" ] || { echo "ERROR: dry run recorded a generation"; exit 1; }
+$IMP --config /tmp/imp.toml apply --dry-run --compare-to-current > /tmp/plan.txt
+grep -q "Changes compared to generation 1:" /tmp/plan.txt || { echo "ERROR: --compare-to-current ignored by --dry-run"; exit 1; }
+grep -q "0 added, 0 removed, 0 changed, 3 unchanged" /tmp/plan.txt || { echo "ERROR: wrong comparison"; exit 1; }
+
+echo ""
+echo "✅ Apply dry run tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Apply dry run tests", test_script);
+
+    assert!(
+        output.contains("✅ Apply dry run tests passed!"),
+        "Apply dry run tests did not pass. See output above."
+    );
+}
+
+#[test]
+fn test_lazy_source() {
+    let test_script = r#"
+#!/bin/bash
+set -e
+
+IMP="/imp-bin/imp"
+
+mkdir -p /persist
+cat > /tmp/imp.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+directories = [{ directory = "/tmp/lazy/app", on_missing_source = "lazy", mode = "0750" }]
+EOF
+
+echo "=== Apply leaves the target waiting for its first write ==="
+$IMP --config /tmp/imp.toml apply
+[ ! -e /persist/tmp/lazy/app ] || { echo "ERROR: lazy source was created"; exit 1; }
+[ -d /tmp/lazy/app ] || { echo "ERROR: target directory missing"; exit 1; }
+[ "$(stat -c %a /tmp/lazy/app)" = "750" ] || { echo "ERROR: mode not applied to the waiting directory"; exit 1; }
+if findmnt /tmp/lazy/app > /dev/null; then
+    echo "ERROR: waiting target is mounted"; exit 1
+fi
+grep -q '"lazy": true' /tmp/imp-state/generations.json || { echo "ERROR: lazy not recorded"; exit 1; }
+$IMP --config /tmp/imp.toml mounts > /tmp/mounts.txt
+grep -q 'waiting for its first write' /tmp/mounts.txt || { echo "ERROR: mounts doesn't show the waiting entry"; exit 1; }
+$IMP --config /tmp/imp.toml verify --strict || { echo "ERROR: waiting entry failed verify"; exit 1; }
+
+echo "=== A write is reported and moved to the source by verify --fix ==="
+echo 'state' > /tmp/lazy/app/data
+$IMP --config /tmp/imp.toml verify > /tmp/verify.txt 2>&1
+grep -q 'written to before its lazy source existed' /tmp/verify.txt || { cat /tmp/verify.txt; echo "ERROR: write not reported"; exit 1; }
+$IMP --config /tmp/imp.toml verify --fix
+[ "$(cat /persist/tmp/lazy/app/data)" = "state" ] || { echo "ERROR: content not moved to the source"; exit 1; }
+findmnt /tmp/lazy/app > /dev/null || { echo "ERROR: target not mounted after fix"; exit 1; }
+[ "$(cat /tmp/lazy/app/data)" = "state" ] || { echo "ERROR: content not visible at the target"; exit 1; }
+$IMP --config /tmp/imp.toml verify --strict || { echo "ERROR: verify failed after fix"; exit 1; }
+
+echo "=== Files can't be lazy ==="
+cat > /tmp/bad.toml <<'EOF'
+state_dir = "/tmp/imp-state"
+
+[persistence."/persist"]
+files = [{ file = "/tmp/lazy/app.conf", on_missing_source = "lazy" }]
+EOF
+if $IMP --config /tmp/bad.toml validate > /tmp/validate.txt 2>&1; then
+    echo "ERROR: lazy file entry accepted"; exit 1
+fi
+cat /tmp/validate.txt
+
+echo ""
+echo "✅ Lazy source tests passed!"
+"#;
+
+    let output = run_in_privileged_container("Lazy source tests", test_script);
+
+    assert!(
+        output.contains("✅ Lazy source tests passed!"),
+        "Lazy source tests did not pass. See output above."
+    );
+}